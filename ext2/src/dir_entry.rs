@@ -0,0 +1,110 @@
+use crate::inode::FileKind;
+use alloc::string::String;
+use alloc::vec::Vec;
+use filesystem::{Error, u16_at, u32_at};
+
+/// Size of the fixed part in front of the name.
+const HEADER_SIZE: usize = 8;
+
+/// An entry of a directory, linking a name to an inode.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: u32,
+
+    /// `None` for filesystems without the `filetype` feature, the inode has
+    /// to be read to find out.
+    pub kind: Option<FileKind>,
+}
+
+/// Parses the linked list of entries in the data of a directory. Every
+/// entry's `rec_len` points to the next one, unused entries have inode 0.
+///
+/// `.` and `..` are left out.
+pub(crate) fn parse(data: &[u8], has_file_type: bool) -> Result<Vec<DirEntry>, Error> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+
+    while offset + HEADER_SIZE <= data.len() {
+        let raw = &data[offset..];
+        let inode = u32_at(raw, 0);
+        let record_length = u16_at(raw, 4) as usize;
+        let (name_length, file_type) = if has_file_type {
+            (raw[6] as usize, raw[7])
+        } else {
+            (u16_at(raw, 6) as usize, 0)
+        };
+
+        if record_length < HEADER_SIZE
+            || record_length > raw.len()
+            || HEADER_SIZE + name_length > record_length
+        {
+            return Err(Error::CorruptedData);
+        }
+
+        let name = &raw[HEADER_SIZE..HEADER_SIZE + name_length];
+        if inode != 0 && name != b"." && name != b".." {
+            entries.push(DirEntry {
+                name: String::from_utf8_lossy(name).into(),
+                inode,
+                kind: match file_type {
+                    0 => None,
+                    1 => Some(FileKind::File),
+                    2 => Some(FileKind::Directory),
+                    7 => Some(FileKind::Symlink),
+                    _ => Some(FileKind::Other),
+                },
+            });
+        }
+
+        offset += record_length;
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(inode: u32, record_length: u16, name: &str, file_type: u8) -> Vec<u8> {
+        let mut raw = Vec::new();
+        raw.extend(inode.to_le_bytes());
+        raw.extend(record_length.to_le_bytes());
+        raw.push(name.len() as u8);
+        raw.push(file_type);
+        raw.extend(name.as_bytes());
+        raw.resize(record_length as usize, 0);
+        raw
+    }
+
+    #[test]
+    fn unused_and_dot_entries_are_skipped() {
+        let mut data = entry(2, 12, ".", 2);
+        data.extend(entry(2, 12, "..", 2));
+        data.extend(entry(12, 20, "notes.txt", 1));
+        data.extend(entry(0, 12, "gone", 1));
+        data.extend(entry(13, 1024 - 56, "bin", 2));
+
+        let entries = parse(&data, true).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "notes.txt");
+        assert_eq!(entries[0].kind, Some(FileKind::File));
+        assert_eq!(entries[1].inode, 13);
+
+        // Without the file type the byte is the upper half of the length.
+        let entries = parse(&entry(12, 16, "a", 0), false).unwrap();
+        assert_eq!(entries[0].kind, None);
+    }
+
+    #[test]
+    fn record_lengths_have_to_stay_in_the_block() {
+        let mut data = entry(12, 8, "", 1);
+        data[4..6].copy_from_slice(&4u16.to_le_bytes());
+        assert_eq!(parse(&data, true), Err(Error::CorruptedData));
+
+        let mut data = entry(12, 16, "a", 1);
+        data[4..6].copy_from_slice(&32u16.to_le_bytes());
+        assert_eq!(parse(&data, true), Err(Error::CorruptedData));
+    }
+}
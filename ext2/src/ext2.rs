@@ -0,0 +1,254 @@
+use crate::dir_entry::{self, DirEntry};
+use crate::inode::{BLOCK_POINTERS, DIRECT_BLOCKS, FileKind, Inode};
+use crate::superblock::{self, INCOMPAT_FILETYPE, SUPERBLOCK_OFFSET, SUPERBLOCK_SIZE, Superblock};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use filesystem::{BLOCK_SIZE, BlockDevice, BlockIndex, Error, INodeIndex, Metadata, u32_at};
+use spin::Mutex;
+
+const ROOT_INODE: u32 = 2;
+
+/// An ext2 filesystem, only ever read.
+///
+/// Paths are resolved without following symlinks, `read_link` returns their
+/// target. Block sizes of 1 KiB and more are mapped onto the `BLOCK_SIZE`
+/// sectors of the device.
+pub struct Ext2<D: BlockDevice> {
+    block_device: Mutex<D>,
+    superblock: Superblock,
+}
+
+impl<D: BlockDevice> Ext2<D> {
+    /// Returns `true` if the device looks like it holds an ext2 filesystem.
+    pub fn probe(block_device: &mut D) -> bool {
+        Self::read_superblock(block_device).is_ok()
+    }
+
+    pub fn mount(mut block_device: D) -> Result<Self, Error> {
+        let superblock = Self::read_superblock(&mut block_device)?;
+
+        let sectors = superblock.blocks_count as usize * superblock.sectors_per_block();
+        if sectors > block_device.total_blocks() {
+            return Err(Error::DeviceTooSmall);
+        }
+
+        Ok(Self {
+            block_device: Mutex::new(block_device),
+            superblock,
+        })
+    }
+
+    fn read_superblock(block_device: &mut D) -> Result<Superblock, Error> {
+        let mut bytes = [0; SUPERBLOCK_SIZE];
+        let first = SUPERBLOCK_OFFSET / BLOCK_SIZE;
+        for (i, sector) in bytes.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+            block_device.read_block(BlockIndex::from_raw((first + i) as u32), sector);
+        }
+        Superblock::parse(&bytes)
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.superblock.block_size
+    }
+
+    /// Returns the inode of `path`, a symlink itself rather than its target.
+    pub fn inode(&self, path: &str) -> Result<Inode, Error> {
+        let mut inode = self.read_inode(ROOT_INODE)?;
+        for name in path.split('/').filter(|s| !s.is_empty()) {
+            let entry = self
+                .dir_entries(&inode)?
+                .into_iter()
+                .find(|entry| entry.name == name)
+                .ok_or(Error::NotFound)?;
+            inode = self.read_inode(entry.inode)?;
+        }
+        Ok(inode)
+    }
+
+    pub fn stat(&self, path: &str) -> Result<Metadata, Error> {
+        let inode = self.inode(path)?;
+        let is_directory = inode.kind == FileKind::Directory;
+        let size = if is_directory {
+            self.dir_entries(&inode)?.len()
+        } else {
+            inode.size as usize
+        };
+
+        Ok(Metadata {
+            inode: INodeIndex::new(inode.number),
+            size,
+            // Both count 512 byte sectors.
+            allocated_blocks: inode.sectors as usize * 512 / BLOCK_SIZE,
+            is_directory,
+            inline: false,
+            compressed: false,
+        })
+    }
+
+    /// Returns the entries of the directory at `path`, without `.` and `..`.
+    pub fn entries(&self, path: &str) -> Result<Vec<DirEntry>, Error> {
+        self.dir_entries(&self.inode(path)?)
+    }
+
+    /// Returns the names of the entries of the directory at `path`.
+    pub fn read_dir(&self, path: &str) -> Result<Vec<String>, Error> {
+        Ok(self
+            .entries(path)?
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect())
+    }
+
+    pub fn read_bytes(&self, path: &str) -> Result<Vec<u8>, Error> {
+        let inode = self.file(path)?;
+        self.read_content(&inode)
+    }
+
+    /// Reads into `buf` starting at `offset`, returning how many bytes got
+    /// read. Nothing is read at or past the end of the file.
+    pub fn read_at(&self, path: &str, offset: usize, buf: &mut [u8]) -> Result<usize, Error> {
+        let inode = self.file(path)?;
+        self.read_inode_at(&inode, offset, buf)
+    }
+
+    /// Returns the target of the symlink at `path`.
+    pub fn read_link(&self, path: &str) -> Result<String, Error> {
+        let inode = self.inode(path)?;
+        if inode.kind != FileKind::Symlink {
+            return Err(Error::OperationNotSupported);
+        }
+
+        let target = match inode.fast_symlink(self.superblock.block_size) {
+            Some(target) => target[..inode.size as usize].to_vec(),
+            None => self.read_content(&inode)?,
+        };
+        Ok(String::from_utf8_lossy(&target).into())
+    }
+
+    fn file(&self, path: &str) -> Result<Inode, Error> {
+        let inode = self.inode(path)?;
+        match inode.kind {
+            FileKind::File => Ok(inode),
+            FileKind::Directory => Err(Error::IsDirectory),
+            FileKind::Symlink | FileKind::Other => Err(Error::OperationNotSupported),
+        }
+    }
+
+    fn read_inode(&self, number: u32) -> Result<Inode, Error> {
+        if number == 0 || number > self.superblock.inodes_count {
+            return Err(Error::CorruptedData);
+        }
+
+        let index = number - 1;
+        let group = index / self.superblock.inodes_per_group;
+        let (block, offset) = self.superblock.group_descriptor_location(group);
+        let descriptor_block = self.read_block(block)?;
+        let inode_table = superblock::inode_table(&descriptor_block[offset..]);
+
+        let inode_size = self.superblock.inode_size;
+        let position = (index % self.superblock.inodes_per_group) as usize * inode_size;
+        let block_size = self.superblock.block_size;
+        let table_block = self.read_block(inode_table + (position / block_size) as u32)?;
+
+        let raw = &table_block[position % block_size..][..inode_size];
+        Ok(Inode::parse(number, raw))
+    }
+
+    fn dir_entries(&self, inode: &Inode) -> Result<Vec<DirEntry>, Error> {
+        if inode.kind != FileKind::Directory {
+            return Err(Error::NotADirectory);
+        }
+
+        let has_file_type = self.superblock.feature_incompat & INCOMPAT_FILETYPE != 0;
+        let mut entries = dir_entry::parse(&self.read_content(inode)?, has_file_type)?;
+        for entry in entries.iter_mut().filter(|entry| entry.kind.is_none()) {
+            entry.kind = Some(self.read_inode(entry.inode)?.kind);
+        }
+        Ok(entries)
+    }
+
+    fn read_content(&self, inode: &Inode) -> Result<Vec<u8>, Error> {
+        // Holes make files bigger than their allocated blocks, but not
+        // bigger than the filesystem, so anything else is a corrupted size
+        // that mustn't get allocated.
+        let capacity = self.superblock.blocks_count as u64 * self.superblock.block_size as u64;
+        if inode.size > capacity {
+            return Err(Error::CorruptedData);
+        }
+
+        let mut bytes = vec![0; inode.size as usize];
+        let read = self.read_inode_at(inode, 0, &mut bytes)?;
+        bytes.truncate(read);
+        Ok(bytes)
+    }
+
+    fn read_inode_at(&self, inode: &Inode, offset: usize, buf: &mut [u8]) -> Result<usize, Error> {
+        let block_size = self.superblock.block_size;
+        let len = buf.len().min((inode.size as usize).saturating_sub(offset));
+
+        let mut done = 0;
+        while done < len {
+            let position = offset + done;
+            let in_block = position % block_size;
+            let chunk = (block_size - in_block).min(len - done);
+            let target = &mut buf[done..done + chunk];
+
+            match self.map_block(inode, position / block_size)? {
+                // A hole reads as zeros.
+                0 => target.fill(0),
+                block => target.copy_from_slice(&self.read_block(block)?[in_block..][..chunk]),
+            }
+            done += chunk;
+        }
+        Ok(done)
+    }
+
+    /// Returns the block holding the `index`th block of the inode's data,
+    /// 0 for holes.
+    fn map_block(&self, inode: &Inode, mut index: usize) -> Result<u32, Error> {
+        if index < DIRECT_BLOCKS {
+            return Ok(inode.blocks[index]);
+        }
+        index -= DIRECT_BLOCKS;
+
+        // Each level of indirection multiplies the blocks reachable.
+        let pointers = self.superblock.block_size / 4;
+        let mut reachable = pointers;
+        for (level, &root) in inode.blocks[DIRECT_BLOCKS..BLOCK_POINTERS]
+            .iter()
+            .enumerate()
+        {
+            if index < reachable {
+                let mut block = root;
+                for depth in (0..=level).rev() {
+                    if block == 0 {
+                        return Ok(0);
+                    }
+                    let slot = index / pointers.pow(depth as u32) % pointers;
+                    block = u32_at(&self.read_block(block)?, slot * 4);
+                }
+                return Ok(block);
+            }
+            index -= reachable;
+            reachable *= pointers;
+        }
+
+        Err(Error::FileTooLarge)
+    }
+
+    fn read_block(&self, block: u32) -> Result<Vec<u8>, Error> {
+        if block >= self.superblock.blocks_count {
+            return Err(Error::CorruptedData);
+        }
+
+        let sectors = self.superblock.sectors_per_block();
+        let first = block as usize * sectors;
+        let mut bytes = vec![0; self.superblock.block_size];
+        let mut block_device = self.block_device.lock();
+        for (i, sector) in bytes.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+            block_device.read_block(BlockIndex::from_raw((first + i) as u32), sector);
+        }
+        Ok(bytes)
+    }
+}
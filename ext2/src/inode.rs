@@ -0,0 +1,110 @@
+use filesystem::{u16_at, u32_at};
+
+/// Number of block pointers in an inode: 12 direct ones followed by a
+/// single, double and triple indirect one.
+pub(crate) const BLOCK_POINTERS: usize = 15;
+pub(crate) const DIRECT_BLOCKS: usize = 12;
+
+/// Symlinks with targets shorter than this store them in the block
+/// pointers instead of a data block.
+const FAST_SYMLINK_MAX: usize = BLOCK_POINTERS * 4;
+
+const MODE_TYPE_MASK: u16 = 0xF000;
+const MODE_DIRECTORY: u16 = 0x4000;
+const MODE_REGULAR: u16 = 0x8000;
+const MODE_SYMLINK: u16 = 0xA000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileKind {
+    File,
+    Directory,
+    Symlink,
+    /// Devices, FIFOs and sockets.
+    Other,
+}
+
+/// The metadata of a file as stored in its inode.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Inode {
+    /// Inode number, the root directory is inode 2.
+    pub number: u32,
+    pub kind: FileKind,
+
+    /// Permission bits, without the file type.
+    pub permissions: u16,
+    pub uid: u32,
+    pub gid: u32,
+
+    /// Number of directory entries pointing to the inode.
+    pub links: u16,
+    pub size: u64,
+
+    /// Last modification as seconds since the Unix epoch.
+    pub mtime: u32,
+
+    /// Allocated space in 512 byte sectors.
+    pub(crate) sectors: u32,
+    pub(crate) blocks: [u32; BLOCK_POINTERS],
+    pub(crate) file_acl: u32,
+}
+
+impl Inode {
+    pub(crate) fn parse(number: u32, raw: &[u8]) -> Self {
+        let mode = u16_at(raw, 0);
+        let kind = match mode & MODE_TYPE_MASK {
+            MODE_REGULAR => FileKind::File,
+            MODE_DIRECTORY => FileKind::Directory,
+            MODE_SYMLINK => FileKind::Symlink,
+            _ => FileKind::Other,
+        };
+
+        let mut size = u32_at(raw, 4) as u64;
+        // Only regular files use the upper half, directories used to keep
+        // their ACL there.
+        if kind == FileKind::File {
+            size |= (u32_at(raw, 108) as u64) << 32;
+        }
+
+        let mut blocks = [0; BLOCK_POINTERS];
+        for (i, block) in blocks.iter_mut().enumerate() {
+            *block = u32_at(raw, 40 + i * 4);
+        }
+
+        Self {
+            number,
+            kind,
+            permissions: mode & !MODE_TYPE_MASK,
+            uid: u16_at(raw, 2) as u32 | (u16_at(raw, 120) as u32) << 16,
+            gid: u16_at(raw, 24) as u32 | (u16_at(raw, 122) as u32) << 16,
+            links: u16_at(raw, 26),
+            size,
+            mtime: u32_at(raw, 16),
+            sectors: u32_at(raw, 28),
+            blocks,
+            file_acl: u32_at(raw, 104),
+        }
+    }
+
+    /// Returns the target of a symlink stored in the inode itself, `None`
+    /// if it's in a data block.
+    pub(crate) fn fast_symlink(&self, block_size: usize) -> Option<[u8; FAST_SYMLINK_MAX]> {
+        // An extended attribute block is counted in the sectors too.
+        let acl_sectors = if self.file_acl != 0 {
+            block_size as u32 / 512
+        } else {
+            0
+        };
+        if self.kind != FileKind::Symlink
+            || self.size as usize >= FAST_SYMLINK_MAX
+            || self.sectors != acl_sectors
+        {
+            return None;
+        }
+
+        let mut target = [0; FAST_SYMLINK_MAX];
+        for (chunk, block) in target.chunks_exact_mut(4).zip(self.blocks) {
+            chunk.copy_from_slice(&block.to_le_bytes());
+        }
+        Some(target)
+    }
+}
@@ -0,0 +1,14 @@
+//! A read-only ext2 implementation on top of `filesystem::BlockDevice`, to
+//! use images built by host tools like `mke2fs`.
+
+#![cfg_attr(not(test), no_std)]
+extern crate alloc;
+
+mod dir_entry;
+mod ext2;
+mod inode;
+mod superblock;
+
+pub use dir_entry::DirEntry;
+pub use ext2::Ext2;
+pub use inode::{FileKind, Inode};
@@ -0,0 +1,101 @@
+use filesystem::{BLOCK_SIZE, Error, u16_at, u32_at};
+
+/// The superblock always starts 1024 bytes into the device.
+pub(crate) const SUPERBLOCK_OFFSET: usize = 1024;
+pub(crate) const SUPERBLOCK_SIZE: usize = 1024;
+
+const MAGIC: u16 = 0xEF53;
+
+/// Largest block size Linux supports.
+const MAX_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Revision 0 has fixed 128 byte inodes and no feature flags.
+const GOOD_OLD_REVISION: u32 = 0;
+const GOOD_OLD_INODE_SIZE: usize = 128;
+
+/// Directory entries store the type of the file they point to.
+pub(crate) const INCOMPAT_FILETYPE: u32 = 0x0002;
+/// Group metadata can be placed anywhere, the descriptors still tell where.
+const INCOMPAT_FLEX_BG: u32 = 0x0200;
+/// Incompatible features that don't change how the filesystem is read. A
+/// journal that needs recovery, extents or 64 bit block numbers all do.
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_FILETYPE | INCOMPAT_FLEX_BG;
+
+const GROUP_DESCRIPTOR_SIZE: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Superblock {
+    pub(crate) inodes_count: u32,
+    pub(crate) blocks_count: u32,
+    pub(crate) first_data_block: u32,
+    pub(crate) block_size: usize,
+    pub(crate) inodes_per_group: u32,
+    pub(crate) inode_size: usize,
+    pub(crate) feature_incompat: u32,
+}
+
+impl Superblock {
+    /// Parses the superblock, rejecting features that would need anything
+    /// but reading the block groups, inode tables and block maps.
+    pub(crate) fn parse(bytes: &[u8]) -> Result<Self, Error> {
+        if u16_at(bytes, 56) != MAGIC {
+            return Err(Error::InvalidSuperblock);
+        }
+
+        let log_block_size = u32_at(bytes, 24);
+        let block_size = 1024usize
+            .checked_shl(log_block_size)
+            .filter(|size| *size <= MAX_BLOCK_SIZE)
+            .ok_or(Error::InvalidSuperblock)?;
+
+        let revision = u32_at(bytes, 76);
+        let (inode_size, feature_incompat) = if revision == GOOD_OLD_REVISION {
+            (GOOD_OLD_INODE_SIZE, 0)
+        } else {
+            (u16_at(bytes, 88) as usize, u32_at(bytes, 96))
+        };
+
+        let unsupported = feature_incompat & !SUPPORTED_INCOMPAT;
+        if unsupported != 0 {
+            return Err(Error::UnsupportedFeatures(unsupported));
+        }
+
+        let superblock = Self {
+            inodes_count: u32_at(bytes, 0),
+            blocks_count: u32_at(bytes, 4),
+            first_data_block: u32_at(bytes, 20),
+            block_size,
+            inodes_per_group: u32_at(bytes, 40),
+            inode_size,
+            feature_incompat,
+        };
+
+        if superblock.inodes_per_group == 0
+            || u32_at(bytes, 32) == 0
+            || !inode_size.is_power_of_two()
+            || !(GOOD_OLD_INODE_SIZE..=block_size).contains(&inode_size)
+        {
+            return Err(Error::InvalidSuperblock);
+        }
+
+        Ok(superblock)
+    }
+
+    /// Number of `BLOCK_SIZE` sectors making up a filesystem block.
+    pub(crate) fn sectors_per_block(&self) -> usize {
+        self.block_size / BLOCK_SIZE
+    }
+
+    /// Where the descriptor of `group` is stored, as block and byte offset
+    /// within it. The table follows the block holding the superblock.
+    pub(crate) fn group_descriptor_location(&self, group: u32) -> (u32, usize) {
+        let offset = group as usize * GROUP_DESCRIPTOR_SIZE;
+        let block = self.first_data_block + 1 + (offset / self.block_size) as u32;
+        (block, offset % self.block_size)
+    }
+}
+
+/// Returns the inode table block of a group descriptor.
+pub(crate) fn inode_table(descriptor: &[u8]) -> u32 {
+    u32_at(descriptor, 8)
+}
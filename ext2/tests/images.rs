@@ -0,0 +1,157 @@
+//! Reads images built by `mke2fs`, see `fixtures/README.md`.
+
+use ext2::{Ext2, FileKind};
+use filesystem::{BLOCK_SIZE, Error, MemoryDisk};
+
+const TREE_IMAGE: &[u8] = include_bytes!("fixtures/tree.img");
+const BIG_IMAGE: &[u8] = include_bytes!("fixtures/big.img");
+
+fn mount(image: &[u8]) -> Ext2<MemoryDisk> {
+    Ext2::mount(MemoryDisk(image.to_vec())).unwrap()
+}
+
+#[test]
+fn directories_list_their_entries() {
+    let fs = mount(TREE_IMAGE);
+    assert_eq!(fs.block_size(), 2048);
+
+    assert_eq!(
+        fs.read_dir("/").unwrap(),
+        ["lost+found", "bin", "docs", "hello.txt"]
+    );
+    assert_eq!(fs.read_dir("/bin").unwrap(), Vec::<String>::new());
+
+    let entries = fs.entries("/docs").unwrap();
+    let kinds: Vec<_> = entries
+        .iter()
+        .map(|entry| (entry.name.as_str(), entry.kind))
+        .collect();
+    assert_eq!(
+        kinds,
+        [
+            ("link", Some(FileKind::Symlink)),
+            ("readme.md", Some(FileKind::File)),
+            ("hello.txt", Some(FileKind::File)),
+        ]
+    );
+
+    let root = fs.stat("/").unwrap();
+    assert!(root.is_directory);
+    assert_eq!(root.size, 4);
+}
+
+#[test]
+fn hard_links_share_an_inode() {
+    let fs = mount(TREE_IMAGE);
+
+    let original = fs.inode("/hello.txt").unwrap();
+    let link = fs.inode("/docs/hello.txt").unwrap();
+    assert_eq!(original, link);
+    assert_eq!(original.links, 2);
+    assert_eq!(original.kind, FileKind::File);
+    assert_eq!(original.permissions, 0o644);
+    assert_eq!((original.uid, original.gid), (0, 0));
+
+    assert_eq!(
+        fs.read_bytes("/docs/hello.txt").unwrap(),
+        b"Hello from ext2!\n"
+    );
+    assert_eq!(
+        fs.read_bytes("/docs/readme.md").unwrap(),
+        b"# Docs\n\nConverted from an ext2 image.\n"
+    );
+}
+
+#[test]
+fn symlinks_are_not_followed() {
+    let fs = mount(TREE_IMAGE);
+
+    assert_eq!(fs.read_link("/docs/link").unwrap(), "../hello.txt");
+    assert_eq!(fs.inode("/docs/link").unwrap().kind, FileKind::Symlink);
+    assert_eq!(
+        fs.read_bytes("/docs/link"),
+        Err(Error::OperationNotSupported)
+    );
+    assert_eq!(
+        fs.read_link("/hello.txt"),
+        Err(Error::OperationNotSupported)
+    );
+}
+
+#[test]
+fn indirect_blocks_and_holes_are_read() {
+    let fs = mount(BIG_IMAGE);
+    assert_eq!(fs.block_size(), 1024);
+
+    let expected: Vec<u8> = (0..300 * 1024).map(|i| (i * 7 % 251) as u8).collect();
+    assert_eq!(fs.read_bytes("/big.bin").unwrap(), expected);
+
+    // Crosses from the single into the double indirect blocks.
+    let offset = (12 + 256) * 1024 - 100;
+    let mut buf = [0; 300];
+    assert_eq!(fs.read_at("/big.bin", offset, &mut buf), Ok(300));
+    assert_eq!(buf[..], expected[offset..offset + 300]);
+
+    let sparse = fs.read_bytes("/sparse.bin").unwrap();
+    assert_eq!(sparse.len(), 40 * 1024 + 3);
+    assert!(sparse[..40 * 1024].iter().all(|byte| *byte == 0));
+    assert_eq!(&sparse[40 * 1024..], b"end");
+
+    // Only the data block and the indirect block pointing to it.
+    assert_eq!(fs.stat("/sparse.bin").unwrap().allocated_blocks, 4);
+}
+
+#[test]
+fn lookups_report_the_kind_of_failure() {
+    let fs = mount(TREE_IMAGE);
+
+    assert_eq!(fs.read_bytes("/missing"), Err(Error::NotFound));
+    assert_eq!(fs.read_bytes("/docs"), Err(Error::IsDirectory));
+    assert_eq!(fs.read_dir("/hello.txt"), Err(Error::NotADirectory));
+    assert_eq!(fs.stat("/hello.txt/x"), Err(Error::NotADirectory));
+}
+
+#[test]
+fn only_ext2_images_mount() {
+    assert_eq!(
+        Ext2::mount(MemoryDisk(vec![0; 64 * BLOCK_SIZE])).err(),
+        Some(Error::InvalidSuperblock)
+    );
+    assert!(Ext2::probe(&mut MemoryDisk(TREE_IMAGE.to_vec())));
+
+    // Extents need more than block maps.
+    let mut image = TREE_IMAGE.to_vec();
+    image[1024 + 96] |= 0x40;
+    assert_eq!(
+        Ext2::mount(MemoryDisk(image)).err(),
+        Some(Error::UnsupportedFeatures(0x40))
+    );
+
+    let image = TREE_IMAGE[..TREE_IMAGE.len() / 2].to_vec();
+    assert_eq!(
+        Ext2::mount(MemoryDisk(image)).err(),
+        Some(Error::DeviceTooSmall)
+    );
+}
+
+#[test]
+fn sizes_beyond_the_filesystem_are_corrupted() {
+    let number = mount(TREE_IMAGE).inode("/hello.txt").unwrap().number as usize;
+
+    let mut image = TREE_IMAGE.to_vec();
+    let field = |at: usize, len: usize| {
+        let mut bytes = [0; 4];
+        bytes[..len].copy_from_slice(&image[at..at + len]);
+        u32::from_le_bytes(bytes) as usize
+    };
+    // The only group descriptor is in the block after the superblock.
+    let inode_table = field(2048 + 8, 4);
+    let inode_size = field(1024 + 88, 2);
+    let at = inode_table * 2048 + (number - 1) * inode_size;
+    // The upper half of the size, making it at least 4 GiB.
+    image[at + 108..at + 112].copy_from_slice(&1u32.to_le_bytes());
+
+    let fs = mount(&image);
+    assert!(fs.stat("/hello.txt").unwrap().size > 1 << 32);
+    assert_eq!(fs.read_bytes("/hello.txt"), Err(Error::CorruptedData));
+}
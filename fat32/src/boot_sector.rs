@@ -0,0 +1,91 @@
+use filesystem::{BLOCK_SIZE, Error, u16_at, u32_at};
+
+/// Marks the end of a valid boot sector.
+const SIGNATURE: [u8; 2] = [0x55, 0xAA];
+
+/// The fields of the BIOS Parameter Block in the first sector that are
+/// needed to find the FATs, the data region and the root directory.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct BootSector {
+    pub(crate) sectors_per_cluster: usize,
+    pub(crate) reserved_sectors: usize,
+    pub(crate) fat_count: usize,
+    pub(crate) sectors_per_fat: usize,
+    pub(crate) total_sectors: usize,
+    pub(crate) root_cluster: u32,
+}
+
+impl BootSector {
+    /// Parses the first sector of the device.
+    ///
+    /// Only FAT32 with 512 byte sectors, the size of a `BLOCK_SIZE` block, is
+    /// supported. FAT12 and FAT16 are told apart by their fixed-size root
+    /// directory and 16 bit FAT size.
+    pub(crate) fn parse(sector: &[u8]) -> Result<Self, Error> {
+        if sector[510..512] != SIGNATURE {
+            return Err(Error::InvalidSuperblock);
+        }
+
+        let bytes_per_sector = u16_at(sector, 0x0B) as usize;
+        let sectors_per_cluster = sector[0x0D] as usize;
+        let root_entries = u16_at(sector, 0x11);
+        let sectors_per_fat_16 = u16_at(sector, 0x16);
+
+        if bytes_per_sector != BLOCK_SIZE
+            || !sectors_per_cluster.is_power_of_two()
+            || root_entries != 0
+            || sectors_per_fat_16 != 0
+        {
+            return Err(Error::InvalidSuperblock);
+        }
+
+        let total_sectors = match u16_at(sector, 0x13) {
+            0 => u32_at(sector, 0x20) as usize,
+            sectors => sectors as usize,
+        };
+
+        let boot_sector = Self {
+            sectors_per_cluster,
+            reserved_sectors: u16_at(sector, 0x0E) as usize,
+            fat_count: sector[0x10] as usize,
+            sectors_per_fat: u32_at(sector, 0x24) as usize,
+            total_sectors,
+            root_cluster: u32_at(sector, 0x2C),
+        };
+
+        if boot_sector.reserved_sectors == 0
+            || boot_sector.fat_count == 0
+            || boot_sector.sectors_per_fat == 0
+            || boot_sector.first_data_sector() >= total_sectors
+            || !boot_sector.is_data_cluster(boot_sector.root_cluster)
+        {
+            return Err(Error::InvalidSuperblock);
+        }
+
+        Ok(boot_sector)
+    }
+
+    pub(crate) fn first_data_sector(&self) -> usize {
+        self.reserved_sectors + self.fat_count * self.sectors_per_fat
+    }
+
+    /// Number of clusters in the data region, the first one is cluster 2.
+    pub(crate) fn cluster_count(&self) -> usize {
+        let data_sectors = self.total_sectors - self.first_data_sector();
+        // The FAT may be too small to describe every cluster of the region.
+        (data_sectors / self.sectors_per_cluster).min(self.sectors_per_fat * BLOCK_SIZE / 4 - 2)
+    }
+
+    pub(crate) fn cluster_size(&self) -> usize {
+        self.sectors_per_cluster * BLOCK_SIZE
+    }
+
+    pub(crate) fn is_data_cluster(&self, cluster: u32) -> bool {
+        (2..self.cluster_count() as u32 + 2).contains(&cluster)
+    }
+
+    /// The first sector of `cluster`, which has to be a data cluster.
+    pub(crate) fn cluster_sector(&self, cluster: u32) -> usize {
+        self.first_data_sector() + (cluster as usize - 2) * self.sectors_per_cluster
+    }
+}
@@ -0,0 +1,195 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use filesystem::{u16_at, u32_at};
+
+pub(crate) const DIR_ENTRY_SIZE: usize = 32;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+/// Read-only, hidden, system and volume ID at once mark a long name entry.
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+/// First name byte of entries that got deleted.
+const DELETED: u8 = 0xE5;
+/// Stands in for a first name byte of 0xE5, which marks deleted entries.
+const KANJI_E5: u8 = 0x05;
+/// Flag in the sequence number of the last part of a long name.
+const LAST_LONG_ENTRY: u8 = 0x40;
+/// Number of UCS-2 characters stored in a single long name entry.
+const LONG_NAME_CHARS: usize = 13;
+
+/// Flags in the reserved byte Windows NT uses for 8.3 names in lower case.
+const LOWER_CASE_BASE: u8 = 0x08;
+const LOWER_CASE_EXTENSION: u8 = 0x10;
+
+/// A file or directory in a FAT directory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirEntry {
+    /// The long name if there is one, the 8.3 name otherwise.
+    pub name: String,
+    pub is_directory: bool,
+
+    /// Size in bytes, always 0 for directories.
+    pub size: u32,
+
+    /// First cluster of the content, 0 for empty files.
+    pub(crate) cluster: u32,
+
+    /// Byte offset of the entry on the device, unique for every entry.
+    pub(crate) position: u64,
+}
+
+/// Result of parsing a single raw entry.
+pub(crate) enum Parsed {
+    Entry(DirEntry),
+    /// Long name parts, deleted entries, volume labels, `.` and `..`.
+    Skipped,
+    /// Nothing follows in the directory.
+    End,
+}
+
+/// Parses the 32 byte entries of a directory one after another, collecting
+/// the long name entries in front of each 8.3 entry.
+#[derive(Default)]
+pub(crate) struct EntryParser {
+    /// Parts of the long name in the order they're stored, last part first.
+    long_name: Vec<[u16; LONG_NAME_CHARS]>,
+    checksum: u8,
+}
+
+impl EntryParser {
+    pub(crate) fn parse(&mut self, raw: &[u8], position: u64) -> Parsed {
+        let attributes = raw[11];
+
+        match raw[0] {
+            0 => return Parsed::End,
+            DELETED => {
+                self.long_name.clear();
+                return Parsed::Skipped;
+            }
+            _ => {}
+        }
+
+        if attributes & ATTR_LONG_NAME == ATTR_LONG_NAME {
+            self.push_long_name(raw);
+            return Parsed::Skipped;
+        }
+
+        let long_name = core::mem::take(&mut self.long_name);
+        if attributes & ATTR_VOLUME_ID != 0 || raw[0] == b'.' {
+            return Parsed::Skipped;
+        }
+
+        let short_name: &[u8; 11] = raw[..11].try_into().unwrap();
+        let name = if !long_name.is_empty() && self.checksum == checksum(short_name) {
+            decode_long_name(&long_name)
+        } else {
+            decode_short_name(short_name, raw[12])
+        };
+
+        let cluster = (u16_at(raw, 0x14) as u32) << 16 | u16_at(raw, 0x1A) as u32;
+        let is_directory = attributes & ATTR_DIRECTORY != 0;
+
+        Parsed::Entry(DirEntry {
+            name,
+            is_directory,
+            size: if is_directory { 0 } else { u32_at(raw, 0x1C) },
+            cluster,
+            position,
+        })
+    }
+
+    fn push_long_name(&mut self, raw: &[u8]) {
+        let sequence = raw[0];
+        // A new name starts with its last part, anything collected before
+        // belongs to an orphaned name.
+        if sequence & LAST_LONG_ENTRY != 0 {
+            self.long_name.clear();
+            self.checksum = raw[13];
+        } else if self.long_name.is_empty() || raw[13] != self.checksum {
+            return;
+        }
+
+        let mut chars = [0; LONG_NAME_CHARS];
+        let offsets = (1..11)
+            .step_by(2)
+            .chain((14..26).step_by(2))
+            .chain([28, 30]);
+        for (char, offset) in chars.iter_mut().zip(offsets) {
+            *char = u16_at(raw, offset);
+        }
+        self.long_name.push(chars);
+    }
+}
+
+/// The checksum of an 8.3 name stored in each of its long name entries.
+pub(crate) fn checksum(short_name: &[u8; 11]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+fn decode_long_name(parts: &[[u16; LONG_NAME_CHARS]]) -> String {
+    let units = parts
+        .iter()
+        .rev()
+        .flatten()
+        .copied()
+        .take_while(|&unit| unit != 0x0000 && unit != 0xFFFF);
+
+    char::decode_utf16(units)
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+
+/// Turns the space padded `NAME    EXT` into `NAME.EXT`.
+fn decode_short_name(short_name: &[u8; 11], case_flags: u8) -> String {
+    let mut base = short_name[..8].to_vec();
+    if base[0] == KANJI_E5 {
+        base[0] = DELETED;
+    }
+
+    let part = |bytes: &[u8], lower: bool| -> String {
+        bytes
+            .iter()
+            .take_while(|&&byte| byte != b' ')
+            .map(|&byte| {
+                let c = byte as char;
+                if lower { c.to_ascii_lowercase() } else { c }
+            })
+            .collect()
+    };
+
+    let mut name = part(&base, case_flags & LOWER_CASE_BASE != 0);
+    let extension = part(&short_name[8..], case_flags & LOWER_CASE_EXTENSION != 0);
+    if !extension.is_empty() {
+        name.push('.');
+        name.push_str(&extension);
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_names_drop_padding_and_honor_case_flags() {
+        assert_eq!(decode_short_name(b"README  TXT", 0), "README.TXT");
+        assert_eq!(
+            decode_short_name(b"MAKEFILE   ", LOWER_CASE_BASE),
+            "makefile"
+        );
+        assert_eq!(
+            decode_short_name(b"\x05BC     TXT", LOWER_CASE_EXTENSION),
+            "\u{e5}BC.txt"
+        );
+    }
+
+    #[test]
+    fn checksum_matches_the_spec() {
+        // Computed with the reference implementation from the FAT spec.
+        assert_eq!(checksum(b"README  TXT"), 0x73);
+        assert_eq!(checksum(b"           "), 0xF7);
+    }
+}
@@ -0,0 +1,254 @@
+use crate::boot_sector::BootSector;
+use crate::dir_entry::{DIR_ENTRY_SIZE, DirEntry, EntryParser, Parsed};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use filesystem::{BLOCK_SIZE, BlockDevice, BlockIndex, Error, INodeIndex, Metadata, u32_at};
+use spin::Mutex;
+
+/// FAT entries at or above this value end a cluster chain.
+const END_OF_CHAIN: u32 = 0x0FFF_FFF8;
+/// Only the lower 28 bits of a FAT32 entry are used.
+const FAT_ENTRY_MASK: u32 = 0x0FFF_FFFF;
+
+/// A FAT32 filesystem, only ever read.
+///
+/// Names are matched ignoring ASCII case like FAT does. As FAT has no inode
+/// numbers, the position of a directory entry on the device is used instead.
+/// The size of a directory is its number of entries.
+pub struct Fat32<D: BlockDevice> {
+    block_device: Mutex<D>,
+    boot_sector: BootSector,
+}
+
+/// Where a file or directory starts, the root has no directory entry.
+enum Node {
+    Root,
+    Entry(DirEntry),
+}
+
+impl<D: BlockDevice> Fat32<D> {
+    /// Returns `true` if the device looks like it holds a FAT32 filesystem.
+    pub fn probe(block_device: &mut D) -> bool {
+        let mut sector = [0; BLOCK_SIZE];
+        block_device.read_block(BlockIndex::from_raw(0), &mut sector);
+        BootSector::parse(&sector).is_ok()
+    }
+
+    pub fn mount(mut block_device: D) -> Result<Self, Error> {
+        let mut sector = [0; BLOCK_SIZE];
+        block_device.read_block(BlockIndex::from_raw(0), &mut sector);
+        let boot_sector = BootSector::parse(&sector)?;
+
+        if boot_sector.total_sectors > block_device.total_blocks() {
+            return Err(Error::DeviceTooSmall);
+        }
+
+        Ok(Self {
+            block_device: Mutex::new(block_device),
+            boot_sector,
+        })
+    }
+
+    pub fn stat(&self, path: &str) -> Result<Metadata, Error> {
+        let (inode, size, is_directory, first_cluster) = match self.node(path)? {
+            Node::Root => (
+                INodeIndex::root(),
+                self.read_dir(path)?.len(),
+                true,
+                self.boot_sector.root_cluster,
+            ),
+            Node::Entry(entry) => (
+                INodeIndex::new((entry.position / DIR_ENTRY_SIZE as u64) as u32),
+                if entry.is_directory {
+                    self.dir_entries(entry.cluster)?.len()
+                } else {
+                    entry.size as usize
+                },
+                entry.is_directory,
+                entry.cluster,
+            ),
+        };
+
+        let clusters = if first_cluster == 0 {
+            0
+        } else {
+            self.chain(first_cluster)?.len()
+        };
+
+        Ok(Metadata {
+            inode,
+            size,
+            allocated_blocks: clusters * self.boot_sector.sectors_per_cluster,
+            is_directory,
+            inline: false,
+            compressed: false,
+        })
+    }
+
+    /// Returns the entries of the directory at `path`, without `.` and `..`.
+    pub fn entries(&self, path: &str) -> Result<Vec<DirEntry>, Error> {
+        match self.node(path)? {
+            Node::Root => self.dir_entries(self.boot_sector.root_cluster),
+            Node::Entry(entry) if entry.is_directory => self.dir_entries(entry.cluster),
+            Node::Entry(_) => Err(Error::NotADirectory),
+        }
+    }
+
+    /// Returns the names of the entries of the directory at `path`.
+    pub fn read_dir(&self, path: &str) -> Result<Vec<String>, Error> {
+        Ok(self
+            .entries(path)?
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect())
+    }
+
+    pub fn read_bytes(&self, path: &str) -> Result<Vec<u8>, Error> {
+        let entry = self.file(path)?;
+        // Checks the size before allocating it.
+        let chain = self.file_chain(&entry)?;
+        let mut bytes = vec![0; entry.size as usize];
+        let read = self.read_chain_at(&entry, &chain, 0, &mut bytes)?;
+        bytes.truncate(read);
+        Ok(bytes)
+    }
+
+    /// Reads into `buf` starting at `offset`, returning how many bytes got
+    /// read. Nothing is read at or past the end of the file.
+    pub fn read_at(&self, path: &str, offset: usize, buf: &mut [u8]) -> Result<usize, Error> {
+        let entry = self.file(path)?;
+        let chain = self.file_chain(&entry)?;
+        self.read_chain_at(&entry, &chain, offset, buf)
+    }
+
+    fn file(&self, path: &str) -> Result<DirEntry, Error> {
+        match self.node(path)? {
+            Node::Entry(entry) if !entry.is_directory => Ok(entry),
+            _ => Err(Error::IsDirectory),
+        }
+    }
+
+    /// Returns the clusters of a file, which have to hold all of its bytes.
+    fn file_chain(&self, entry: &DirEntry) -> Result<Vec<u32>, Error> {
+        if entry.size == 0 {
+            return Ok(Vec::new());
+        }
+
+        let chain = self.chain(entry.cluster)?;
+        if chain.len() * self.boot_sector.cluster_size() < entry.size as usize {
+            return Err(Error::CorruptedData);
+        }
+        Ok(chain)
+    }
+
+    fn read_chain_at(
+        &self,
+        entry: &DirEntry,
+        chain: &[u32],
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, Error> {
+        let len = buf.len().min((entry.size as usize).saturating_sub(offset));
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let cluster_size = self.boot_sector.cluster_size();
+        let mut cluster = vec![0; cluster_size];
+        let mut done = 0;
+        while done < len {
+            let position = offset + done;
+            let in_cluster = position % cluster_size;
+            let chunk = (cluster_size - in_cluster).min(len - done);
+
+            self.read_cluster(chain[position / cluster_size], &mut cluster);
+            buf[done..done + chunk].copy_from_slice(&cluster[in_cluster..in_cluster + chunk]);
+            done += chunk;
+        }
+        Ok(done)
+    }
+
+    /// Finds the file or directory at the absolute `path`.
+    fn node(&self, path: &str) -> Result<Node, Error> {
+        let mut node = Node::Root;
+        for name in path.split('/').filter(|s| !s.is_empty()) {
+            let cluster = match &node {
+                Node::Root => self.boot_sector.root_cluster,
+                Node::Entry(entry) if entry.is_directory => entry.cluster,
+                Node::Entry(_) => return Err(Error::NotADirectory),
+            };
+
+            let entry = self
+                .dir_entries(cluster)?
+                .into_iter()
+                .find(|entry| entry.name.eq_ignore_ascii_case(name))
+                .ok_or(Error::NotFound)?;
+            node = Node::Entry(entry);
+        }
+        Ok(node)
+    }
+
+    fn dir_entries(&self, first_cluster: u32) -> Result<Vec<DirEntry>, Error> {
+        let cluster_size = self.boot_sector.cluster_size();
+        let mut cluster = vec![0; cluster_size];
+        let mut parser = EntryParser::default();
+        let mut entries = Vec::new();
+
+        for index in self.chain(first_cluster)? {
+            self.read_cluster(index, &mut cluster);
+            let start = (self.boot_sector.cluster_sector(index) * BLOCK_SIZE) as u64;
+
+            for (i, raw) in cluster.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+                let position = start + (i * DIR_ENTRY_SIZE) as u64;
+                match parser.parse(raw, position) {
+                    Parsed::Entry(entry) => entries.push(entry),
+                    Parsed::Skipped => {}
+                    Parsed::End => return Ok(entries),
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Follows the FAT from `first_cluster` and returns every cluster of the
+    /// chain in order.
+    fn chain(&self, first_cluster: u32) -> Result<Vec<u32>, Error> {
+        let mut chain = Vec::new();
+        let mut sector = [0; BLOCK_SIZE];
+        let mut loaded_sector = None;
+        let mut cluster = first_cluster;
+
+        loop {
+            // A chain longer than the number of clusters loops.
+            if !self.boot_sector.is_data_cluster(cluster)
+                || chain.len() >= self.boot_sector.cluster_count()
+            {
+                return Err(Error::CorruptedData);
+            }
+            chain.push(cluster);
+
+            let offset = cluster as usize * 4;
+            let fat_sector = self.boot_sector.reserved_sectors + offset / BLOCK_SIZE;
+            if loaded_sector != Some(fat_sector) {
+                self.block_device
+                    .lock()
+                    .read_block(BlockIndex::from_raw(fat_sector as u32), &mut sector);
+                loaded_sector = Some(fat_sector);
+            }
+
+            cluster = u32_at(&sector, offset % BLOCK_SIZE) & FAT_ENTRY_MASK;
+            if cluster >= END_OF_CHAIN {
+                return Ok(chain);
+            }
+        }
+    }
+
+    fn read_cluster(&self, cluster: u32, buf: &mut [u8]) {
+        let first_sector = self.boot_sector.cluster_sector(cluster);
+        let mut block_device = self.block_device.lock();
+        for (i, sector) in buf.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+            block_device.read_block(BlockIndex::from_raw((first_sector + i) as u32), sector);
+        }
+    }
+}
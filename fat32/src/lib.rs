@@ -0,0 +1,12 @@
+//! A read-only FAT32 implementation on top of `filesystem::BlockDevice`, to
+//! exchange files with images created by host tools like `mkfs.vfat`.
+
+#![cfg_attr(not(test), no_std)]
+extern crate alloc;
+
+mod boot_sector;
+mod dir_entry;
+mod fat32;
+
+pub use dir_entry::DirEntry;
+pub use fat32::Fat32;
@@ -0,0 +1,321 @@
+use fat32::Fat32;
+use filesystem::{BLOCK_SIZE, Error, MemoryDisk};
+
+enum Node {
+    File(&'static str, Vec<u8>),
+    Dir(&'static str, Vec<Node>),
+}
+
+const TOTAL_SECTORS: usize = 2048;
+const RESERVED_SECTORS: usize = 32;
+const FAT_COUNT: usize = 2;
+const SECTORS_PER_FAT: usize = TOTAL_SECTORS * 4 / BLOCK_SIZE;
+const FIRST_DATA_SECTOR: usize = RESERVED_SECTORS + FAT_COUNT * SECTORS_PER_FAT;
+
+/// Builds FAT32 images like `mkfs.vfat -F 32 -s 1` would, but small enough
+/// for tests. Clusters of a chain are handed out in descending order, so
+/// readers have to follow the FAT rather than assume contiguous files.
+struct ImageBuilder {
+    image: Vec<u8>,
+    next_cluster: u32,
+    short_names: usize,
+}
+
+impl ImageBuilder {
+    fn build(root: Vec<Node>) -> Vec<u8> {
+        let mut builder = Self {
+            image: vec![0; TOTAL_SECTORS * BLOCK_SIZE],
+            next_cluster: 2,
+            short_names: 0,
+        };
+
+        // The media descriptor and end of chain marker in the reserved
+        // entries 0 and 1.
+        builder.set_fat(0, 0x0FFF_FFF8);
+        builder.set_fat(1, 0x0FFF_FFFF);
+        let root_cluster = builder.dir(&root, true);
+
+        let boot = &mut builder.image[..BLOCK_SIZE];
+        boot[0..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+        boot[3..11].copy_from_slice(b"mkfs.fat");
+        boot[0x0B..0x0D].copy_from_slice(&(BLOCK_SIZE as u16).to_le_bytes());
+        boot[0x0D] = 1;
+        boot[0x0E..0x10].copy_from_slice(&(RESERVED_SECTORS as u16).to_le_bytes());
+        boot[0x10] = FAT_COUNT as u8;
+        boot[0x15] = 0xF8;
+        boot[0x20..0x24].copy_from_slice(&(TOTAL_SECTORS as u32).to_le_bytes());
+        boot[0x24..0x28].copy_from_slice(&(SECTORS_PER_FAT as u32).to_le_bytes());
+        boot[0x2C..0x30].copy_from_slice(&root_cluster.to_le_bytes());
+        boot[0x52..0x5A].copy_from_slice(b"FAT32   ");
+        boot[510..512].copy_from_slice(&[0x55, 0xAA]);
+
+        builder.image
+    }
+
+    fn set_fat(&mut self, cluster: u32, value: u32) {
+        for fat in 0..FAT_COUNT {
+            let start = (RESERVED_SECTORS + fat * SECTORS_PER_FAT) * BLOCK_SIZE;
+            let at = start + cluster as usize * 4;
+            self.image[at..at + 4].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    /// Stores `data` in a new cluster chain and returns its first cluster,
+    /// 0 if there is no data.
+    fn chain(&mut self, data: &[u8]) -> u32 {
+        let count = data.len().div_ceil(BLOCK_SIZE) as u32;
+        if count == 0 {
+            return 0;
+        }
+
+        let clusters: Vec<u32> = (self.next_cluster..self.next_cluster + count)
+            .rev()
+            .collect();
+        self.next_cluster += count;
+
+        for (i, &cluster) in clusters.iter().enumerate() {
+            let next = clusters.get(i + 1).copied().unwrap_or(0x0FFF_FFFF);
+            self.set_fat(cluster, next);
+
+            let chunk = &data[i * BLOCK_SIZE..data.len().min((i + 1) * BLOCK_SIZE)];
+            let at = (FIRST_DATA_SECTOR + cluster as usize - 2) * BLOCK_SIZE;
+            self.image[at..at + chunk.len()].copy_from_slice(chunk);
+        }
+        clusters[0]
+    }
+
+    fn dir(&mut self, nodes: &[Node], is_root: bool) -> u32 {
+        let mut entries = Vec::new();
+        if !is_root {
+            entries.extend(short_entry(b".          ", 0x10, 0, 0));
+            entries.extend(short_entry(b"..         ", 0x10, 0, 0));
+        }
+
+        for node in nodes {
+            let (name, attributes, cluster, size) = match node {
+                Node::File(name, data) => (*name, 0x20, self.chain(data), data.len()),
+                Node::Dir(name, children) => (*name, 0x10, self.dir(children, false), 0),
+            };
+            let short_name = self.short_name(name, &mut entries);
+            entries.extend(short_entry(&short_name, attributes, cluster, size as u32));
+        }
+
+        // An empty cluster ends the directory and keeps the root non-empty.
+        entries.resize(entries.len().next_multiple_of(BLOCK_SIZE) + BLOCK_SIZE, 0);
+        self.chain(&entries)
+    }
+
+    /// Returns the 8.3 name for `name`, adding long name entries to
+    /// `entries` if it doesn't fit.
+    fn short_name(&mut self, name: &str, entries: &mut Vec<u8>) -> [u8; 11] {
+        let (base, extension) = name.split_once('.').unwrap_or((name, ""));
+        let fits = base.len() <= 8
+            && extension.len() <= 3
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b'.');
+
+        let mut short_name = [b' '; 11];
+        if fits {
+            short_name[..base.len()].copy_from_slice(base.as_bytes());
+            short_name[8..8 + extension.len()].copy_from_slice(extension.as_bytes());
+            return short_name;
+        }
+
+        self.short_names += 1;
+        let generated = format!("LONG~{}", self.short_names);
+        short_name[..generated.len()].copy_from_slice(generated.as_bytes());
+
+        let checksum = short_name
+            .iter()
+            .fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b));
+        let mut units: Vec<u16> = name.encode_utf16().collect();
+        units.push(0);
+        units.resize(units.len().next_multiple_of(13), 0xFFFF);
+
+        let parts: Vec<&[u16]> = units.chunks(13).collect();
+        for (i, part) in parts.iter().enumerate().rev() {
+            let mut entry = [0u8; 32];
+            entry[0] = (i + 1) as u8 | if i + 1 == parts.len() { 0x40 } else { 0 };
+            entry[11] = 0x0F;
+            entry[13] = checksum;
+            let offsets = (1..11)
+                .step_by(2)
+                .chain((14..26).step_by(2))
+                .chain([28, 30]);
+            for (unit, offset) in part.iter().zip(offsets) {
+                entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            entries.extend(entry);
+        }
+        short_name
+    }
+}
+
+fn short_entry(name: &[u8; 11], attributes: u8, cluster: u32, size: u32) -> [u8; 32] {
+    let mut entry = [0u8; 32];
+    entry[..11].copy_from_slice(name);
+    entry[11] = attributes;
+    entry[0x14..0x16].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[0x1A..0x1C].copy_from_slice(&(cluster as u16).to_le_bytes());
+    entry[0x1C..0x20].copy_from_slice(&size.to_le_bytes());
+    entry
+}
+
+fn mount(root: Vec<Node>) -> Fat32<MemoryDisk> {
+    Fat32::mount(MemoryDisk(ImageBuilder::build(root))).unwrap()
+}
+
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 % 251) as u8).collect()
+}
+
+#[test]
+fn directories_list_short_and_long_names() {
+    let fs = mount(vec![
+        Node::File("README.TXT", b"hello".to_vec()),
+        Node::Dir(
+            "docs",
+            vec![
+                Node::File("a rather long file name, über 26 chars.md", b"x".to_vec()),
+                Node::Dir("EMPTY", vec![]),
+            ],
+        ),
+    ]);
+
+    assert_eq!(fs.read_dir("/").unwrap(), ["README.TXT", "docs"]);
+    assert_eq!(
+        fs.read_dir("/docs").unwrap(),
+        ["a rather long file name, über 26 chars.md", "EMPTY"]
+    );
+    assert_eq!(fs.read_dir("/docs/EMPTY").unwrap(), Vec::<String>::new());
+
+    let entries = fs.entries("/docs").unwrap();
+    assert!(!entries[0].is_directory);
+    assert_eq!(entries[0].size, 1);
+    assert!(entries[1].is_directory);
+
+    assert_eq!(fs.read_bytes("/readme.txt").unwrap(), b"hello");
+    assert_eq!(
+        fs.read_bytes("/DOCS/A RATHER LONG FILE NAME, über 26 chars.md")
+            .unwrap(),
+        b"x"
+    );
+}
+
+#[test]
+fn files_are_read_along_their_cluster_chain() {
+    let content = pattern(5 * BLOCK_SIZE + 123);
+    let fs = mount(vec![
+        Node::File("SMALL", b"abc".to_vec()),
+        Node::File("BIG.BIN", content.clone()),
+        Node::File("EMPTY", vec![]),
+    ]);
+
+    assert_eq!(fs.read_bytes("/BIG.BIN").unwrap(), content);
+    assert_eq!(fs.read_bytes("/EMPTY").unwrap(), b"");
+
+    let mut buf = [0; 600];
+    let offset = BLOCK_SIZE - 50;
+    assert_eq!(fs.read_at("/BIG.BIN", offset, &mut buf), Ok(600));
+    assert_eq!(buf[..], content[offset..offset + 600]);
+    assert_eq!(fs.read_at("/BIG.BIN", content.len() - 10, &mut buf), Ok(10));
+    assert_eq!(fs.read_at("/BIG.BIN", content.len() + 10, &mut buf), Ok(0));
+
+    let metadata = fs.stat("/BIG.BIN").unwrap();
+    assert_eq!(metadata.size, content.len());
+    assert_eq!(metadata.allocated_blocks, 6);
+    assert!(!metadata.is_directory);
+    assert_eq!(fs.stat("/EMPTY").unwrap().allocated_blocks, 0);
+}
+
+#[test]
+fn directories_span_clusters() {
+    let names: Vec<&'static str> = (0..40)
+        .map(|i| &*format!("file number {i}").leak())
+        .collect();
+    let fs = mount(vec![Node::Dir(
+        "MANY",
+        names
+            .iter()
+            .map(|name| Node::File(name, name.as_bytes().to_vec()))
+            .collect(),
+    )]);
+
+    assert_eq!(fs.read_dir("/MANY").unwrap(), names);
+    assert_eq!(
+        fs.read_bytes("/MANY/file number 39").unwrap(),
+        b"file number 39"
+    );
+    assert_eq!(fs.stat("/MANY").unwrap().size, 40);
+    assert_eq!(fs.stat("/").unwrap().size, 1);
+}
+
+#[test]
+fn lookups_report_the_kind_of_failure() {
+    let fs = mount(vec![
+        Node::File("FILE", b"abc".to_vec()),
+        Node::Dir("DIR", vec![]),
+    ]);
+
+    assert_eq!(fs.read_bytes("/missing"), Err(Error::NotFound));
+    assert_eq!(fs.read_bytes("/DIR"), Err(Error::IsDirectory));
+    assert_eq!(fs.read_dir("/FILE"), Err(Error::NotADirectory));
+    assert_eq!(fs.stat("/FILE/x").err(), Some(Error::NotADirectory));
+
+    let root = fs.stat("/").unwrap();
+    let file = fs.stat("/FILE").unwrap();
+    assert!(root.is_directory);
+    assert_ne!(root.inode, file.inode);
+}
+
+#[test]
+fn only_fat32_images_mount() {
+    assert_eq!(
+        Fat32::mount(MemoryDisk(vec![0; 64 * BLOCK_SIZE])).err(),
+        Some(Error::InvalidSuperblock)
+    );
+
+    // FAT16 has a fixed-size root directory.
+    let mut image = ImageBuilder::build(vec![]);
+    image[0x11..0x13].copy_from_slice(&512u16.to_le_bytes());
+    assert!(!Fat32::probe(&mut MemoryDisk(image)));
+
+    let mut image = ImageBuilder::build(vec![]);
+    assert!(Fat32::probe(&mut MemoryDisk(image.clone())));
+    image.truncate(TOTAL_SECTORS / 2 * BLOCK_SIZE);
+    assert_eq!(
+        Fat32::mount(MemoryDisk(image)).err(),
+        Some(Error::DeviceTooSmall)
+    );
+}
+
+#[test]
+fn looping_cluster_chains_are_corrupted() {
+    let mut image = ImageBuilder::build(vec![Node::File("LOOP", pattern(3 * BLOCK_SIZE))]);
+
+    // The file got clusters 4, 3, 2. Point the last one back to the first.
+    let at = RESERVED_SECTORS * BLOCK_SIZE + 2 * 4;
+    image[at..at + 4].copy_from_slice(&4u32.to_le_bytes());
+
+    let fs = Fat32::mount(MemoryDisk(image)).unwrap();
+    assert_eq!(fs.read_bytes("/LOOP"), Err(Error::CorruptedData));
+}
+
+#[test]
+fn sizes_beyond_the_cluster_chain_are_corrupted() {
+    let mut image = ImageBuilder::build(vec![Node::File("HUGE", pattern(10))]);
+
+    let at = image
+        .windows(11)
+        .position(|name| name == b"HUGE       ")
+        .unwrap();
+    image[at + 28..at + 32].copy_from_slice(&u32::MAX.to_le_bytes());
+
+    let fs = Fat32::mount(MemoryDisk(image)).unwrap();
+    assert_eq!(fs.read_bytes("/HUGE"), Err(Error::CorruptedData));
+    assert_eq!(
+        fs.read_at("/HUGE", 0, &mut [0; 4]),
+        Err(Error::CorruptedData)
+    );
+}
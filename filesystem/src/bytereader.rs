@@ -1,3 +1,14 @@
+/// Reads the little-endian `u16` at `offset`, for the on-disk formats of
+/// other filesystems.
+pub fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+/// Reads the little-endian `u32` at `offset`, see `u16_at`.
+pub fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
 /// Helper struct to read raw bytes.
 pub(crate) struct ByteReader<'a> {
     bytes: &'a [u8],
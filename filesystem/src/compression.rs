@@ -0,0 +1,163 @@
+//! Small LZ77-style codec used for compressed files.
+//!
+//! The stream is a sequence of tokens, each starting with a control byte:
+//!
+//! `0xxxxxxx`  literal run of `x + 1` bytes which follow the control byte
+//! `1xxxxxxx`  match of `x + MIN_MATCH` bytes, followed by a u16 (le) offset
+//!             back into the already decoded output
+
+use alloc::vec::Vec;
+
+/// Shortest match worth encoding, a match token takes 3 bytes.
+const MIN_MATCH: usize = 3;
+
+/// Longest match a single token can describe.
+const MAX_MATCH: usize = 0x7f + MIN_MATCH;
+
+/// Longest literal run a single token can describe.
+const MAX_LITERALS: usize = 0x80;
+
+/// Number of entries in the match finder hash table.
+const HASH_SIZE: usize = 1 << 12;
+
+const MATCH_FLAG: u8 = 0x80;
+
+fn hash(bytes: &[u8]) -> usize {
+    let value = u32::from(bytes[0]) | u32::from(bytes[1]) << 8 | u32::from(bytes[2]) << 16;
+    (value.wrapping_mul(2654435761) >> 20) as usize % HASH_SIZE
+}
+
+fn flush_literals(out: &mut Vec<u8>, literals: &[u8]) {
+    for chunk in literals.chunks(MAX_LITERALS) {
+        out.push((chunk.len() - 1) as u8);
+        out.extend_from_slice(chunk);
+    }
+}
+
+/// Compresses `input`. The output is never more than `input.len() / 128 + 1`
+/// bytes larger than the input.
+pub(crate) fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len());
+    let mut table = alloc::vec![usize::MAX; HASH_SIZE];
+    let mut literal_start = 0;
+    let mut pos = 0;
+
+    while pos + MIN_MATCH <= input.len() {
+        let slot = &mut table[hash(&input[pos..])];
+        let candidate = *slot;
+        *slot = pos;
+
+        let found = candidate != usize::MAX
+            && pos - candidate <= u16::MAX as usize
+            && input[candidate..candidate + MIN_MATCH] == input[pos..pos + MIN_MATCH];
+
+        if !found {
+            pos += 1;
+            continue;
+        }
+
+        let len = input[pos..]
+            .iter()
+            .zip(&input[candidate..])
+            .take(MAX_MATCH)
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        flush_literals(&mut out, &input[literal_start..pos]);
+        out.push(MATCH_FLAG | (len - MIN_MATCH) as u8);
+        out.extend_from_slice(&((pos - candidate) as u16).to_le_bytes());
+
+        pos += len;
+        literal_start = pos;
+    }
+
+    flush_literals(&mut out, &input[literal_start..]);
+    out
+}
+
+/// Decompresses `input` which must decode to exactly `len` bytes. Returns
+/// `None` if the stream is corrupted.
+pub(crate) fn decompress(input: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(len);
+    let mut pos = 0;
+
+    while pos < input.len() {
+        let control = input[pos];
+        pos += 1;
+
+        if control & MATCH_FLAG == 0 {
+            let count = control as usize + 1;
+            out.extend_from_slice(input.get(pos..pos + count)?);
+            pos += count;
+        } else {
+            let count = (control & !MATCH_FLAG) as usize + MIN_MATCH;
+            let offset = u16::from_le_bytes(input.get(pos..pos + 2)?.try_into().ok()?) as usize;
+            pos += 2;
+
+            if offset == 0 || offset > out.len() {
+                return None;
+            }
+
+            // Matches may overlap the bytes they produce, so copy bytewise.
+            let start = out.len() - offset;
+            for i in 0..count {
+                out.push(out[start + i]);
+            }
+        }
+
+        if out.len() > len {
+            return None;
+        }
+    }
+
+    (out.len() == len).then_some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(input: &[u8]) -> Vec<u8> {
+        let compressed = compress(input);
+        assert_eq!(decompress(&compressed, input.len()).unwrap(), input);
+        compressed
+    }
+
+    #[test]
+    fn roundtrips_empty_and_short_inputs() {
+        roundtrip(b"");
+        roundtrip(b"a");
+        roundtrip(b"ab");
+        roundtrip(b"abc");
+    }
+
+    #[test]
+    fn repetitive_text_shrinks() {
+        let text = "lemon shark swims in the sea. ".repeat(100);
+        let compressed = roundtrip(text.as_bytes());
+        assert!(compressed.len() < text.len() / 10);
+    }
+
+    #[test]
+    fn incompressible_input_grows_by_control_bytes_only() {
+        let mut state = 0x1234_5678u32;
+        let input: Vec<u8> = (0..4096)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        let compressed = roundtrip(&input);
+        assert!(compressed.len() <= input.len() + input.len() / MAX_LITERALS + 1);
+    }
+
+    #[test]
+    fn corrupted_streams_are_rejected() {
+        let compressed = compress(&b"abcabcabcabc".repeat(4));
+        assert_eq!(decompress(&compressed, 47), None);
+        assert_eq!(decompress(&compressed[..compressed.len() - 1], 48), None);
+        assert_eq!(decompress(&[MATCH_FLAG, 1, 0], 3), None);
+    }
+}
@@ -6,6 +6,9 @@ use crate::{
 extern crate alloc;
 use alloc::string::String;
 
+/// Max length of a file or directory name in bytes.
+pub const MAX_NAME_LEN: usize = 24;
+
 /// The `DirEntry` contains metadata about an entry in a directory such as a
 /// file or another directory which is pointed to by the `INodeIndex`.
 /// NOTE: BLOCK_SIZE must always be a multiple of `DirEntry` to ensure tighly fitted entries.
@@ -13,7 +16,7 @@ use alloc::string::String;
 #[repr(C)]
 pub(crate) struct DirEntry {
     /// Name of the directory
-    name: [u8; 24],
+    name: [u8; MAX_NAME_LEN],
 
     /// INode index of this directory
     inode: INodeIndex,
@@ -32,9 +35,9 @@ impl core::fmt::Debug for DirEntry {
 
 impl DirEntry {
     pub(crate) fn new(name_string: String, inode: INodeIndex) -> Self {
-        let mut name = [0u8; 24];
+        let mut name = [0u8; MAX_NAME_LEN];
         let bytes = name_string.as_bytes();
-        let len = bytes.len().min(24);
+        let len = bytes.len().min(MAX_NAME_LEN);
 
         name[..len].copy_from_slice(&bytes[..len]);
 
@@ -58,7 +61,7 @@ impl DiskFormat for DirEntry {
     }
 
     fn read_from(reader: &mut ByteReader) -> Self {
-        let name = reader.read_bytes(24).try_into().unwrap();
+        let name = reader.read_bytes(MAX_NAME_LEN).try_into().unwrap();
         let inode = INodeIndex::new(reader.read_u32());
         Self { name, inode }
     }
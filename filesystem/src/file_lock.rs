@@ -0,0 +1,206 @@
+use crate::{Error, INodeIndex};
+
+extern crate alloc;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// An open file or directory as returned by `Filesystem::open`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FileHandle(u32);
+
+impl FileHandle {
+    pub fn new(val: u32) -> Self {
+        Self(val)
+    }
+
+    pub fn inner(&self) -> u32 {
+        self.0
+    }
+}
+
+/// Kind of an advisory lock, like `flock(2)`. Any number of handles can
+/// hold a shared lock, an exclusive lock excludes every other handle.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LockKind {
+    Shared,
+    Exclusive,
+}
+
+/// A lock held on a file, as returned by `Filesystem::locks`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LockInfo {
+    pub handle: FileHandle,
+    pub inode: INodeIndex,
+
+    /// Path the handle was opened with.
+    pub path: String,
+    pub kind: LockKind,
+}
+
+struct OpenFile {
+    /// Opened `INode`, `None` once it got removed.
+    inode: Option<INodeIndex>,
+    path: String,
+}
+
+/// Open handles and the advisory locks they hold, only kept in memory.
+///
+/// Locks are advisory: nothing but `lock` checks them, reading and writing
+/// a locked file works as usual.
+#[derive(Default)]
+pub(crate) struct LockTable {
+    next_handle: u32,
+    open: BTreeMap<FileHandle, OpenFile>,
+
+    /// Holders of every locked `INode`, keyed by the raw `INodeIndex`.
+    holders: BTreeMap<u32, Vec<(FileHandle, LockKind)>>,
+}
+
+impl LockTable {
+    pub(crate) fn open(&mut self, inode: INodeIndex, path: &str) -> FileHandle {
+        let handle = FileHandle(self.next_handle);
+        self.next_handle += 1;
+        self.open.insert(
+            handle,
+            OpenFile {
+                inode: Some(inode),
+                path: path.into(),
+            },
+        );
+        handle
+    }
+
+    /// Closes `handle`, releasing its lock.
+    pub(crate) fn close(&mut self, handle: FileHandle) -> Result<(), Error> {
+        self.unlock(handle)?;
+        self.open.remove(&handle);
+        Ok(())
+    }
+
+    /// Drops all locks on a removed `INode` and detaches the handles opened
+    /// on it, its index may be reused by an unrelated file.
+    pub(crate) fn forget(&mut self, inode: INodeIndex) {
+        self.holders.remove(&inode.inner());
+        for file in self.open.values_mut() {
+            if file.inode == Some(inode) {
+                file.inode = None;
+            }
+        }
+    }
+
+    /// Takes or converts the lock of `handle`, returning `false` if another
+    /// handle holds a conflicting lock.
+    pub(crate) fn try_lock(&mut self, handle: FileHandle, kind: LockKind) -> Result<bool, Error> {
+        let inode = self.inode(handle)?.ok_or(Error::NotFound)?;
+        let holders = self.holders.entry(inode.inner()).or_default();
+
+        let conflict = holders.iter().any(|&(holder, held)| {
+            holder != handle && (kind == LockKind::Exclusive || held == LockKind::Exclusive)
+        });
+        if conflict {
+            return Ok(false);
+        }
+
+        match holders.iter_mut().find(|(holder, _)| *holder == handle) {
+            Some((_, held)) => *held = kind,
+            None => holders.push((handle, kind)),
+        }
+        Ok(true)
+    }
+
+    /// Releases the lock of `handle`, unlocking an unlocked handle is fine.
+    pub(crate) fn unlock(&mut self, handle: FileHandle) -> Result<(), Error> {
+        let Some(inode) = self.inode(handle)?.map(|inode| inode.inner()) else {
+            return Ok(());
+        };
+
+        if let Some(holders) = self.holders.get_mut(&inode) {
+            holders.retain(|(holder, _)| *holder != handle);
+            if holders.is_empty() {
+                self.holders.remove(&inode);
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn locks(&self) -> Vec<LockInfo> {
+        self.holders
+            .values()
+            .flatten()
+            .map(|&(handle, kind)| {
+                let file = &self.open[&handle];
+                LockInfo {
+                    handle,
+                    inode: file.inode.expect("Holders are never detached"),
+                    path: file.path.clone(),
+                    kind,
+                }
+            })
+            .collect()
+    }
+
+    /// The `INode` of an open handle, `None` if it got removed.
+    fn inode(&self, handle: FileHandle) -> Result<Option<INodeIndex>, Error> {
+        self.open
+            .get(&handle)
+            .map(|file| file.inode)
+            .ok_or(Error::BadHandle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_locks_exclude_exclusive_ones() {
+        let mut table = LockTable::default();
+        let a = table.open(INodeIndex::new(3), "/file");
+        let b = table.open(INodeIndex::new(3), "/file");
+        let other = table.open(INodeIndex::new(4), "/other");
+
+        assert_eq!(table.try_lock(a, LockKind::Shared), Ok(true));
+        assert_eq!(table.try_lock(b, LockKind::Shared), Ok(true));
+        assert_eq!(table.try_lock(b, LockKind::Exclusive), Ok(false));
+        assert_eq!(table.try_lock(other, LockKind::Exclusive), Ok(true));
+
+        table.unlock(a).unwrap();
+        assert_eq!(table.try_lock(b, LockKind::Exclusive), Ok(true));
+        assert_eq!(table.try_lock(a, LockKind::Shared), Ok(false));
+        assert_eq!(table.locks().len(), 2);
+    }
+
+    #[test]
+    fn closing_a_handle_releases_its_lock() {
+        let mut table = LockTable::default();
+        let a = table.open(INodeIndex::new(3), "/file");
+        let b = table.open(INodeIndex::new(3), "/file");
+
+        assert_eq!(table.try_lock(a, LockKind::Exclusive), Ok(true));
+        table.close(a).unwrap();
+
+        assert!(table.locks().is_empty());
+        assert_eq!(table.try_lock(b, LockKind::Exclusive), Ok(true));
+        assert_eq!(table.unlock(a), Err(Error::BadHandle));
+        assert_eq!(table.close(a), Err(Error::BadHandle));
+    }
+
+    #[test]
+    fn forgetting_an_inode_drops_its_locks() {
+        let mut table = LockTable::default();
+        let a = table.open(INodeIndex::new(3), "/file");
+        let other = table.open(INodeIndex::new(4), "/other");
+        assert_eq!(table.try_lock(a, LockKind::Exclusive), Ok(true));
+        assert_eq!(table.try_lock(other, LockKind::Shared), Ok(true));
+
+        table.forget(INodeIndex::new(3));
+        assert_eq!(table.locks().len(), 1);
+        assert_eq!(table.try_lock(a, LockKind::Shared), Err(Error::NotFound));
+        assert_eq!(table.unlock(a), Ok(()));
+        assert_eq!(table.close(a), Ok(()));
+
+        let reused = table.open(INodeIndex::new(3), "/new");
+        assert_eq!(table.try_lock(reused, LockKind::Exclusive), Ok(true));
+    }
+}
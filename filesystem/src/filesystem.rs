@@ -21,15 +21,27 @@
 
 extern crate alloc;
 use crate::bytereader::{ByteReader, ByteWriter, DiskFormat};
-use crate::dir_entry::DirEntry;
-use crate::inode::{INODE_BLOCKS, INode};
+use crate::compression;
+use crate::dir_entry::{DirEntry, MAX_NAME_LEN};
+use crate::file_lock::{FileHandle, LockInfo, LockKind, LockTable};
+use crate::inode::{INLINE_CAPACITY, INODE_BLOCKS, INODE_SIZE, INode, LEGACY_INODE_SIZE};
 use crate::inode_cache::INodeCache;
-use crate::layout::Layout;
+use crate::inode_locks::INodeLocks;
+use crate::inspect::{INodeInfo, RegionInfo, SuperBlockInfo};
+use crate::layout::{DataBlockIndex, Layout};
+use crate::snapshot::{
+    DUMP_RECORDS_PER_BLOCK, DumpBlock, MAX_SNAPSHOTS, SNAPSHOT_NAME_LEN, SnapshotEntry,
+    SnapshotInfo,
+};
+use crate::walk::Walker;
+use crate::watch::{WatchEvent, WatchId, WatchRegistry};
+use crate::xattr::{MAX_XATTR_NAME, MAX_XATTR_VALUE, MAX_XATTRS, XattrBlock};
 use crate::{BlockIndex, INodeIndex};
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 use bitmap::Bitmap;
 use core::mem;
+use spin::{Mutex, RwLock};
 
 /// Number of `DirEntry` per block.
 pub(crate) const DIR_ENTRY_PER_BLOCK: usize = BLOCK_SIZE / core::mem::size_of::<DirEntry>();
@@ -39,8 +51,14 @@ pub const BLOCK_SIZE: usize = 512;
 
 // TODO(mt): create a trait which calculates the 'per block' values to remove those ugly constants.
 
-/// Number of INodes per block.
-pub(crate) const INODES_PER_BLOCK: usize = BLOCK_SIZE / core::mem::size_of::<INode>();
+/// Max size of a file, limited by the number of blocks an `INode` can hold.
+pub(crate) const MAX_FILE_SIZE: usize = INODE_BLOCKS * BLOCK_SIZE;
+
+/// Size of the header in front of a compressed stream, holding its length.
+const COMPRESSED_HEADER_SIZE: usize = mem::size_of::<u32>();
+
+/// Number of bits tracked by a single bitmap block.
+const BITS_PER_BLOCK: usize = BLOCK_SIZE * 8;
 
 /// Max number of INodes supported by the Filesystem
 pub(crate) const MAX_INODES: usize = 4096;
@@ -50,11 +68,22 @@ const MAGIC: u64 = 0x4e4f4d454c; // lemon (le)
 
 const DIR_ENTRY_SIZE: usize = mem::size_of::<DirEntry>();
 
-/// Version of the filesystem implementation. Increment when doing a breaking change.
-const FILESYSTEM_VERSION: u32 = 2;
+/// Version of the superblock format.
+///
+/// Version 5 introduced feature flags, format changes since then are
+/// described by `Features` instead of a new version. Older versions are
+/// mapped to the features they implied, see `Features::of_legacy_version`.
+const FILESYSTEM_VERSION: u32 = 5;
+
+/// On-disk size: one little-endian u64 followed by eighteen little-endian u32 fields.
+const SUPERBLOCK_ENCODED_SIZE: usize = 8 + 18 * 4;
+
+/// `SuperBlock::state` while mounted read-write or after a crash. Images
+/// written before the state was recorded also decode as dirty.
+const STATE_DIRTY: u32 = 0;
 
-/// On-disk size: one little-endian u64 followed by twelve little-endian u32 fields.
-const SUPERBLOCK_ENCODED_SIZE: usize = 8 + 12 * 4;
+/// `SuperBlock::state` after `Filesystem::unmount`.
+const STATE_CLEAN: u32 = 1;
 
 /// The trait that any block device backend must implement.
 pub trait BlockDevice {
@@ -82,6 +111,24 @@ pub enum Error {
     InvalidSuperblock,
     DeviceTooSmall,
     UnsupportedFilesystemVersion(u32),
+    /// The image uses incompatible features this implementation doesn't know.
+    UnsupportedFeatures(u32),
+    /// The image has an older format which can only be mounted read-only
+    /// until it's converted with `Filesystem::upgrade`.
+    UpgradeRequired(u32),
+    ReadOnly,
+    CorruptedData,
+    /// The snapshot table is full, see `Filesystem::delete_snapshot`.
+    TooManySnapshots,
+    /// An extended attribute value is too large, or all of them don't fit
+    /// into their block together.
+    XattrTooLarge,
+    /// The file already has `MAX_XATTRS` extended attributes.
+    TooManyXattrs,
+    /// The `FileHandle` or `WatchId` was never opened or is already closed.
+    BadHandle,
+    /// A non-blocking `Filesystem::lock` found a conflicting lock.
+    WouldBlock,
 }
 
 impl core::error::Error for Error {}
@@ -92,6 +139,26 @@ impl core::fmt::Display for Error {
     }
 }
 
+/// Information about a file or directory as returned by `Filesystem::stat`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Metadata {
+    pub inode: INodeIndex,
+
+    /// Logical size in bytes, including holes.
+    pub size: usize,
+
+    /// Number of data blocks actually allocated on disk.
+    pub allocated_blocks: usize,
+
+    pub is_directory: bool,
+
+    /// Content is stored inside the `INode` instead of data blocks.
+    pub inline: bool,
+
+    /// Data blocks hold a compressed stream, `size` is the logical size.
+    pub compressed: bool,
+}
+
 struct Buffer {
     buf: [u8; BLOCK_SIZE],
 }
@@ -137,7 +204,6 @@ pub(crate) struct DirEntryReader<'dev, Dev> {
 
 pub(crate) struct PositionDirEntry {
     entry: DirEntry,
-    block_index: BlockIndex,
     byte_offset: usize,
     logical_index: usize,
 }
@@ -188,15 +254,13 @@ impl<'dev, Dev: BlockDevice> Iterator for DirEntryReader<'dev, Dev> {
         self.buf_pos += 1;
         self.remaining -= 1;
 
-        self.inode
-            .block(self.block_cursor - 1)
-            .to_block()
-            .map(|block_index| PositionDirEntry {
-                entry,
-                block_index,
-                byte_offset: (self.buf_pos - 1) * mem::size_of::<DirEntry>(),
-                logical_index: self.total - self.remaining - 1,
-            })
+        let block = self.inode.block(self.block_cursor - 1);
+
+        (!block.is_empty()).then(|| PositionDirEntry {
+            entry,
+            byte_offset: (self.buf_pos - 1) * mem::size_of::<DirEntry>(),
+            logical_index: self.total - self.remaining - 1,
+        })
     }
 }
 
@@ -206,6 +270,105 @@ enum Entry {
     Directory,
 }
 
+/// Feature flags stored in the `SuperBlock`, following the ext2 scheme.
+///
+/// * `compat`    - can be ignored by implementations that don't know them
+/// * `ro_compat` - unknown bits only allow mounting read-only
+/// * `incompat`  - unknown bits prevent mounting at all
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub(crate) struct Features {
+    pub(crate) compat: u32,
+    pub(crate) ro_compat: u32,
+    pub(crate) incompat: u32,
+}
+
+impl Features {
+    /// The superblock records whether the filesystem was unmounted cleanly.
+    pub(crate) const COMPAT_MOUNT_STATE: u32 = 1 << 0;
+
+    /// Files may contain holes, i.e. empty block slots below their size.
+    pub(crate) const INCOMPAT_SPARSE: u32 = 1 << 0;
+
+    /// Small files may store their content inside the `INode`.
+    pub(crate) const INCOMPAT_INLINE_DATA: u32 = 1 << 1;
+
+    /// Files may be stored as compressed streams.
+    pub(crate) const INCOMPAT_COMPRESSION: u32 = 1 << 2;
+
+    /// The layout has a snapshot region and data blocks may be shared with
+    /// snapshots, see `Filesystem::create_snapshot`.
+    pub(crate) const INCOMPAT_SNAPSHOTS: u32 = 1 << 3;
+
+    /// `INode` records have `INODE_SIZE` bytes and may point to a block of
+    /// extended attributes. Without it they have `LEGACY_INODE_SIZE` bytes.
+    pub(crate) const INCOMPAT_XATTR: u32 = 1 << 4;
+
+    /// Features enabled on every image mounted read-write. Snapshots change
+    /// the layout and are only enabled by the first snapshot.
+    const DEFAULT: Self = Self {
+        compat: Self::COMPAT_MOUNT_STATE,
+        ro_compat: 0,
+        incompat: Self::INCOMPAT_SPARSE | Self::INCOMPAT_INLINE_DATA | Self::INCOMPAT_COMPRESSION,
+    };
+
+    /// Features of a newly formatted image, including the ones which change
+    /// the layout.
+    const NEW_IMAGE: Self = Self {
+        incompat: Self::DEFAULT.incompat | Self::INCOMPAT_XATTR,
+        ..Self::DEFAULT
+    };
+
+    /// Every feature this implementation understands.
+    const SUPPORTED: Self = Self {
+        incompat: Self::NEW_IMAGE.incompat | Self::INCOMPAT_SNAPSHOTS,
+        ..Self::NEW_IMAGE
+    };
+
+    /// Features implied by the format versions before feature flags existed.
+    fn of_legacy_version(version: u32) -> Option<Self> {
+        let v3 = Self {
+            compat: Self::COMPAT_MOUNT_STATE,
+            ro_compat: 0,
+            incompat: Self::INCOMPAT_SPARSE | Self::INCOMPAT_INLINE_DATA,
+        };
+
+        match version {
+            1 | 2 => Some(Self::default()),
+            3 => Some(v3),
+            4 => Some(Self {
+                incompat: v3.incompat | Self::INCOMPAT_COMPRESSION,
+                ..v3
+            }),
+            _ => None,
+        }
+    }
+
+    fn has_snapshots(&self) -> bool {
+        self.incompat & Self::INCOMPAT_SNAPSHOTS != 0
+    }
+
+    fn has_xattr(&self) -> bool {
+        self.incompat & Self::INCOMPAT_XATTR != 0
+    }
+
+    /// Size of the `INode` records in the inode table.
+    fn inode_size(&self) -> usize {
+        if self.has_xattr() {
+            INODE_SIZE
+        } else {
+            LEGACY_INODE_SIZE
+        }
+    }
+
+    fn union(self, other: Self) -> Self {
+        Self {
+            compat: self.compat | other.compat,
+            ro_compat: self.ro_compat | other.ro_compat,
+            incompat: self.incompat | other.incompat,
+        }
+    }
+}
+
 /// The versioned `SuperBlock` describes every filesystem region on disk.
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub(crate) struct SuperBlock {
@@ -218,10 +381,14 @@ pub(crate) struct SuperBlock {
     inode_bitmap_blocks: u32,
     data_bitmap_start: u32,
     data_bitmap_blocks: u32,
+    snapshot_start: u32,
+    snapshot_blocks: u32,
     inode_table_start: u32,
     inode_table_blocks: u32,
     data_start: u32,
     data_blocks: u32,
+    state: u32,
+    features: Features,
 }
 
 impl DiskFormat for SuperBlock {
@@ -239,6 +406,12 @@ impl DiskFormat for SuperBlock {
         writer.write_u32(self.inode_table_blocks);
         writer.write_u32(self.data_start);
         writer.write_u32(self.data_blocks);
+        writer.write_u32(self.state);
+        writer.write_u32(self.features.compat);
+        writer.write_u32(self.features.ro_compat);
+        writer.write_u32(self.features.incompat);
+        writer.write_u32(self.snapshot_start);
+        writer.write_u32(self.snapshot_blocks);
     }
 
     fn read_from(reader: &mut ByteReader) -> Self {
@@ -256,12 +429,26 @@ impl DiskFormat for SuperBlock {
             inode_table_blocks: reader.read_u32(),
             data_start: reader.read_u32(),
             data_blocks: reader.read_u32(),
+            state: reader.read_u32(),
+            features: Features {
+                compat: reader.read_u32(),
+                ro_compat: reader.read_u32(),
+                incompat: reader.read_u32(),
+            },
+            snapshot_start: reader.read_u32(),
+            snapshot_blocks: reader.read_u32(),
         }
     }
 }
 
 impl SuperBlock {
-    fn from_layout(total_blocks: usize, inode_count: usize, layout: Layout) -> Self {
+    fn from_layout(
+        total_blocks: usize,
+        inode_count: usize,
+        layout: Layout,
+        state: u32,
+        features: Features,
+    ) -> Self {
         Self {
             magic: MAGIC,
             version: FILESYSTEM_VERSION,
@@ -272,25 +459,87 @@ impl SuperBlock {
             inode_bitmap_blocks: layout.inode_bitmap_blocks as u32,
             data_bitmap_start: layout.data_bitmap_start as u32,
             data_bitmap_blocks: layout.data_bitmap_blocks as u32,
+            snapshot_start: layout.snapshot_start as u32,
+            snapshot_blocks: layout.snapshot_blocks as u32,
             inode_table_start: layout.inode_table_start as u32,
             inode_table_blocks: layout.inode_table_blocks as u32,
             data_start: layout.data_start as u32,
             data_blocks: layout.data_blocks as u32,
+            state,
+            features,
+        }
+    }
+
+    /// Decodes a superblock of any known version, normalizing older versions
+    /// to the current encoding while keeping their `version`.
+    ///
+    /// Version 1 only stored the magic, block size and block count, the
+    /// regions were derived from the block count. Versions 1 and 2 didn't
+    /// record a state and are treated as clean.
+    fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        let mut superblock = Self::read_from(&mut ByteReader::new(bytes));
+
+        // Version 1 has the block size where later versions have the version.
+        if superblock.version == BLOCK_SIZE as u32 && superblock.inode_count == 0 {
+            let total_blocks = superblock.block_size as usize;
+            let layout = Layout::canonical(total_blocks, MAX_INODES, LEGACY_INODE_SIZE, false)
+                .ok_or(Error::InvalidSuperblock)?;
+            superblock = Self::from_layout(
+                total_blocks,
+                MAX_INODES,
+                layout,
+                STATE_CLEAN,
+                Features::default(),
+            );
+            superblock.magic = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+            superblock.version = 1;
+            return Ok(superblock);
+        }
+
+        if superblock.version != FILESYSTEM_VERSION {
+            // Version 1 never had a version field, see above.
+            let features = Features::of_legacy_version(superblock.version)
+                .filter(|_| superblock.version != 1)
+                .ok_or(Error::UnsupportedFilesystemVersion(superblock.version))?;
+            superblock.features = features;
+            if superblock.version == 2 {
+                superblock.state = STATE_CLEAN;
+            }
+        }
+
+        // The snapshot region is only recorded with snapshots enabled,
+        // otherwise it's empty and the inode table follows the data bitmap.
+        if !superblock.features.has_snapshots() {
+            superblock.snapshot_start = superblock
+                .data_bitmap_start
+                .wrapping_add(superblock.data_bitmap_blocks);
+            superblock.snapshot_blocks = 0;
         }
+
+        Ok(superblock)
     }
 
     fn validate(&self, device_blocks: usize) -> Result<Layout, Error> {
         if self.magic != MAGIC {
             return Err(Error::InvalidSuperblock);
         }
-        if self.version != FILESYSTEM_VERSION {
-            return Err(Error::UnsupportedFilesystemVersion(self.version));
+        if self.features.incompat & !Features::SUPPORTED.incompat != 0 {
+            return Err(Error::UnsupportedFeatures(
+                self.features.incompat & !Features::SUPPORTED.incompat,
+            ));
         }
         if self.block_size as usize != BLOCK_SIZE
-            || usize::try_from(self.total_blocks).ok() != Some(device_blocks)
+            || usize::try_from(self.total_blocks).is_ok_and(|total| total > device_blocks)
         {
             return Err(Error::InvalidSuperblock);
         }
+        if (self.total_blocks as usize) < device_blocks {
+            log::warn!(
+                "device has {device_blocks} blocks but the filesystem only uses {}, \
+                 use `grow` to make the remaining blocks available",
+                self.total_blocks
+            );
+        }
 
         let inode_count = self.inode_count as usize;
         let layout = Layout {
@@ -298,10 +547,13 @@ impl SuperBlock {
             inode_bitmap_blocks: self.inode_bitmap_blocks as usize,
             data_bitmap_start: self.data_bitmap_start as usize,
             data_bitmap_blocks: self.data_bitmap_blocks as usize,
+            snapshot_start: self.snapshot_start as usize,
+            snapshot_blocks: self.snapshot_blocks as usize,
             inode_table_start: self.inode_table_start as usize,
             inode_table_blocks: self.inode_table_blocks as usize,
             data_start: self.data_start as usize,
             data_blocks: self.data_blocks as usize,
+            inode_size: self.features.inode_size(),
         };
 
         let inode_bitmap_end = layout
@@ -312,6 +564,10 @@ impl SuperBlock {
             .data_bitmap_start
             .checked_add(layout.data_bitmap_blocks)
             .ok_or(Error::InvalidSuperblock)?;
+        let snapshot_end = layout
+            .snapshot_start
+            .checked_add(layout.snapshot_blocks)
+            .ok_or(Error::InvalidSuperblock)?;
         let inode_table_end = layout
             .inode_table_start
             .checked_add(layout.inode_table_blocks)
@@ -323,9 +579,10 @@ impl SuperBlock {
 
         if layout.inode_bitmap_start != 1
             || layout.data_bitmap_start != inode_bitmap_end
-            || layout.inode_table_start != data_bitmap_end
+            || layout.snapshot_start != data_bitmap_end
+            || layout.inode_table_start != snapshot_end
             || layout.data_start != inode_table_end
-            || data_end != device_blocks
+            || data_end != self.total_blocks as usize
         {
             return Err(Error::InvalidSuperblock);
         }
@@ -337,7 +594,7 @@ impl SuperBlock {
         };
         let inode_table_capacity = layout
             .inode_table_blocks
-            .checked_mul(INODES_PER_BLOCK)
+            .checked_mul(layout.inodes_per_block())
             .ok_or(Error::InvalidSuperblock)?;
 
         if inode_count == 0
@@ -350,7 +607,13 @@ impl SuperBlock {
             return Err(Error::InvalidSuperblock);
         }
 
-        let canonical = Layout::new(device_blocks, inode_count).ok_or(Error::InvalidSuperblock)?;
+        let canonical = Layout::canonical(
+            self.total_blocks as usize,
+            inode_count,
+            layout.inode_size,
+            self.features.has_snapshots(),
+        )
+        .ok_or(Error::InvalidSuperblock)?;
         if layout != canonical {
             return Err(Error::InvalidSuperblock);
         }
@@ -359,12 +622,6 @@ impl SuperBlock {
     }
 }
 
-struct ResolvedPath<'a> {
-    parent: INodeIndex,
-    basename: &'a str,
-    basename_inode: INodeIndex,
-}
-
 /// Terminology:
 /// * INode      - is a block of metadata about a file - written to INode blocks
 /// * DirEntry   - contains a name and the associated INode ID - is written to
@@ -372,22 +629,77 @@ struct ResolvedPath<'a> {
 /// * RawData    - contains raw file content - written to Data block
 /// * Superblock - the first block in the filesystem containing metadata
 ///   about the state of the filesystem
+///
+/// Files and directories are accessed through `&self`, so the filesystem
+/// can be shared between threads. Operations moving or replacing the whole
+/// tree, like `grow` and `rollback`, need `&mut self`.
+///
+/// Locks are always taken in this order to prevent deadlocks, later locks
+/// are only held for short updates:
+/// 1. `inode_locks`, from the root towards the leaves
+/// 2. `inode_cache`
+/// 3. `inode_bitmap`
+/// 4. `data_bitmap`, then `refcounts`
+/// 5. `block_device`, only held for single block reads and writes
+///
+/// `file_locks` and `watches` are taken last, no other lock is taken while
+/// holding them.
 pub struct Filesystem<D> {
-    block_device: D,
-    inode_bitmap: Bitmap,
-    data_bitmap: Bitmap,
-    inode_cache: INodeCache,
+    block_device: Mutex<D>,
+    inode_bitmap: Mutex<Bitmap>,
+    data_bitmap: Mutex<Bitmap>,
+    inode_cache: Mutex<INodeCache>,
+    inode_locks: INodeLocks,
+
+    /// Open handles and their advisory locks, see `Filesystem::lock`.
+    file_locks: Mutex<LockTable>,
+
+    /// Subscribers to changes, see `Filesystem::watch`.
+    watches: Mutex<WatchRegistry>,
     layout: Layout,
+
+    /// Number of blocks covered by the filesystem. The device may be larger,
+    /// see `Filesystem::grow`.
+    total_blocks: usize,
+
+    /// Features recorded in the superblock.
+    features: Features,
+
+    /// Number of references to every data block, only with snapshots
+    /// enabled. Blocks referenced more than once are shared with a snapshot
+    /// and copied before they are modified.
+    refcounts: Mutex<Option<Vec<u8>>>,
+
+    /// When set, no API is allowed to write to the `block_device`.
+    read_only: bool,
+
+    /// Whether the superblock was marked clean when this filesystem got mounted.
+    mounted_clean: bool,
 }
 
-fn entry_display(inode: &INode, name: String) -> (char, String, String) {
-    let type_char = if inode.is_directory() { 'd' } else { 'f' };
-    let size_str = if inode.is_directory() {
+impl Metadata {
+    fn new(inode_index: INodeIndex, inode: &INode) -> Self {
+        Self {
+            inode: inode_index,
+            size: inode.size() as usize,
+            allocated_blocks: inode.used_blocks().count(),
+            is_directory: inode.is_directory(),
+            inline: inode.is_inline(),
+            compressed: inode.is_compressed(),
+        }
+    }
+}
+
+/// The type, size and name columns of a directory listing, with a `/`
+/// after the names of directories.
+pub fn entry_display(metadata: &Metadata, name: String) -> (char, String, String) {
+    let type_char = if metadata.is_directory { 'd' } else { 'f' };
+    let size_str = if metadata.is_directory {
         "-".into()
     } else {
-        alloc::format!("{}", inode.size())
+        alloc::format!("{}", metadata.size)
     };
-    let display_name = if inode.is_directory() {
+    let display_name = if metadata.is_directory {
         alloc::format!("{name}/")
     } else {
         name
@@ -403,17 +715,43 @@ impl<Dev: BlockDevice> Filesystem<Dev> {
         }
         let mut buf = [0u8; BLOCK_SIZE];
         block_device.read_block(BlockIndex::from_raw(0), &mut buf);
-        Ok(SuperBlock::read_from(&mut ByteReader::new(
-            &buf[..SUPERBLOCK_ENCODED_SIZE],
-        )))
+        SuperBlock::decode(&buf[..SUPERBLOCK_ENCODED_SIZE])
+    }
+
+    pub fn new(block_device: Dev) -> Result<Self, Error> {
+        Self::mount(block_device, false)
+    }
+
+    /// Mounts the filesystem without ever writing to the `block_device`.
+    ///
+    /// Every mutating API returns `Error::ReadOnly` and `flush` is a no-op.
+    pub fn mount_read_only(block_device: Dev) -> Result<Self, Error> {
+        Self::mount(block_device, true)
     }
 
-    pub fn new(mut block_device: Dev) -> Result<Self, Error> {
+    fn mount(mut block_device: Dev, mut read_only: bool) -> Result<Self, Error> {
         let sb = Self::read_superblock(&mut block_device)?;
         let layout = sb.validate(block_device.total_blocks())?;
         let inode_count = sb.inode_count as usize;
 
-        log::info!("mounted layout: {layout:?}");
+        if !read_only && sb.version != FILESYSTEM_VERSION {
+            return Err(Error::UpgradeRequired(sb.version));
+        }
+
+        let unknown_ro_compat = sb.features.ro_compat & !Features::SUPPORTED.ro_compat;
+        if !read_only && unknown_ro_compat != 0 {
+            log::warn!(
+                "unknown read-only compatible features {unknown_ro_compat:#x}, mounting read-only"
+            );
+            read_only = true;
+        }
+
+        log::info!("mounted layout: {layout:?} read_only={read_only}");
+
+        let mounted_clean = sb.state == STATE_CLEAN;
+        if !mounted_clean {
+            log::warn!("filesystem was not cleanly unmounted, metadata might be lost");
+        }
 
         let inode_bitmap = read_bitmap(
             &mut block_device,
@@ -427,59 +765,228 @@ impl<Dev: BlockDevice> Filesystem<Dev> {
             layout.data_bitmap_blocks,
             layout.data_blocks,
         )?;
+        let refcounts = layout.has_snapshots().then(|| {
+            read_refcounts(
+                &mut block_device,
+                layout.refcount_start(),
+                layout.data_blocks,
+            )
+        });
 
         let mut fs = Self {
-            inode_bitmap,
-            data_bitmap,
-            inode_cache: INodeCache::new(layout, inode_count),
-            block_device,
+            inode_bitmap: Mutex::new(inode_bitmap),
+            data_bitmap: Mutex::new(data_bitmap),
+            inode_cache: Mutex::new(INodeCache::new(layout, inode_count)),
+            inode_locks: INodeLocks::new(inode_count),
+            file_locks: Mutex::new(LockTable::default()),
+            watches: Mutex::new(WatchRegistry::default()),
+            block_device: Mutex::new(block_device),
             layout,
+            total_blocks: sb.total_blocks as usize,
+            features: sb.features,
+            refcounts: Mutex::new(refcounts),
+            read_only,
+            mounted_clean,
         };
 
         fs.validate_root_inode()?;
 
+        // Mark the filesystem as in use so an unclean shutdown is detected on
+        // the next mount. Any file written from now on may use every feature
+        // this implementation supports, so they are enabled here as well.
+        if !read_only {
+            fs.features = fs.features.union(Features::DEFAULT);
+            fs.write_superblock_state(STATE_DIRTY);
+        }
+
         Ok(fs)
     }
 
+    /// Returns `true` if the image on `block_device` has an older format
+    /// version, which can only be mounted read-only until it's upgraded.
+    pub fn needs_upgrade(block_device: &mut Dev) -> Result<bool, Error> {
+        Ok(Self::read_superblock(block_device)?.version != FILESYSTEM_VERSION)
+    }
+
+    /// Decodes the superblock without mounting, so images which fail to
+    /// mount can still be inspected. `SuperBlockInfo::problem` holds the
+    /// error a mount would fail with.
+    pub fn inspect_superblock(block_device: &mut Dev) -> Result<SuperBlockInfo, Error> {
+        let sb = Self::read_superblock(block_device)?;
+        let region = |name, start, blocks| RegionInfo {
+            name,
+            start,
+            blocks,
+        };
+
+        Ok(SuperBlockInfo {
+            magic: sb.magic,
+            version: sb.version,
+            block_size: sb.block_size,
+            total_blocks: sb.total_blocks,
+            inode_count: sb.inode_count,
+            clean: sb.state == STATE_CLEAN,
+            compat: sb.features.compat,
+            ro_compat: sb.features.ro_compat,
+            incompat: sb.features.incompat,
+            inode_size: sb.features.inode_size(),
+            regions: alloc::vec![
+                region("superblock", 0, 1),
+                region(
+                    "inode bitmap",
+                    sb.inode_bitmap_start,
+                    sb.inode_bitmap_blocks
+                ),
+                region("data bitmap", sb.data_bitmap_start, sb.data_bitmap_blocks),
+                region("snapshots", sb.snapshot_start, sb.snapshot_blocks),
+                region("inode table", sb.inode_table_start, sb.inode_table_blocks),
+                region("data", sb.data_start, sb.data_blocks),
+            ],
+            problem: sb.validate(block_device.total_blocks()).err(),
+        })
+    }
+
+    /// Reads the record of inode `index` straight from the inode table,
+    /// without mounting. The superblock has to be valid.
+    pub fn inspect_inode(block_device: &mut Dev, index: INodeIndex) -> Result<INodeInfo, Error> {
+        let sb = Self::read_superblock(block_device)?;
+        let layout = sb.validate(block_device.total_blocks())?;
+        if index.inner() >= sb.inode_count {
+            return Err(Error::NotFound);
+        }
+
+        let mut buf = [0u8; BLOCK_SIZE];
+        let bit = index.inner() as usize;
+        let bitmap_block = layout.inode_bitmap_start + bit / BITS_PER_BLOCK;
+        block_device.read_block(BlockIndex::from_raw(bitmap_block as u32), &mut buf);
+        let word = bit % BITS_PER_BLOCK / 32 * 4;
+        let word = u32::from_le_bytes(buf[word..word + 4].try_into().unwrap());
+
+        let (block, offset) = layout.inode_to_block(index);
+        block_device.read_block(block, &mut buf);
+        let raw = buf[offset.0 as usize..][..layout.inode_size].to_vec();
+        let inode = INode::read_record(&raw);
+
+        Ok(INodeInfo {
+            index,
+            allocated: word & (1 << (bit % 32)) != 0,
+            size: inode.size(),
+            is_directory: inode.is_directory(),
+            inline: inode.is_inline(),
+            compressed: inode.is_compressed(),
+            blocks: (0..INODE_BLOCKS).map(|i| inode.block(i).raw()).collect(),
+            xattr: inode.xattr_block().raw(),
+            raw,
+        })
+    }
+
+    /// Converts an image of an older format version in place to the current
+    /// one, returning the version it had before.
+    ///
+    /// All older versions share the current layout, so only the superblock
+    /// is rewritten. Its features are the ones implied by the old version
+    /// plus everything this implementation supports. The clean state of the
+    /// image is preserved.
+    pub fn upgrade(block_device: Dev) -> Result<u32, Error> {
+        let mut fs = Self::mount(block_device, true)?;
+        let sb = Self::read_superblock(fs.block_device.get_mut())?;
+
+        if sb.version == FILESYSTEM_VERSION {
+            return Ok(sb.version);
+        }
+
+        log::info!("upgrading filesystem from version {}", sb.version);
+
+        fs.read_only = false;
+        fs.features = fs.features.union(Features::DEFAULT);
+        fs.write_superblock_state(sb.state);
+
+        Ok(sb.version)
+    }
+
     /// Formats a blank block device as a `LemonShark` filesystem.
     ///
     /// Writes the superblock, initialises empty bitmaps, creates the root
     /// directory inode with `.` and `..` entries, and flushes everything to
     /// disk.
-    pub fn format(mut block_device: Dev) -> Result<(), Error> {
+    pub fn format(block_device: Dev) -> Result<(), Error> {
+        Self::format_with_features(block_device, Features::NEW_IMAGE)
+    }
+
+    fn format_with_features(mut block_device: Dev, features: Features) -> Result<(), Error> {
         let total_blocks = block_device.total_blocks();
         if u32::try_from(total_blocks).is_err() {
             return Err(Error::DeviceTooSmall);
         }
-        let layout = Layout::new(total_blocks, MAX_INODES).ok_or(Error::DeviceTooSmall)?;
-
-        let mut fs = Self {
-            block_device,
-            inode_bitmap: Bitmap::new(MAX_INODES),
-            data_bitmap: Bitmap::new(layout.data_blocks),
-            inode_cache: INodeCache::new(layout, MAX_INODES),
+        let layout = Layout::canonical(total_blocks, MAX_INODES, features.inode_size(), false)
+            .ok_or(Error::DeviceTooSmall)?;
+
+        let fs = Self {
+            block_device: Mutex::new(block_device),
+            inode_bitmap: Mutex::new(Bitmap::new(MAX_INODES)),
+            data_bitmap: Mutex::new(Bitmap::new(layout.data_blocks)),
+            inode_cache: Mutex::new(INodeCache::new(layout, MAX_INODES)),
+            inode_locks: INodeLocks::new(MAX_INODES),
+            file_locks: Mutex::new(LockTable::default()),
+            watches: Mutex::new(WatchRegistry::default()),
             layout,
+            total_blocks,
+            features,
+            refcounts: Mutex::new(None),
+            read_only: false,
+            mounted_clean: true,
         };
 
         fs.create_empty_root();
-        fs.flush();
+        fs.unmount();
+        Ok(())
+    }
+
+    /// Returns `true` when mounted with `mount_read_only`.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Number of blocks covered by the filesystem, which is at most the size
+    /// of the block device.
+    pub fn total_blocks(&self) -> usize {
+        self.total_blocks
+    }
+
+    /// Returns `false` if the previous session didn't call `unmount`.
+    pub fn mounted_clean(&self) -> bool {
+        self.mounted_clean
+    }
+
+    fn ensure_writable(&self) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
         Ok(())
     }
 
     /// Returns a mutable reference to the underlying block device.
     /// Useful for device-specific operations like debug dumps.
     pub fn block_device_mut(&mut self) -> &mut Dev {
-        &mut self.block_device
+        self.block_device.get_mut()
+    }
+
+    /// Runs `f` with the locked block device, for raw access next to the
+    /// mounted filesystem. Writes bypass the filesystem and its caches, so
+    /// they can corrupt it.
+    pub fn with_block_device<R>(&self, f: impl FnOnce(&mut Dev) -> R) -> R {
+        f(&mut self.block_device.lock())
     }
 
     /// Reads the root `INode` at `INodeIndex(0)` and ensures it's a valid directory.
-    fn validate_root_inode(&mut self) -> Result<(), Error> {
+    fn validate_root_inode(&self) -> Result<(), Error> {
         let mut buf = [0u8; BLOCK_SIZE];
 
         let (index, offset) = self.layout.inode_to_block(INodeIndex::new(0));
-        self.block_device.read_block(index, &mut buf);
+        self.block_device.lock().read_block(index, &mut buf);
 
-        let root_node = INode::read_from(&mut ByteReader::at(&buf, offset.0 as usize));
+        let offset = offset.0 as usize;
+        let root_node = INode::read_record(&buf[offset..offset + self.layout.inode_size]);
 
         if !root_node.is_directory() {
             return Err(Error::CorruptedRoot);
@@ -489,25 +996,29 @@ impl<Dev: BlockDevice> Filesystem<Dev> {
     }
 
     /// Writes an `INode` to disk.
-    fn write_inode_to_disk(&mut self, inode_index: INodeIndex, inode: &INode) {
+    fn write_inode_to_disk(&self, inode_index: INodeIndex, inode: &INode) {
         let (block_index, byte_offset) = self.layout.inode_to_block(inode_index);
 
-        modify_block(&mut self.block_device, block_index, |buf| {
-            buf.write_struct_at(inode, byte_offset.0 as usize);
+        let offset = byte_offset.0 as usize;
+        let inode_size = self.layout.inode_size;
+        modify_block(&mut *self.block_device.lock(), block_index, |buf| {
+            inode.write_record(&mut buf.inner()[offset..offset + inode_size]);
         });
     }
 
     /// Writes a new `INode` to disk returning it's `INodeIndex`.
-    fn new_inode(&mut self, inode: &INode) -> Option<INodeIndex> {
-        let free = INodeIndex::new(self.inode_bitmap.find_free()?.try_into().ok()?);
-
-        log::trace!("writing inode to {free:?} in {:?}", self.inode_bitmap);
-
-        self.inode_bitmap.set(free.inner() as usize);
+    fn new_inode(&self, inode: &INode) -> Option<INodeIndex> {
+        let free = {
+            let mut inode_bitmap = self.inode_bitmap.lock();
+            let free = INodeIndex::new(inode_bitmap.find_free()?.try_into().ok()?);
+            log::trace!("writing inode to {free:?} in {inode_bitmap:?}");
+            inode_bitmap.set(free.inner() as usize);
+            free
+        };
 
         self.write_inode_to_disk(free, inode);
 
-        self.inode_cache.register_new_inode(free, *inode);
+        self.inode_cache.lock().register_new_inode(free, *inode);
 
         Some(free)
     }
@@ -520,58 +1031,177 @@ impl<Dev: BlockDevice> Filesystem<Dev> {
         }
     }
 
-    /// Resovles a `Path` by walking from the root until the leaf is found.
+    /// Walks the directories `parts` starting at the root, returning the
+    /// last one.
+    ///
+    /// Only the directory being read is locked. Directories are never
+    /// freed, so an `INodeIndex` found in a parent stays valid after its
+    /// lock is released.
+    fn walk_dirs<'a>(&self, parts: impl Iterator<Item = &'a str>) -> Result<INodeIndex, Error> {
+        let mut current = INodeIndex::root();
+
+        for part in parts {
+            let next = {
+                let _current = self.inode_locks.read(current);
+                self.find_entry(current, part)?
+            };
+
+            // TODO(mt): this check doens't allow files and directories to have the same name. That is fine for now!
+            if !self.lookup_inode(next).is_directory() {
+                return Err(Error::NotADirectory);
+            }
+
+            current = next;
+        }
+
+        Ok(current)
+    }
+
+    /// Looks up `name` in the directory `dir`, which has to be locked by
+    /// the caller.
+    fn find_entry(&self, dir: INodeIndex, name: &str) -> Result<INodeIndex, Error> {
+        self.read_dir_entry(dir)
+            .iter()
+            .find(|e| Self::byte_compare(name, &e.name()))
+            .map(DirEntry::inode)
+            .ok_or(Error::NotFound)
+    }
+
+    /// Resovles a `Path` by walking from the root until the leaf is found
+    /// and locks its `INode` with `lock`. The root directory is `/`.
+    ///
+    /// A file is locked while its parent directory is still locked, so it
+    /// can't be removed and its `INodeIndex` reused in between.
     ///
     /// Current limitations are that it's not possible to have a file with the same name as a
     /// directory.
     ///
-    /// TODO(mt): this assumes that `path` is absolute. How and where is this enforced? IIRC this
-    /// is how the shell does it but it's not validated anywhere as far as I know.
+    /// Paths are always resolved from the root, relative paths have to be
+    /// turned into absolute ones with `absolute_path` first.
     ///
     /// TODO(mt): also the `byte_compare` handling is quite awkward. Would be nice to get rid of
     /// this.
-    fn resolve_path<'a>(&mut self, path: &'a str) -> Result<ResolvedPath<'a>, Error> {
-        let mut parent_dir = INodeIndex::new(0);
+    fn lock_path<'a, G>(
+        &'a self,
+        path: &str,
+        lock: impl FnOnce(&'a RwLock<()>) -> G,
+    ) -> Result<(INodeIndex, G), Error> {
+        let (dirs, Some(basename)) = split_path(path) else {
+            let root = INodeIndex::root();
+            return Ok((root, lock(self.inode_locks.get(root))));
+        };
 
-        let (path, basename) = path.rsplit_once('/').unwrap_or((path, path));
+        let parent = self.walk_dirs(dirs.into_iter())?;
 
-        for part in path.split('/').filter(|s| !s.is_empty()) {
-            let dir_entries = self.read_dir_entry(parent_dir);
+        let parent_lock = self.inode_locks.read(parent);
+        let index = self.find_entry(parent, basename)?;
 
-            let next = dir_entries
-                .iter()
-                .find(|e| Self::byte_compare(part, &e.name()))
-                .ok_or(Error::NotFound)?;
+        // `.` and `..` lead to directories which are already locked or
+        // would be locked out of order.
+        if self.lookup_inode(index).is_directory() {
+            drop(parent_lock);
+        }
 
-            // TODO(mt): this check doens't allow files and directories to have the same name. That is fine for now!
-            if !self.lookup_inode(next.inode()).is_directory() {
+        Ok((index, lock(self.inode_locks.get(index))))
+    }
+
+    /// Resolves `path` relative to the directory `cwd`, or from the root if
+    /// it starts with a `/`. `.` and `..` are looked up like any other entry.
+    ///
+    /// Nothing stays locked, a file can be removed right after it got
+    /// resolved. Directories are never freed, so their index stays valid.
+    pub fn resolve_at(&self, cwd: INodeIndex, path: &str) -> Result<INodeIndex, Error> {
+        let mut current = if path.starts_with('/') {
+            INodeIndex::root()
+        } else {
+            cwd
+        };
+
+        for part in path.split('/').filter(|s| !s.is_empty()) {
+            if !self.lookup_inode(current).is_directory() {
                 return Err(Error::NotADirectory);
             }
 
-            parent_dir = next.inode();
+            let _lock = self.inode_locks.read(current);
+            current = self.find_entry(current, part)?;
         }
 
-        let dir_entries = self.read_dir_entry(parent_dir);
+        Ok(current)
+    }
 
-        let file = dir_entries
-            .iter()
-            .find(|e| Self::byte_compare(basename, &e.name()))
-            .ok_or(Error::NotFound)?;
+    /// Returns the absolute path of a directory by following the `..`
+    /// entries up to the root. Files have no `..` entry, so their path
+    /// can't be found.
+    pub fn canonical_path(&self, dir: INodeIndex) -> Result<String, Error> {
+        if !self.lookup_inode(dir).is_directory() {
+            return Err(Error::NotADirectory);
+        }
 
-        Ok(ResolvedPath {
-            parent: parent_dir,
-            basename,
-            basename_inode: file.inode(),
-        })
+        let mut names = Vec::new();
+        let mut current = dir;
+
+        while current != INodeIndex::root() {
+            let parent = {
+                let _lock = self.inode_locks.read(current);
+                self.find_entry(current, "..")?
+            };
+
+            let _lock = self.inode_locks.read(parent);
+            let name = self
+                .read_dir_entry(parent)
+                .into_iter()
+                .map(|entry| (entry.inode(), entry.name()))
+                .find(|(inode, name)| *inode == current && name != "." && name != "..")
+                .map(|(_, name)| name)
+                .ok_or(Error::CorruptedData)?;
+
+            names.push(name);
+            current = parent;
+        }
+
+        names.reverse();
+        Ok(alloc::format!("/{}", names.join("/")))
+    }
+
+    /// Turns `path` relative to the directory `cwd` into an absolute path
+    /// without `.`, `..` and redundant slashes, which can be passed to every
+    /// other API.
+    ///
+    /// `..` is resolved by dropping the previous component, this is fine
+    /// as every directory has exactly one parent.
+    pub fn absolute_path(&self, cwd: INodeIndex, path: &str) -> Result<String, Error> {
+        let base = if path.starts_with('/') {
+            String::new()
+        } else {
+            self.canonical_path(cwd)?
+        };
+
+        let mut parts = Vec::new();
+        for part in base.split('/').chain(path.split('/')) {
+            match part {
+                "" | "." => {}
+                ".." => {
+                    parts.pop();
+                }
+                _ => parts.push(part),
+            }
+        }
+
+        Ok(alloc::format!("/{}", parts.join("/")))
     }
 
-    fn lookup_inode(&mut self, inode_index: INodeIndex) -> &INode {
-        self.inode_cache.get(inode_index, &mut self.block_device)
+    /// Returns a copy of the `INode`, reading it from disk when it's not
+    /// cached yet.
+    fn lookup_inode(&self, inode_index: INodeIndex) -> INode {
+        *self.inode_cache.lock().get(inode_index, &self.block_device)
     }
 
-    fn lookup_inode_mut(&mut self, inode_index: INodeIndex) -> &mut INode {
-        self.inode_cache
-            .get_mut(inode_index, &mut self.block_device)
+    /// Modifies the cached `INode`, marking it dirty.
+    fn update_inode<R>(&self, inode_index: INodeIndex, f: impl FnOnce(&mut INode) -> R) -> R {
+        f(self
+            .inode_cache
+            .lock()
+            .get_mut(inode_index, &self.block_device))
     }
 
     /// Removes a `path` from the filesystem by performing a swap-remove.
@@ -580,34 +1210,170 @@ impl<Dev: BlockDevice> Filesystem<Dev> {
     /// 2. Store it locally and clear it's memory
     /// 3. Go to the slot of the to-be-removed entry
     /// 4. Overwrite it with the removed 'last entry'
-    pub fn remove_dir_entry(&mut self, path: &str) -> Result<(), Error> {
-        let resolved = self.resolve_path(path)?;
-        let to_remove = resolved.basename;
-        let to_remove_inode = *self.lookup_inode(resolved.basename_inode);
-        let parent_inode = *self.lookup_inode(resolved.parent);
+    pub fn remove_dir_entry(&self, path: &str) -> Result<(), Error> {
+        self.ensure_writable()?;
+        let (dirs, to_remove) = split_path(path);
+        let to_remove = to_remove.ok_or(Error::NotFound)?;
+        let parent = self.walk_dirs(dirs.into_iter())?;
+
+        let _parent_lock = self.inode_locks.write(parent);
+        let to_remove_index = self.find_entry(parent, to_remove)?;
 
         // TODO(mt): right now we only support removing files, not directories as this would
         // require removing all of it's files etc.
-        if to_remove_inode.is_directory() {
+        if self.lookup_inode(to_remove_index).is_directory() {
+            return Err(Error::OperationNotSupported);
+        }
+
+        // Wait until nobody reads or writes the file anymore. It can't be
+        // opened again while the parent is locked.
+        let _lock = self.inode_locks.write(to_remove_index);
+        let to_remove_inode = self.lookup_inode(to_remove_index);
+
+        self.unlink_entry(parent, to_remove)?;
+
+        // Free the blocks of the deleted inode, including its xattr block.
+        // Blocks are cleared before they're freed, once free another thread
+        // may allocate them.
+        for block in to_remove_inode.owned_blocks() {
+            if !self.is_shared(block) {
+                let block_index = block.to_block().expect("Checked in `used_blocks`");
+                modify_block(&mut *self.block_device.lock(), block_index, |buf| {
+                    buf.clear()
+                });
+            }
+            self.free_data_block(block);
+        }
+
+        self.notify(parent, || WatchEvent::Removed(path.into()));
+        self.notify(to_remove_index, || WatchEvent::Removed(path.into()));
+
+        // The cache entry and the watches go first, the index may be reused
+        // as soon as it's free in the bitmap.
+        self.watches.lock().forget(to_remove_index);
+        self.file_locks.lock().forget(to_remove_index);
+        self.inode_cache.lock().remove(to_remove_index);
+        self.inode_bitmap
+            .lock()
+            .unset(to_remove_index.inner() as usize);
+
+        Ok(())
+    }
+
+    /// Renames or moves the file or directory `from` to `to`.
+    ///
+    /// An existing `to` isn't replaced. Directories can only be renamed
+    /// within their parent, moving them would need their `..` entry and a
+    /// check against moving them into themselves.
+    pub fn rename(&self, from: &str, to: &str) -> Result<(), Error> {
+        self.ensure_writable()?;
+        let (from_dirs, from_name) = split_path(from);
+        let (to_dirs, to_name) = split_path(to);
+
+        let from_name = from_name.ok_or(Error::OperationNotSupported)?;
+        let to_name = to_name.ok_or(Error::EmptyName)?;
+        if to_name.len() > MAX_NAME_LEN {
+            return Err(Error::NameTooLong);
+        }
+        if [from_name, to_name]
+            .iter()
+            .any(|name| [".", ".."].contains(name))
+        {
             return Err(Error::OperationNotSupported);
         }
 
+        let from_parent = self.walk_dirs(from_dirs.into_iter())?;
+        let to_parent = self.walk_dirs(to_dirs.into_iter())?;
+
+        // Only renames hold two directory locks at once, locking them in the
+        // order of their index keeps two renames from deadlocking.
+        let (first, second) = if from_parent.inner() <= to_parent.inner() {
+            (from_parent, to_parent)
+        } else {
+            (to_parent, from_parent)
+        };
+        let _first_lock = self.inode_locks.write(first);
+        let _second_lock = (first != second).then(|| self.inode_locks.write(second));
+
+        let inode_index = self.find_entry(from_parent, from_name)?;
+        if self.find_entry(to_parent, to_name).is_ok() {
+            return Err(Error::EntryExists);
+        }
+
+        if from_parent == to_parent {
+            self.rename_entry(from_parent, from_name, to_name)?;
+        } else {
+            if self.lookup_inode(inode_index).is_directory() {
+                return Err(Error::OperationNotSupported);
+            }
+            if !self.lookup_inode(to_parent).has_space() {
+                return Err(Error::NoFreeInodeBlocks);
+            }
+
+            self.write_dir_entry(DirEntry::new(to_name.to_string(), inode_index), to_parent)?;
+            self.unlink_entry(from_parent, from_name)?;
+        }
+
+        let event = || WatchEvent::Renamed {
+            from: from.into(),
+            to: to.into(),
+        };
+        self.notify(from_parent, event);
+        if to_parent != from_parent {
+            self.notify(to_parent, event);
+        }
+        self.notify(inode_index, event);
+
+        Ok(())
+    }
+
+    /// Changes the name of the `DirEntry` `from` in place. The directory has
+    /// to be locked exclusively by the caller.
+    fn rename_entry(&self, parent: INodeIndex, from: &str, to: &str) -> Result<(), Error> {
+        let parent_inode = self.lookup_inode(parent);
+
+        let found = DirEntryReader::new(&mut *self.block_device.lock(), parent_inode)
+            .find(|entry| entry.entry.name() == from)
+            .ok_or(Error::NotFound)?;
+
+        let block_index =
+            self.block_for_write(parent, found.logical_index / DIR_ENTRY_PER_BLOCK)?;
+        let renamed = DirEntry::new(to.to_string(), found.entry.inode());
+        modify_block(&mut *self.block_device.lock(), block_index, |buf| {
+            buf.write_struct_at(&renamed, found.byte_offset);
+        });
+
+        Ok(())
+    }
+
+    /// Removes the `DirEntry` `name` from the directory `parent` without
+    /// touching the `INode` it points to. The directory has to be locked
+    /// exclusively by the caller.
+    fn unlink_entry(&self, parent: INodeIndex, name: &str) -> Result<(), Error> {
+        let parent_inode = self.lookup_inode(parent);
+
         let num_parent_entries = unsafe { parent_inode.current_dir_entries() };
 
-        let found = DirEntryReader::new(self.block_device_mut(), parent_inode)
-            .find(|entry| entry.entry.name() == to_remove)
+        let found = DirEntryReader::new(&mut *self.block_device.lock(), parent_inode)
+            .find(|entry| entry.entry.name() == name)
             .ok_or(Error::NotFound)?;
 
         let last_block_slot = (num_parent_entries - 1) / DIR_ENTRY_PER_BLOCK;
         let last_block_offset = (num_parent_entries - 1) % DIR_ENTRY_PER_BLOCK * DIR_ENTRY_SIZE;
 
-        let Some(last_block_index) = parent_inode.block(last_block_slot).to_block() else {
+        if parent_inode.block(last_block_slot).is_empty() {
             log::error!("Could not find last_block_index. This should never happen.");
             return Err(Error::NotFound);
-        };
+        }
+
+        // Both blocks are looked up before modifying anything, so copying a
+        // block shared with a snapshot can't fail halfway through.
+        let found_block_index =
+            self.block_for_write(parent, found.logical_index / DIR_ENTRY_PER_BLOCK)?;
+        let last_block_index = self.block_for_write(parent, last_block_slot)?;
 
         // Read the last entry from disk - then zero out its memory
-        let last_entry = modify_block(&mut self.block_device, last_block_index, |buf| {
+        let last_entry = modify_block(&mut *self.block_device.lock(), last_block_index, |buf| {
             let entry = buf.read_struct_at::<DirEntry>(last_block_offset);
             buf.clear_struct_at::<DirEntry>(last_block_offset);
             entry
@@ -615,7 +1381,7 @@ impl<Dev: BlockDevice> Filesystem<Dev> {
 
         if found.logical_index != num_parent_entries - 1 {
             // swap-remove the entry
-            modify_block(&mut self.block_device, found.block_index, |buf| {
+            modify_block(&mut *self.block_device.lock(), found_block_index, |buf| {
                 buf.write_struct_at(&last_entry, found.byte_offset);
             });
         }
@@ -625,31 +1391,14 @@ impl<Dev: BlockDevice> Filesystem<Dev> {
 
         if is_only_entry_in_last_block {
             // removing the last entry in the directory, remove last block from the parent inode
-            self.data_bitmap.unset(
-                parent_inode
-                    .block(last_block_slot)
-                    .bitmap_index(&self.layout),
-            );
-            self.lookup_inode_mut(resolved.parent)
-                .block_mut(last_block_slot)
-                .unwrap()
-                .clear();
-        }
-
-        self.lookup_inode_mut(resolved.parent)
-            .shrink(mem::size_of::<DirEntry>());
-
-        // Free the blocks of the deleted inode.
-        for block in to_remove_inode.used_blocks() {
-            let block_index = block.to_block().expect("Checked in `used_blocks`");
-            modify_block(&mut self.block_device, block_index, |buf| buf.clear());
-            self.data_bitmap.unset(block.bitmap_index(&self.layout));
+            let last_block = self.lookup_inode(parent).block(last_block_slot);
+            self.free_data_block(last_block);
+            self.update_inode(parent, |inode| {
+                inode.block_mut(last_block_slot).unwrap().clear()
+            });
         }
 
-        // TODO(mt): double check that this is correct.
-        self.inode_bitmap
-            .unset(resolved.basename_inode.inner() as usize);
-        self.inode_cache.remove(resolved.basename_inode);
+        self.update_inode(parent, |inode| inode.shrink(mem::size_of::<DirEntry>()));
 
         Ok(())
     }
@@ -660,47 +1409,25 @@ impl<Dev: BlockDevice> Filesystem<Dev> {
     ///
     /// When adding a directory - this should also set the default directories
     /// '.' and '..'. TODO(mt): this should not happen in here tho.
-    fn new_dir_entry(&mut self, path: &str, entry_type: Entry) -> Result<INodeIndex, Error> {
-        // Path is separated by '/'. Split to get the parts.
-        let mut parts: Vec<_> = path.split('/').filter(|s| !s.is_empty()).collect();
+    fn new_dir_entry(&self, path: &str, entry_type: Entry) -> Result<INodeIndex, Error> {
+        self.ensure_writable()?;
 
-        // Start traversing at root index.
-        let mut current = INodeIndex::new(0);
-
-        let new_entry_name = parts.pop().ok_or(Error::EmptyName)?;
+        // Path is separated by '/'. Split to get the parts.
+        let (parts, new_entry_name) = split_path(path);
+        let new_entry_name = new_entry_name.ok_or(Error::EmptyName)?;
 
-        if new_entry_name.len() > 24 {
+        if new_entry_name.len() > MAX_NAME_LEN {
             return Err(Error::NameTooLong);
         }
 
-        // Iterate over parts of the path to walk the filesystem.
-        for part in parts {
-            // Read all `DirEntry` from the current INode.
-            let dir_entries = self.read_dir_entry(current);
-
-            // Find the entry for `part`.
-            let next = dir_entries
-                .iter()
-                .find(|e| Self::byte_compare(part, &e.name()))
-                .ok_or(Error::NotFound)?;
-
-            // If the INode that matches the `part` name is not a directory
-            // return an error as we can't go in there.
-            if !self
-                .inode_cache
-                .get(next.inode(), &mut self.block_device)
-                .is_directory()
-            {
-                return Err(Error::NotADirectory);
-            }
-
-            // Update the current index.
-            current = next.inode();
-        }
+        // Walk the filesystem starting at the root.
+        let current = self.walk_dirs(parts.into_iter())?;
 
-        let parent_inode = self.inode_cache.get(current, &mut self.block_device);
+        // The parent stays locked until the new entry is complete, nobody
+        // can look it up before.
+        let _parent_lock = self.inode_locks.write(current);
 
-        if !parent_inode.has_space() {
+        if !self.lookup_inode(current).has_space() {
             return Err(Error::NoFreeInodeBlocks);
         }
 
@@ -735,6 +1462,8 @@ impl<Dev: BlockDevice> Filesystem<Dev> {
             self.write_dir_entry(parent, inode_index).unwrap();
         }
 
+        self.notify(current, || WatchEvent::Created(path.into()));
+
         Ok(inode_index)
     }
 
@@ -742,43 +1471,125 @@ impl<Dev: BlockDevice> Filesystem<Dev> {
     /// which has some free space and we can write the new `DirEntry` to that
     /// block. If not then we need to allocate a new block and attach this to
     /// the `INode`.
-    fn write_dir_entry(&mut self, entry: DirEntry, inode_index: INodeIndex) -> Result<(), Error> {
-        let inode = self
-            .inode_cache
-            .get_mut(inode_index, &mut self.block_device);
+    fn write_dir_entry(&self, entry: DirEntry, inode_index: INodeIndex) -> Result<(), Error> {
+        let inode = self.lookup_inode(inode_index);
 
         if !inode.is_directory() {
             return Err(Error::NotADirectory);
         }
 
-        let slot = inode.write_slot();
+        let slot = inode.size() as usize / BLOCK_SIZE;
+        let byte_offset = inode.size() as usize % BLOCK_SIZE;
 
-        let Some(block) = slot.block else {
+        if slot >= INODE_BLOCKS {
             return Err(Error::NoFreeInodeBlocks);
-        };
-
-        if block.is_empty() {
-            let free = self.data_bitmap.find_free().ok_or(Error::NoSpaceLeft)?;
-            let free_block_index = self.layout.data_block(free);
-            *block = free_block_index;
-            self.data_bitmap.set(free);
         }
 
-        let block_index = block.to_block().expect("Is set by block above");
+        let block_index = self.block_for_write(inode_index, slot)?;
 
-        modify_block(&mut self.block_device, block_index, |buf| {
-            buf.write_struct_at(&entry, slot.byte_offset);
+        modify_block(&mut *self.block_device.lock(), block_index, |buf| {
+            buf.write_struct_at(&entry, byte_offset);
         });
 
-        inode.advance(DIR_ENTRY_SIZE);
+        self.update_inode(inode_index, |inode| inode.advance(DIR_ENTRY_SIZE));
 
         Ok(())
     }
 
-    /// Reads all the `DirEntry`s for that INode and returns them in a Vec.
-    fn read_dir_entry(&mut self, inode_index: INodeIndex) -> Vec<DirEntry> {
-        // Get the `INode`
-        let inode = self.inode_cache.get(inode_index, &mut self.block_device);
+    /// Allocates a zeroed data block.
+    ///
+    /// Blocks are zeroed so that partially written blocks of sparse files
+    /// read back zeros where nothing was written.
+    fn allocate_data_block(&self) -> Result<DataBlockIndex, Error> {
+        let free = {
+            let mut data_bitmap = self.data_bitmap.lock();
+            let free = data_bitmap.find_free().ok_or(Error::NoSpaceLeft)?;
+            data_bitmap.set(free);
+            if let Some(refcounts) = &mut *self.refcounts.lock() {
+                refcounts[free] = 1;
+            }
+            free
+        };
+
+        let block = self.layout.data_block(free);
+        self.block_device.lock().write_block(
+            block.to_block().expect("Data blocks are never zero"),
+            &[0u8; BLOCK_SIZE],
+        );
+
+        Ok(block)
+    }
+
+    /// Drops a reference to a data block, freeing it once it's no longer
+    /// used by the filesystem or a snapshot. Returns `true` if it got freed.
+    fn free_data_block(&self, block: DataBlockIndex) -> bool {
+        let index = block.bitmap_index(&self.layout);
+        let mut data_bitmap = self.data_bitmap.lock();
+
+        if let Some(refcounts) = &mut *self.refcounts.lock() {
+            refcounts[index] = refcounts[index].saturating_sub(1);
+            if refcounts[index] > 0 {
+                return false;
+            }
+        }
+
+        data_bitmap.unset(index);
+        true
+    }
+
+    /// Returns `true` if the data block is shared with a snapshot.
+    fn is_shared(&self, block: DataBlockIndex) -> bool {
+        self.refcounts
+            .lock()
+            .as_ref()
+            .is_some_and(|refcounts| refcounts[block.bitmap_index(&self.layout)] > 1)
+    }
+
+    fn add_reference(&self, block: DataBlockIndex) {
+        let index = block.bitmap_index(&self.layout);
+        if let Some(refcounts) = &mut *self.refcounts.lock() {
+            refcounts[index] += 1;
+        }
+    }
+
+    /// Returns the block backing `slot` of the `INode`, allocating it if the
+    /// slot is still a hole. A block shared with a snapshot is copied first.
+    fn block_for_write(&self, inode_index: INodeIndex, slot: usize) -> Result<BlockIndex, Error> {
+        let current = self.lookup_inode(inode_index).block(slot);
+
+        if let Some(block) = current.to_block()
+            && !self.is_shared(current)
+        {
+            return Ok(block);
+        }
+
+        let block = self.allocate_data_block()?;
+
+        if let Some(shared) = current.to_block() {
+            self.copy_block(shared.inner() as usize, block.raw() as usize);
+            self.free_data_block(current);
+        }
+
+        self.update_inode(inode_index, |inode| {
+            *inode
+                .block_mut(slot)
+                .expect("slot is checked by the caller") = block;
+        });
+
+        Ok(block.to_block().expect("Is set by `allocate_data_block`"))
+    }
+
+    /// Locks the directory while reading its `DirEntry`s.
+    pub(crate) fn read_dir_entry_locked(&self, inode_index: INodeIndex) -> Vec<DirEntry> {
+        let _lock = self.inode_locks.read(inode_index);
+        self.read_dir_entry(inode_index)
+    }
+
+    /// Reads all the `DirEntry`s for that INode and returns them in a Vec.
+    /// The directory has to be locked by the caller.
+    fn read_dir_entry(&self, inode_index: INodeIndex) -> Vec<DirEntry> {
+        // Get the `INode`
+        let inode = self.lookup_inode(inode_index);
 
         // If the `INode` is empty there is nothing to do here.
         if inode.size() == 0 || !inode.is_directory() {
@@ -792,7 +1603,9 @@ impl<Dev: BlockDevice> Filesystem<Dev> {
 
         // Loop and read all `DirEntry`s into `res`.
         for block_index in inode.used_blocks().flat_map(|b| b.to_block()) {
-            self.block_device.read_block(block_index, buf.inner());
+            self.block_device
+                .lock()
+                .read_block(block_index, buf.inner());
 
             let items_in_block = (max_items - res.len()).min(DIR_ENTRY_PER_BLOCK);
 
@@ -809,7 +1622,7 @@ impl<Dev: BlockDevice> Filesystem<Dev> {
         res
     }
 
-    pub fn create_empty_root(&mut self) {
+    pub fn create_empty_root(&self) {
         // Create the root INode.
         let root_inode = INode::new_empty_directory();
 
@@ -830,47 +1643,74 @@ impl<Dev: BlockDevice> Filesystem<Dev> {
         log::info!("initialized with empty root directory");
     }
 
-    fn append_to_file(&mut self, path: &str, bytes: &[u8]) -> Result<usize, Error> {
-        let resolved = self.resolve_path(path)?;
+    fn append_to_file(&self, path: &str, bytes: &[u8]) -> Result<usize, Error> {
+        self.ensure_writable()?;
+        let (inode_index, _lock) = self.lock_path(path, RwLock::write)?;
+        let size = self.lookup_inode(inode_index).size() as usize;
 
-        let inode = self
-            .inode_cache
-            .get_mut(resolved.basename_inode, &mut self.block_device);
+        let written = self.write_at_inode(inode_index, size, bytes)?;
+        self.notify(inode_index, || WatchEvent::Modified(path.into()));
+        Ok(written)
+    }
 
-        if inode.is_directory() {
+    /// Writes `bytes` at `offset`, allocating only the blocks that are
+    /// touched. Writing past the end leaves the skipped blocks as holes.
+    /// The `INode` has to be locked exclusively by the caller.
+    fn write_at_inode(
+        &self,
+        inode_index: INodeIndex,
+        offset: usize,
+        bytes: &[u8],
+    ) -> Result<usize, Error> {
+        if self.lookup_inode(inode_index).is_directory() {
             return Err(Error::IsDirectory);
         }
 
-        if inode.size() as usize + bytes.len() > 16 * BLOCK_SIZE {
+        if offset
+            .checked_add(bytes.len())
+            .is_none_or(|end| end > MAX_FILE_SIZE)
+        {
             return Err(Error::FileTooLarge);
         }
 
-        let mut total_bytes = bytes.len();
-        let mut bytes_written = 0;
-
-        while total_bytes > 0 {
-            let slot = inode.write_slot();
+        if bytes.is_empty() {
+            return Ok(0);
+        }
 
-            let Some(block) = slot.block else {
-                return Err(Error::FileTooLarge);
-            };
+        if self.lookup_inode(inode_index).is_compressed() {
+            self.decompress_file(inode_index)?;
+        }
 
-            if block.is_empty() {
-                let free = self.data_bitmap.find_free().ok_or(Error::NoSpaceLeft)?;
-                let free_block_index = self.layout.data_block(free);
-                *block = free_block_index;
-                self.data_bitmap.set(free);
+        let end = offset + bytes.len();
+
+        if self.lookup_inode(inode_index).is_inline() {
+            if end <= INLINE_CAPACITY {
+                self.update_inode(inode_index, |inode| {
+                    let mut data = inode.inline_data();
+                    data[offset..end].copy_from_slice(bytes);
+                    inode.set_inline_data(&data);
+                    if inode.size() < end as u32 {
+                        inode.set_size(end as u32);
+                    }
+                });
+                return Ok(bytes.len());
             }
 
-            let bytes_to_write = total_bytes.min(slot.capacity);
-            log::debug!("writing {}/{} bytes", bytes_to_write, total_bytes);
+            self.promote_inline(inode_index)?;
+        }
 
-            let block_index = block
-                .to_block()
-                .expect("Is set by the block above in this function");
+        let mut bytes_written = 0;
+
+        while bytes_written < bytes.len() {
+            let position = offset + bytes_written;
+            let slot = position / BLOCK_SIZE;
+            let write_start = position % BLOCK_SIZE;
+            let bytes_to_write = (BLOCK_SIZE - write_start).min(bytes.len() - bytes_written);
+            log::debug!("writing {}/{} bytes", bytes_to_write, bytes.len());
 
-            modify_block(&mut self.block_device, block_index, |buf| {
-                let write_start = slot.byte_offset;
+            let block_index = self.block_for_write(inode_index, slot)?;
+
+            modify_block(&mut *self.block_device.lock(), block_index, |buf| {
                 let write_end = write_start + bytes_to_write;
 
                 let read_start = bytes_written;
@@ -878,64 +1718,480 @@ impl<Dev: BlockDevice> Filesystem<Dev> {
                 buf.inner()[write_start..write_end].copy_from_slice(&bytes[read_start..read_end]);
             });
 
-            inode.advance(bytes_to_write);
-
-            total_bytes -= bytes_to_write;
             bytes_written += bytes_to_write;
+
+            self.update_inode(inode_index, |inode| {
+                if inode.size() < (offset + bytes_written) as u32 {
+                    inode.set_size((offset + bytes_written) as u32);
+                }
+            });
         }
 
         Ok(bytes_written)
     }
 
-    pub fn read_file(&mut self, path: &str) -> Result<String, Error> {
-        let resolved = self.resolve_path(path)?;
+    /// Moves the content of an inline `INode` into a freshly allocated data
+    /// block. The block is allocated first so a full disk leaves the file
+    /// untouched.
+    fn promote_inline(&self, inode_index: INodeIndex) -> Result<(), Error> {
+        let size = self.lookup_inode(inode_index).size() as usize;
 
-        let inode = self
-            .inode_cache
-            .get_mut(resolved.basename_inode, &mut self.block_device);
+        if size == 0 {
+            self.update_inode(inode_index, INode::take_inline_data);
+            return Ok(());
+        }
+
+        let block = self.allocate_data_block()?;
+
+        let data = self.update_inode(inode_index, |inode| {
+            let data = inode.take_inline_data();
+            *inode.block_mut(0).expect("INode has at least one slot") = block;
+            data
+        });
+
+        modify_block(
+            &mut *self.block_device.lock(),
+            block.to_block().expect("Is set by `allocate_data_block`"),
+            |buf| buf.inner()[..size].copy_from_slice(&data[..size]),
+        );
+
+        Ok(())
+    }
+
+    /// Reads the whole content of a file. Holes read back as zeros.
+    pub fn read_bytes(&self, path: &str) -> Result<Vec<u8>, Error> {
+        let (inode_index, _lock) = self.lock_path(path, RwLock::read)?;
+
+        let inode = self.lookup_inode(inode_index);
 
         if inode.is_directory() {
             return Err(Error::IsDirectory);
         }
 
+        let size = inode.size() as usize;
+
+        if inode.is_inline() {
+            return Ok(inode.inline_data()[..size].to_vec());
+        }
+
+        if inode.is_compressed() {
+            return self.read_compressed(&inode);
+        }
+
+        Ok(self.read_data_blocks(&inode, size))
+    }
+
+    /// Reads the first `len` bytes stored in the data blocks of `inode`.
+    fn read_data_blocks(&self, inode: &INode, len: usize) -> Vec<u8> {
         let mut buf = Buffer::new();
+        let mut bytes = Vec::with_capacity(len);
 
-        let mut total_bytes = inode.size() as usize;
-        let mut string = String::with_capacity(total_bytes);
+        for slot in 0..len.div_ceil(BLOCK_SIZE) {
+            let valid_bytes = (len - slot * BLOCK_SIZE).min(BLOCK_SIZE);
+
+            match inode.block(slot).to_block() {
+                Some(block) => {
+                    self.block_device.lock().read_block(block, buf.inner());
+                    bytes.extend_from_slice(&buf.inner()[..valid_bytes]);
+                }
+                None => bytes.resize(bytes.len() + valid_bytes, 0),
+            }
+        }
 
-        for block in inode.used_blocks().map(|b| b.to_block().unwrap()) {
-            self.block_device.read_block(block, buf.inner());
+        bytes
+    }
 
-            let valid_bytes = total_bytes.min(BLOCK_SIZE);
-            total_bytes -= valid_bytes;
+    /// Reads and decodes the compressed stream of `inode`.
+    fn read_compressed(&self, inode: &INode) -> Result<Vec<u8>, Error> {
+        let header = self.read_data_blocks(inode, COMPRESSED_HEADER_SIZE);
+        let stream_len = u32::from_le_bytes(header.try_into().unwrap()) as usize;
 
-            string.push_str(str::from_utf8(&buf.inner()[..valid_bytes]).unwrap());
+        if stream_len > MAX_FILE_SIZE - COMPRESSED_HEADER_SIZE {
+            return Err(Error::CorruptedData);
         }
 
-        Ok(string)
+        let stored = self.read_data_blocks(inode, COMPRESSED_HEADER_SIZE + stream_len);
+
+        compression::decompress(&stored[COMPRESSED_HEADER_SIZE..], inode.size() as usize)
+            .ok_or(Error::CorruptedData)
     }
 
-    /// Writes the superblock to block_index 0
-    fn write_superblock(&mut self, superblock: &SuperBlock) {
-        modify_block(&mut self.block_device, BlockIndex::from_raw(0), |buf| {
-            buf.clear();
-            superblock.write_to(&mut ByteWriter::new(
-                &mut buf.inner()[..SUPERBLOCK_ENCODED_SIZE],
-            ));
+    /// Frees all data blocks of a file and turns it into an empty file.
+    fn truncate_inode(&self, inode_index: INodeIndex) {
+        let inode = self.lookup_inode(inode_index);
+
+        for block in inode.used_blocks() {
+            self.free_data_block(block);
+        }
+
+        self.update_inode(inode_index, INode::reset_file);
+    }
+
+    /// Rewrites a compressed file uncompressed so it can be modified in
+    /// place. Fails without touching the file if the plain data won't fit.
+    fn decompress_file(&self, inode_index: INodeIndex) -> Result<(), Error> {
+        let inode = self.lookup_inode(inode_index);
+        let bytes = self.read_compressed(&inode)?;
+
+        let needed = bytes.len().div_ceil(BLOCK_SIZE);
+        let available = self.free_data_blocks()
+            + inode
+                .used_blocks()
+                .filter(|block| !self.is_shared(*block))
+                .count();
+        if needed > available {
+            return Err(Error::NoSpaceLeft);
+        }
+
+        self.truncate_inode(inode_index);
+        self.write_at_inode(inode_index, 0, &bytes)?;
+
+        Ok(())
+    }
+
+    fn free_data_blocks(&self) -> usize {
+        let data_bitmap = self.data_bitmap.lock();
+        (0..self.layout.data_blocks)
+            .filter(|index| !data_bitmap.is_set(*index))
+            .count()
+    }
+
+    /// Replaces the content of a file with `bytes`, storing it compressed
+    /// when that saves at least one data block. Later partial writes
+    /// decompress the file first.
+    pub fn write_compressed(&self, path: &str, bytes: &[u8]) -> Result<usize, Error> {
+        self.ensure_writable()?;
+        let (inode_index, _lock) = self.lock_path(path, RwLock::write)?;
+
+        if self.lookup_inode(inode_index).is_directory() {
+            return Err(Error::IsDirectory);
+        }
+
+        if bytes.len() > MAX_FILE_SIZE {
+            return Err(Error::FileTooLarge);
+        }
+
+        let compressed = compression::compress(bytes);
+        let stored_len = COMPRESSED_HEADER_SIZE + compressed.len();
+        let plain = bytes.len() <= INLINE_CAPACITY
+            || stored_len.div_ceil(BLOCK_SIZE) >= bytes.len().div_ceil(BLOCK_SIZE);
+
+        // Checked before truncating, so a full disk keeps the old content.
+        let needed = if bytes.len() <= INLINE_CAPACITY {
+            0
+        } else if plain {
+            bytes.len().div_ceil(BLOCK_SIZE)
+        } else {
+            stored_len.div_ceil(BLOCK_SIZE)
+        };
+        let available = self.free_data_blocks()
+            + self
+                .lookup_inode(inode_index)
+                .used_blocks()
+                .filter(|block| !self.is_shared(*block))
+                .count();
+        if needed > available {
+            return Err(Error::NoSpaceLeft);
+        }
+
+        self.truncate_inode(inode_index);
+
+        if plain {
+            let written = self.write_at_inode(inode_index, 0, bytes)?;
+            self.notify(inode_index, || WatchEvent::Modified(path.into()));
+            return Ok(written);
+        }
+
+        let mut stored = Vec::with_capacity(stored_len);
+        stored.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        stored.extend_from_slice(&compressed);
+
+        self.promote_inline(inode_index)?;
+        self.write_at_inode(inode_index, 0, &stored)?;
+
+        self.update_inode(inode_index, |inode| {
+            inode.set_compressed();
+            inode.set_size(bytes.len() as u32);
         });
+        self.notify(inode_index, || WatchEvent::Modified(path.into()));
+
+        Ok(bytes.len())
     }
 
-    pub fn dump_dir(&mut self, path: &str, out: &mut impl core::fmt::Write) -> Result<(), Error> {
-        log::info!("`ls` for \"{path}\"");
+    /// Reads the whole content of a file as text. Invalid UTF-8 is replaced.
+    pub fn read_file(&self, path: &str) -> Result<String, Error> {
+        let bytes = self.read_bytes(path)?;
+
+        Ok(String::from_utf8(bytes)
+            .unwrap_or_else(|error| String::from_utf8_lossy(error.as_bytes()).into_owned()))
+    }
+
+    /// Changes the size of a file.
+    ///
+    /// Growing leaves a hole at the end, shrinking frees all blocks past the
+    /// new end.
+    pub fn set_len(&self, path: &str, len: usize) -> Result<(), Error> {
+        self.ensure_writable()?;
+        let (inode_index, _lock) = self.lock_path(path, RwLock::write)?;
+        let inode = self.lookup_inode(inode_index);
+
+        if inode.is_directory() {
+            return Err(Error::IsDirectory);
+        }
+
+        if len > MAX_FILE_SIZE {
+            return Err(Error::FileTooLarge);
+        }
+
+        if inode.is_compressed() {
+            self.decompress_file(inode_index)?;
+        }
+        let inode = self.lookup_inode(inode_index);
+
+        if inode.is_inline() {
+            if len > INLINE_CAPACITY {
+                self.promote_inline(inode_index)?;
+            } else if len < inode.size() as usize {
+                self.update_inode(inode_index, |inode| {
+                    let mut data = inode.inline_data();
+                    data[len..].fill(0);
+                    inode.set_inline_data(&data);
+                });
+            }
+        } else if len < inode.size() as usize {
+            for slot in len.div_ceil(BLOCK_SIZE)..INODE_BLOCKS {
+                let block = inode.block(slot);
+                if block.is_empty() {
+                    continue;
+                }
+                self.free_data_block(block);
+                self.update_inode(inode_index, |inode| inode.block_mut(slot).unwrap().clear());
+            }
+
+            // Zero the tail of the last block so growing again reads zeros.
+            if !inode.block(len / BLOCK_SIZE).is_empty() && !len.is_multiple_of(BLOCK_SIZE) {
+                let block = self.block_for_write(inode_index, len / BLOCK_SIZE)?;
+                modify_block(&mut *self.block_device.lock(), block, |buf| {
+                    buf.inner()[len % BLOCK_SIZE..].fill(0);
+                });
+            }
+        }
 
-        let inode_index = match path {
-            "/" => INodeIndex::root(),
-            _ => self.resolve_path(path)?.basename_inode,
+        self.update_inode(inode_index, |inode| inode.set_size(len as u32));
+        self.notify(inode_index, || WatchEvent::Modified(path.into()));
+
+        Ok(())
+    }
+
+    pub fn stat(&self, path: &str) -> Result<Metadata, Error> {
+        let (inode_index, _lock) = self.lock_path(path, RwLock::read)?;
+        Ok(Metadata::new(inode_index, &self.lookup_inode(inode_index)))
+    }
+
+    /// Returns the names of the entries of the directory at `path` in
+    /// directory order, without `.` and `..`.
+    pub fn read_dir(&self, path: &str) -> Result<Vec<String>, Error> {
+        let (inode_index, _lock) = self.lock_path(path, RwLock::read)?;
+        if !self.lookup_inode(inode_index).is_directory() {
+            return Err(Error::NotADirectory);
+        }
+
+        Ok(self
+            .read_dir_entry(inode_index)
+            .iter()
+            .map(DirEntry::name)
+            .filter(|name| !name.is_empty() && name != "." && name != "..")
+            .collect())
+    }
+
+    /// Opens `path` to take advisory locks on it with `lock`.
+    pub fn open(&self, path: &str) -> Result<FileHandle, Error> {
+        let (inode_index, _lock) = self.lock_path(path, RwLock::read)?;
+        Ok(self.file_locks.lock().open(inode_index, path))
+    }
+
+    /// Closes `handle`, releasing its lock.
+    pub fn close(&self, handle: FileHandle) -> Result<(), Error> {
+        self.file_locks.lock().close(handle)
+    }
+
+    /// Takes an advisory lock on the file of `handle`, or converts the lock
+    /// it already holds, like `flock(2)`.
+    ///
+    /// Without `blocking` a conflicting lock of another handle returns
+    /// `Error::WouldBlock`, otherwise this spins until it's released. Locks
+    /// are only kept in memory and are gone after unmounting.
+    pub fn lock(&self, handle: FileHandle, kind: LockKind, blocking: bool) -> Result<(), Error> {
+        loop {
+            if self.file_locks.lock().try_lock(handle, kind)? {
+                return Ok(());
+            }
+            if !blocking {
+                return Err(Error::WouldBlock);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn unlock(&self, handle: FileHandle) -> Result<(), Error> {
+        self.file_locks.lock().unlock(handle)
+    }
+
+    /// Lists every advisory lock and the handle holding it.
+    pub fn locks(&self) -> Vec<LockInfo> {
+        self.file_locks.lock().locks()
+    }
+
+    /// Subscribes to the changes of `path`, see `WatchEvent`. Events are
+    /// queued until they're taken with `read_events`.
+    pub fn watch(&self, path: &str) -> Result<WatchId, Error> {
+        let (inode_index, _lock) = self.lock_path(path, RwLock::read)?;
+        Ok(self.watches.lock().watch(inode_index))
+    }
+
+    pub fn unwatch(&self, id: WatchId) -> Result<(), Error> {
+        self.watches.lock().unwatch(id)
+    }
+
+    /// Takes the queued events of a watch, oldest first.
+    pub fn read_events(&self, id: WatchId) -> Result<Vec<WatchEvent>, Error> {
+        self.watches.lock().drain(id)
+    }
+
+    fn notify(&self, inode_index: INodeIndex, event: impl Fn() -> WatchEvent) {
+        self.watches.lock().notify(inode_index, event);
+    }
+
+    /// Sets the extended attribute `name` of `path` to `value`, replacing
+    /// an existing value.
+    pub fn set_xattr(&self, path: &str, name: &str, value: &[u8]) -> Result<(), Error> {
+        self.ensure_writable()?;
+        validate_xattr_name(name)?;
+        if value.len() > MAX_XATTR_VALUE {
+            return Err(Error::XattrTooLarge);
+        }
+
+        let (inode_index, _lock) = self.lock_path(path, RwLock::write)?;
+        let mut xattrs = self.read_xattrs(inode_index)?;
+        if xattrs.get(name).is_none() && xattrs.len() == MAX_XATTRS {
+            return Err(Error::TooManyXattrs);
+        }
+
+        xattrs.set(name, value);
+        if !xattrs.fits() {
+            return Err(Error::XattrTooLarge);
+        }
+
+        self.write_xattrs(inode_index, &xattrs)
+    }
+
+    pub fn get_xattr(&self, path: &str, name: &str) -> Result<Vec<u8>, Error> {
+        let (inode_index, _lock) = self.lock_path(path, RwLock::read)?;
+        let xattrs = self.read_xattrs(inode_index)?;
+
+        xattrs.get(name).map(<[u8]>::to_vec).ok_or(Error::NotFound)
+    }
+
+    /// Lists the names of the extended attributes of `path` in the order
+    /// they were added.
+    pub fn list_xattr(&self, path: &str) -> Result<Vec<String>, Error> {
+        let (inode_index, _lock) = self.lock_path(path, RwLock::read)?;
+        let xattrs = self.read_xattrs(inode_index)?;
+
+        Ok(xattrs.names().map(String::from).collect())
+    }
+
+    /// Removes the extended attribute `name` of `path`. The xattr block is
+    /// freed together with the last attribute.
+    pub fn remove_xattr(&self, path: &str, name: &str) -> Result<(), Error> {
+        self.ensure_writable()?;
+
+        let (inode_index, _lock) = self.lock_path(path, RwLock::write)?;
+        let mut xattrs = self.read_xattrs(inode_index)?;
+        if !xattrs.remove(name) {
+            return Err(Error::NotFound);
+        }
+
+        self.write_xattrs(inode_index, &xattrs)
+    }
+
+    /// Reads the extended attributes of an `INode`. Images without the
+    /// xattr feature have no room for the xattr block pointer.
+    fn read_xattrs(&self, inode_index: INodeIndex) -> Result<XattrBlock, Error> {
+        if !self.features.has_xattr() {
+            return Err(Error::OperationNotSupported);
+        }
+
+        let Some(block_index) = self.lookup_inode(inode_index).xattr_block().to_block() else {
+            return Ok(XattrBlock::default());
+        };
+
+        let mut buf = Buffer::new();
+        self.block_device
+            .lock()
+            .read_block(block_index, buf.inner());
+        XattrBlock::decode(buf.inner()).ok_or(Error::CorruptedData)
+    }
+
+    /// Writes the extended attributes of an `INode` into its xattr block.
+    ///
+    /// A block shared with a snapshot is replaced by a new one instead of
+    /// being modified, and an empty set of attributes frees the block.
+    fn write_xattrs(&self, inode_index: INodeIndex, xattrs: &XattrBlock) -> Result<(), Error> {
+        let current = self.lookup_inode(inode_index).xattr_block();
+
+        if xattrs.is_empty() {
+            if !current.is_empty() {
+                self.free_data_block(current);
+                self.update_inode(inode_index, |inode| {
+                    inode.set_xattr_block(DataBlockIndex::default());
+                });
+            }
+            return Ok(());
+        }
+
+        let block = if current.is_empty() || self.is_shared(current) {
+            let block = self.allocate_data_block()?;
+            if !current.is_empty() {
+                self.free_data_block(current);
+            }
+            self.update_inode(inode_index, |inode| inode.set_xattr_block(block));
+            block
+        } else {
+            current
         };
 
+        let mut buf = Buffer::new();
+        xattrs.encode(buf.inner());
+        self.block_device
+            .lock()
+            .write_block(block.to_block().expect("Checked above"), buf.inner());
+
+        Ok(())
+    }
+
+    /// Writes the superblock to block_index 0
+    fn write_superblock(&self, superblock: &SuperBlock) {
+        modify_block(
+            &mut *self.block_device.lock(),
+            BlockIndex::from_raw(0),
+            |buf| {
+                buf.clear();
+                superblock.write_to(&mut ByteWriter::new(
+                    &mut buf.inner()[..SUPERBLOCK_ENCODED_SIZE],
+                ));
+            },
+        );
+    }
+
+    pub fn dump_dir(&self, path: &str, out: &mut impl core::fmt::Write) -> Result<(), Error> {
+        log::info!("`ls` for \"{path}\"");
+
+        let (inode_index, _lock) = self.lock_path(path, RwLock::read)?;
+
         log::info!("Found inode_index={inode_index:?} for path=\"{path}\"");
 
-        let inode = *self.lookup_inode(inode_index);
+        let inode = self.lookup_inode(inode_index);
         log::info!("Found inode={inode:?} for path=\"{path}\"");
 
         if !inode.is_directory() {
@@ -952,8 +2208,8 @@ impl<Dev: BlockDevice> Filesystem<Dev> {
             if name.is_empty() {
                 continue;
             }
-            let entry_inode = *self.inode_cache.get(entry.inode(), &mut self.block_device);
-            let (type_char, size_str, display_name) = entry_display(&entry_inode, name);
+            let metadata = Metadata::new(entry.inode(), &self.lookup_inode(entry.inode()));
+            let (type_char, size_str, display_name) = entry_display(&metadata, name);
             let _ = writeln!(
                 out,
                 "  {}     [{:>3}]  {:>9}  {}",
@@ -967,28 +2223,36 @@ impl<Dev: BlockDevice> Filesystem<Dev> {
         Ok(())
     }
 
-    pub fn tree(&mut self, out: &mut impl core::fmt::Write) {
+    /// Walks the tree below `path` depth-first, see `Walker`.
+    pub fn walk(&self, path: &str) -> Result<Walker<'_, Dev>, Error> {
+        self.stat(path)?;
+        let (mut dirs, name) = split_path(path);
+        dirs.extend(name);
+        Ok(Walker::new(self, alloc::format!("/{}", dirs.join("/"))))
+    }
+
+    pub fn tree(&self, out: &mut impl core::fmt::Write) {
         fn inner(
-            fs: &mut Filesystem<impl BlockDevice>,
+            fs: &Filesystem<impl BlockDevice>,
             entry: &DirEntry,
             prefix: &str,
             is_last: bool,
             out: &mut impl core::fmt::Write,
         ) {
             let connector = if is_last { "└── " } else { "├── " };
-            let entry_inode = *fs.inode_cache.get(entry.inode(), &mut fs.block_device);
-            let (type_char, size_str, name) = entry_display(&entry_inode, entry.name());
+            let metadata = Metadata::new(entry.inode(), &fs.lookup_inode(entry.inode()));
+            let (type_char, size_str, name) = entry_display(&metadata, entry.name());
             let _ = writeln!(
                 out,
                 "{}{}{}  {:>9}  {}",
                 prefix, connector, type_char, size_str, name
             );
 
-            if entry_inode.is_directory() {
+            if metadata.is_directory {
                 let child_prefix =
                     alloc::format!("{}{}   ", prefix, if is_last { " " } else { "│" });
                 let children: Vec<DirEntry> = fs
-                    .read_dir_entry(entry.inode())
+                    .read_dir_entry_locked(entry.inode())
                     .into_iter()
                     .filter(|e| !e.name().starts_with('.'))
                     .collect();
@@ -1001,7 +2265,7 @@ impl<Dev: BlockDevice> Filesystem<Dev> {
 
         let _ = writeln!(out, "/");
         let root_entries: Vec<DirEntry> = self
-            .read_dir_entry(INodeIndex::new(0))
+            .read_dir_entry_locked(INodeIndex::root())
             .into_iter()
             .filter(|e| !e.name().starts_with('.'))
             .collect();
@@ -1011,70 +2275,642 @@ impl<Dev: BlockDevice> Filesystem<Dev> {
         }
     }
 
-    pub fn flush(&mut self) {
-        // Write the `INodeCache` to disk.
-        //
-        // Quick hack here to be able to call `write_inode_to_disk`.
-        let mut inode_cache = core::mem::take(&mut self.inode_cache);
+    pub fn flush(&self) {
+        if self.read_only {
+            log::debug!("not flushing read-only filesystem");
+            return;
+        }
 
+        // Write the `INodeCache` to disk. The cache stays locked, so a
+        // concurrent flush can't write an older copy of an `INode` later.
+        let mut inode_cache = self.inode_cache.lock();
         for (idx, inode) in inode_cache.drain() {
             self.write_inode_to_disk(idx, &inode);
         }
+        drop(inode_cache);
 
-        self.inode_cache = inode_cache;
-
-        // Write the superblock to disk
-        let superblock = SuperBlock::from_layout(
-            self.block_device.total_blocks(),
-            self.inode_bitmap.len(),
-            self.layout,
-        );
-
-        self.write_superblock(&superblock);
+        // The filesystem stays mounted, so it's still considered dirty.
+        self.write_superblock_state(STATE_DIRTY);
 
+        let inode_bitmap = self.inode_bitmap.lock();
         write_bitmap(
-            &mut self.block_device,
+            &mut *self.block_device.lock(),
             self.layout.inode_bitmap_start,
             self.layout.inode_bitmap_blocks,
-            &self.inode_bitmap,
+            &inode_bitmap,
         );
+        drop(inode_bitmap);
 
+        let data_bitmap = self.data_bitmap.lock();
         write_bitmap(
-            &mut self.block_device,
+            &mut *self.block_device.lock(),
             self.layout.data_bitmap_start,
             self.layout.data_bitmap_blocks,
-            &self.data_bitmap,
+            &data_bitmap,
         );
+        drop(data_bitmap);
+
+        if let Some(refcounts) = &*self.refcounts.lock() {
+            write_refcounts(
+                &mut *self.block_device.lock(),
+                self.layout.refcount_start(),
+                refcounts,
+            );
+        }
 
         log::debug!("flushed");
     }
 
-    pub fn mkdir(&mut self, path: &str) -> Result<INodeIndex, Error> {
-        self.new_dir_entry(path, Entry::Directory)
-    }
+    /// Writes back the dirty metadata of a single file or directory.
+    ///
+    /// Data blocks are written through on every write, so this persists the
+    /// `INode` itself plus the bitmap blocks tracking its allocations. The
+    /// `DirEntry` in the parent directory is not synced.
+    pub fn fsync(&self, path: &str) -> Result<(), Error> {
+        let (inode_index, _lock) = self.lock_path(path, RwLock::read)?;
+
+        if self.read_only {
+            return Ok(());
+        }
 
-    pub fn create_file(&mut self, path: &str) -> Result<INodeIndex, Error> {
-        self.new_dir_entry(path, Entry::File)
+        let inode = self.lookup_inode(inode_index);
+        {
+            let mut inode_cache = self.inode_cache.lock();
+            if let Some(dirty) = inode_cache.take_dirty(inode_index) {
+                self.write_inode_to_disk(inode_index, &dirty);
+            }
+        }
+
+        let inode_bitmap = self.inode_bitmap.lock();
+        write_bitmap_block(
+            &mut *self.block_device.lock(),
+            self.layout.inode_bitmap_start,
+            &inode_bitmap,
+            inode_index.inner() as usize / BITS_PER_BLOCK,
+        );
+        drop(inode_bitmap);
+
+        let mut bitmap_blocks: Vec<usize> = inode
+            .owned_blocks()
+            .map(|block| block.bitmap_index(&self.layout) / BITS_PER_BLOCK)
+            .collect();
+        bitmap_blocks.sort_unstable();
+        bitmap_blocks.dedup();
+
+        let data_bitmap = self.data_bitmap.lock();
+        for offset in bitmap_blocks {
+            write_bitmap_block(
+                &mut *self.block_device.lock(),
+                self.layout.data_bitmap_start,
+                &data_bitmap,
+                offset,
+            );
+        }
+        drop(data_bitmap);
+
+        if let Some(refcounts) = &*self.refcounts.lock() {
+            let mut refcount_blocks: Vec<usize> = inode
+                .owned_blocks()
+                .map(|block| block.bitmap_index(&self.layout) / BLOCK_SIZE)
+                .collect();
+            refcount_blocks.sort_unstable();
+            refcount_blocks.dedup();
+
+            for offset in refcount_blocks {
+                write_refcount_block(
+                    &mut *self.block_device.lock(),
+                    self.layout.refcount_start(),
+                    refcounts,
+                    offset,
+                );
+            }
+        }
+
+        log::debug!("fsynced {inode_index:?}");
+        Ok(())
     }
 
-    pub fn write_to_file(&mut self, path: &str, bytes: &[u8]) -> Result<usize, Error> {
-        self.append_to_file(path, bytes)
+    /// Flushes everything and marks the filesystem as cleanly unmounted,
+    /// handing back the block device.
+    pub fn unmount(self) -> Dev {
+        self.mark_clean();
+        self.block_device.into_inner()
     }
-}
 
-fn read_bitmap<Dev: BlockDevice>(
-    block_device: &mut Dev,
-    start: usize,
-    blocks: usize,
-    logical_len: usize,
-) -> Result<Bitmap, Error> {
-    let word_count = logical_len.div_ceil(u32::BITS as usize);
-    let bytes_needed = word_count.checked_mul(4).ok_or(Error::InvalidSuperblock)?;
-    let region_bytes = blocks
-        .checked_mul(BLOCK_SIZE)
-        .ok_or(Error::InvalidSuperblock)?;
-    if bytes_needed > region_bytes {
-        return Err(Error::InvalidSuperblock);
+    /// Flushes and records a clean unmount like `unmount`, for owners that
+    /// can't give up the filesystem such as a shared driver. The next flush
+    /// marks it dirty again.
+    pub fn mark_clean(&self) {
+        if !self.read_only {
+            self.flush();
+            self.write_superblock_state(STATE_CLEAN);
+            log::info!("unmounted");
+        }
+    }
+
+    /// Overwrites every free data block and unused inode record with zeros.
+    ///
+    /// Freeing a block doesn't always clear it, e.g. when a file shrinks, so
+    /// without this the unused parts of an image depend on its history.
+    pub fn zero_unused(&mut self) -> Result<(), Error> {
+        self.ensure_writable()?;
+        let layout = self.layout;
+        let device = self.block_device.get_mut();
+        let mut buf = Buffer::new();
+
+        let data_bitmap = self.data_bitmap.get_mut();
+        for index in (0..layout.data_blocks).filter(|index| !data_bitmap.is_set(*index)) {
+            let block = layout
+                .data_block(index)
+                .to_block()
+                .expect("Data blocks are never zero");
+            device.read_block(block, buf.inner());
+            if buf.inner().iter().any(|byte| *byte != 0) {
+                device.write_block(block, &[0u8; BLOCK_SIZE]);
+            }
+        }
+
+        let inode_bitmap = self.inode_bitmap.get_mut();
+        for index in (0..inode_bitmap.len()).filter(|index| !inode_bitmap.is_set(*index)) {
+            let (block, offset) = layout.inode_to_block(INodeIndex::new(index as u32));
+            let record = offset.0 as usize..offset.0 as usize + layout.inode_size;
+            device.read_block(block, buf.inner());
+            if buf.inner()[record.clone()].iter().any(|byte| *byte != 0) {
+                buf.inner()[record].fill(0);
+                device.write_block(block, buf.inner());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Grows the filesystem to cover `new_total_blocks` of the device.
+    ///
+    /// The data region, data bitmap and reference counts are extended. When
+    /// they need more blocks, the inode table and all used data blocks are moved
+    /// further into the device and every block pointer is adjusted. Files are
+    /// kept intact, but an interruption while moving corrupts the filesystem.
+    pub fn grow(&mut self, new_total_blocks: usize) -> Result<(), Error> {
+        self.ensure_writable()?;
+
+        if new_total_blocks < self.total_blocks {
+            return Err(Error::OperationNotSupported);
+        }
+        if new_total_blocks > self.block_device.get_mut().total_blocks()
+            || u32::try_from(new_total_blocks).is_err()
+        {
+            return Err(Error::DeviceTooSmall);
+        }
+        if new_total_blocks == self.total_blocks {
+            return Ok(());
+        }
+
+        let new = Layout::canonical(
+            new_total_blocks,
+            self.inode_bitmap.get_mut().len(),
+            self.layout.inode_size,
+            self.layout.has_snapshots(),
+        )
+        .ok_or(Error::DeviceTooSmall)?;
+
+        log::info!(
+            "growing filesystem from {} to {new_total_blocks} blocks",
+            self.total_blocks
+        );
+
+        self.relocate(new)?;
+        self.total_blocks = new_total_blocks;
+        self.flush();
+
+        Ok(())
+    }
+
+    /// Moves the inode table, the snapshot region and all used data blocks
+    /// to the positions described by `new` and adjusts every block pointer.
+    ///
+    /// Regions may only move further into the device. Fails with
+    /// `Error::NoSpaceLeft` if a used data block lies past the end of the
+    /// new data region. The caller has to flush afterwards.
+    fn relocate(&mut self, new: Layout) -> Result<(), Error> {
+        let old = self.layout;
+        let inode_count = self.inode_bitmap.get_mut().len();
+
+        debug_assert!(new.data_start >= old.data_start);
+        debug_assert!(new.inode_table_start >= old.inode_table_start);
+
+        let old_bitmap = self.data_bitmap.get_mut();
+        if (new.data_blocks..old.data_blocks).any(|index| old_bitmap.is_set(index)) {
+            return Err(Error::NoSpaceLeft);
+        }
+
+        let delta = new.data_start - old.data_start;
+
+        // Everything cached has to be on disk before it's moved.
+        self.flush();
+
+        let mut data_bitmap = Bitmap::new(new.data_blocks);
+        let mut refcounts = new
+            .has_snapshots()
+            .then(|| alloc::vec![0u8; new.data_blocks]);
+        let old_bitmap = self.data_bitmap.get_mut();
+        let old_refcounts = self.refcounts.get_mut();
+        for index in 0..old.data_blocks.min(new.data_blocks) {
+            if old_bitmap.is_set(index) {
+                data_bitmap.set(index);
+                if let Some(refcounts) = &mut refcounts {
+                    refcounts[index] = old_refcounts.as_ref().map_or(1, |old| old[index]);
+                }
+            }
+        }
+
+        // Regions only move towards the end, copying backwards never
+        // overwrites a block that still has to be copied.
+        if delta > 0 {
+            for index in (0..old.data_blocks).rev() {
+                if self.data_bitmap.get_mut().is_set(index) {
+                    self.copy_block(old.data_start + index, new.data_start + index);
+                }
+            }
+        }
+        if new.inode_table_start != old.inode_table_start {
+            for index in (0..old.inode_table_blocks).rev() {
+                self.copy_block(old.inode_table_start + index, new.inode_table_start + index);
+            }
+        }
+
+        // The reference counts are written from memory by the next flush.
+        if old.has_snapshots() {
+            self.copy_block(old.snapshot_start, new.snapshot_start);
+        } else if new.has_snapshots() {
+            self.block_device.lock().write_block(
+                BlockIndex::from_raw(new.snapshot_start as u32),
+                &[0u8; BLOCK_SIZE],
+            );
+        }
+
+        self.layout = new;
+        *self.data_bitmap.get_mut() = data_bitmap;
+        *self.refcounts.get_mut() = refcounts;
+        *self.inode_cache.get_mut() = INodeCache::new(new, inode_count);
+
+        if delta > 0 {
+            for index in self.allocated_inodes() {
+                self.update_inode(index, |inode| inode.relocate_blocks(delta as u32));
+            }
+            self.relocate_snapshots(delta as u32);
+        }
+
+        Ok(())
+    }
+
+    /// Moves every block pointer stored in the snapshot region `delta`
+    /// blocks further into the device, after the data blocks were moved.
+    fn relocate_snapshots(&mut self, delta: u32) {
+        let mut table = self.read_snapshot_table();
+        let relocated = |block: DataBlockIndex| match block.raw() {
+            0 => block,
+            raw => DataBlockIndex::from_raw_unchecked(raw + delta),
+        };
+
+        let inode_size = self.layout.inode_size;
+
+        for entry in table.iter_mut().filter(|entry| !entry.is_free()) {
+            entry.head = relocated(entry.head);
+
+            let mut next = entry.head;
+            while let Some(block) = next.to_block() {
+                next = modify_block(&mut *self.block_device.lock(), block, |buf| {
+                    let mut dump = DumpBlock::decode(buf.inner(), inode_size);
+                    dump.next = relocated(dump.next);
+                    for (_, inode) in dump.records.iter_mut() {
+                        inode.relocate_blocks(delta);
+                    }
+                    dump.encode(buf.inner(), inode_size);
+                    dump.next
+                });
+            }
+        }
+
+        self.write_snapshot_table(&table);
+    }
+
+    /// Takes a snapshot named `name` of every file and directory.
+    ///
+    /// The `INode`s are copied into the snapshot while their data blocks are
+    /// shared with it: a shared block is copied when it's written to, see
+    /// `block_for_write`. The first snapshot enables the snapshot region,
+    /// which moves the inode table and all data blocks like `grow`.
+    pub fn create_snapshot(&mut self, name: &str) -> Result<(), Error> {
+        self.ensure_writable()?;
+
+        if name.is_empty() {
+            return Err(Error::EmptyName);
+        }
+        if name.len() > SNAPSHOT_NAME_LEN {
+            return Err(Error::NameTooLong);
+        }
+
+        if !self.layout.has_snapshots() {
+            self.enable_snapshots()?;
+        }
+
+        let mut table = self.read_snapshot_table();
+        if table
+            .iter()
+            .any(|entry| !entry.is_free() && entry.name() == name)
+        {
+            return Err(Error::EntryExists);
+        }
+        let slot = table
+            .iter()
+            .position(SnapshotEntry::is_free)
+            .ok_or(Error::TooManySnapshots)?;
+
+        let inodes: Vec<(INodeIndex, INode)> = self
+            .allocated_inodes()
+            .into_iter()
+            .map(|index| (index, self.lookup_inode(index)))
+            .collect();
+
+        let dump_blocks = inodes.len().div_ceil(DUMP_RECORDS_PER_BLOCK);
+        if dump_blocks > self.free_data_blocks() {
+            return Err(Error::NoSpaceLeft);
+        }
+        let chain = (0..dump_blocks)
+            .map(|_| self.allocate_data_block())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut buf = Buffer::new();
+        for (i, records) in inodes.chunks(DUMP_RECORDS_PER_BLOCK).enumerate() {
+            let dump = DumpBlock {
+                next: chain.get(i + 1).copied().unwrap_or_default(),
+                records: records.to_vec(),
+            };
+            buf.clear();
+            dump.encode(buf.inner(), self.layout.inode_size);
+            self.block_device.lock().write_block(
+                chain[i]
+                    .to_block()
+                    .expect("Is set by `allocate_data_block`"),
+                buf.inner(),
+            );
+        }
+
+        for (_, inode) in &inodes {
+            for block in inode.owned_blocks() {
+                self.add_reference(block);
+            }
+        }
+
+        table[slot] = SnapshotEntry::new(name, chain[0], inodes.len() as u32);
+        self.write_snapshot_table(&table);
+        self.flush();
+
+        log::info!("created snapshot \"{name}\" of {} inodes", inodes.len());
+        Ok(())
+    }
+
+    /// Lists all snapshots.
+    pub fn snapshots(&self) -> Vec<SnapshotInfo> {
+        self.read_snapshot_table()
+            .iter()
+            .filter(|entry| !entry.is_free())
+            .map(|entry| SnapshotInfo {
+                name: entry.name(),
+                inodes: entry.inodes as usize,
+            })
+            .collect()
+    }
+
+    /// Deletes the snapshot `name`, freeing every block only it still uses.
+    pub fn delete_snapshot(&mut self, name: &str) -> Result<(), Error> {
+        self.ensure_writable()?;
+
+        let mut table = self.read_snapshot_table();
+        let slot = Self::find_snapshot(&table, name)?;
+        let (chain, records) = self.read_dump(table[slot].head);
+
+        for (_, inode) in &records {
+            for block in inode.owned_blocks() {
+                self.free_data_block(block);
+            }
+        }
+        for block in chain {
+            self.free_data_block(block);
+        }
+
+        table[slot] = SnapshotEntry::new("", DataBlockIndex::default(), 0);
+        self.write_snapshot_table(&table);
+        self.flush();
+
+        log::info!("deleted snapshot \"{name}\"");
+        Ok(())
+    }
+
+    /// Restores every file and directory to the state of the snapshot
+    /// `name`. Changes since the snapshot are lost, the snapshot is kept.
+    pub fn rollback(&mut self, name: &str) -> Result<(), Error> {
+        self.ensure_writable()?;
+
+        let table = self.read_snapshot_table();
+        let slot = Self::find_snapshot(&table, name)?;
+        let (_, records) = self.read_dump(table[slot].head);
+        let inode_count = self.inode_bitmap.get_mut().len();
+
+        // Every block the snapshot uses is still referenced by it, so only
+        // blocks written since the snapshot are freed here.
+        for index in self.allocated_inodes() {
+            let inode = self.lookup_inode(index);
+            for block in inode.owned_blocks() {
+                self.free_data_block(block);
+            }
+
+            // Files created since the snapshot are gone, like after
+            // `remove_dir_entry`.
+            if !records.iter().any(|(record, _)| *record == index) {
+                self.watches.get_mut().forget(index);
+                self.file_locks.get_mut().forget(index);
+            }
+        }
+
+        *self.inode_bitmap.get_mut() = Bitmap::new(inode_count);
+        *self.inode_cache.get_mut() = INodeCache::new(self.layout, inode_count);
+
+        for (index, inode) in &records {
+            self.inode_bitmap.get_mut().set(index.inner() as usize);
+            self.write_inode_to_disk(*index, inode);
+            for block in inode.owned_blocks() {
+                self.add_reference(block);
+            }
+        }
+
+        self.flush();
+
+        log::info!("rolled back to snapshot \"{name}\"");
+        Ok(())
+    }
+
+    /// Moves to the canonical layout with a snapshot region.
+    fn enable_snapshots(&mut self) -> Result<(), Error> {
+        let new = Layout::canonical(
+            self.total_blocks,
+            self.inode_bitmap.get_mut().len(),
+            self.layout.inode_size,
+            true,
+        )
+        .ok_or(Error::NoSpaceLeft)?;
+
+        log::info!("enabling snapshots");
+
+        self.relocate(new)?;
+        self.features.incompat |= Features::INCOMPAT_SNAPSHOTS;
+        self.flush();
+
+        Ok(())
+    }
+
+    fn allocated_inodes(&self) -> Vec<INodeIndex> {
+        let inode_bitmap = self.inode_bitmap.lock();
+        (0..inode_bitmap.len())
+            .filter(|index| inode_bitmap.is_set(*index))
+            .map(|index| INodeIndex::new(index as u32))
+            .collect()
+    }
+
+    fn find_snapshot(table: &[SnapshotEntry], name: &str) -> Result<usize, Error> {
+        table
+            .iter()
+            .position(|entry| !entry.is_free() && entry.name() == name)
+            .ok_or(Error::NotFound)
+    }
+
+    /// Reads all `MAX_SNAPSHOTS` entries of the snapshot table, which is
+    /// empty without a snapshot region.
+    fn read_snapshot_table(&self) -> Vec<SnapshotEntry> {
+        if !self.layout.has_snapshots() {
+            return Vec::new();
+        }
+
+        let mut buf = Buffer::new();
+        self.block_device.lock().read_block(
+            BlockIndex::from_raw(self.layout.snapshot_start as u32),
+            buf.inner(),
+        );
+
+        (0..MAX_SNAPSHOTS)
+            .map(|i| buf.read_struct_at(i * mem::size_of::<SnapshotEntry>()))
+            .collect()
+    }
+
+    fn write_snapshot_table(&self, table: &[SnapshotEntry]) {
+        let mut buf = Buffer::new();
+        for (i, entry) in table.iter().enumerate() {
+            buf.write_struct_at(entry, i * mem::size_of::<SnapshotEntry>());
+        }
+
+        self.block_device.lock().write_block(
+            BlockIndex::from_raw(self.layout.snapshot_start as u32),
+            buf.inner(),
+        );
+    }
+
+    /// Reads the dump chain starting at `head`, returning its blocks and the
+    /// `INode`s stored in it.
+    fn read_dump(&self, head: DataBlockIndex) -> (Vec<DataBlockIndex>, Vec<(INodeIndex, INode)>) {
+        let mut chain = Vec::new();
+        let mut records = Vec::new();
+        let mut buf = Buffer::new();
+        let mut next = head;
+
+        // A corrupted chain could loop, it can't be longer than the data region.
+        while let Some(block) = next.to_block()
+            && chain.len() < self.layout.data_blocks
+        {
+            self.block_device.lock().read_block(block, buf.inner());
+            let dump = DumpBlock::decode(buf.inner(), self.layout.inode_size);
+
+            chain.push(next);
+            records.extend(dump.records);
+            next = dump.next;
+        }
+
+        (chain, records)
+    }
+
+    fn copy_block(&self, from: usize, to: usize) {
+        let mut device = self.block_device.lock();
+        let mut buf = Buffer::new();
+        device.read_block(BlockIndex::from_raw(from as u32), buf.inner());
+        device.write_block(BlockIndex::from_raw(to as u32), buf.inner());
+    }
+
+    fn write_superblock_state(&self, state: u32) {
+        let superblock = SuperBlock::from_layout(
+            self.total_blocks,
+            self.inode_bitmap.lock().len(),
+            self.layout,
+            state,
+            self.features,
+        );
+
+        self.write_superblock(&superblock);
+    }
+
+    pub fn mkdir(&self, path: &str) -> Result<INodeIndex, Error> {
+        self.new_dir_entry(path, Entry::Directory)
+    }
+
+    pub fn create_file(&self, path: &str) -> Result<INodeIndex, Error> {
+        self.new_dir_entry(path, Entry::File)
+    }
+
+    pub fn write_to_file(&self, path: &str, bytes: &[u8]) -> Result<usize, Error> {
+        self.append_to_file(path, bytes)
+    }
+
+    /// Writes `bytes` at `offset` of the file, overwriting existing content.
+    /// Blocks skipped when writing past the end stay unallocated.
+    pub fn write_at(&self, path: &str, offset: usize, bytes: &[u8]) -> Result<usize, Error> {
+        self.ensure_writable()?;
+        let (inode_index, _lock) = self.lock_path(path, RwLock::write)?;
+        let written = self.write_at_inode(inode_index, offset, bytes)?;
+        self.notify(inode_index, || WatchEvent::Modified(path.into()));
+        Ok(written)
+    }
+}
+
+/// Splits `path` into its directories and the last component, ignoring
+/// redundant slashes. The root directory has no last component.
+fn split_path(path: &str) -> (Vec<&str>, Option<&str>) {
+    let mut parts: Vec<_> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let last = parts.pop();
+    (parts, last)
+}
+
+fn validate_xattr_name(name: &str) -> Result<(), Error> {
+    if name.is_empty() {
+        return Err(Error::EmptyName);
+    }
+    if name.len() > MAX_XATTR_NAME {
+        return Err(Error::NameTooLong);
+    }
+
+    Ok(())
+}
+
+fn read_bitmap<Dev: BlockDevice>(
+    block_device: &mut Dev,
+    start: usize,
+    blocks: usize,
+    logical_len: usize,
+) -> Result<Bitmap, Error> {
+    let word_count = logical_len.div_ceil(u32::BITS as usize);
+    let bytes_needed = word_count.checked_mul(4).ok_or(Error::InvalidSuperblock)?;
+    let region_bytes = blocks
+        .checked_mul(BLOCK_SIZE)
+        .ok_or(Error::InvalidSuperblock)?;
+    if bytes_needed > region_bytes {
+        return Err(Error::InvalidSuperblock);
     }
 
     let blocks_needed = bytes_needed.div_ceil(BLOCK_SIZE);
@@ -1099,18 +2935,62 @@ fn write_bitmap<Dev: BlockDevice>(
 ) {
     debug_assert!(bitmap.as_words().len() * 4 <= blocks * BLOCK_SIZE);
 
-    let mut words = bitmap.as_words().iter();
     for offset in 0..blocks {
-        let mut buf = [0u8; BLOCK_SIZE];
-        for slot in buf.chunks_exact_mut(4) {
-            let Some(word) = words.next() else {
-                break;
-            };
-            slot.copy_from_slice(&word.to_le_bytes());
-        }
-        block_device.write_block(BlockIndex::from_raw((start + offset) as u32), &buf);
+        write_bitmap_block(block_device, start, bitmap, offset);
+    }
+}
+
+/// Writes the `offset`th block of the bitmap region starting at `start`.
+fn write_bitmap_block<Dev: BlockDevice>(
+    block_device: &mut Dev,
+    start: usize,
+    bitmap: &Bitmap,
+    offset: usize,
+) {
+    const WORDS_PER_BLOCK: usize = BLOCK_SIZE / 4;
+
+    let mut buf = [0u8; BLOCK_SIZE];
+    let words = bitmap.as_words().iter().skip(offset * WORDS_PER_BLOCK);
+    for (slot, word) in buf.chunks_exact_mut(4).zip(words) {
+        slot.copy_from_slice(&word.to_le_bytes());
+    }
+    block_device.write_block(BlockIndex::from_raw((start + offset) as u32), &buf);
+}
+
+/// Reads one reference count byte per data block.
+fn read_refcounts<Dev: BlockDevice>(
+    block_device: &mut Dev,
+    start: usize,
+    data_blocks: usize,
+) -> Vec<u8> {
+    let mut refcounts = Vec::with_capacity(data_blocks);
+    let mut buf = [0u8; BLOCK_SIZE];
+    for offset in 0..data_blocks.div_ceil(BLOCK_SIZE) {
+        block_device.read_block(BlockIndex::from_raw((start + offset) as u32), &mut buf);
+        let remaining = data_blocks - refcounts.len();
+        refcounts.extend_from_slice(&buf[..remaining.min(BLOCK_SIZE)]);
     }
-    debug_assert!(words.next().is_none());
+    refcounts
+}
+
+fn write_refcounts<Dev: BlockDevice>(block_device: &mut Dev, start: usize, refcounts: &[u8]) {
+    for offset in 0..refcounts.len().div_ceil(BLOCK_SIZE) {
+        write_refcount_block(block_device, start, refcounts, offset);
+    }
+}
+
+/// Writes the `offset`th block of the reference counts starting at `start`.
+fn write_refcount_block<Dev: BlockDevice>(
+    block_device: &mut Dev,
+    start: usize,
+    refcounts: &[u8],
+    offset: usize,
+) {
+    let mut buf = [0u8; BLOCK_SIZE];
+    let counts = &refcounts[offset * BLOCK_SIZE..];
+    let len = counts.len().min(BLOCK_SIZE);
+    buf[..len].copy_from_slice(&counts[..len]);
+    block_device.write_block(BlockIndex::from_raw((start + offset) as u32), &buf);
 }
 
 /// A wrapper ensuring the read block at `index` is updated and written to disk.
@@ -1129,7 +3009,6 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::layout::DataBlockIndex;
     use std::cell::RefCell;
     use std::rc::Rc;
 
@@ -1196,6 +3075,22 @@ mod tests {
         shared
     }
 
+    /// A device formatted like before feature flags changed the layout.
+    fn legacy_formatted_device() -> Ramdisk {
+        let ramdisk = Ramdisk::new();
+        let shared = ramdisk.share();
+        Filesystem::format_with_features(ramdisk, Features::DEFAULT).unwrap();
+        shared
+    }
+
+    /// Copies `device` onto a new device of `total_blocks`, like `truncate -s`.
+    fn resized_device(device: &Ramdisk, total_blocks: usize) -> Ramdisk {
+        let resized = Ramdisk::with_blocks(total_blocks);
+        let len = device.data.borrow().len().min(total_blocks * BLOCK_SIZE);
+        resized.data.borrow_mut()[..len].copy_from_slice(&device.data.borrow()[..len]);
+        resized
+    }
+
     fn read_test_superblock(device: &Ramdisk) -> SuperBlock {
         let data = device.data.borrow();
         SuperBlock::read_from(&mut ByteReader::new(&data[..SUPERBLOCK_ENCODED_SIZE]))
@@ -1218,12 +3113,12 @@ mod tests {
     }
 
     fn inode_copy(fs: &mut Filesystem<Ramdisk>, idx: INodeIndex) -> INode {
-        *fs.inode_cache.get(idx, &mut fs.block_device)
+        fs.lookup_inode(idx)
     }
 
     fn remount(fs: Filesystem<Ramdisk>) -> Filesystem<Ramdisk> {
         let Filesystem { block_device, .. } = fs;
-        Filesystem::new(block_device).expect("remount failed")
+        Filesystem::new(block_device.into_inner()).expect("remount failed")
     }
 
     fn find_entry_inode(
@@ -1272,7 +3167,7 @@ mod tests {
 
     #[test]
     fn create_file_and_read_back_small_content() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         fs.create_file("/hello.txt").unwrap();
         let written = fs.write_to_file("/hello.txt", b"hello filesystem").unwrap();
@@ -1286,14 +3181,15 @@ mod tests {
         let mut fs = make_fs();
 
         let idx = fs.create_file("/append.txt").unwrap();
-        fs.write_to_file("/append.txt", b"hello ").unwrap();
+        let first = "a".repeat(INLINE_CAPACITY);
+        fs.write_to_file("/append.txt", first.as_bytes()).unwrap();
         fs.write_to_file("/append.txt", b"world").unwrap();
 
         let inode = inode_copy(&mut fs, idx);
         let used_blocks = inode.used_blocks().count();
 
-        assert_eq!(fs.read_file("/append.txt").unwrap(), "hello world");
-        assert_eq!(inode.size(), 11);
+        assert_eq!(fs.read_file("/append.txt").unwrap(), first + "world");
+        assert_eq!(inode.size() as usize, INLINE_CAPACITY + 5);
         assert_eq!(used_blocks, 1);
     }
 
@@ -1336,7 +3232,7 @@ mod tests {
 
     #[test]
     fn write_max_file_size_then_overflow() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         fs.create_file("/max.txt").unwrap();
         let content = vec![b'Z'; MAX_FILE_SIZE];
@@ -1353,7 +3249,7 @@ mod tests {
 
     #[test]
     fn writing_to_file() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         fs.create_file("/text.txt").expect("Unable to create file");
         let content = "A".repeat(511);
@@ -1382,7 +3278,7 @@ mod tests {
 
     #[test]
     fn create_directory_structure() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         fs.mkdir("/test").expect("Could not create directory");
         fs.mkdir("/test/foo")
@@ -1393,7 +3289,7 @@ mod tests {
 
     #[test]
     fn duplicate_directory_name_in_same_dir_returns_error() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         fs.mkdir("/dup").unwrap();
         let res = fs.mkdir("/dup");
@@ -1403,7 +3299,7 @@ mod tests {
 
     #[test]
     fn file_then_directory_same_name_in_same_parent_returns_error() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         fs.create_file("/same").unwrap();
         let res = fs.mkdir("/same");
@@ -1413,7 +3309,7 @@ mod tests {
 
     #[test]
     fn directory_then_file_same_name_in_same_parent_returns_error() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         fs.mkdir("/same").unwrap();
         let res = fs.create_file("/same");
@@ -1423,7 +3319,7 @@ mod tests {
 
     #[test]
     fn same_name_in_different_directories_is_allowed() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         fs.mkdir("/a").unwrap();
         fs.mkdir("/b").unwrap();
@@ -1434,7 +3330,7 @@ mod tests {
 
     #[test]
     fn create_in_missing_parent_returns_directory_does_not_exist() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         let file_res = fs.create_file("/missing/file.txt");
         let dir_res = fs.mkdir("/missing/subdir");
@@ -1445,7 +3341,7 @@ mod tests {
 
     #[test]
     fn create_file_into_file_returns_not_a_directory() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         fs.create_file("/test.txt").unwrap();
         let res = fs.create_file("/test.txt/huh");
@@ -1455,7 +3351,7 @@ mod tests {
 
     #[test]
     fn mkdir_multi_level_into_file_component_returns_not_a_directory() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         fs.mkdir("/a").unwrap();
         fs.create_file("/a/file").unwrap();
@@ -1466,7 +3362,7 @@ mod tests {
 
     #[test]
     fn dot_points_to_self_for_new_directories() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         let a = fs.mkdir("/a").unwrap();
         let b = fs.mkdir("/a/b").unwrap();
@@ -1483,7 +3379,7 @@ mod tests {
 
     #[test]
     fn dotdot_points_to_actual_parent_for_nested_dirs() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         let parent = fs.mkdir("/a").unwrap();
         let child = fs.mkdir("/a/b").unwrap();
@@ -1521,7 +3417,7 @@ mod tests {
         let max_entries_for_inode = 16 * DIR_ENTRY_PER_BLOCK;
         let full_size = (max_entries_for_inode * core::mem::size_of::<DirEntry>()) as u32;
 
-        let inode = fs.inode_cache.get_mut(cap, &mut fs.block_device);
+        let inode = fs.inode_cache.get_mut().get_mut(cap, &fs.block_device);
 
         inode.set_size(full_size);
         for block in inode.blocks_mut().filter(|b| b.is_empty()) {
@@ -1529,7 +3425,8 @@ mod tests {
         }
 
         fs.inode_cache
-            .get_mut(cap, &mut fs.block_device)
+            .get_mut()
+            .get_mut(cap, &fs.block_device)
             .set_size(full_size);
 
         let overflow = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
@@ -1545,7 +3442,7 @@ mod tests {
 
     #[test]
     fn name_exactly_24_bytes_roundtrip() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         let name = "abcdefghijklmnopqrstuvwx";
         assert_eq!(name.len(), 24);
@@ -1563,7 +3460,7 @@ mod tests {
 
     #[test]
     fn name_longer_than_24_bytes_is_rejected() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         let name = "abcdefghijklmnopqrstuvwxy";
         assert_eq!(name.len(), 25);
@@ -1577,7 +3474,7 @@ mod tests {
 
     #[test]
     fn empty_path_is_rejected_without_panic() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| fs.create_file("")));
         assert!(result.is_ok(), "empty path should not panic");
@@ -1603,7 +3500,7 @@ mod tests {
 
     #[test]
     fn root_path_is_rejected() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         let res = fs.create_file("/");
         assert!(res.is_err(), "`/` should not be a valid creatable entry");
@@ -1611,7 +3508,7 @@ mod tests {
 
     #[test]
     fn trailing_slash_path_is_rejected() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         fs.mkdir("/dir").unwrap();
         let res = fs.create_file("/dir/");
@@ -1621,7 +3518,7 @@ mod tests {
 
     #[test]
     fn flush_and_remount_preserves_structure_and_content() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         fs.mkdir("/dir").unwrap();
         let file = fs.create_file("/dir/notes.txt").unwrap();
@@ -1641,13 +3538,13 @@ mod tests {
         let mut fs = make_fs();
 
         let first = fs.create_file("/one").unwrap();
-        fs.write_to_file("/one", b"hello").unwrap();
+        fs.write_to_file("/one", &[b'1'; BLOCK_SIZE]).unwrap();
         let first_block = first_data_block(&inode_copy(&mut fs, first)).unwrap();
         fs.flush();
 
         let mut fs = remount(fs);
         let second = fs.create_file("/two").unwrap();
-        fs.write_to_file("/two", b"world").unwrap();
+        fs.write_to_file("/two", &[b'2'; BLOCK_SIZE]).unwrap();
 
         let second_block = first_data_block(&inode_copy(&mut fs, second)).unwrap();
 
@@ -1664,10 +3561,10 @@ mod tests {
         fs.write_to_file("/idempotent/file.txt", b"hello").unwrap();
 
         fs.flush();
-        let after_first_flush = fs.block_device.data.borrow().clone();
+        let after_first_flush = fs.block_device.get_mut().data.borrow().clone();
 
         fs.flush();
-        let after_second_flush = fs.block_device.data.borrow().clone();
+        let after_second_flush = fs.block_device.get_mut().data.borrow().clone();
 
         assert_eq!(after_first_flush, after_second_flush);
     }
@@ -1689,18 +3586,18 @@ mod tests {
 
     #[test]
     fn append_after_remount_preserves_and_extends() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         fs.create_file("/append-remount.txt").unwrap();
         fs.write_to_file("/append-remount.txt", b"abc").unwrap();
         fs.flush();
 
-        let mut fs = remount(fs);
+        let fs = remount(fs);
         fs.write_to_file("/append-remount.txt", b"def").unwrap();
         assert_eq!(fs.read_file("/append-remount.txt").unwrap(), "abcdef");
         fs.flush();
 
-        let mut fs = remount(fs);
+        let fs = remount(fs);
         assert_eq!(fs.read_file("/append-remount.txt").unwrap(), "abcdef");
     }
 
@@ -1708,9 +3605,9 @@ mod tests {
     fn inode_exhaustion_returns_error_not_panic() {
         let mut fs = make_fs();
 
-        let bits = bitmap_capacity_bits(&fs.inode_bitmap);
+        let bits = bitmap_capacity_bits(fs.inode_bitmap.get_mut());
         for idx in 0..bits {
-            fs.inode_bitmap.set(idx);
+            fs.inode_bitmap.get_mut().set(idx);
         }
 
         let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
@@ -1730,13 +3627,13 @@ mod tests {
 
         fs.create_file("/data-oom.txt").unwrap();
 
-        let bits = bitmap_capacity_bits(&fs.data_bitmap);
+        let bits = bitmap_capacity_bits(fs.data_bitmap.get_mut());
         for idx in 0..bits {
-            fs.data_bitmap.set(idx);
+            fs.data_bitmap.get_mut().set(idx);
         }
 
         let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
-            fs.write_to_file("/data-oom.txt", b"x")
+            fs.write_to_file("/data-oom.txt", &[b'x'; BLOCK_SIZE])
         }));
 
         assert!(
@@ -1754,20 +3651,20 @@ mod tests {
         let max_entries = 16 * DIR_ENTRY_PER_BLOCK;
         let full_size = (max_entries * core::mem::size_of::<DirEntry>()) as u32;
 
-        let inode = fs.inode_cache.get_mut(cap, &mut fs.block_device);
+        let inode = fs.inode_cache.get_mut().get_mut(cap, &fs.block_device);
 
         inode.set_size(full_size);
         for block in inode.blocks_mut().filter(|b| b.is_empty()) {
             *block = DataBlockIndex::from_raw_unchecked(1);
         }
 
-        let before = bitmap_set_count(&fs.inode_bitmap);
+        let before = bitmap_set_count(fs.inode_bitmap.get_mut());
 
         let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
             fs.create_file("/cap-leak/new")
         }));
 
-        let after = bitmap_set_count(&fs.inode_bitmap);
+        let after = bitmap_set_count(fs.inode_bitmap.get_mut());
 
         assert_eq!(
             before, after,
@@ -1779,7 +3676,7 @@ mod tests {
 
     #[test]
     fn remount_without_flush_drops_unflushed_namespace_changes() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         fs.mkdir("/tmp").unwrap();
         fs.create_file("/tmp/ephemeral.txt").unwrap();
@@ -1819,8 +3716,9 @@ mod tests {
         let first = fs.create_file("/first.txt").unwrap();
         let second = fs.create_file("/second.txt").unwrap();
 
-        fs.write_to_file("/first.txt", b"aaa").unwrap();
-        fs.write_to_file("/second.txt", b"bbb").unwrap();
+        fs.write_to_file("/first.txt", &[b'a'; BLOCK_SIZE]).unwrap();
+        fs.write_to_file("/second.txt", &[b'b'; BLOCK_SIZE])
+            .unwrap();
 
         let first_block = first_data_block(&inode_copy(&mut fs, first)).unwrap();
         let second_block = first_data_block(&inode_copy(&mut fs, second)).unwrap();
@@ -1830,7 +3728,7 @@ mod tests {
 
     #[test]
     fn writing_multiple_blocks() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         let content = "A".repeat(MAX_FILE_SIZE);
         fs.create_file("/test.txt").unwrap();
@@ -1842,7 +3740,7 @@ mod tests {
 
     #[test]
     fn file_and_directory_with_same_name() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         fs.mkdir("/test").unwrap();
         fs.mkdir("/test/x").unwrap();
@@ -1851,7 +3749,7 @@ mod tests {
 
     #[test]
     fn writing_to_directory_returns_error() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         fs.mkdir("/test").unwrap();
         let res = fs.write_to_file("/test", b"xd");
@@ -1860,7 +3758,7 @@ mod tests {
 
     #[test]
     fn mkdir_into_file_returns_error() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         fs.create_file("/test.txt").unwrap();
         let res = fs.mkdir("/test.txt/huh");
@@ -1871,7 +3769,7 @@ mod tests {
 
     #[test]
     fn remove_file_from_root() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         fs.create_file("/file.txt").unwrap();
         fs.remove_dir_entry("/file.txt").unwrap();
@@ -1908,7 +3806,7 @@ mod tests {
 
     #[test]
     fn remove_dir_from_root() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         fs.mkdir("/emptydir").unwrap();
         let res = fs.remove_dir_entry("/emptydir");
@@ -1917,7 +3815,7 @@ mod tests {
 
     #[test]
     fn failed_directory_remove_does_not_remove_entry() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         fs.mkdir("/emptydir").unwrap();
         assert_eq!(
@@ -1942,11 +3840,11 @@ mod tests {
         let mut fs = make_fs();
 
         let idx = fs.create_file("/tracked.txt").unwrap();
-        assert!(fs.inode_bitmap.is_set(idx.inner() as usize));
+        assert!(fs.inode_bitmap.get_mut().is_set(idx.inner() as usize));
 
         fs.remove_dir_entry("/tracked.txt").unwrap();
 
-        assert!(!fs.inode_bitmap.is_set(idx.inner() as usize));
+        assert!(!fs.inode_bitmap.get_mut().is_set(idx.inner() as usize));
     }
 
     #[test]
@@ -1954,12 +3852,11 @@ mod tests {
         let mut fs = make_fs();
 
         fs.create_file("/data.txt").unwrap();
-        fs.write_to_file("/data.txt", b"some content that occupies a block")
-            .unwrap();
+        fs.write_to_file("/data.txt", &[b'd'; BLOCK_SIZE]).unwrap();
 
-        let before = bitmap_set_count(&fs.data_bitmap);
+        let before = bitmap_set_count(fs.data_bitmap.get_mut());
         fs.remove_dir_entry("/data.txt").unwrap();
-        let after = bitmap_set_count(&fs.data_bitmap);
+        let after = bitmap_set_count(fs.data_bitmap.get_mut());
 
         assert!(
             after < before,
@@ -1969,7 +3866,7 @@ mod tests {
 
     #[test]
     fn removing_dirty_inode_can_be_flushed() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         fs.create_file("/dirty.txt").unwrap();
         fs.write_to_file("/dirty.txt", b"dirty inode").unwrap();
@@ -1980,7 +3877,7 @@ mod tests {
 
     #[test]
     fn remove_allows_name_reuse() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         fs.create_file("/reuse.txt").unwrap();
         fs.remove_dir_entry("/reuse.txt").unwrap();
@@ -1991,13 +3888,13 @@ mod tests {
 
     #[test]
     fn remove_and_remount_does_not_see_entry() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         fs.create_file("/gone.txt").unwrap();
         fs.remove_dir_entry("/gone.txt").unwrap();
         fs.flush();
 
-        let mut fs = remount(fs);
+        let fs = remount(fs);
         let names: Vec<_> = fs
             .read_dir_entry(INodeIndex::new(0))
             .into_iter()
@@ -2008,7 +3905,7 @@ mod tests {
 
     #[test]
     fn remove_nonexistent_returns_not_found() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         let res = fs.remove_dir_entry("/nope.txt");
         assert_eq!(res.err(), Some(Error::NotFound));
@@ -2016,7 +3913,7 @@ mod tests {
 
     #[test]
     fn remove_in_missing_parent_returns_directory_does_not_exist() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         let res = fs.remove_dir_entry("/nope/file.txt");
         assert_eq!(res.err(), Some(Error::NotFound));
@@ -2024,7 +3921,7 @@ mod tests {
 
     #[test]
     fn remove_with_empty_path_returns_error() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         let result =
             std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| fs.remove_dir_entry("")));
@@ -2037,7 +3934,7 @@ mod tests {
 
     #[test]
     fn remove_root_path_returns_error() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         let result =
             std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| fs.remove_dir_entry("/")));
@@ -2051,7 +3948,7 @@ mod tests {
 
     #[test]
     fn remove_through_file_as_component_returns_not_a_directory() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         fs.create_file("/file.txt").unwrap();
         let res = fs.remove_dir_entry("/file.txt/thing");
@@ -2061,7 +3958,7 @@ mod tests {
 
     #[test]
     fn remove_nonempty_directory_returns_not_empty() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         fs.mkdir("/a").unwrap();
         fs.create_file("/a/child.txt").unwrap();
@@ -2072,7 +3969,7 @@ mod tests {
 
     #[test]
     fn remove_does_not_affect_siblings() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         fs.create_file("/a").unwrap();
         fs.create_file("/b").unwrap();
@@ -2093,7 +3990,7 @@ mod tests {
 
     #[test]
     fn remove_dot_entry_is_rejected() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         fs.mkdir("/dir").unwrap();
 
@@ -2124,7 +4021,7 @@ mod tests {
 
     #[test]
     fn remove_then_readd_same_name_can_reuse_inode() {
-        let mut fs = make_fs();
+        let fs = make_fs();
 
         let old_idx = fs.create_file("/fresh.txt").unwrap();
         fs.remove_dir_entry("/fresh.txt").unwrap();
@@ -2139,15 +4036,74 @@ mod tests {
 
     #[test]
     fn superblock_round_trip_has_fixed_encoded_size() {
-        assert_eq!(SUPERBLOCK_ENCODED_SIZE, 56);
-        let layout = Layout::new(RAMDISK_SIZE / BLOCK_SIZE, MAX_INODES).unwrap();
-        let expected = SuperBlock::from_layout(RAMDISK_SIZE / BLOCK_SIZE, MAX_INODES, layout);
+        assert_eq!(SUPERBLOCK_ENCODED_SIZE, 80);
+        let layout =
+            Layout::canonical(RAMDISK_SIZE / BLOCK_SIZE, MAX_INODES, INODE_SIZE, false).unwrap();
+        let expected = SuperBlock::from_layout(
+            RAMDISK_SIZE / BLOCK_SIZE,
+            MAX_INODES,
+            layout,
+            STATE_CLEAN,
+            Features::DEFAULT,
+        );
         let mut bytes = [0u8; SUPERBLOCK_ENCODED_SIZE];
         expected.write_to(&mut ByteWriter::new(&mut bytes));
         let decoded = SuperBlock::read_from(&mut ByteReader::new(&bytes));
         assert_eq!(decoded, expected);
     }
 
+    #[test]
+    fn inspect_decodes_superblock_and_inodes_without_mounting() {
+        let device = formatted_device(RAMDISK_SIZE / BLOCK_SIZE);
+        let fs = Filesystem::new(device.share()).unwrap();
+        fs.mkdir("/docs").unwrap();
+        fs.create_file("/docs/small.txt").unwrap();
+        fs.write_to_file("/docs/small.txt", b"tiny").unwrap();
+        fs.create_file("/big.bin").unwrap();
+        fs.write_to_file("/big.bin", &[7; BLOCK_SIZE + 1]).unwrap();
+        let big = fs.stat("/big.bin").unwrap().inode;
+        fs.remove_dir_entry("/docs/small.txt").unwrap();
+        fs.flush();
+
+        // Still mounted, so the superblock is marked dirty.
+        let mut device = device.share();
+        let info = Filesystem::inspect_superblock(&mut device).unwrap();
+        assert_eq!(info.magic, MAGIC);
+        assert_eq!(info.version, FILESYSTEM_VERSION);
+        assert!(!info.clean);
+        assert_eq!(info.total_blocks as usize, RAMDISK_SIZE / BLOCK_SIZE);
+        assert_eq!(info.inode_size, INODE_SIZE);
+        assert_eq!(info.regions[1].start, fs.layout.inode_bitmap_start as u32);
+        assert_eq!(info.regions[5].blocks, fs.layout.data_blocks as u32);
+        assert_eq!(info.problem, None);
+
+        let inode = Filesystem::inspect_inode(&mut device, big).unwrap();
+        assert!(inode.allocated && !inode.is_directory && !inode.inline);
+        assert_eq!(inode.size as usize, BLOCK_SIZE + 1);
+        assert_eq!(inode.blocks.iter().filter(|block| **block != 0).count(), 2);
+        assert_eq!(inode.raw.len(), INODE_SIZE);
+        assert_eq!(&inode.raw[..4], &(BLOCK_SIZE as u32 + 1).to_le_bytes());
+
+        // The removed file's record is left behind unallocated.
+        let removed = Filesystem::inspect_inode(&mut device, INodeIndex::new(2)).unwrap();
+        assert!(!removed.allocated);
+        assert_eq!(
+            Filesystem::inspect_inode(&mut device, INodeIndex::new(MAX_INODES as u32)).err(),
+            Some(Error::NotFound)
+        );
+
+        // Broken superblocks are still decoded.
+        let mut superblock = read_test_superblock(&device);
+        superblock.data_blocks += 1;
+        write_test_superblock(&device, &superblock);
+        let info = Filesystem::inspect_superblock(&mut device).unwrap();
+        assert_eq!(info.problem, Some(Error::InvalidSuperblock));
+        assert_eq!(
+            Filesystem::inspect_inode(&mut device, big).err(),
+            Some(Error::InvalidSuperblock)
+        );
+    }
+
     #[test]
     fn formatted_bitmaps_have_exact_logical_lengths_and_raw_words() {
         let device = formatted_device(RAMDISK_SIZE / BLOCK_SIZE);
@@ -2168,8 +4124,8 @@ mod tests {
         drop(data);
 
         let fs = Filesystem::new(device).unwrap();
-        assert_eq!(fs.inode_bitmap.len(), MAX_INODES);
-        assert_eq!(fs.data_bitmap.len(), fs.layout.data_blocks);
+        assert_eq!(fs.inode_bitmap.lock().len(), MAX_INODES);
+        assert_eq!(fs.data_bitmap.lock().len(), fs.layout.data_blocks);
     }
 
     #[test]
@@ -2187,11 +4143,11 @@ mod tests {
         }
 
         let fs = Filesystem::new(device).unwrap();
-        assert_eq!(fs.data_bitmap.as_words().len(), word_count);
-        assert_eq!(fs.data_bitmap.len(), superblock.data_blocks as usize);
+        assert_eq!(fs.data_bitmap.lock().as_words().len(), word_count);
+        assert_eq!(fs.data_bitmap.lock().len(), superblock.data_blocks as usize);
         let remainder = superblock.data_blocks as usize % 32;
         assert_eq!(
-            fs.data_bitmap.as_words()[word_count - 1],
+            fs.data_bitmap.lock().as_words()[word_count - 1],
             if remainder == 0 {
                 u32::MAX
             } else {
@@ -2209,19 +4165,33 @@ mod tests {
     }
 
     #[test]
-    fn rejects_v1_superblock() {
-        let device = Ramdisk::new();
+    fn v1_superblock_mounts_read_only_and_requires_upgrade() {
+        let device = formatted_device(RAMDISK_SIZE / BLOCK_SIZE);
         {
             let mut data = device.data.borrow_mut();
+            data[..BLOCK_SIZE].fill(0);
             let mut writer = ByteWriter::new(&mut data[..16]);
             writer.write_u64(MAGIC);
             writer.write_u32(BLOCK_SIZE as u32);
             writer.write_u32((RAMDISK_SIZE / BLOCK_SIZE) as u32);
         }
+
         assert_eq!(
-            Filesystem::new(device).err(),
-            Some(Error::UnsupportedFilesystemVersion(BLOCK_SIZE as u32))
+            Filesystem::new(device.share()).err(),
+            Some(Error::UpgradeRequired(1))
         );
+        let fs = Filesystem::mount_read_only(device.share()).unwrap();
+        assert!(fs.mounted_clean());
+
+        assert_eq!(Filesystem::needs_upgrade(&mut device.share()), Ok(true));
+        assert_eq!(Filesystem::upgrade(device.share()), Ok(1));
+        assert_eq!(Filesystem::needs_upgrade(&mut device.share()), Ok(false));
+        let superblock = read_test_superblock(&device);
+        assert_eq!(superblock.version, FILESYSTEM_VERSION);
+        assert_eq!(superblock.features, Features::DEFAULT);
+        assert_eq!(superblock.state, STATE_CLEAN);
+        let fs = Filesystem::new(device).unwrap();
+        fs.create_file("/after-upgrade").unwrap();
     }
 
     #[test]
@@ -2248,6 +4218,17 @@ mod tests {
         );
     }
 
+    #[test]
+    fn rejects_device_smaller_than_recorded() {
+        let device = formatted_device(RAMDISK_SIZE / BLOCK_SIZE);
+        let smaller = resized_device(&device, RAMDISK_SIZE / BLOCK_SIZE - 1);
+
+        assert_eq!(
+            Filesystem::new(smaller).err(),
+            Some(Error::InvalidSuperblock)
+        );
+    }
+
     #[test]
     fn rejects_overlapping_or_out_of_range_regions() {
         assert_eq!(
@@ -2294,7 +4275,7 @@ mod tests {
     #[test]
     fn formatting_too_small_device_returns_error() {
         let minimum_blocks = (1..RAMDISK_SIZE / BLOCK_SIZE)
-            .find(|&blocks| Layout::new(blocks, MAX_INODES).is_some())
+            .find(|&blocks| Layout::canonical(blocks, MAX_INODES, INODE_SIZE, false).is_some())
             .unwrap();
         assert_eq!(
             Filesystem::format(Ramdisk::with_blocks(minimum_blocks - 1)),
@@ -2305,12 +4286,1213 @@ mod tests {
     #[test]
     fn data_exhaustion_stops_before_device_end() {
         let minimum_blocks = (1..RAMDISK_SIZE / BLOCK_SIZE)
-            .find(|&blocks| Layout::new(blocks, MAX_INODES).is_some())
+            .find(|&blocks| Layout::canonical(blocks, MAX_INODES, INODE_SIZE, false).is_some())
             .unwrap();
         let device = formatted_device(minimum_blocks);
-        let mut fs = Filesystem::new(device).unwrap();
+        let fs = Filesystem::new(device).unwrap();
         assert_eq!(fs.layout.data_blocks, 1);
         fs.create_file("/full").unwrap();
-        assert_eq!(fs.write_to_file("/full", b"x"), Err(Error::NoSpaceLeft));
+        assert_eq!(
+            fs.write_to_file("/full", &[b'x'; BLOCK_SIZE]),
+            Err(Error::NoSpaceLeft)
+        );
+    }
+    #[test]
+    fn read_only_mount_rejects_mutations() {
+        let fs = make_fs();
+        fs.mkdir("/dir").unwrap();
+        fs.create_file("/dir/file").unwrap();
+        fs.write_to_file("/dir/file", b"content").unwrap();
+        fs.flush();
+
+        let fs = Filesystem::mount_read_only(fs.block_device.into_inner()).unwrap();
+        assert!(fs.is_read_only());
+        assert_eq!(fs.mkdir("/other"), Err(Error::ReadOnly));
+        assert_eq!(fs.create_file("/dir/new"), Err(Error::ReadOnly));
+        assert_eq!(fs.write_to_file("/dir/file", b"!"), Err(Error::ReadOnly));
+        assert_eq!(fs.remove_dir_entry("/dir/file"), Err(Error::ReadOnly));
+        assert_eq!(fs.read_file("/dir/file").unwrap(), "content");
+    }
+
+    #[test]
+    fn read_only_mount_never_writes_to_device() {
+        let mut fs = make_fs();
+        fs.create_file("/file").unwrap();
+        fs.write_to_file("/file", b"content").unwrap();
+        fs.flush();
+        let before = fs.block_device.get_mut().data.borrow().clone();
+
+        let mut fs = Filesystem::mount_read_only(fs.block_device.into_inner()).unwrap();
+        let _ = fs.create_file("/new");
+        let _ = fs.write_to_file("/file", b"more");
+        fs.read_file("/file").unwrap();
+        fs.dump_dir("/", &mut String::new()).unwrap();
+        fs.tree(&mut String::new());
+        fs.flush();
+
+        assert_eq!(*fs.block_device.get_mut().data.borrow(), before);
+    }
+    #[test]
+    fn fsync_persists_only_that_inode() {
+        let fs = make_fs();
+        fs.create_file("/synced").unwrap();
+        fs.create_file("/unsynced").unwrap();
+        fs.flush();
+
+        fs.write_to_file("/synced", b"durable").unwrap();
+        fs.write_to_file("/unsynced", b"lost").unwrap();
+        fs.fsync("/synced").unwrap();
+
+        let fs = remount(fs);
+        assert_eq!(fs.read_file("/synced").unwrap(), "durable");
+        assert_eq!(fs.read_file("/unsynced").unwrap(), "");
+    }
+
+    #[test]
+    fn fsync_persists_data_bitmap_of_the_inode() {
+        let mut fs = make_fs();
+        let synced = fs.create_file("/synced").unwrap();
+        fs.create_file("/other").unwrap();
+        fs.flush();
+
+        fs.write_to_file("/synced", &[b's'; BLOCK_SIZE]).unwrap();
+        fs.fsync("/synced").unwrap();
+        let synced_block = first_data_block(&inode_copy(&mut fs, synced));
+
+        let mut fs = remount(fs);
+        let other = find_entry_inode(&mut fs, INodeIndex::root(), "other").unwrap();
+        fs.write_to_file("/other", &[b'o'; BLOCK_SIZE]).unwrap();
+        assert_ne!(first_data_block(&inode_copy(&mut fs, other)), synced_block);
+    }
+
+    #[test]
+    fn fsync_missing_path_returns_not_found() {
+        let fs = make_fs();
+        assert_eq!(fs.fsync("/missing"), Err(Error::NotFound));
+        assert_eq!(fs.fsync("/"), Ok(()));
+    }
+
+    #[test]
+    fn unmount_marks_filesystem_clean() {
+        let fs = make_fs();
+        assert!(fs.mounted_clean());
+        fs.create_file("/file").unwrap();
+        fs.write_to_file("/file", b"content").unwrap();
+
+        let mut fs = Filesystem::new(fs.unmount()).unwrap();
+        assert!(fs.mounted_clean());
+        assert_eq!(fs.read_file("/file").unwrap(), "content");
+        assert_eq!(
+            read_test_superblock(fs.block_device.get_mut()).state,
+            STATE_DIRTY
+        );
+    }
+
+    #[test]
+    fn mark_clean_records_a_clean_unmount_in_place() {
+        let fs = make_fs();
+        fs.create_file("/file").unwrap();
+        fs.mark_clean();
+
+        let fs = remount(fs);
+        assert!(fs.mounted_clean());
+        assert!(fs.stat("/file").is_ok());
+    }
+
+    #[test]
+    fn mount_after_unclean_shutdown_is_detected() {
+        let fs = make_fs();
+        fs.create_file("/file").unwrap();
+        fs.flush();
+
+        let fs = remount(fs);
+        assert!(!fs.mounted_clean());
+
+        let fs = Filesystem::new(fs.unmount()).unwrap();
+        assert!(fs.mounted_clean());
+    }
+
+    #[test]
+    fn read_only_unmount_does_not_mark_clean() {
+        let fs = remount(make_fs());
+        let before = fs.block_device.lock().data.borrow().clone();
+
+        let device = Filesystem::mount_read_only(fs.block_device.into_inner())
+            .unwrap()
+            .unmount();
+
+        assert_eq!(*device.data.borrow(), before);
+        assert!(!Filesystem::new(device).unwrap().mounted_clean());
+    }
+    #[test]
+    fn write_past_end_leaves_holes_that_read_as_zeros() {
+        let fs = make_fs();
+        fs.create_file("/sparse").unwrap();
+
+        fs.write_at("/sparse", 3 * BLOCK_SIZE + 10, b"tail")
+            .unwrap();
+
+        let bytes = fs.read_bytes("/sparse").unwrap();
+        assert_eq!(bytes.len(), 3 * BLOCK_SIZE + 14);
+        assert!(bytes[..3 * BLOCK_SIZE + 10].iter().all(|b| *b == 0));
+        assert_eq!(&bytes[3 * BLOCK_SIZE + 10..], b"tail");
+
+        let metadata = fs.stat("/sparse").unwrap();
+        assert_eq!(metadata.size, 3 * BLOCK_SIZE + 14);
+        assert_eq!(metadata.allocated_blocks, 1);
+    }
+
+    #[test]
+    fn write_at_overwrites_and_fills_holes() {
+        let fs = make_fs();
+        fs.create_file("/file").unwrap();
+        fs.write_to_file("/file", b"hello world").unwrap();
+
+        fs.write_at("/file", 6, b"WORLD").unwrap();
+        assert_eq!(fs.read_file("/file").unwrap(), "hello WORLD");
+
+        fs.write_at("/file", 2 * BLOCK_SIZE, b"x").unwrap();
+        fs.write_at("/file", BLOCK_SIZE + 1, b"y").unwrap();
+        let bytes = fs.read_bytes("/file").unwrap();
+        assert_eq!(bytes[BLOCK_SIZE], 0);
+        assert_eq!(bytes[BLOCK_SIZE + 1], b'y');
+        assert_eq!(bytes[2 * BLOCK_SIZE], b'x');
+        assert_eq!(fs.stat("/file").unwrap().allocated_blocks, 3);
+    }
+
+    #[test]
+    fn write_at_beyond_max_file_size_is_rejected() {
+        let fs = make_fs();
+        fs.create_file("/file").unwrap();
+
+        assert_eq!(
+            fs.write_at("/file", MAX_FILE_SIZE, b"x"),
+            Err(Error::FileTooLarge)
+        );
+        assert_eq!(fs.stat("/file").unwrap().size, 0);
+        assert_eq!(fs.write_at("/file", MAX_FILE_SIZE - 1, b"x"), Ok(1));
+    }
+
+    #[test]
+    fn holes_survive_remount() {
+        let fs = make_fs();
+        fs.create_file("/sparse").unwrap();
+        fs.write_at("/sparse", 5 * BLOCK_SIZE, b"end").unwrap();
+
+        let fs = Filesystem::new(fs.unmount()).unwrap();
+        let metadata = fs.stat("/sparse").unwrap();
+        assert_eq!(metadata.size, 5 * BLOCK_SIZE + 3);
+        assert_eq!(metadata.allocated_blocks, 1);
+        assert!(fs.read_bytes("/sparse").unwrap().ends_with(b"\0end"));
+    }
+
+    #[test]
+    fn set_len_grows_with_hole_and_shrinks_freeing_blocks() {
+        let mut fs = make_fs();
+        fs.create_file("/file").unwrap();
+        fs.write_to_file("/file", &vec![b'a'; 3 * BLOCK_SIZE])
+            .unwrap();
+        let used_before = bitmap_set_count(fs.data_bitmap.get_mut());
+
+        fs.set_len("/file", 10).unwrap();
+        assert_eq!(fs.read_file("/file").unwrap(), "a".repeat(10));
+        assert_eq!(bitmap_set_count(fs.data_bitmap.get_mut()), used_before - 2);
+
+        fs.set_len("/file", 2 * BLOCK_SIZE).unwrap();
+        let bytes = fs.read_bytes("/file").unwrap();
+        assert_eq!(bytes.len(), 2 * BLOCK_SIZE);
+        assert!(bytes[10..].iter().all(|b| *b == 0));
+        assert_eq!(fs.stat("/file").unwrap().allocated_blocks, 1);
+    }
+
+    #[test]
+    fn zero_unused_clears_freed_blocks_and_inode_records() {
+        let mut fs = make_fs();
+        fs.create_file("/file").unwrap();
+        fs.write_to_file("/file", &vec![b'a'; 3 * BLOCK_SIZE])
+            .unwrap();
+        fs.set_len("/file", 10).unwrap();
+        let removed = fs.create_file("/removed").unwrap();
+        fs.write_to_file("/removed", b"gone").unwrap();
+        fs.flush();
+        fs.remove_dir_entry("/removed").unwrap();
+
+        let unused = |fs: &mut Filesystem<Ramdisk>| {
+            let layout = fs.layout;
+            let data_bitmap = fs.data_bitmap.get_mut().clone();
+            let device = fs.block_device.get_mut();
+            let mut buf = [0u8; BLOCK_SIZE];
+            let mut bytes = Vec::new();
+            for index in (0..layout.data_blocks).filter(|index| !data_bitmap.is_set(*index)) {
+                device.read_block(layout.data_block(index).to_block().unwrap(), &mut buf);
+                bytes.extend_from_slice(&buf);
+            }
+            let (block, offset) = layout.inode_to_block(removed);
+            device.read_block(block, &mut buf);
+            bytes.extend_from_slice(&buf[offset.0 as usize..][..layout.inode_size]);
+            bytes
+        };
+        assert!(unused(&mut fs).iter().any(|byte| *byte != 0));
+
+        fs.zero_unused().unwrap();
+        assert!(unused(&mut fs).iter().all(|byte| *byte == 0));
+        assert_eq!(fs.read_file("/file").unwrap(), "a".repeat(10));
+    }
+
+    #[test]
+    fn stat_reports_directories_and_missing_paths() {
+        let fs = make_fs();
+        let dir = fs.mkdir("/dir").unwrap();
+
+        let metadata = fs.stat("/dir").unwrap();
+        assert!(metadata.is_directory);
+        assert_eq!(metadata.inode, dir);
+        assert_eq!(metadata.allocated_blocks, 1);
+        assert!(fs.stat("/").unwrap().is_directory);
+        assert_eq!(fs.stat("/missing"), Err(Error::NotFound));
+        assert_eq!(fs.set_len("/dir", 0), Err(Error::IsDirectory));
+    }
+
+    #[test]
+    fn small_files_are_stored_inline() {
+        let mut fs = make_fs();
+        fs.create_file("/tiny").unwrap();
+        let used_before = bitmap_set_count(fs.data_bitmap.get_mut());
+
+        fs.write_to_file("/tiny", b"hello ").unwrap();
+        fs.write_at("/tiny", 6, b"world").unwrap();
+
+        let metadata = fs.stat("/tiny").unwrap();
+        assert!(metadata.inline);
+        assert_eq!(metadata.allocated_blocks, 0);
+        assert_eq!(bitmap_set_count(fs.data_bitmap.get_mut()), used_before);
+        assert_eq!(fs.read_file("/tiny").unwrap(), "hello world");
+
+        let fs = Filesystem::new(fs.unmount()).unwrap();
+        assert!(fs.stat("/tiny").unwrap().inline);
+        assert_eq!(fs.read_file("/tiny").unwrap(), "hello world");
+    }
+
+    #[test]
+    fn inline_file_is_promoted_when_it_outgrows_the_inode() {
+        let fs = make_fs();
+        fs.create_file("/grow").unwrap();
+        let content = vec![b'x'; INLINE_CAPACITY];
+        fs.write_to_file("/grow", &content).unwrap();
+        assert!(fs.stat("/grow").unwrap().inline);
+
+        fs.write_to_file("/grow", b"!").unwrap();
+
+        let metadata = fs.stat("/grow").unwrap();
+        assert!(!metadata.inline);
+        assert_eq!(metadata.allocated_blocks, 1);
+        let mut expected = content;
+        expected.push(b'!');
+        assert_eq!(fs.read_bytes("/grow").unwrap(), expected);
+
+        let fs = Filesystem::new(fs.unmount()).unwrap();
+        assert_eq!(fs.read_bytes("/grow").unwrap(), expected);
+    }
+
+    #[test]
+    fn promotion_without_free_blocks_keeps_inline_content() {
+        let mut fs = make_fs();
+        fs.create_file("/tiny").unwrap();
+        fs.write_to_file("/tiny", b"keep").unwrap();
+
+        for idx in 0..bitmap_capacity_bits(fs.data_bitmap.get_mut()) {
+            fs.data_bitmap.get_mut().set(idx);
+        }
+
+        assert_eq!(
+            fs.write_to_file("/tiny", &[b'x'; INLINE_CAPACITY]),
+            Err(Error::NoSpaceLeft)
+        );
+        assert!(fs.stat("/tiny").unwrap().inline);
+        assert_eq!(fs.read_file("/tiny").unwrap(), "keep");
+    }
+
+    #[test]
+    fn set_len_on_inline_file() {
+        let fs = make_fs();
+        fs.create_file("/file").unwrap();
+        fs.write_to_file("/file", b"abcdef").unwrap();
+
+        fs.set_len("/file", 2).unwrap();
+        fs.set_len("/file", 4).unwrap();
+        assert_eq!(fs.read_bytes("/file").unwrap(), b"ab\0\0");
+        assert!(fs.stat("/file").unwrap().inline);
+
+        fs.set_len("/file", BLOCK_SIZE + 1).unwrap();
+        let metadata = fs.stat("/file").unwrap();
+        assert!(!metadata.inline);
+        assert_eq!(metadata.allocated_blocks, 1);
+        assert!(fs.read_bytes("/file").unwrap().starts_with(b"ab\0\0\0"));
+    }
+
+    #[test]
+    fn removing_inline_file_frees_no_data_blocks() {
+        let mut fs = make_fs();
+        fs.create_file("/keep").unwrap();
+        fs.write_to_file("/keep", &[b'k'; BLOCK_SIZE]).unwrap();
+        fs.create_file("/tiny").unwrap();
+        fs.write_to_file("/tiny", b"tiny").unwrap();
+        let used_before = bitmap_set_count(fs.data_bitmap.get_mut());
+
+        fs.remove_dir_entry("/tiny").unwrap();
+
+        assert_eq!(bitmap_set_count(fs.data_bitmap.get_mut()), used_before);
+        assert_eq!(fs.read_bytes("/keep").unwrap(), [b'k'; BLOCK_SIZE]);
+    }
+
+    fn compressible_text() -> Vec<u8> {
+        "lemon shark config value = 42\n".repeat(100).into_bytes()
+    }
+
+    #[test]
+    fn compressed_file_roundtrips_and_saves_blocks() {
+        let fs = make_fs();
+        fs.create_file("/text").unwrap();
+        let text = compressible_text();
+
+        assert_eq!(fs.write_compressed("/text", &text), Ok(text.len()));
+
+        let metadata = fs.stat("/text").unwrap();
+        assert!(metadata.compressed);
+        assert_eq!(metadata.size, text.len());
+        assert!(metadata.allocated_blocks < text.len().div_ceil(BLOCK_SIZE));
+        assert_eq!(fs.read_bytes("/text").unwrap(), text);
+
+        let fs = Filesystem::new(fs.unmount()).unwrap();
+        assert!(fs.stat("/text").unwrap().compressed);
+        assert_eq!(fs.read_bytes("/text").unwrap(), text);
+    }
+
+    #[test]
+    fn incompressible_data_is_stored_plain() {
+        let fs = make_fs();
+        fs.create_file("/noise").unwrap();
+        let noise: Vec<u8> = (0..3 * BLOCK_SIZE)
+            .map(|i| (i * 7919 % 251) as u8 ^ (i / 251) as u8)
+            .collect();
+        fs.create_file("/tiny").unwrap();
+
+        fs.write_compressed("/noise", &noise).unwrap();
+        fs.write_compressed("/tiny", b"aaaaaaaa").unwrap();
+
+        assert!(!fs.stat("/noise").unwrap().compressed);
+        assert_eq!(fs.read_bytes("/noise").unwrap(), noise);
+        assert!(fs.stat("/tiny").unwrap().inline);
+        assert_eq!(fs.read_file("/tiny").unwrap(), "aaaaaaaa");
+    }
+
+    #[test]
+    fn compressed_write_without_free_blocks_keeps_old_content() {
+        let mut fs = make_fs();
+        fs.create_file("/text").unwrap();
+        fs.write_to_file("/text", &[b'o'; BLOCK_SIZE]).unwrap();
+
+        for idx in 0..bitmap_capacity_bits(fs.data_bitmap.get_mut()) {
+            fs.data_bitmap.get_mut().set(idx);
+        }
+
+        let noise: Vec<u8> = (0..3 * BLOCK_SIZE)
+            .map(|i| (i * 7919 % 251) as u8 ^ (i / 251) as u8)
+            .collect();
+        assert_eq!(
+            fs.write_compressed("/text", &noise),
+            Err(Error::NoSpaceLeft)
+        );
+        assert_eq!(fs.read_bytes("/text").unwrap(), [b'o'; BLOCK_SIZE]);
+
+        // The file's own block can be reused for content that fits into it.
+        let text = compressible_text();
+        assert_eq!(fs.write_compressed("/text", &text), Ok(text.len()));
+        assert_eq!(fs.read_bytes("/text").unwrap(), text);
+    }
+
+    #[test]
+    fn writing_into_compressed_file_decompresses_it() {
+        let fs = make_fs();
+        fs.create_file("/text").unwrap();
+        let mut text = compressible_text();
+        fs.write_compressed("/text", &text).unwrap();
+
+        fs.write_to_file("/text", b"tail").unwrap();
+        fs.write_at("/text", 0, b"L").unwrap();
+        text.extend_from_slice(b"tail");
+        text[0] = b'L';
+
+        let metadata = fs.stat("/text").unwrap();
+        assert!(!metadata.compressed);
+        assert_eq!(metadata.allocated_blocks, text.len().div_ceil(BLOCK_SIZE));
+        assert_eq!(fs.read_bytes("/text").unwrap(), text);
+
+        fs.write_compressed("/text", &text).unwrap();
+        fs.set_len("/text", 10).unwrap();
+        assert!(!fs.stat("/text").unwrap().compressed);
+        assert_eq!(fs.read_bytes("/text").unwrap(), &text[..10]);
+    }
+
+    #[test]
+    fn removing_compressed_file_frees_its_blocks() {
+        let mut fs = make_fs();
+        let used_before = bitmap_set_count(fs.data_bitmap.get_mut());
+        fs.create_file("/text").unwrap();
+        fs.write_compressed("/text", &compressible_text()).unwrap();
+
+        fs.remove_dir_entry("/text").unwrap();
+
+        assert_eq!(bitmap_set_count(fs.data_bitmap.get_mut()), used_before);
+    }
+
+    #[test]
+    fn corrupted_compressed_stream_is_reported() {
+        let mut fs = make_fs();
+        let idx = fs.create_file("/text").unwrap();
+        fs.write_compressed("/text", &compressible_text()).unwrap();
+        let block = first_data_block(&inode_copy(&mut fs, idx)).unwrap();
+
+        modify_block(
+            fs.block_device.get_mut(),
+            BlockIndex::from_raw(block),
+            |buf| {
+                buf.inner()[..COMPRESSED_HEADER_SIZE].copy_from_slice(&1u32.to_le_bytes());
+            },
+        );
+
+        assert_eq!(fs.read_bytes("/text"), Err(Error::CorruptedData));
+        assert_eq!(fs.write_to_file("/text", b"x"), Err(Error::CorruptedData));
+    }
+
+    #[test]
+    fn legacy_versions_require_upgrade_for_read_write() {
+        for version in 2..FILESYSTEM_VERSION {
+            let device = legacy_formatted_device();
+            let mut superblock = read_test_superblock(&device);
+            superblock.version = version;
+            write_test_superblock(&device, &superblock);
+
+            assert_eq!(
+                Filesystem::new(device).err(),
+                Some(Error::UpgradeRequired(version))
+            );
+        }
+    }
+
+    #[test]
+    fn legacy_versions_imply_their_features() {
+        let device = legacy_formatted_device();
+        let mut superblock = read_test_superblock(&device);
+        superblock.version = 3;
+        superblock.state = STATE_DIRTY;
+        superblock.features = Features::default();
+        write_test_superblock(&device, &superblock);
+
+        let fs = Filesystem::mount_read_only(device.share()).unwrap();
+        assert!(!fs.mounted_clean());
+        assert_eq!(fs.features, Features::of_legacy_version(3).unwrap());
+        drop(fs);
+
+        assert_eq!(Filesystem::upgrade(device.share()), Ok(3));
+        let upgraded = read_test_superblock(&device);
+        assert_eq!(upgraded.version, FILESYSTEM_VERSION);
+        assert_eq!(upgraded.features, Features::DEFAULT);
+        assert_eq!(upgraded.state, STATE_DIRTY);
+        assert_eq!(Filesystem::upgrade(device), Ok(FILESYSTEM_VERSION));
+    }
+
+    #[test]
+    fn unknown_incompat_features_are_rejected() {
+        assert_eq!(
+            mount_with_modified_superblock(|sb| sb.features.incompat |= 1 << 31).err(),
+            Some(Error::UnsupportedFeatures(1 << 31))
+        );
+    }
+
+    #[test]
+    fn unknown_ro_compat_features_force_read_only() {
+        let fs = mount_with_modified_superblock(|sb| sb.features.ro_compat |= 1 << 31).unwrap();
+        assert!(fs.is_read_only());
+
+        // Unknown compatible features are ignored.
+        let fs = mount_with_modified_superblock(|sb| sb.features.compat |= 1 << 31).unwrap();
+        assert!(!fs.is_read_only());
+    }
+
+    #[test]
+    fn future_versions_are_rejected() {
+        assert_eq!(
+            mount_with_modified_superblock(|sb| sb.version = FILESYSTEM_VERSION + 1).err(),
+            Some(Error::UnsupportedFilesystemVersion(FILESYSTEM_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn plain_files_are_unaffected_by_compression_support() {
+        let fs = make_fs();
+        fs.create_file("/plain").unwrap();
+        let text = compressible_text();
+        fs.write_to_file("/plain", &text).unwrap();
+
+        let fs = Filesystem::new(fs.unmount()).unwrap();
+        let metadata = fs.stat("/plain").unwrap();
+        assert!(!metadata.compressed);
+        assert_eq!(metadata.allocated_blocks, text.len().div_ceil(BLOCK_SIZE));
+        assert_eq!(fs.read_bytes("/plain").unwrap(), text);
+    }
+
+    #[test]
+    fn uncompressed_files_read_back_after_enabling_compression() {
+        let ramdisk = Ramdisk::new();
+        let device = ramdisk.share();
+        let v3 = Features::of_legacy_version(3).unwrap();
+        Filesystem::format_with_features(ramdisk, v3).unwrap();
+
+        let fs = Filesystem::new(device.share()).unwrap();
+        let text = compressible_text();
+        fs.create_file("/plain").unwrap();
+        fs.write_to_file("/plain", &text).unwrap();
+        fs.create_file("/tiny").unwrap();
+        fs.write_to_file("/tiny", b"tiny").unwrap();
+        drop(fs.unmount());
+
+        let mut superblock = read_test_superblock(&device);
+        superblock.version = 3;
+        write_test_superblock(&device, &superblock);
+        assert_eq!(Filesystem::upgrade(device.share()), Ok(3));
+        assert_ne!(
+            read_test_superblock(&device).features.incompat & Features::INCOMPAT_COMPRESSION,
+            0
+        );
+
+        let fs = Filesystem::new(device).unwrap();
+        fs.create_file("/compressed").unwrap();
+        fs.write_compressed("/compressed", &text).unwrap();
+
+        assert!(!fs.stat("/plain").unwrap().compressed);
+        assert_eq!(fs.read_bytes("/plain").unwrap(), text);
+        assert_eq!(fs.read_bytes("/tiny").unwrap(), b"tiny");
+        assert_eq!(fs.read_bytes("/compressed").unwrap(), text);
+    }
+
+    /// Populates `fs` with one file of every storage kind, returning their
+    /// expected contents.
+    fn populate_for_grow(fs: &mut Filesystem<Ramdisk>) -> Vec<(&'static str, Vec<u8>)> {
+        let mut sparse = vec![0; 2 * BLOCK_SIZE];
+        sparse.extend_from_slice(b"end");
+        let files = vec![
+            ("/inline", b"tiny".to_vec()),
+            ("/dir/plain", vec![b'p'; 3 * BLOCK_SIZE + 5]),
+            ("/dir/compressed", compressible_text()),
+            ("/dir/sparse", sparse),
+        ];
+        fs.mkdir("/dir").unwrap();
+        for (path, _) in &files {
+            fs.create_file(path).unwrap();
+        }
+        fs.write_to_file("/inline", &files[0].1).unwrap();
+        fs.write_to_file("/dir/plain", &files[1].1).unwrap();
+        fs.write_compressed("/dir/compressed", &files[2].1).unwrap();
+        fs.write_at("/dir/sparse", 2 * BLOCK_SIZE, b"end").unwrap();
+        files
+    }
+
+    #[test]
+    fn mounting_larger_device_keeps_recorded_size() {
+        let device = formatted_device(RAMDISK_SIZE / BLOCK_SIZE);
+        let larger = resized_device(&device, RAMDISK_SIZE / BLOCK_SIZE + 100);
+
+        let fs = Filesystem::new(larger).unwrap();
+
+        assert_eq!(fs.total_blocks(), RAMDISK_SIZE / BLOCK_SIZE);
+        assert_eq!(
+            read_test_superblock(&fs.unmount()).total_blocks as usize,
+            RAMDISK_SIZE / BLOCK_SIZE
+        );
+    }
+
+    #[test]
+    fn grow_extends_data_region_in_place() {
+        let mut fs = make_fs();
+        let files = populate_for_grow(&mut fs);
+        let old = fs.layout;
+        let used = bitmap_set_count(fs.data_bitmap.get_mut());
+
+        let total = RAMDISK_SIZE / BLOCK_SIZE + 100;
+        let mut fs = Filesystem::new(resized_device(&fs.unmount(), total)).unwrap();
+        fs.grow(total).unwrap();
+
+        assert_eq!(fs.layout.data_start, old.data_start);
+        assert_eq!(fs.layout.data_blocks, old.data_blocks + 100);
+        assert_eq!(bitmap_set_count(fs.data_bitmap.get_mut()), used);
+
+        let fs = Filesystem::new(fs.unmount()).unwrap();
+        assert_eq!(fs.total_blocks(), total);
+        for (path, contents) in &files {
+            assert_eq!(&fs.read_bytes(path).unwrap(), contents, "{path}");
+        }
+    }
+
+    #[test]
+    fn grow_relocates_when_data_bitmap_needs_more_blocks() {
+        let mut fs = make_fs();
+        let files = populate_for_grow(&mut fs);
+        let old = fs.layout;
+        let used = bitmap_set_count(fs.data_bitmap.get_mut());
+
+        let total = 4 * RAMDISK_SIZE / BLOCK_SIZE;
+        let mut fs = Filesystem::new(resized_device(&fs.unmount(), total)).unwrap();
+        fs.grow(total).unwrap();
+
+        assert_eq!(
+            fs.layout,
+            Layout::canonical(total, MAX_INODES, INODE_SIZE, false).unwrap()
+        );
+        assert!(fs.layout.data_start > old.data_start);
+        assert_eq!(bitmap_set_count(fs.data_bitmap.get_mut()), used);
+
+        let mut fs = Filesystem::new(fs.unmount()).unwrap();
+        for (path, contents) in &files {
+            assert_eq!(&fs.read_bytes(path).unwrap(), contents, "{path}");
+        }
+
+        // The grown data region is usable and doesn't hand out used blocks.
+        fs.create_file("/new").unwrap();
+        fs.write_to_file("/new", &[b'n'; BLOCK_SIZE]).unwrap();
+        assert_eq!(bitmap_set_count(fs.data_bitmap.get_mut()), used + 1);
+        for (path, contents) in &files {
+            assert_eq!(&fs.read_bytes(path).unwrap(), contents, "{path}");
+        }
+    }
+
+    #[test]
+    fn grow_rejects_shrinking_and_sizes_beyond_the_device() {
+        let mut fs = make_fs();
+        let total = fs.total_blocks();
+
+        assert_eq!(fs.grow(total - 1), Err(Error::OperationNotSupported));
+        assert_eq!(fs.grow(total + 1), Err(Error::DeviceTooSmall));
+        assert_eq!(fs.grow(total), Ok(()));
+
+        let mut fs = Filesystem::mount_read_only(fs.unmount()).unwrap();
+        assert_eq!(fs.grow(total), Err(Error::ReadOnly));
+    }
+
+    fn assert_files(fs: &mut Filesystem<Ramdisk>, files: &[(&str, Vec<u8>)]) {
+        for (path, contents) in files {
+            assert_eq!(&fs.read_bytes(path).unwrap(), contents, "{path}");
+        }
+    }
+
+    #[test]
+    fn first_snapshot_enables_the_snapshot_region() {
+        let mut fs = make_fs();
+        let files = populate_for_grow(&mut fs);
+        assert!(fs.snapshots().is_empty());
+
+        fs.create_snapshot("base").unwrap();
+
+        let total = fs.total_blocks();
+        assert_eq!(
+            fs.layout,
+            Layout::canonical(total, MAX_INODES, INODE_SIZE, true).unwrap()
+        );
+        assert_files(&mut fs, &files);
+
+        let mut fs = Filesystem::new(fs.unmount()).unwrap();
+        assert!(fs.features.has_snapshots());
+        assert_eq!(
+            fs.snapshots(),
+            vec![SnapshotInfo {
+                name: "base".into(),
+                inodes: 6,
+            }]
+        );
+        assert_files(&mut fs, &files);
+    }
+
+    #[test]
+    fn writes_after_a_snapshot_copy_shared_blocks() {
+        let mut fs = make_fs();
+        let files = populate_for_grow(&mut fs);
+        fs.create_snapshot("base").unwrap();
+
+        let plain = fs.stat("/dir/plain").unwrap().inode;
+        let before = inode_copy(&mut fs, plain);
+
+        fs.write_at("/dir/plain", 0, b"changed").unwrap();
+
+        let after = inode_copy(&mut fs, plain);
+        assert_ne!(first_data_block(&before), first_data_block(&after));
+        assert_eq!(before.block(1).raw(), after.block(1).raw());
+        assert_eq!(&fs.read_bytes("/dir/plain").unwrap()[..7], b"changed");
+
+        fs.rollback("base").unwrap();
+        assert_files(&mut fs, &files);
+    }
+
+    #[test]
+    fn rollback_restores_the_snapshot_tree() {
+        let mut fs = make_fs();
+        let files = populate_for_grow(&mut fs);
+        fs.create_snapshot("base").unwrap();
+        let used = bitmap_set_count(fs.data_bitmap.get_mut());
+
+        fs.remove_dir_entry("/dir/plain").unwrap();
+        fs.set_len("/dir/sparse", 10).unwrap();
+        fs.create_file("/new").unwrap();
+        fs.write_to_file("/new", &[b'n'; 2 * BLOCK_SIZE]).unwrap();
+        fs.mkdir("/other").unwrap();
+
+        fs.rollback("base").unwrap();
+
+        assert_eq!(fs.stat("/new"), Err(Error::NotFound));
+        assert_eq!(fs.stat("/other"), Err(Error::NotFound));
+        assert_eq!(bitmap_set_count(fs.data_bitmap.get_mut()), used);
+        assert_files(&mut fs, &files);
+
+        // The snapshot is kept and the restored tree stays writable.
+        let mut fs = Filesystem::new(fs.unmount()).unwrap();
+        assert_eq!(fs.snapshots().len(), 1);
+        assert_files(&mut fs, &files);
+        fs.create_file("/new").unwrap();
+        fs.write_to_file("/dir/plain", b"more").unwrap();
+        fs.rollback("base").unwrap();
+        assert_files(&mut fs, &files);
+    }
+
+    #[test]
+    fn deleting_a_snapshot_frees_blocks_only_it_uses() {
+        let mut fs = make_fs();
+        let files = populate_for_grow(&mut fs);
+        fs.create_snapshot("first").unwrap();
+        let used = bitmap_set_count(fs.data_bitmap.get_mut());
+
+        fs.create_snapshot("second").unwrap();
+        fs.write_to_file("/dir/plain", b"appended").unwrap();
+        fs.write_at("/dir/plain", 0, &[b'x'; BLOCK_SIZE]).unwrap();
+        fs.remove_dir_entry("/dir/sparse").unwrap();
+
+        fs.delete_snapshot("second").unwrap();
+        fs.rollback("first").unwrap();
+
+        assert_eq!(bitmap_set_count(fs.data_bitmap.get_mut()), used);
+        assert_files(&mut fs, &files);
+
+        fs.delete_snapshot("first").unwrap();
+        assert!(fs.snapshots().is_empty());
+        assert_eq!(fs.rollback("first"), Err(Error::NotFound));
+
+        // Without snapshots every block is freed right away again.
+        let used = bitmap_set_count(fs.data_bitmap.get_mut());
+        fs.remove_dir_entry("/dir/plain").unwrap();
+        assert_eq!(bitmap_set_count(fs.data_bitmap.get_mut()), used - 4);
+    }
+
+    #[test]
+    fn snapshot_names_are_validated() {
+        let mut fs = make_fs();
+
+        assert_eq!(fs.create_snapshot(""), Err(Error::EmptyName));
+        assert_eq!(
+            fs.create_snapshot(&"s".repeat(SNAPSHOT_NAME_LEN + 1)),
+            Err(Error::NameTooLong)
+        );
+        assert_eq!(fs.delete_snapshot("missing"), Err(Error::NotFound));
+
+        for i in 0..MAX_SNAPSHOTS {
+            fs.create_snapshot(&alloc::format!("snap{i}")).unwrap();
+        }
+        assert_eq!(fs.create_snapshot("snap0"), Err(Error::EntryExists));
+        assert_eq!(fs.create_snapshot("extra"), Err(Error::TooManySnapshots));
+
+        let mut fs = Filesystem::mount_read_only(fs.unmount()).unwrap();
+        assert_eq!(fs.snapshots().len(), MAX_SNAPSHOTS);
+        assert_eq!(fs.create_snapshot("extra"), Err(Error::ReadOnly));
+        assert_eq!(fs.delete_snapshot("snap0"), Err(Error::ReadOnly));
+        assert_eq!(fs.rollback("snap0"), Err(Error::ReadOnly));
+    }
+
+    #[test]
+    fn grow_keeps_snapshots() {
+        let mut fs = make_fs();
+        let files = populate_for_grow(&mut fs);
+        fs.create_snapshot("base").unwrap();
+        fs.write_at("/dir/plain", 0, b"changed").unwrap();
+
+        let total = 4 * RAMDISK_SIZE / BLOCK_SIZE;
+        let mut fs = Filesystem::new(resized_device(&fs.unmount(), total)).unwrap();
+        fs.grow(total).unwrap();
+        assert_eq!(
+            fs.layout,
+            Layout::canonical(total, MAX_INODES, INODE_SIZE, true).unwrap()
+        );
+
+        let mut fs = Filesystem::new(fs.unmount()).unwrap();
+        assert_eq!(&fs.read_bytes("/dir/plain").unwrap()[..7], b"changed");
+        fs.rollback("base").unwrap();
+        assert_files(&mut fs, &files);
+    }
+
+    #[test]
+    fn xattrs_can_be_set_listed_and_removed() {
+        let mut fs = make_fs();
+        fs.create_file("/file").unwrap();
+        let used = bitmap_set_count(fs.data_bitmap.get_mut());
+
+        fs.set_xattr("/file", "user.mime_type", b"text/plain")
+            .unwrap();
+        fs.set_xattr("/file", "user.build_id", &[1, 2, 3]).unwrap();
+        fs.set_xattr("/", "security.label", b"root").unwrap();
+        fs.set_xattr("/file", "user.mime_type", b"text/markdown")
+            .unwrap();
+        assert_eq!(bitmap_set_count(fs.data_bitmap.get_mut()), used + 2);
+
+        let mut fs = Filesystem::new(fs.unmount()).unwrap();
+        assert_eq!(
+            fs.list_xattr("/file").unwrap(),
+            ["user.mime_type", "user.build_id"]
+        );
+        assert_eq!(
+            fs.get_xattr("/file", "user.mime_type").unwrap(),
+            b"text/markdown"
+        );
+        assert_eq!(fs.get_xattr("/", "security.label").unwrap(), b"root");
+        assert_eq!(fs.get_xattr("/file", "user.missing"), Err(Error::NotFound));
+        assert_eq!(
+            fs.remove_xattr("/file", "user.missing"),
+            Err(Error::NotFound)
+        );
+
+        fs.remove_xattr("/file", "user.mime_type").unwrap();
+        fs.remove_xattr("/file", "user.build_id").unwrap();
+        assert!(fs.list_xattr("/file").unwrap().is_empty());
+        assert_eq!(bitmap_set_count(fs.data_bitmap.get_mut()), used + 1);
+    }
+
+    #[test]
+    fn xattr_limits_are_enforced() {
+        let fs = make_fs();
+        fs.create_file("/file").unwrap();
+
+        assert_eq!(fs.set_xattr("/file", "", b"v"), Err(Error::EmptyName));
+        assert_eq!(
+            fs.set_xattr("/file", &"n".repeat(MAX_XATTR_NAME + 1), b"v"),
+            Err(Error::NameTooLong)
+        );
+        assert_eq!(
+            fs.set_xattr("/file", "user.big", &[0; MAX_XATTR_VALUE + 1]),
+            Err(Error::XattrTooLarge)
+        );
+        assert_eq!(
+            fs.set_xattr("/missing", "user.a", b"v"),
+            Err(Error::NotFound)
+        );
+
+        // Two maximum values don't fit into one block together.
+        fs.set_xattr("/file", "user.a", &[1; MAX_XATTR_VALUE])
+            .unwrap();
+        assert_eq!(
+            fs.set_xattr("/file", "user.b", &[2; MAX_XATTR_VALUE]),
+            Err(Error::XattrTooLarge)
+        );
+        fs.remove_xattr("/file", "user.a").unwrap();
+
+        for i in 0..MAX_XATTRS {
+            fs.set_xattr("/file", &alloc::format!("user.{i}"), b"v")
+                .unwrap();
+        }
+        assert_eq!(
+            fs.set_xattr("/file", "user.extra", b"v"),
+            Err(Error::TooManyXattrs)
+        );
+        fs.set_xattr("/file", "user.0", b"replaced").unwrap();
+
+        let fs = Filesystem::mount_read_only(fs.unmount()).unwrap();
+        assert_eq!(fs.get_xattr("/file", "user.0").unwrap(), b"replaced");
+        assert_eq!(fs.set_xattr("/file", "user.0", b"v"), Err(Error::ReadOnly));
+        assert_eq!(fs.remove_xattr("/file", "user.0"), Err(Error::ReadOnly));
+    }
+
+    #[test]
+    fn removing_a_file_frees_its_xattr_block() {
+        let mut fs = make_fs();
+        let used = bitmap_set_count(fs.data_bitmap.get_mut());
+
+        fs.create_file("/file").unwrap();
+        fs.write_to_file("/file", &[b'x'; BLOCK_SIZE]).unwrap();
+        fs.set_xattr("/file", "user.a", b"v").unwrap();
+        fs.remove_dir_entry("/file").unwrap();
+
+        assert_eq!(bitmap_set_count(fs.data_bitmap.get_mut()), used);
+    }
+
+    #[test]
+    fn snapshots_keep_xattrs() {
+        let mut fs = make_fs();
+        fs.create_file("/file").unwrap();
+        fs.set_xattr("/file", "user.a", b"before").unwrap();
+        fs.create_snapshot("base").unwrap();
+        let used = bitmap_set_count(fs.data_bitmap.get_mut());
+
+        // The shared xattr block is replaced instead of modified.
+        fs.set_xattr("/file", "user.a", b"after").unwrap();
+        assert_eq!(bitmap_set_count(fs.data_bitmap.get_mut()), used + 1);
+        fs.remove_dir_entry("/file").unwrap();
+
+        fs.rollback("base").unwrap();
+        assert_eq!(fs.get_xattr("/file", "user.a").unwrap(), b"before");
+        assert_eq!(bitmap_set_count(fs.data_bitmap.get_mut()), used);
+    }
+
+    #[test]
+    fn legacy_images_dont_support_xattrs() {
+        let fs = Filesystem::new(legacy_formatted_device()).unwrap();
+        fs.create_file("/file").unwrap();
+
+        assert_eq!(
+            fs.set_xattr("/file", "user.a", b"v"),
+            Err(Error::OperationNotSupported)
+        );
+        assert_eq!(fs.list_xattr("/file"), Err(Error::OperationNotSupported));
+    }
+
+    #[test]
+    fn advisory_locks_follow_their_handles() {
+        let fs = make_fs();
+        let file = fs.create_file("/file").unwrap();
+        assert_eq!(fs.open("/missing"), Err(Error::NotFound));
+
+        let reader = fs.open("/file").unwrap();
+        let writer = fs.open("/file").unwrap();
+        fs.lock(reader, LockKind::Shared, false).unwrap();
+        assert_eq!(
+            fs.lock(writer, LockKind::Exclusive, false),
+            Err(Error::WouldBlock)
+        );
+        assert_eq!(
+            fs.locks(),
+            vec![LockInfo {
+                handle: reader,
+                inode: file,
+                path: "/file".into(),
+                kind: LockKind::Shared,
+            }]
+        );
+
+        // Locks are advisory, writing is still possible.
+        fs.write_to_file("/file", b"content").unwrap();
+
+        fs.close(reader).unwrap();
+        fs.lock(writer, LockKind::Exclusive, false).unwrap();
+        fs.unlock(writer).unwrap();
+        assert!(fs.locks().is_empty());
+        assert_eq!(
+            fs.lock(reader, LockKind::Shared, false),
+            Err(Error::BadHandle)
+        );
+    }
+
+    #[test]
+    fn removing_a_locked_file_releases_its_locks() {
+        let fs = make_fs();
+        let removed = fs.create_file("/old").unwrap();
+        let stale = fs.open("/old").unwrap();
+        fs.lock(stale, LockKind::Exclusive, false).unwrap();
+
+        fs.remove_dir_entry("/old").unwrap();
+        assert!(fs.locks().is_empty());
+        assert_eq!(
+            fs.lock(stale, LockKind::Shared, false),
+            Err(Error::NotFound)
+        );
+
+        // The new file reuses the index but none of the old locks.
+        assert_eq!(fs.create_file("/new").unwrap(), removed);
+        let handle = fs.open("/new").unwrap();
+        fs.lock(handle, LockKind::Exclusive, false).unwrap();
+        fs.close(stale).unwrap();
+        assert_eq!(fs.locks().len(), 1);
+    }
+
+    #[test]
+    fn rename_moves_files_and_keeps_their_content() {
+        let fs = make_fs();
+        fs.mkdir("/a").unwrap();
+        fs.mkdir("/b").unwrap();
+        let file = fs.create_file("/a/file").unwrap();
+        fs.write_to_file("/a/file", b"content").unwrap();
+
+        fs.rename("/a/file", "/a/renamed").unwrap();
+        fs.rename("/a/renamed", "/b/moved").unwrap();
+        fs.rename("/b", "/c").unwrap();
+
+        assert_eq!(fs.stat("/a/file"), Err(Error::NotFound));
+        assert_eq!(fs.stat("/c/moved").unwrap().inode, file);
+        assert_eq!(fs.read_file("/c/moved").unwrap(), "content");
+        assert_eq!(fs.read_dir_entry(fs.stat("/a").unwrap().inode).len(), 2);
+
+        fs.create_file("/other").unwrap();
+        assert_eq!(fs.rename("/other", "/c/moved"), Err(Error::EntryExists));
+        assert_eq!(fs.rename("/c", "/a/c"), Err(Error::OperationNotSupported));
+        assert_eq!(fs.rename("/c/..", "/up"), Err(Error::OperationNotSupported));
+        assert_eq!(fs.rename("/missing", "/x"), Err(Error::NotFound));
+
+        let fs = Filesystem::new(fs.unmount()).unwrap();
+        assert_eq!(fs.read_file("/c/moved").unwrap(), "content");
+        assert!(fs.stat("/c").unwrap().is_directory);
+    }
+
+    #[test]
+    fn rename_reads_an_evicted_parent_from_disk() {
+        let fs = make_fs();
+        let dir = fs.mkdir("/dir").unwrap();
+        fs.create_file("/dir/file").unwrap();
+
+        fs.flush();
+        fs.inode_cache.lock().remove(dir);
+        fs.rename_entry(dir, "file", "renamed").unwrap();
+
+        assert!(fs.stat("/dir/renamed").is_ok());
+        assert_eq!(fs.stat("/dir/file"), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn watches_report_changes_of_files_and_directories() {
+        let fs = make_fs();
+        fs.mkdir("/etc").unwrap();
+        let dir = fs.watch("/etc").unwrap();
+
+        fs.create_file("/etc/config").unwrap();
+        let file = fs.watch("/etc/config").unwrap();
+        fs.write_to_file("/etc/config", b"a=1").unwrap();
+        fs.write_at("/etc/config", 2, b"2").unwrap();
+        fs.set_len("/etc/config", 1).unwrap();
+        fs.rename("/etc/config", "/etc/config.old").unwrap();
+        fs.create_file("/unwatched").unwrap();
+
+        assert_eq!(
+            fs.read_events(dir).unwrap(),
+            vec![
+                WatchEvent::Created("/etc/config".into()),
+                WatchEvent::Renamed {
+                    from: "/etc/config".into(),
+                    to: "/etc/config.old".into(),
+                },
+            ]
+        );
+        assert_eq!(
+            fs.read_events(file).unwrap(),
+            vec![
+                WatchEvent::Modified("/etc/config".into()),
+                WatchEvent::Modified("/etc/config".into()),
+                WatchEvent::Modified("/etc/config".into()),
+                WatchEvent::Renamed {
+                    from: "/etc/config".into(),
+                    to: "/etc/config.old".into(),
+                },
+            ]
+        );
+
+        fs.remove_dir_entry("/etc/config.old").unwrap();
+        let removed = vec![WatchEvent::Removed("/etc/config.old".into())];
+        assert_eq!(fs.read_events(dir).unwrap(), removed);
+        assert_eq!(fs.read_events(file).unwrap(), removed);
+
+        // The index of the removed file gets reused.
+        fs.create_file("/etc/new").unwrap();
+        fs.write_to_file("/etc/new", b"x").unwrap();
+        assert!(fs.read_events(file).unwrap().is_empty());
+
+        fs.unwatch(file).unwrap();
+        assert_eq!(fs.read_events(file), Err(Error::BadHandle));
+    }
+
+    #[test]
+    fn relative_paths_resolve_against_a_working_directory() {
+        let fs = make_fs();
+        let usr = fs.mkdir("/usr").unwrap();
+        let bin = fs.mkdir("/usr/bin").unwrap();
+        let file = fs.create_file("/usr/bin/sh").unwrap();
+        let root = INodeIndex::root();
+
+        assert_eq!(fs.resolve_at(usr, "bin/sh"), Ok(file));
+        assert_eq!(fs.resolve_at(bin, "./sh"), Ok(file));
+        assert_eq!(fs.resolve_at(bin, ".."), Ok(usr));
+        assert_eq!(fs.resolve_at(bin, "../../.."), Ok(root));
+        assert_eq!(fs.resolve_at(bin, "/usr"), Ok(usr));
+        assert_eq!(fs.resolve_at(bin, ""), Ok(bin));
+        assert_eq!(fs.resolve_at(bin, "sh/x"), Err(Error::NotADirectory));
+        assert_eq!(fs.resolve_at(bin, "missing"), Err(Error::NotFound));
+
+        assert_eq!(fs.canonical_path(root).unwrap(), "/");
+        assert_eq!(fs.canonical_path(bin).unwrap(), "/usr/bin");
+        assert_eq!(fs.canonical_path(file), Err(Error::NotADirectory));
+
+        assert_eq!(fs.absolute_path(bin, "../lib//x").unwrap(), "/usr/lib/x");
+        assert_eq!(fs.absolute_path(bin, "/etc/./../tmp/").unwrap(), "/tmp");
+        assert_eq!(fs.absolute_path(root, "..").unwrap(), "/");
+    }
+
+    #[test]
+    fn redundant_slashes_are_ignored() {
+        let fs = make_fs();
+        fs.mkdir("//dir/").unwrap();
+        fs.create_file("/dir//file").unwrap();
+        fs.write_to_file("dir/file", b"content").unwrap();
+
+        assert_eq!(fs.read_file("//dir///file").unwrap(), "content");
+        assert!(fs.stat("/dir/").unwrap().is_directory);
+        assert!(fs.stat("//").unwrap().is_directory);
+        assert_eq!(fs.read_file("/dir/./../dir/file").unwrap(), "content");
+
+        fs.rename("/dir//file", "dir/renamed").unwrap();
+        fs.remove_dir_entry("/dir/renamed/").unwrap();
+        assert_eq!(fs.remove_dir_entry("//"), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn read_dir_lists_names_without_dot_entries() {
+        let fs = make_fs();
+        fs.mkdir("/dir").unwrap();
+        fs.create_file("/dir/b").unwrap();
+        fs.mkdir("/dir/a").unwrap();
+
+        assert_eq!(fs.read_dir("/dir").unwrap(), ["b", "a"]);
+        assert!(fs.read_dir("/dir/a").unwrap().is_empty());
+        assert_eq!(fs.read_dir("/").unwrap(), ["dir"]);
+        assert_eq!(fs.read_dir("/dir/b"), Err(Error::NotADirectory));
+        assert_eq!(fs.read_dir("/missing"), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn walks_are_depth_first_and_can_be_pruned() {
+        let fs = make_fs();
+        fs.mkdir("/a").unwrap();
+        fs.mkdir("/a/b").unwrap();
+        fs.create_file("/a/b/deep").unwrap();
+        fs.create_file("/a/file").unwrap();
+        fs.mkdir("/c").unwrap();
+
+        let entries: Vec<_> = fs.walk("/").unwrap().collect();
+        let paths: Vec<_> = entries.iter().map(|entry| entry.path.as_str()).collect();
+        assert_eq!(paths, ["/", "/a", "/a/b", "/a/b/deep", "/a/file", "/c"]);
+        assert_eq!(entries[3].depth, 3);
+        assert_eq!(entries[3].name(), "deep");
+        assert!(!entries[3].metadata.is_directory);
+
+        let mut walker = fs.walk("a//").unwrap();
+        let mut paths = Vec::new();
+        while let Some(entry) = walker.next() {
+            if entry.name() == "b" {
+                walker.skip_dir();
+            }
+            paths.push(entry.path);
+        }
+        assert_eq!(paths, ["/a", "/a/b", "/a/file"]);
+
+        assert_eq!(fs.walk("/a/file").unwrap().count(), 1);
+        assert!(fs.walk("/missing").is_err());
     }
 }
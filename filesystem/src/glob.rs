@@ -0,0 +1,97 @@
+extern crate alloc;
+use alloc::vec::Vec;
+
+/// Returns `true` if `path` matches the glob `pattern`.
+///
+/// Patterns are matched component by component, redundant slashes are
+/// ignored:
+/// * `*` matches any number of characters within a component
+/// * `?` matches a single character within a component
+/// * `**` as a whole component matches any number of components
+///
+/// A pattern without a slash like `*.txt` matches single names, so it can
+/// be used for the name of an entry as well.
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<_> = components(pattern).collect();
+    let path: Vec<_> = components(path).collect();
+    match_components(&pattern, &path)
+}
+
+/// Returns `true` if `pattern` contains any wildcard.
+pub fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?'])
+}
+
+fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|s| !s.is_empty())
+}
+
+fn match_components(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|skip| match_components(rest, &path[skip..])),
+        Some((first, rest)) => path
+            .split_first()
+            .is_some_and(|(name, path)| match_name(first, name) && match_components(rest, path)),
+    }
+}
+
+/// Matches a single component by remembering the last `*` and retrying
+/// with it swallowing one more character whenever the rest doesn't match.
+fn match_name(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    let (mut p, mut n) = (0, 0);
+    let mut star = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards_stay_within_a_component() {
+        assert!(glob_match("*.txt", "notes.txt"));
+        assert!(glob_match("*", ".hidden"));
+        assert!(glob_match("a?c*", "abc"));
+        assert!(glob_match("/etc/*.conf", "/etc/app.conf"));
+        assert!(glob_match("/etc//*.conf", "/etc/app.conf/"));
+        assert!(!glob_match("*.txt", "notes.txt.bak"));
+        assert!(!glob_match("/etc/*", "/etc/app/app.conf"));
+        assert!(!glob_match("a?c", "ac"));
+        assert!(glob_match("*a*b", "xaybab"));
+    }
+
+    #[test]
+    fn double_star_matches_any_depth() {
+        assert!(glob_match("/**/*.rs", "/main.rs"));
+        assert!(glob_match("/**/*.rs", "/src/fs/mod.rs"));
+        assert!(glob_match("/src/**", "/src"));
+        assert!(glob_match("/src/**/mod.rs", "/src/a/b/mod.rs"));
+        assert!(!glob_match("/src/**/mod.rs", "/lib/mod.rs"));
+    }
+}
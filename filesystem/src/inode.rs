@@ -1,6 +1,5 @@
 use crate::{
-    BLOCK_SIZE,
-    bytereader::{self, DiskFormat},
+    bytereader::{self, ByteReader, ByteWriter, DiskFormat},
     dir_entry::DirEntry,
     layout::DataBlockIndex,
 };
@@ -10,13 +9,44 @@ use core::mem;
 /// Hardcoded number of blocks that an INode can hold.
 pub(crate) const INODE_BLOCKS: usize = 16;
 
+/// Number of bytes a file can store inline by reusing the `blocks` array.
+pub(crate) const INLINE_CAPACITY: usize = INODE_BLOCKS * mem::size_of::<u32>();
+
+/// Size of an `INode` record in the inode table.
+pub(crate) const INODE_SIZE: usize = mem::size_of::<INode>();
+
+/// Size of an `INode` record before the `xattr` pointer was added. Images
+/// without extended attributes still use it, see `INode::read_record`.
+pub(crate) const LEGACY_INODE_SIZE: usize = 72;
+
+/// `INode::flags` bit set while the file content lives inside `blocks`.
+const FLAG_INLINE: u8 = 1 << 0;
+
+/// `INode::flags` bit set when the data blocks hold a compressed stream.
+const FLAG_COMPRESSED: u8 = 1 << 1;
+
 /// The `INode` contains metadata about a file.
 ///
+/// Files can be sparse: an empty slot in `blocks` below `size` is a hole
+/// which reads back as zeros.
+///
+/// Files of at most `INLINE_CAPACITY` bytes are stored inline: the raw bytes
+/// of `blocks` hold the content and no data block is used. They are promoted
+/// to data blocks once they grow past that.
+///
+/// Compressed files keep their logical length in `size` while the data
+/// blocks hold a compressed stream, see `Filesystem::write_compressed`.
+///
+/// Extended attributes are stored in the separate `xattr` block, see
+/// `Filesystem::set_xattr`.
+///
 /// Memory layout:
 /// `size`          4 bytes
 /// `blocks`        64 bytes
 /// `is_directory`  1 byte
-/// `padding`       3 bytes
+/// `flags`         1 byte
+/// `padding`       2 bytes
+/// `xattr`         4 bytes
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub(crate) struct INode {
@@ -28,12 +58,12 @@ pub(crate) struct INode {
 
     /// Flag indicating if this is a directory.
     is_directory: bool,
-}
 
-pub(crate) struct WriteSlot<'a> {
-    pub block: Option<&'a mut DataBlockIndex>,
-    pub byte_offset: usize,
-    pub capacity: usize,
+    /// Bitset of `FLAG_*` values.
+    flags: u8,
+
+    /// Block holding the extended attributes, empty if there are none.
+    xattr: DataBlockIndex,
 }
 
 impl INode {
@@ -42,17 +72,8 @@ impl INode {
             size: 0,
             is_directory: true,
             blocks: core::array::from_fn(|_| Default::default()),
-        }
-    }
-
-    pub(crate) fn write_slot(&mut self) -> WriteSlot<'_> {
-        let slot = self.size as usize / BLOCK_SIZE;
-        let offset = self.size as usize % BLOCK_SIZE;
-
-        WriteSlot {
-            block: self.blocks.get_mut(slot),
-            byte_offset: offset,
-            capacity: BLOCK_SIZE - offset,
+            flags: 0,
+            xattr: DataBlockIndex::default(),
         }
     }
 
@@ -61,6 +82,8 @@ impl INode {
             size: 0,
             is_directory: false,
             blocks: core::array::from_fn(|_| Default::default()),
+            flags: FLAG_INLINE,
+            xattr: DataBlockIndex::default(),
         }
     }
 
@@ -68,8 +91,6 @@ impl INode {
         self.size
     }
 
-    #[allow(unused)]
-    #[cfg(test)]
     pub(crate) fn set_size(&mut self, size: u32) {
         self.size = size;
     }
@@ -100,7 +121,105 @@ impl INode {
     }
 
     pub(crate) fn used_blocks(&self) -> impl Iterator<Item = DataBlockIndex> + '_ {
-        self.blocks.iter().copied().filter(|b| !b.is_empty())
+        let blocks = if self.is_inline() {
+            &[][..]
+        } else {
+            &self.blocks[..]
+        };
+
+        blocks.iter().copied().filter(|b| !b.is_empty())
+    }
+
+    pub(crate) fn is_compressed(&self) -> bool {
+        self.flags & FLAG_COMPRESSED != 0
+    }
+
+    pub(crate) fn set_compressed(&mut self) {
+        assert!(!self.is_inline());
+        self.flags |= FLAG_COMPRESSED;
+    }
+
+    /// Drops all content, turning this into an empty inline file. The caller
+    /// is responsible for freeing the blocks first. Extended attributes are
+    /// kept.
+    pub(crate) fn reset_file(&mut self) {
+        *self = Self {
+            xattr: self.xattr,
+            ..Self::new_empty_file()
+        };
+    }
+
+    /// Every data block referenced by this `INode`: the content blocks and
+    /// the `xattr` block.
+    pub(crate) fn owned_blocks(&self) -> impl Iterator<Item = DataBlockIndex> + '_ {
+        self.used_blocks()
+            .chain(Some(self.xattr).filter(|block| !block.is_empty()))
+    }
+
+    pub(crate) fn xattr_block(&self) -> DataBlockIndex {
+        self.xattr
+    }
+
+    pub(crate) fn set_xattr_block(&mut self, block: DataBlockIndex) {
+        self.xattr = block;
+    }
+
+    /// Moves all block pointers `delta` blocks further into the device.
+    pub(crate) fn relocate_blocks(&mut self, delta: u32) {
+        let relocate = |block: &mut DataBlockIndex| {
+            *block = DataBlockIndex::from_raw_unchecked(block.raw() + delta);
+        };
+
+        if !self.xattr.is_empty() {
+            relocate(&mut self.xattr);
+        }
+
+        if self.is_inline() {
+            return;
+        }
+
+        self.blocks
+            .iter_mut()
+            .filter(|b| !b.is_empty())
+            .for_each(relocate);
+    }
+
+    pub(crate) fn is_inline(&self) -> bool {
+        self.flags & FLAG_INLINE != 0
+    }
+
+    /// Returns the inline content. Bytes past `size` are always zero.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the `INode` isn't inline.
+    pub(crate) fn inline_data(&self) -> [u8; INLINE_CAPACITY] {
+        assert!(self.is_inline());
+
+        let mut data = [0u8; INLINE_CAPACITY];
+        for (chunk, block) in data.chunks_exact_mut(4).zip(self.blocks.iter()) {
+            chunk.copy_from_slice(&block.raw().to_le_bytes());
+        }
+        data
+    }
+
+    /// Replaces the inline content with `data`.
+    pub(crate) fn set_inline_data(&mut self, data: &[u8; INLINE_CAPACITY]) {
+        assert!(self.is_inline());
+
+        for (chunk, block) in data.chunks_exact(4).zip(self.blocks.iter_mut()) {
+            *block =
+                DataBlockIndex::from_raw_unchecked(u32::from_le_bytes(chunk.try_into().unwrap()));
+        }
+    }
+
+    /// Turns an inline `INode` into one using data blocks, returning the
+    /// inline content. All block slots are holes afterwards.
+    pub(crate) fn take_inline_data(&mut self) -> [u8; INLINE_CAPACITY] {
+        let data = self.inline_data();
+        self.blocks.iter_mut().for_each(DataBlockIndex::clear);
+        self.flags &= !FLAG_INLINE;
+        data
     }
 
     pub(crate) fn block(&self, block_index: usize) -> DataBlockIndex {
@@ -125,6 +244,9 @@ impl DiskFormat for INode {
         }
 
         writer.write_u8(if self.is_directory { 1 } else { 0 });
+        writer.write_u8(self.flags);
+        writer.write_bytes(&[0u8; 2]);
+        writer.write_u32(self.xattr.raw());
     }
 
     fn read_from(reader: &mut bytereader::ByteReader) -> Self {
@@ -137,11 +259,36 @@ impl DiskFormat for INode {
         });
 
         let is_directory = reader.read_u8() != 0;
+        let flags = reader.read_u8();
+        reader.read_bytes(2);
+        let xattr = DataBlockIndex::from_raw_unchecked(reader.read_u32());
 
         Self {
             size,
             blocks,
             is_directory,
+            flags,
+            xattr,
         }
     }
 }
+
+impl INode {
+    /// Decodes a record of the inode table, which is shorter than
+    /// `INODE_SIZE` on images using `LEGACY_INODE_SIZE`. Missing fields are
+    /// zero.
+    pub(crate) fn read_record(record: &[u8]) -> Self {
+        let mut bytes = [0u8; INODE_SIZE];
+        bytes[..record.len()].copy_from_slice(record);
+        Self::read_from(&mut ByteReader::new(&bytes))
+    }
+
+    /// Encodes `self` into a record of the inode table, see `read_record`.
+    pub(crate) fn write_record(&self, record: &mut [u8]) {
+        debug_assert!(record.len() == INODE_SIZE || self.xattr.is_empty());
+
+        let mut bytes = [0u8; INODE_SIZE];
+        self.write_to(&mut ByteWriter::new(&mut bytes));
+        record.copy_from_slice(&bytes[..record.len()]);
+    }
+}
@@ -1,9 +1,6 @@
-use crate::{
-    BLOCK_SIZE, BlockDevice, INode, INodeIndex,
-    bytereader::{ByteReader, DiskFormat},
-    layout::Layout,
-};
+use crate::{BLOCK_SIZE, BlockDevice, INode, INodeIndex, layout::Layout};
 use bitmap::Bitmap;
+use spin::Mutex;
 
 extern crate alloc;
 use alloc::vec::Vec;
@@ -27,16 +24,19 @@ impl INodeCache {
         }
     }
 
-    /// Reads the `INode` from disk if it's not already in the cache.
-    fn read_from_disk<D: BlockDevice>(&self, index: INodeIndex, device: &mut D) -> INode {
+    /// Reads the `INode` from disk if it's not already in the cache. The
+    /// device is only locked for that read.
+    fn read_from_disk<D: BlockDevice>(&self, index: INodeIndex, device: &Mutex<D>) -> INode {
         let mut buf = [0u8; BLOCK_SIZE];
-        let (block_index, byte_offset) = self.layout.as_ref().unwrap().inode_to_block(index);
-        device.read_block(block_index, &mut buf);
-        INode::read_from(&mut ByteReader::at(&buf, byte_offset.0 as usize))
+        let layout = self.layout.as_ref().unwrap();
+        let (block_index, byte_offset) = layout.inode_to_block(index);
+        device.lock().read_block(block_index, &mut buf);
+        let offset = byte_offset.0 as usize;
+        INode::read_record(&buf[offset..offset + layout.inode_size])
     }
 
     /// Get a `&INode` from the cache, fetching it from disk when not present.
-    pub fn get<D: BlockDevice>(&mut self, index: INodeIndex, device: &mut D) -> &INode {
+    pub fn get<D: BlockDevice>(&mut self, index: INodeIndex, device: &Mutex<D>) -> &INode {
         if self.inodes.len() <= index.inner() as usize {
             self.inodes
                 .resize_with(index.inner() as usize + 1, Default::default);
@@ -55,7 +55,7 @@ impl INodeCache {
 
     /// Get a `&mut INode` from the cache, fetching it from disk when not
     /// present.
-    pub fn get_mut<D: BlockDevice>(&mut self, index: INodeIndex, device: &mut D) -> &mut INode {
+    pub fn get_mut<D: BlockDevice>(&mut self, index: INodeIndex, device: &Mutex<D>) -> &mut INode {
         if self.inodes.len() <= index.inner() as usize {
             self.inodes
                 .resize_with(index.inner() as usize + 1, Default::default);
@@ -89,6 +89,17 @@ impl INodeCache {
         self.inodes[index.inner() as usize] = Some(inode);
     }
 
+    /// Returns the `INode` at `index` if it is dirty and marks it clean.
+    pub fn take_dirty(&mut self, index: INodeIndex) -> Option<INode> {
+        let idx = index.inner() as usize;
+        if idx >= self.dirty.len() || !self.dirty.is_set(idx) {
+            return None;
+        }
+
+        self.dirty.unset(idx);
+        self.inodes.get(idx).copied().flatten()
+    }
+
     pub fn drain(&mut self) -> impl Iterator<Item = (INodeIndex, INode)> {
         self.dirty
             .drain_ones()
@@ -0,0 +1,35 @@
+use crate::INodeIndex;
+
+extern crate alloc;
+use alloc::vec::Vec;
+use spin::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// One lock per `INode`, guarding the content of a file or directory.
+///
+/// Readers of a file or directory take it shared, anything changing the
+/// content or the `INode` itself takes it exclusively. Locks of different
+/// `INode`s are always taken from the root towards the leaves, so the
+/// parent directory of a file is locked before the file.
+pub(crate) struct INodeLocks {
+    locks: Vec<RwLock<()>>,
+}
+
+impl INodeLocks {
+    pub(crate) fn new(inode_count: usize) -> Self {
+        Self {
+            locks: (0..inode_count).map(|_| RwLock::new(())).collect(),
+        }
+    }
+
+    pub(crate) fn get(&self, index: INodeIndex) -> &RwLock<()> {
+        &self.locks[index.inner() as usize]
+    }
+
+    pub(crate) fn read(&self, index: INodeIndex) -> RwLockReadGuard<'_, ()> {
+        self.get(index).read()
+    }
+
+    pub(crate) fn write(&self, index: INodeIndex) -> RwLockWriteGuard<'_, ()> {
+        self.get(index).write()
+    }
+}
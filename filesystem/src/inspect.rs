@@ -0,0 +1,62 @@
+use crate::{Error, INodeIndex};
+
+use alloc::vec::Vec;
+
+/// The decoded superblock of an image as returned by
+/// `Filesystem::inspect_superblock`.
+#[derive(Debug, PartialEq)]
+pub struct SuperBlockInfo {
+    pub magic: u64,
+    pub version: u32,
+    pub block_size: u32,
+    pub total_blocks: u32,
+    pub inode_count: u32,
+
+    /// Whether the image was unmounted cleanly.
+    pub clean: bool,
+
+    pub compat: u32,
+    pub ro_compat: u32,
+    pub incompat: u32,
+
+    /// Size of an `INode` record in the inode table.
+    pub inode_size: usize,
+
+    /// The regions of the layout in order, starting with the superblock.
+    pub regions: Vec<RegionInfo>,
+
+    /// Why mounting the image would fail, `None` if the superblock is valid.
+    pub problem: Option<Error>,
+}
+
+/// A region of the layout, see `SuperBlockInfo::regions`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegionInfo {
+    pub name: &'static str,
+    pub start: u32,
+    pub blocks: u32,
+}
+
+/// A raw record of the inode table as returned by
+/// `Filesystem::inspect_inode`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct INodeInfo {
+    pub index: INodeIndex,
+
+    /// Whether the inode bitmap marks the record as in use.
+    pub allocated: bool,
+
+    pub size: u32,
+    pub is_directory: bool,
+    pub inline: bool,
+    pub compressed: bool,
+
+    /// Every block slot, 0 for holes. Inline files keep their content here.
+    pub blocks: Vec<u32>,
+
+    /// Block holding the extended attributes, 0 if there are none.
+    pub xattr: u32,
+
+    /// The record as stored in the inode table.
+    pub raw: Vec<u8>,
+}
@@ -1,7 +1,9 @@
-use core::mem;
 use core::num::NonZeroU32;
 
-use crate::{BLOCK_SIZE, INODES_PER_BLOCK, INode};
+use crate::BLOCK_SIZE;
+
+/// Number of blocks at the start of the snapshot region holding the table.
+pub(crate) const SNAPSHOT_TABLE_BLOCKS: usize = 1;
 
 /// An Index into the blocks used for the block device.
 #[derive(Debug, Clone, Copy)]
@@ -64,6 +66,11 @@ impl DataBlockIndex {
         Self(NonZeroU32::new(val))
     }
 
+    /// The raw on-disk value, zero for an empty slot.
+    pub(crate) fn raw(self) -> u32 {
+        self.0.map_or(0, NonZeroU32::get)
+    }
+
     pub(crate) fn to_block(self) -> Option<BlockIndex> {
         self.0.map(|v| BlockIndex(v.get()))
     }
@@ -91,10 +98,15 @@ pub(crate) struct Layout {
     pub(crate) inode_bitmap_blocks: usize,
     pub(crate) data_bitmap_start: usize,
     pub(crate) data_bitmap_blocks: usize,
+    pub(crate) snapshot_start: usize,
+    pub(crate) snapshot_blocks: usize,
     pub(crate) inode_table_start: usize,
     pub(crate) inode_table_blocks: usize,
     pub(crate) data_start: usize,
     pub(crate) data_blocks: usize,
+
+    /// Size of an `INode` record in the inode table, see `inode::INODE_SIZE`.
+    pub(crate) inode_size: usize,
 }
 
 impl Layout {
@@ -109,61 +121,105 @@ impl Layout {
     /// | DataBitmap   |
     /// | ...          |
     /// +--------------+
+    /// | Snapshots    |  only with snapshots enabled
+    /// | ...          |
+    /// +--------------+
     /// | INode blocks |
     /// | ...          |
     /// +--------------+
     /// | Data blocks  |
     /// | ...          |
     /// +--------------+
-    pub(crate) fn new(total_blocks: usize, inode_count: usize) -> Option<Self> {
+    ///
+    /// This is the layout for `total_blocks` with `inode_size` byte `INode` records,
+    /// optionally with the snapshot region holding the snapshot table and a
+    /// reference count per data block.
+    pub(crate) fn canonical(
+        total_blocks: usize,
+        inode_count: usize,
+        inode_size: usize,
+        snapshots: bool,
+    ) -> Option<Self> {
         const SUPERBLOCK_BLOCKS: usize = 1;
         const BITS_PER_BLOCK: usize = BLOCK_SIZE * 8;
 
         let inode_bitmap_blocks = inode_count.div_ceil(BITS_PER_BLOCK);
-        let inode_table_blocks = inode_count.div_ceil(INODES_PER_BLOCK);
+        let inode_table_blocks = inode_count.div_ceil(BLOCK_SIZE / inode_size);
 
         let fixed = SUPERBLOCK_BLOCKS
             .checked_add(inode_bitmap_blocks)?
             .checked_add(inode_table_blocks)?;
         let remaining = total_blocks.checked_sub(fixed)?;
 
-        let mut data_bitmap_blocks = remaining.div_ceil(BITS_PER_BLOCK);
+        // Blocks needed to track `data_blocks`: the data bitmap and, with
+        // snapshots, the snapshot table plus one byte per data block.
+        let metadata_blocks = |data_blocks: usize| {
+            let bitmap = data_blocks.div_ceil(BITS_PER_BLOCK);
+            let snapshot = if snapshots {
+                SNAPSHOT_TABLE_BLOCKS + data_blocks.div_ceil(BLOCK_SIZE)
+            } else {
+                0
+            };
+            (bitmap, snapshot)
+        };
+
+        let (mut data_bitmap_blocks, mut snapshot_blocks) = metadata_blocks(remaining);
 
         let (data_start, data_blocks) = loop {
-            let data_start = fixed.checked_add(data_bitmap_blocks)?;
+            let data_start = fixed
+                .checked_add(data_bitmap_blocks)?
+                .checked_add(snapshot_blocks)?;
             let data_blocks = total_blocks.checked_sub(data_start)?;
 
             if data_blocks == 0 {
                 return None;
             }
 
-            let next = data_blocks.div_ceil(BITS_PER_BLOCK);
+            let next = metadata_blocks(data_blocks);
 
-            if next == data_bitmap_blocks {
+            if next == (data_bitmap_blocks, snapshot_blocks) {
                 break (data_start, data_blocks);
             }
 
-            data_bitmap_blocks = next;
+            (data_bitmap_blocks, snapshot_blocks) = next;
         };
 
+        let data_bitmap_start = 1 + inode_bitmap_blocks;
+        let snapshot_start = data_bitmap_start + data_bitmap_blocks;
+
         Some(Self {
             inode_bitmap_start: 1,
             inode_bitmap_blocks,
-            data_bitmap_start: 1 + inode_bitmap_blocks,
+            data_bitmap_start,
             data_bitmap_blocks,
-            inode_table_start: 1 + inode_bitmap_blocks + data_bitmap_blocks,
+            snapshot_start,
+            snapshot_blocks,
+            inode_table_start: snapshot_start + snapshot_blocks,
             inode_table_blocks,
             data_start,
             data_blocks,
+            inode_size,
         })
     }
 
+    pub(crate) fn inodes_per_block(&self) -> usize {
+        BLOCK_SIZE / self.inode_size
+    }
+
+    pub(crate) fn has_snapshots(&self) -> bool {
+        self.snapshot_blocks != 0
+    }
+
+    /// First block of the per data block reference counts.
+    pub(crate) fn refcount_start(&self) -> usize {
+        self.snapshot_start + SNAPSHOT_TABLE_BLOCKS
+    }
+
     pub(crate) fn inode_to_block(&self, inode: INodeIndex) -> (BlockIndex, ByteOffset) {
-        let block_index =
-            BlockIndex(self.inode_table_start as u32 + (inode.0 / INODES_PER_BLOCK as u32));
+        let inodes_per_block = self.inodes_per_block() as u32;
+        let block_index = BlockIndex(self.inode_table_start as u32 + (inode.0 / inodes_per_block));
 
-        let offset =
-            ByteOffset((inode.0 % INODES_PER_BLOCK as u32) * mem::size_of::<INode>() as u32);
+        let offset = ByteOffset((inode.0 % inodes_per_block) * self.inode_size as u32);
 
         (block_index, offset)
     }
@@ -2,14 +2,34 @@
 extern crate alloc;
 
 mod bytereader;
+mod compression;
 mod dir_entry;
+mod file_lock;
 mod filesystem;
+mod glob;
 mod inode;
 mod inode_cache;
+mod inode_locks;
+mod inspect;
 mod layout;
+mod memory_disk;
+mod snapshot;
+mod walk;
+mod watch;
+mod xattr;
 
 pub use crate::layout::{BlockIndex, INodeIndex};
-pub use filesystem::{BLOCK_SIZE, BlockDevice, Error, Filesystem};
+pub use bytereader::{u16_at, u32_at};
+pub use dir_entry::MAX_NAME_LEN;
+pub use file_lock::{FileHandle, LockInfo, LockKind};
+pub use filesystem::{BLOCK_SIZE, BlockDevice, Error, Filesystem, Metadata, entry_display};
+pub use glob::{glob_match, is_glob};
+pub use inspect::{INodeInfo, RegionInfo, SuperBlockInfo};
+#[doc(hidden)]
+pub use memory_disk::MemoryDisk;
+pub use snapshot::SnapshotInfo;
+pub use walk::{WalkEntry, Walker};
+pub use watch::{WatchEvent, WatchId};
+pub use xattr::{MAX_XATTR_NAME, MAX_XATTR_VALUE, MAX_XATTRS};
 
-pub(crate) use filesystem::INODES_PER_BLOCK;
 pub(crate) use inode::INode;
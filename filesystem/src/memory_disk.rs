@@ -0,0 +1,24 @@
+extern crate alloc;
+use crate::{BLOCK_SIZE, BlockDevice, BlockIndex};
+use alloc::vec::Vec;
+
+/// A `BlockDevice` keeping an image in memory, for the tests of the
+/// filesystems built on this crate.
+#[doc(hidden)]
+pub struct MemoryDisk(pub Vec<u8>);
+
+impl BlockDevice for MemoryDisk {
+    fn read_block(&mut self, block_idx: BlockIndex, buf: &mut [u8]) {
+        let start = block_idx.inner() as usize * BLOCK_SIZE;
+        buf.copy_from_slice(&self.0[start..start + BLOCK_SIZE]);
+    }
+
+    fn write_block(&mut self, block_idx: BlockIndex, data: &[u8]) {
+        let start = block_idx.inner() as usize * BLOCK_SIZE;
+        self.0[start..start + BLOCK_SIZE].copy_from_slice(data);
+    }
+
+    fn total_blocks(&mut self) -> usize {
+        self.0.len() / BLOCK_SIZE
+    }
+}
@@ -0,0 +1,146 @@
+use crate::{
+    BLOCK_SIZE, INode, INodeIndex,
+    bytereader::{ByteReader, ByteWriter, DiskFormat},
+    inode::INODE_SIZE,
+    layout::DataBlockIndex,
+};
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem;
+
+/// Max length of a snapshot name, the same as for a `DirEntry`.
+pub(crate) const SNAPSHOT_NAME_LEN: usize = 24;
+
+/// Number of snapshots the snapshot table can hold.
+pub(crate) const MAX_SNAPSHOTS: usize = BLOCK_SIZE / mem::size_of::<SnapshotEntry>();
+
+/// Size of the `INodeIndex` in front of every `INode` record of a dump.
+const DUMP_INDEX_SIZE: usize = mem::size_of::<u32>();
+
+/// Size of the header in front of the records of a dump block.
+const DUMP_HEADER_SIZE: usize = 2 * mem::size_of::<u32>();
+
+/// Number of dump records per `DumpBlock`. Records are as large as the
+/// records of the inode table, this holds for the legacy size as well.
+pub(crate) const DUMP_RECORDS_PER_BLOCK: usize =
+    (BLOCK_SIZE - DUMP_HEADER_SIZE) / (DUMP_INDEX_SIZE + INODE_SIZE);
+
+/// Information about a snapshot as returned by `Filesystem::snapshots`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotInfo {
+    pub name: String,
+
+    /// Number of files and directories frozen in the snapshot.
+    pub inodes: usize,
+}
+
+/// An entry of the snapshot table. A snapshot is a copy of every allocated
+/// `INode` at the time it was taken, stored in a chain of dump blocks
+/// starting at `head`. The data blocks of those inodes are shared with the
+/// live filesystem and copied before they are modified.
+///
+/// Memory layout:
+/// `name`    24 bytes
+/// `head`    4 bytes
+/// `inodes`  4 bytes
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub(crate) struct SnapshotEntry {
+    /// Name of the snapshot, an empty name marks a free entry.
+    name: [u8; SNAPSHOT_NAME_LEN],
+
+    /// First block of the dump chain.
+    pub(crate) head: DataBlockIndex,
+
+    /// Number of inodes in the dump chain.
+    pub(crate) inodes: u32,
+}
+
+impl SnapshotEntry {
+    pub(crate) fn new(name: &str, head: DataBlockIndex, inodes: u32) -> Self {
+        let mut bytes = [0u8; SNAPSHOT_NAME_LEN];
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+
+        Self {
+            name: bytes,
+            head,
+            inodes,
+        }
+    }
+
+    pub(crate) fn is_free(&self) -> bool {
+        self.name[0] == 0
+    }
+
+    pub(crate) fn name(&self) -> String {
+        let len = self.name.iter().take_while(|&&b| b != 0).count();
+        String::from_utf8_lossy(&self.name[..len]).into_owned()
+    }
+}
+
+impl DiskFormat for SnapshotEntry {
+    fn write_to(&self, writer: &mut ByteWriter) {
+        writer.write_bytes(&self.name);
+        writer.write_u32(self.head.raw());
+        writer.write_u32(self.inodes);
+    }
+
+    fn read_from(reader: &mut ByteReader) -> Self {
+        Self {
+            name: reader.read_bytes(SNAPSHOT_NAME_LEN).try_into().unwrap(),
+            head: DataBlockIndex::from_raw_unchecked(reader.read_u32()),
+            inodes: reader.read_u32(),
+        }
+    }
+}
+
+/// A block of the dump chain of a snapshot.
+///
+/// Memory layout:
+/// `next`     4 bytes, the next block of the chain or zero
+/// `count`    4 bytes, number of used records
+/// `records`  `DUMP_RECORDS_PER_BLOCK` records of an index and an `INode`
+pub(crate) struct DumpBlock {
+    pub(crate) next: DataBlockIndex,
+    pub(crate) records: Vec<(INodeIndex, INode)>,
+}
+
+impl DumpBlock {
+    /// Encodes the block with `inode_size` byte `INode` records.
+    pub(crate) fn encode(&self, block: &mut [u8], inode_size: usize) {
+        debug_assert!(self.records.len() <= DUMP_RECORDS_PER_BLOCK);
+
+        let mut header = ByteWriter::new(block);
+        header.write_u32(self.next.raw());
+        header.write_u32(self.records.len() as u32);
+
+        for (i, (index, inode)) in self.records.iter().enumerate() {
+            let offset = DUMP_HEADER_SIZE + i * (DUMP_INDEX_SIZE + inode_size);
+            ByteWriter::at(block, offset).write_u32(index.inner());
+            let record = offset + DUMP_INDEX_SIZE;
+            inode.write_record(&mut block[record..record + inode_size]);
+        }
+    }
+
+    pub(crate) fn decode(block: &[u8], inode_size: usize) -> Self {
+        let mut header = ByteReader::new(block);
+        let next = DataBlockIndex::from_raw_unchecked(header.read_u32());
+        let count = (header.read_u32() as usize).min(DUMP_RECORDS_PER_BLOCK);
+
+        let records = (0..count)
+            .map(|i| {
+                let offset = DUMP_HEADER_SIZE + i * (DUMP_INDEX_SIZE + inode_size);
+                let index = INodeIndex::new(ByteReader::at(block, offset).read_u32());
+                let record = offset + DUMP_INDEX_SIZE;
+                (
+                    index,
+                    INode::read_record(&block[record..record + inode_size]),
+                )
+            })
+            .collect();
+
+        Self { next, records }
+    }
+}
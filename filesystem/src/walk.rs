@@ -0,0 +1,97 @@
+use crate::{BlockDevice, Filesystem, INodeIndex, Metadata};
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// A file or directory found by `Walker`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WalkEntry {
+    /// Absolute path of the entry.
+    pub path: String,
+    pub metadata: Metadata,
+
+    /// Number of directories between the start of the walk and the entry,
+    /// zero for the start itself.
+    pub depth: usize,
+}
+
+impl WalkEntry {
+    /// The last component of the path, `/` for the root.
+    pub fn name(&self) -> &str {
+        match self.path.rsplit_once('/') {
+            Some((_, "")) | None => "/",
+            Some((_, name)) => name,
+        }
+    }
+}
+
+/// Depth-first iterator over a directory tree as returned by
+/// `Filesystem::walk`.
+///
+/// Every directory is yielded before its children, which follow in
+/// directory order. Calling `skip_dir` right after a directory got yielded
+/// prunes its children. Directories are only locked while they're read,
+/// so entries changed during the walk may or may not show up.
+pub struct Walker<'fs, D> {
+    fs: &'fs Filesystem<D>,
+
+    /// Entries still to visit, the next one on top.
+    pending: Vec<(String, usize)>,
+
+    /// The last yielded directory, its children are read on the next call.
+    descend: Option<(String, INodeIndex, usize)>,
+}
+
+impl<'fs, D: BlockDevice> Walker<'fs, D> {
+    pub(crate) fn new(fs: &'fs Filesystem<D>, path: String) -> Self {
+        Self {
+            fs,
+            pending: alloc::vec![(path, 0)],
+            descend: None,
+        }
+    }
+
+    /// Doesn't descend into the directory yielded last.
+    pub fn skip_dir(&mut self) {
+        self.descend = None;
+    }
+}
+
+impl<D: BlockDevice> Iterator for Walker<'_, D> {
+    type Item = WalkEntry;
+
+    fn next(&mut self) -> Option<WalkEntry> {
+        if let Some((path, dir, depth)) = self.descend.take() {
+            let children = self.fs.read_dir_entry_locked(dir);
+            let parent = path.trim_end_matches('/');
+
+            for child in children.iter().rev() {
+                let name = child.name();
+                if name != "." && name != ".." {
+                    self.pending
+                        .push((alloc::format!("{parent}/{name}"), depth + 1));
+                }
+            }
+        }
+
+        loop {
+            let (path, depth) = self.pending.pop()?;
+
+            // The entry may have been removed since its directory was read.
+            let Ok(metadata) = self.fs.stat(&path) else {
+                continue;
+            };
+
+            if metadata.is_directory {
+                self.descend = Some((path.clone(), metadata.inode, depth));
+            }
+
+            return Some(WalkEntry {
+                path,
+                metadata,
+                depth,
+            });
+        }
+    }
+}
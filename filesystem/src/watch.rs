@@ -0,0 +1,174 @@
+use crate::{Error, INodeIndex};
+
+extern crate alloc;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Max number of undrained events per watch, later events are dropped and
+/// reported as a single `WatchEvent::Overflow`.
+pub(crate) const WATCH_QUEUE_CAPACITY: usize = 64;
+
+/// A subscription to the changes of a file or directory as returned by
+/// `Filesystem::watch`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct WatchId(u32);
+
+impl WatchId {
+    pub fn new(val: u32) -> Self {
+        Self(val)
+    }
+
+    pub fn inner(&self) -> u32 {
+        self.0
+    }
+}
+
+/// A change of a watched file or directory, carrying the paths passed to the
+/// operation that caused it.
+///
+/// A watched directory reports its entries being created, removed and
+/// renamed. A watched file reports being modified, renamed and removed,
+/// after being removed the watch stays silent.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WatchEvent {
+    Created(String),
+    Removed(String),
+    Modified(String),
+    Renamed {
+        from: String,
+        to: String,
+    },
+
+    /// The queue was full and events got dropped.
+    Overflow,
+}
+
+struct Watch {
+    /// Watched `INode`, `None` once it got removed.
+    inode: Option<INodeIndex>,
+    events: VecDeque<WatchEvent>,
+    overflowed: bool,
+}
+
+/// Watches and their queued events, only kept in memory.
+#[derive(Default)]
+pub(crate) struct WatchRegistry {
+    next_id: u32,
+    watches: BTreeMap<WatchId, Watch>,
+
+    /// Watches of every watched `INode`, keyed by the raw `INodeIndex`.
+    by_inode: BTreeMap<u32, Vec<WatchId>>,
+}
+
+impl WatchRegistry {
+    pub(crate) fn watch(&mut self, inode: INodeIndex) -> WatchId {
+        let id = WatchId(self.next_id);
+        self.next_id += 1;
+        self.watches.insert(
+            id,
+            Watch {
+                inode: Some(inode),
+                events: VecDeque::new(),
+                overflowed: false,
+            },
+        );
+        self.by_inode.entry(inode.inner()).or_default().push(id);
+        id
+    }
+
+    pub(crate) fn unwatch(&mut self, id: WatchId) -> Result<(), Error> {
+        let watch = self.watches.remove(&id).ok_or(Error::BadHandle)?;
+        if let Some(inode) = watch.inode {
+            self.detach(inode.inner(), id);
+        }
+        Ok(())
+    }
+
+    /// Takes all queued events of the watch.
+    pub(crate) fn drain(&mut self, id: WatchId) -> Result<Vec<WatchEvent>, Error> {
+        let watch = self.watches.get_mut(&id).ok_or(Error::BadHandle)?;
+        let mut events: Vec<_> = watch.events.drain(..).collect();
+        if core::mem::take(&mut watch.overflowed) {
+            events.push(WatchEvent::Overflow);
+        }
+        Ok(events)
+    }
+
+    /// Queues an event for every watch of `inode`. The event is only built
+    /// if someone watches it.
+    pub(crate) fn notify(&mut self, inode: INodeIndex, event: impl Fn() -> WatchEvent) {
+        let Some(ids) = self.by_inode.get(&inode.inner()) else {
+            return;
+        };
+
+        for id in ids {
+            let watch = self.watches.get_mut(id).expect("Registered with the watch");
+            if watch.events.len() == WATCH_QUEUE_CAPACITY {
+                watch.overflowed = true;
+            } else {
+                watch.events.push_back(event());
+            }
+        }
+    }
+
+    /// Detaches all watches of a removed `INode`, its index may be reused
+    /// by an unrelated file.
+    pub(crate) fn forget(&mut self, inode: INodeIndex) {
+        for id in self.by_inode.remove(&inode.inner()).unwrap_or_default() {
+            if let Some(watch) = self.watches.get_mut(&id) {
+                watch.inode = None;
+            }
+        }
+    }
+
+    fn detach(&mut self, inode: u32, id: WatchId) {
+        if let Some(ids) = self.by_inode.get_mut(&inode) {
+            ids.retain(|watch| *watch != id);
+            if ids.is_empty() {
+                self.by_inode.remove(&inode);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_queues_report_an_overflow() {
+        let mut registry = WatchRegistry::default();
+        let id = registry.watch(INodeIndex::new(3));
+
+        for i in 0..WATCH_QUEUE_CAPACITY + 5 {
+            registry.notify(INodeIndex::new(3), || {
+                WatchEvent::Modified(alloc::format!("/file{i}"))
+            });
+        }
+
+        let events = registry.drain(id).unwrap();
+        assert_eq!(events.len(), WATCH_QUEUE_CAPACITY + 1);
+        assert_eq!(events[0], WatchEvent::Modified("/file0".into()));
+        assert_eq!(events.last(), Some(&WatchEvent::Overflow));
+        assert!(registry.drain(id).unwrap().is_empty());
+    }
+
+    #[test]
+    fn forgotten_inodes_stay_silent() {
+        let mut registry = WatchRegistry::default();
+        let id = registry.watch(INodeIndex::new(3));
+        let other = registry.watch(INodeIndex::new(4));
+
+        registry.forget(INodeIndex::new(3));
+        registry.notify(INodeIndex::new(3), || {
+            WatchEvent::Modified("/reused".into())
+        });
+        registry.notify(INodeIndex::new(4), || WatchEvent::Modified("/other".into()));
+
+        assert!(registry.drain(id).unwrap().is_empty());
+        assert_eq!(registry.drain(other).unwrap().len(), 1);
+        registry.unwatch(id).unwrap();
+        assert_eq!(registry.drain(id), Err(Error::BadHandle));
+    }
+}
@@ -0,0 +1,164 @@
+use crate::{
+    BLOCK_SIZE,
+    bytereader::{ByteReader, ByteWriter},
+};
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem;
+
+/// Max length of an extended attribute name such as `user.mime_type`.
+pub const MAX_XATTR_NAME: usize = 64;
+
+/// Max size of a single extended attribute value.
+pub const MAX_XATTR_VALUE: usize = 256;
+
+/// Max number of extended attributes per `INode`.
+pub const MAX_XATTRS: usize = 16;
+
+/// Size of the entry count in front of the entries.
+const HEADER_SIZE: usize = mem::size_of::<u16>();
+
+/// Size of the name and value length in front of every entry.
+const ENTRY_HEADER_SIZE: usize = mem::size_of::<u8>() + mem::size_of::<u16>();
+
+/// The extended attributes of an `INode`, stored in a single data block.
+///
+/// Memory layout:
+/// `count`    2 bytes
+/// `entries`  `count` times a u8 name length, a u16 value length, the name
+///            and the value
+#[derive(Debug, Default)]
+pub(crate) struct XattrBlock {
+    entries: Vec<(String, Vec<u8>)>,
+}
+
+impl XattrBlock {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .find(|(entry, _)| entry == name)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    pub(crate) fn names(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Sets `name` to `value`, replacing an existing value.
+    pub(crate) fn set(&mut self, name: &str, value: &[u8]) {
+        match self.entries.iter_mut().find(|(entry, _)| entry == name) {
+            Some((_, existing)) => *existing = value.to_vec(),
+            None => self.entries.push((name.into(), value.to_vec())),
+        }
+    }
+
+    /// Removes `name`, returning `false` if it didn't exist.
+    pub(crate) fn remove(&mut self, name: &str) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|(entry, _)| entry != name);
+        self.entries.len() != len
+    }
+
+    /// Returns `true` if all entries fit into a single block.
+    pub(crate) fn fits(&self) -> bool {
+        let size = self
+            .entries
+            .iter()
+            .map(|(name, value)| ENTRY_HEADER_SIZE + name.len() + value.len())
+            .sum::<usize>();
+        HEADER_SIZE + size <= BLOCK_SIZE
+    }
+
+    pub(crate) fn encode(&self, block: &mut [u8]) {
+        debug_assert!(self.fits());
+
+        let mut writer = ByteWriter::new(block);
+        writer.write_bytes(&(self.entries.len() as u16).to_le_bytes());
+
+        for (name, value) in &self.entries {
+            writer.write_u8(name.len() as u8);
+            writer.write_bytes(&(value.len() as u16).to_le_bytes());
+            writer.write_bytes(name.as_bytes());
+            writer.write_bytes(value);
+        }
+    }
+
+    /// Decodes a block, returning `None` if it's corrupted.
+    pub(crate) fn decode(block: &[u8]) -> Option<Self> {
+        let mut reader = ByteReader::new(block);
+        let mut pos = HEADER_SIZE;
+        let count = u16::from_le_bytes(reader.read_bytes(HEADER_SIZE).try_into().unwrap());
+
+        if count as usize > MAX_XATTRS {
+            return None;
+        }
+
+        let mut entries = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            if pos + ENTRY_HEADER_SIZE > block.len() {
+                return None;
+            }
+            let name_len = reader.read_u8() as usize;
+            let value_len = u16::from_le_bytes(reader.read_bytes(2).try_into().unwrap()) as usize;
+            pos += ENTRY_HEADER_SIZE + name_len + value_len;
+            if pos > block.len() {
+                return None;
+            }
+
+            let name = String::from_utf8(reader.read_bytes(name_len).to_vec()).ok()?;
+            entries.push((name, reader.read_bytes(value_len).to_vec()));
+        }
+
+        Some(Self { entries })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrips_and_replaces_entries() {
+        let mut xattrs = XattrBlock::default();
+        xattrs.set("user.mime_type", b"text/plain");
+        xattrs.set("user.build_id", &[0xde, 0xad]);
+        xattrs.set("user.mime_type", b"text/markdown");
+
+        let mut block = [0u8; BLOCK_SIZE];
+        xattrs.encode(&mut block);
+        let decoded = XattrBlock::decode(&block).unwrap();
+
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded.get("user.mime_type"), Some(&b"text/markdown"[..]));
+        assert_eq!(decoded.get("user.build_id"), Some(&[0xde, 0xad][..]));
+        assert_eq!(
+            decoded.names().collect::<Vec<_>>(),
+            ["user.mime_type", "user.build_id"]
+        );
+    }
+
+    #[test]
+    fn detects_overflowing_and_corrupted_blocks() {
+        let mut xattrs = XattrBlock::default();
+        xattrs.set("user.a", &[1; MAX_XATTR_VALUE]);
+        assert!(xattrs.fits());
+        xattrs.set("user.b", &[2; MAX_XATTR_VALUE]);
+        assert!(!xattrs.fits());
+
+        let mut block = [0u8; BLOCK_SIZE];
+        block[..2].copy_from_slice(&1u16.to_le_bytes());
+        block[2] = 10;
+        block[3..5].copy_from_slice(&(BLOCK_SIZE as u16).to_le_bytes());
+        assert!(XattrBlock::decode(&block).is_none());
+    }
+}
@@ -0,0 +1,212 @@
+//! Hammers a shared `Filesystem` from several threads to check that the
+//! per-inode locks keep files and directories consistent and that advisory
+//! locks exclude each other.
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use filesystem::{BLOCK_SIZE, BlockDevice, BlockIndex, Filesystem, LockKind};
+
+const TOTAL_BLOCKS: usize = 8192;
+const THREADS: usize = 6;
+const ROUNDS: usize = 24;
+
+/// Every append to the shared log is one line of this length.
+const LINE_LEN: usize = 16;
+
+/// A block device that can be moved between threads.
+#[derive(Clone)]
+struct MemoryDisk(Arc<Mutex<Vec<u8>>>);
+
+impl MemoryDisk {
+    fn new() -> Self {
+        Self(Arc::new(Mutex::new(vec![0; TOTAL_BLOCKS * BLOCK_SIZE])))
+    }
+}
+
+impl BlockDevice for MemoryDisk {
+    fn read_block(&mut self, block_idx: BlockIndex, buf: &mut [u8]) {
+        let start = block_idx.inner() as usize * BLOCK_SIZE;
+        buf.copy_from_slice(&self.0.lock().unwrap()[start..start + BLOCK_SIZE]);
+    }
+
+    fn write_block(&mut self, block_idx: BlockIndex, data: &[u8]) {
+        let start = block_idx.inner() as usize * BLOCK_SIZE;
+        self.0.lock().unwrap()[start..start + BLOCK_SIZE].copy_from_slice(data);
+    }
+
+    fn total_blocks(&mut self) -> usize {
+        self.0.lock().unwrap().len() / BLOCK_SIZE
+    }
+}
+
+fn file_content(thread: usize, round: usize) -> Vec<u8> {
+    let len = (thread * 131 + round * 37) % (3 * BLOCK_SIZE) + 4;
+    (0..len).map(|i| (thread * 7 + round + i) as u8).collect()
+}
+
+fn log_line(thread: usize, round: usize) -> String {
+    format!("thread {thread} {round:06}\n")
+}
+
+/// Checks what the writer threads leave behind: every odd round's file and
+/// a log with every line exactly once.
+fn assert_final_state<D: BlockDevice>(fs: &Filesystem<D>) {
+    for thread in 0..THREADS {
+        for round in 0..ROUNDS {
+            let path = format!("/t{thread}/f{round}");
+            if round % 2 == 0 {
+                assert!(fs.stat(&path).is_err(), "{path} should be removed");
+            } else {
+                let mut expected = file_content(thread, round);
+                expected[..4].copy_from_slice(b"head");
+                assert_eq!(fs.read_bytes(&path).unwrap(), expected, "{path}");
+            }
+        }
+    }
+
+    let log = fs.read_file("/log").unwrap();
+    assert_eq!(log.len(), THREADS * ROUNDS * LINE_LEN);
+    let mut lines = log.lines().collect::<Vec<_>>();
+    lines.sort_unstable();
+    lines.dedup();
+    assert_eq!(lines.len(), THREADS * ROUNDS);
+}
+
+#[test]
+fn concurrent_operations_keep_the_filesystem_consistent() {
+    let device = MemoryDisk::new();
+    Filesystem::format(device.clone()).unwrap();
+
+    let fs = Arc::new(Filesystem::new(device).unwrap());
+    fs.create_file("/log").unwrap();
+
+    let writers = (0..THREADS)
+        .map(|thread| {
+            let fs = Arc::clone(&fs);
+            thread::spawn(move || {
+                let dir = format!("/t{thread}");
+                fs.mkdir(&dir).unwrap();
+
+                for round in 0..ROUNDS {
+                    let path = format!("{dir}/f{round}");
+                    let content = file_content(thread, round);
+                    fs.create_file(&path).unwrap();
+                    fs.write_to_file(&path, &content).unwrap();
+                    fs.write_at(&path, 0, b"head").unwrap();
+                    assert_eq!(fs.read_bytes(&path).unwrap()[4..], content[4..]);
+
+                    assert_eq!(log_line(thread, round).len(), LINE_LEN);
+                    fs.write_to_file("/log", log_line(thread, round).as_bytes())
+                        .unwrap();
+
+                    if round % 2 == 0 {
+                        fs.remove_dir_entry(&path).unwrap();
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let readers = (0..2)
+        .map(|_| {
+            let fs = Arc::clone(&fs);
+            thread::spawn(move || {
+                for _ in 0..ROUNDS * 4 {
+                    let log = fs.read_file("/log").unwrap();
+                    assert_eq!(log.len() % LINE_LEN, 0, "torn append in {log:?}");
+                    assert!(fs.stat("/").unwrap().is_directory);
+
+                    let mut tree = String::new();
+                    fs.tree(&mut tree);
+                    assert!(tree.contains("log"));
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for handle in writers.into_iter().chain(readers) {
+        handle.join().unwrap();
+    }
+
+    let Ok(fs) = Arc::try_unwrap(fs) else {
+        panic!("filesystem still shared");
+    };
+    assert_final_state(&fs);
+
+    let fs = Filesystem::new(fs.unmount()).unwrap();
+    assert!(fs.mounted_clean());
+    assert_final_state(&fs);
+}
+
+#[test]
+fn blocking_lock_waits_for_the_holder() {
+    let device = MemoryDisk::new();
+    Filesystem::format(device.clone()).unwrap();
+
+    let fs = Arc::new(Filesystem::new(device).unwrap());
+    fs.create_file("/config").unwrap();
+
+    let holder = fs.open("/config").unwrap();
+    fs.lock(holder, LockKind::Exclusive, true).unwrap();
+
+    let waiter = {
+        let fs = Arc::clone(&fs);
+        thread::spawn(move || {
+            let handle = fs.open("/config").unwrap();
+            fs.lock(handle, LockKind::Exclusive, true).unwrap();
+            let content = fs.read_file("/config").unwrap();
+            fs.close(handle).unwrap();
+            content
+        })
+    };
+
+    fs.write_to_file("/config", b"written under the lock")
+        .unwrap();
+    fs.close(holder).unwrap();
+
+    assert_eq!(waiter.join().unwrap(), "written under the lock");
+    assert!(fs.locks().is_empty());
+}
+
+#[test]
+fn opposite_renames_dont_deadlock() {
+    let device = MemoryDisk::new();
+    Filesystem::format(device.clone()).unwrap();
+
+    let fs = Arc::new(Filesystem::new(device).unwrap());
+    for dir in ["/a", "/b"] {
+        fs.mkdir(dir).unwrap();
+    }
+    for i in 0..THREADS {
+        let dir = if i % 2 == 0 { "/a" } else { "/b" };
+        fs.create_file(&format!("{dir}/f{i}")).unwrap();
+    }
+
+    let movers = (0..THREADS)
+        .map(|i| {
+            let fs = Arc::clone(&fs);
+            thread::spawn(move || {
+                let (mut from, mut to) = if i % 2 == 0 {
+                    ("/a", "/b")
+                } else {
+                    ("/b", "/a")
+                };
+                for _ in 0..ROUNDS {
+                    fs.rename(&format!("{from}/f{i}"), &format!("{to}/f{i}"))
+                        .unwrap();
+                    (from, to) = (to, from);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for handle in movers {
+        handle.join().unwrap();
+    }
+
+    for i in 0..THREADS {
+        let dir = if i % 2 == 0 { "/a" } else { "/b" };
+        assert!(fs.stat(&format!("{dir}/f{i}")).is_ok());
+    }
+}
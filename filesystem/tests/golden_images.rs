@@ -0,0 +1,116 @@
+//! Checks that images written by older format versions stay readable and can
+//! be upgraded to the current version, see `fixtures/README.md`.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use filesystem::{BLOCK_SIZE, BlockDevice, BlockIndex, Error, Filesystem};
+
+const V1_IMAGE: &[u8] = include_bytes!("fixtures/v1.img");
+const V2_IMAGE: &[u8] = include_bytes!("fixtures/v2.img");
+
+const MOTD_LINES: usize = 12;
+
+/// A block device backed by a copy of a golden image.
+#[derive(Clone)]
+struct Image(Rc<RefCell<Vec<u8>>>);
+
+impl Image {
+    fn new(bytes: &[u8]) -> Self {
+        Self(Rc::new(RefCell::new(bytes.to_vec())))
+    }
+}
+
+impl BlockDevice for Image {
+    fn read_block(&mut self, block_idx: BlockIndex, buf: &mut [u8]) {
+        let start = block_idx.inner() as usize * BLOCK_SIZE;
+        buf.copy_from_slice(&self.0.borrow()[start..start + BLOCK_SIZE]);
+    }
+
+    fn write_block(&mut self, block_idx: BlockIndex, data: &[u8]) {
+        let start = block_idx.inner() as usize * BLOCK_SIZE;
+        self.0.borrow_mut()[start..start + BLOCK_SIZE].copy_from_slice(data);
+    }
+
+    fn total_blocks(&mut self) -> usize {
+        self.0.borrow().len() / BLOCK_SIZE
+    }
+}
+
+fn expected_motd() -> String {
+    (0..MOTD_LINES)
+        .map(|i| format!("line {i:03}: the quick brown lemon shark jumps over the lazy reef\n"))
+        .collect()
+}
+
+fn assert_golden_content<D: BlockDevice>(fs: &mut Filesystem<D>) {
+    assert_eq!(
+        fs.read_file("/hello.txt").unwrap(),
+        "Hello from a golden LemonFS image!\n"
+    );
+    assert_eq!(fs.read_file("/etc/motd").unwrap(), expected_motd());
+    assert_eq!(
+        fs.read_file("/docs/readme.md").unwrap(),
+        "# Golden image\n\nUsed by the format compatibility tests.\n"
+    );
+
+    let motd = fs.stat("/etc/motd").unwrap();
+    assert_eq!(motd.allocated_blocks, 2);
+    assert!(!motd.inline && !motd.compressed);
+    assert!(fs.stat("/bin").unwrap().is_directory);
+
+    let mut listing = String::new();
+    fs.dump_dir("/bin", &mut listing).unwrap();
+    // Two header lines plus `.` and `..`.
+    assert_eq!(
+        listing.lines().count(),
+        4,
+        "/bin should be empty: {listing}"
+    );
+}
+
+fn check_legacy_image(bytes: &[u8], version: u32) {
+    let image = Image::new(bytes);
+
+    assert_eq!(
+        Filesystem::new(image.clone()).err(),
+        Some(Error::UpgradeRequired(version))
+    );
+
+    let mut fs = Filesystem::mount_read_only(image.clone()).unwrap();
+    assert_golden_content(&mut fs);
+    drop(fs);
+    assert_eq!(
+        *image.0.borrow(),
+        bytes,
+        "read-only mount wrote to the image"
+    );
+
+    assert_eq!(Filesystem::upgrade(image.clone()), Ok(version));
+
+    let mut fs = Filesystem::new(image.clone()).unwrap();
+    assert_golden_content(&mut fs);
+    fs.create_file("/etc/new").unwrap();
+    fs.write_to_file("/etc/new", b"written after upgrade")
+        .unwrap();
+    fs.write_to_file("/etc/motd", b"appended\n").unwrap();
+    drop(fs.unmount());
+
+    let fs = Filesystem::new(image).unwrap();
+    assert!(fs.mounted_clean());
+    assert_eq!(fs.read_file("/etc/new").unwrap(), "written after upgrade");
+    assert_eq!(
+        fs.read_file("/etc/motd").unwrap(),
+        expected_motd() + "appended\n"
+    );
+}
+
+#[test]
+fn version_1_image_is_readable_and_upgradable() {
+    check_legacy_image(V1_IMAGE, 1);
+}
+
+#[test]
+fn version_2_image_is_readable_and_upgradable() {
+    check_legacy_image(V2_IMAGE, 2);
+}
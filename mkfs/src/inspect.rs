@@ -0,0 +1,431 @@
+//! The read-only inspection subcommands, see `InspectQuery`.
+
+use std::fmt::{self, Write as _};
+
+use filesystem::{Filesystem, INodeIndex, Metadata};
+
+use crate::{BuildError, FileBlockDevice, InspectConfig, InspectQuery, child_path};
+
+/// Runs the inspection query of `config`, returning what to print.
+///
+/// `superblock` and `inode` read the image without mounting it, so they
+/// also work on images the kernel refuses. The other queries mount the image
+/// read-only.
+pub fn inspect_image(config: &InspectConfig) -> Result<Vec<u8>, BuildError> {
+    let fs_error = |path: &str| {
+        let path = path.to_string();
+        move |error| BuildError::new(format!("{path}: {error}"))
+    };
+
+    let text = match &config.query {
+        InspectQuery::Ls(path) => {
+            let filesystem = mount(config)?;
+            let metadata = filesystem.stat(path).map_err(fs_error(path))?;
+            let entries = if metadata.is_directory {
+                let mut entries = Vec::new();
+                for name in filesystem.read_dir(path).map_err(fs_error(path))? {
+                    let child = child_path(path.trim_end_matches('/'), &name);
+                    let metadata = filesystem.stat(&child).map_err(fs_error(&child))?;
+                    entries.push((name, metadata));
+                }
+                entries
+            } else {
+                let name = path.rsplit('/').next().unwrap_or(path);
+                vec![(name.to_string(), metadata)]
+            };
+            ls(&entries, config.json)
+        }
+        InspectQuery::Cat(path) => {
+            let contents = mount(config)?.read_bytes(path).map_err(fs_error(path))?;
+            if !config.json {
+                return Ok(contents);
+            }
+            cat_json(path, &contents)
+        }
+        InspectQuery::Stat(path) => {
+            let filesystem = mount(config)?;
+            let metadata = filesystem.stat(path).map_err(fs_error(path))?;
+            let xattrs = filesystem.list_xattr(path).map_err(fs_error(path))?;
+            stat(path, &metadata, &xattrs, config.json)
+        }
+        InspectQuery::Tree(path) => {
+            let filesystem = mount(config)?;
+            let entries: Vec<_> = filesystem.walk(path).map_err(fs_error(path))?.collect();
+            tree(&entries, config.json)
+        }
+        InspectQuery::SuperBlock => {
+            let mut device = FileBlockDevice::open_read_only(&config.image)?;
+            let info = Filesystem::inspect_superblock(&mut device)
+                .map_err(|error| BuildError::new(format!("read superblock: {error}")))?;
+            superblock(&info, config.json)
+        }
+        InspectQuery::INode(index) => {
+            let mut device = FileBlockDevice::open_read_only(&config.image)?;
+            let info = Filesystem::inspect_inode(&mut device, INodeIndex::new(*index))
+                .map_err(|error| BuildError::new(format!("read inode {index}: {error}")))?;
+            inode(&info, config.json)
+        }
+    };
+
+    Ok(text.into_bytes())
+}
+
+fn mount(config: &InspectConfig) -> Result<Filesystem<FileBlockDevice>, BuildError> {
+    let device = FileBlockDevice::open_read_only(&config.image)?;
+    Filesystem::mount_read_only(device)
+        .map_err(|error| BuildError::new(format!("mount image: {error}")))
+}
+
+fn ls(entries: &[(String, Metadata)], json: bool) -> String {
+    if json {
+        let entries = entries
+            .iter()
+            .map(|(name, metadata)| {
+                Json::Object(vec![
+                    ("name", Json::String(name.clone())),
+                    ("inode", Json::Number(metadata.inode.inner().into())),
+                    ("directory", Json::Bool(metadata.is_directory)),
+                    ("size", Json::Number(metadata.size as u64)),
+                ])
+            })
+            .collect();
+        return format!("{}\n", Json::Array(entries));
+    }
+
+    let mut out = String::new();
+    for (name, metadata) in entries {
+        let (kind, suffix) = if metadata.is_directory {
+            ('d', "/")
+        } else {
+            ('-', "")
+        };
+        let _ = writeln!(
+            out,
+            "{kind} {:>5} {:>8}  {name}{suffix}",
+            metadata.inode.inner(),
+            metadata.size
+        );
+    }
+    out
+}
+
+fn cat_json(path: &str, contents: &[u8]) -> String {
+    let content = match std::str::from_utf8(contents) {
+        Ok(text) => ("text", Json::String(text.into())),
+        Err(_) => ("hex", Json::String(hex(contents, ""))),
+    };
+    let object = Json::Object(vec![
+        ("path", Json::String(path.into())),
+        ("size", Json::Number(contents.len() as u64)),
+        content,
+    ]);
+    format!("{object}\n")
+}
+
+fn stat(path: &str, metadata: &Metadata, xattrs: &[String], json: bool) -> String {
+    if json {
+        let object = Json::Object(vec![
+            ("path", Json::String(path.into())),
+            ("inode", Json::Number(metadata.inode.inner().into())),
+            ("directory", Json::Bool(metadata.is_directory)),
+            ("size", Json::Number(metadata.size as u64)),
+            (
+                "allocated_blocks",
+                Json::Number(metadata.allocated_blocks as u64),
+            ),
+            ("inline", Json::Bool(metadata.inline)),
+            ("compressed", Json::Bool(metadata.compressed)),
+            (
+                "xattrs",
+                Json::Array(xattrs.iter().cloned().map(Json::String).collect()),
+            ),
+        ]);
+        return format!("{object}\n");
+    }
+
+    let kind = if metadata.is_directory {
+        "directory"
+    } else {
+        "file"
+    };
+    let mut out = String::new();
+    let _ = writeln!(out, "path        {path}");
+    let _ = writeln!(out, "inode       {}", metadata.inode.inner());
+    let _ = writeln!(out, "type        {kind}");
+    let _ = writeln!(out, "size        {}", metadata.size);
+    let _ = writeln!(out, "blocks      {}", metadata.allocated_blocks);
+    let _ = writeln!(out, "inline      {}", yes_no(metadata.inline));
+    let _ = writeln!(out, "compressed  {}", yes_no(metadata.compressed));
+    let _ = writeln!(out, "xattrs      {}", list_or_none(xattrs));
+    out
+}
+
+fn tree(entries: &[filesystem::WalkEntry], json: bool) -> String {
+    if json {
+        let entries = entries
+            .iter()
+            .map(|entry| {
+                Json::Object(vec![
+                    ("path", Json::String(entry.path.clone())),
+                    ("depth", Json::Number(entry.depth as u64)),
+                    ("inode", Json::Number(entry.metadata.inode.inner().into())),
+                    ("directory", Json::Bool(entry.metadata.is_directory)),
+                    ("size", Json::Number(entry.metadata.size as u64)),
+                ])
+            })
+            .collect();
+        return format!("{}\n", Json::Array(entries));
+    }
+
+    let mut out = String::new();
+    for entry in entries {
+        let name = if entry.depth == 0 {
+            &entry.path
+        } else {
+            entry.name()
+        };
+        let suffix = if entry.metadata.is_directory && name != "/" {
+            "/"
+        } else {
+            ""
+        };
+        let _ = writeln!(
+            out,
+            "{:indent$}{name}{suffix}",
+            "",
+            indent = entry.depth * 2
+        );
+    }
+    out
+}
+
+fn superblock(info: &filesystem::SuperBlockInfo, json: bool) -> String {
+    let problem = info.problem.as_ref().map(ToString::to_string);
+
+    if json {
+        let regions = info
+            .regions
+            .iter()
+            .map(|region| {
+                Json::Object(vec![
+                    ("name", Json::String(region.name.into())),
+                    ("start", Json::Number(region.start.into())),
+                    ("blocks", Json::Number(region.blocks.into())),
+                ])
+            })
+            .collect();
+        let object = Json::Object(vec![
+            ("magic", Json::Number(info.magic)),
+            ("version", Json::Number(info.version.into())),
+            ("block_size", Json::Number(info.block_size.into())),
+            ("total_blocks", Json::Number(info.total_blocks.into())),
+            ("inode_count", Json::Number(info.inode_count.into())),
+            ("inode_size", Json::Number(info.inode_size as u64)),
+            ("clean", Json::Bool(info.clean)),
+            ("compat", Json::Number(info.compat.into())),
+            ("ro_compat", Json::Number(info.ro_compat.into())),
+            ("incompat", Json::Number(info.incompat.into())),
+            ("regions", Json::Array(regions)),
+            ("problem", problem.map_or(Json::Null, Json::String)),
+        ]);
+        return format!("{object}\n");
+    }
+
+    let state = if info.clean { "clean" } else { "dirty" };
+    let mut out = String::new();
+    let _ = writeln!(out, "magic         {:#x}", info.magic);
+    let _ = writeln!(out, "version       {}", info.version);
+    let _ = writeln!(out, "block size    {}", info.block_size);
+    let _ = writeln!(out, "total blocks  {}", info.total_blocks);
+    let _ = writeln!(
+        out,
+        "inodes        {} of {} bytes",
+        info.inode_count, info.inode_size
+    );
+    let _ = writeln!(out, "state         {state}");
+    let _ = writeln!(
+        out,
+        "features      compat {:#x}, ro_compat {:#x}, incompat {:#x}",
+        info.compat, info.ro_compat, info.incompat
+    );
+    let _ = writeln!(
+        out,
+        "problem       {}",
+        problem.as_deref().unwrap_or("none")
+    );
+    let _ = writeln!(out, "\nregion          start  blocks");
+    for region in &info.regions {
+        let _ = writeln!(
+            out,
+            "{:<12} {:>8} {:>7}",
+            region.name, region.start, region.blocks
+        );
+    }
+    out
+}
+
+fn inode(info: &filesystem::INodeInfo, json: bool) -> String {
+    if json {
+        let object = Json::Object(vec![
+            ("index", Json::Number(info.index.inner().into())),
+            ("allocated", Json::Bool(info.allocated)),
+            ("directory", Json::Bool(info.is_directory)),
+            ("size", Json::Number(info.size.into())),
+            ("inline", Json::Bool(info.inline)),
+            ("compressed", Json::Bool(info.compressed)),
+            (
+                "blocks",
+                Json::Array(
+                    info.blocks
+                        .iter()
+                        .map(|block| Json::Number((*block).into()))
+                        .collect(),
+                ),
+            ),
+            ("xattr", Json::Number(info.xattr.into())),
+            ("raw", Json::String(hex(&info.raw, ""))),
+        ]);
+        return format!("{object}\n");
+    }
+
+    let kind = if info.is_directory {
+        "directory"
+    } else {
+        "file"
+    };
+    let flags: Vec<String> = [("inline", info.inline), ("compressed", info.compressed)]
+        .into_iter()
+        .filter(|(_, set)| *set)
+        .map(|(flag, _)| flag.to_string())
+        .collect();
+    let blocks = if info.inline {
+        "none, the content is inline".to_string()
+    } else {
+        let blocks: Vec<_> = info
+            .blocks
+            .iter()
+            .map(|block| match block {
+                0 => "-".to_string(),
+                block => block.to_string(),
+            })
+            .collect();
+        blocks.join(" ")
+    };
+
+    let mut out = String::new();
+    let _ = writeln!(out, "inode         {}", info.index.inner());
+    let _ = writeln!(out, "allocated     {}", yes_no(info.allocated));
+    let _ = writeln!(out, "type          {kind}");
+    let _ = writeln!(out, "size          {}", info.size);
+    let _ = writeln!(out, "flags         {}", list_or_none(&flags));
+    let _ = writeln!(
+        out,
+        "xattr block   {}",
+        match info.xattr {
+            0 => "-".to_string(),
+            block => block.to_string(),
+        }
+    );
+    let _ = writeln!(out, "blocks        {blocks}");
+    let _ = writeln!(out, "raw");
+    for (line, chunk) in info.raw.chunks(16).enumerate() {
+        let _ = writeln!(out, "  {:04x}  {}", line * 16, hex(chunk, " "));
+    }
+    out
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value { "yes" } else { "no" }
+}
+
+fn list_or_none(items: &[String]) -> String {
+    if items.is_empty() {
+        "none".into()
+    } else {
+        items.join(", ")
+    }
+}
+
+fn hex(bytes: &[u8], separator: &str) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+/// Just enough JSON for the `--json` output, written without whitespace.
+enum Json {
+    Null,
+    Bool(bool),
+    Number(u64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(&'static str, Json)>),
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Null => formatter.write_str("null"),
+            Self::Bool(value) => write!(formatter, "{value}"),
+            Self::Number(value) => write!(formatter, "{value}"),
+            Self::String(value) => write_json_string(formatter, value),
+            Self::Array(items) => {
+                formatter.write_char('[')?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        formatter.write_char(',')?;
+                    }
+                    write!(formatter, "{item}")?;
+                }
+                formatter.write_char(']')
+            }
+            Self::Object(fields) => {
+                formatter.write_char('{')?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        formatter.write_char(',')?;
+                    }
+                    write_json_string(formatter, key)?;
+                    write!(formatter, ":{value}")?;
+                }
+                formatter.write_char('}')
+            }
+        }
+    }
+}
+
+fn write_json_string(formatter: &mut fmt::Formatter<'_>, value: &str) -> fmt::Result {
+    formatter.write_char('"')?;
+    for c in value.chars() {
+        match c {
+            '"' => formatter.write_str("\\\"")?,
+            '\\' => formatter.write_str("\\\\")?,
+            '\n' => formatter.write_str("\\n")?,
+            '\r' => formatter.write_str("\\r")?,
+            '\t' => formatter.write_str("\\t")?,
+            c if c.is_control() => write!(formatter, "\\u{:04x}", c as u32)?,
+            c => formatter.write_char(c)?,
+        }
+    }
+    formatter.write_char('"')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_escapes_strings() {
+        let object = Json::Object(vec![
+            ("name", Json::String("a \"b\"\n\\ \u{1}".into())),
+            ("list", Json::Array(vec![Json::Number(1), Json::Null])),
+            ("ok", Json::Bool(true)),
+        ]);
+        assert_eq!(
+            object.to_string(),
+            r#"{"name":"a \"b\"\n\\ \u0001","list":[1,null],"ok":true}"#
+        );
+    }
+}
@@ -1,59 +1,349 @@
+use std::collections::HashSet;
 use std::ffi::{OsStr, OsString};
 use std::fmt;
 use std::fs::{self, File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 
-use filesystem::{BLOCK_SIZE, BlockDevice, BlockIndex, Filesystem};
+use digest::Sha256;
+use ext2::{Ext2, FileKind};
+use filesystem::{
+    BLOCK_SIZE, BlockDevice, BlockIndex, Filesystem, SnapshotInfo, WalkEntry, glob_match,
+};
+use manifest::Manifest;
+
+mod digest;
+mod inspect;
+mod manifest;
+
+pub use inspect::inspect_image;
 
 pub const DEFAULT_SOURCE: &str = "rootfs";
 pub const DEFAULT_OUTPUT: &str = "lemonfs.img";
 pub const DEFAULT_BLOCKS: usize = 16 * 1024 * 1024 / BLOCK_SIZE;
 pub const MAX_FILE_SIZE: u64 = 16 * BLOCK_SIZE as u64;
 
-pub const USAGE: &str = "Usage: mkfs [OPTIONS]
+/// Files below this size are never compressed: they span at most two blocks
+/// so compressing them saves little and costs a decompression on every read.
+pub const COMPRESS_THRESHOLD: usize = 2 * BLOCK_SIZE;
 
-Build a LemonFS image from a host directory.
+pub const USAGE: &str = "Usage: mkfs [OPTIONS]
+       mkfs --manifest <FILE> [--output <FILE>] [--blocks <COUNT>] [--compress]
+       mkfs resize <IMAGE> [--blocks <COUNT>]
+       mkfs upgrade <IMAGE>
+       mkfs snapshot <IMAGE> create|delete|rollback <NAME>
+       mkfs snapshot <IMAGE> list
+       mkfs list <IMAGE> [PATTERN]
+       mkfs convert <EXT2_IMAGE> [--output <FILE>] [--blocks <COUNT>] [--compress]
+       mkfs extract [--image <IMAGE>] --dest <DIR>
+       mkfs ls|tree <IMAGE> [PATH] [--json]
+       mkfs cat|stat <IMAGE> <PATH> [--json]
+       mkfs superblock <IMAGE> [--json]
+       mkfs inode <IMAGE> <INDEX> [--json]
+       mkfs add [--image <IMAGE>] [--compress] <HOST_PATH> <IMAGE_PATH>
+       mkfs rm [--image <IMAGE>] <IMAGE_PATH>
+       mkfs mkdir [--image <IMAGE>] [-p] <IMAGE_PATH>
+       mkfs sync [--image <IMAGE>] [--source <DIR>] [--compress]
+       mkfs verify [--image <IMAGE>] [--source <DIR>]
+
+Build a LemonFS image from a host directory, or grow an existing image with
+`resize`. Without `--blocks`, `resize` grows the filesystem to the current size
+of the image file, e.g. after `truncate -s`. `upgrade` converts an image of an
+older format version in place so the kernel can mount it read-write.
+`snapshot` manages named copy-on-write snapshots of an image, `rollback`
+restores every file to the state of a snapshot. `list` prints every entry of
+an image, or only the paths matching a glob like `/etc/*` or `**/*.txt`.
+`convert` builds a LemonFS image from the files and directories of an ext2
+image, copying hard linked files and skipping symlinks and `/lost+found`.
+`extract` recreates the files and directories of an image (default:
+lemonfs.img) below a host directory. Existing host files are never
+overwritten, existing directories are merged.
+`ls`, `cat`, `stat` and `tree` inspect an image without booting the kernel.
+`superblock` decodes the superblock and layout and `inode` dumps a raw inode
+record with its block list, both even for images which don't mount. `--json`
+prints the result as JSON instead.
+`add`, `rm`, `mkdir` and `sync` change an existing image (default:
+lemonfs.img) in place. `add` copies a host file or directory tree into the
+image, replacing files whose content differs. `rm` removes a file, LemonFS
+can't remove directories. `sync` does the same as `add` for the whole source
+directory (default: rootfs) and keeps entries which only exist in the image.
+`verify` compares an image (default: lemonfs.img) with a source directory
+(default: rootfs) and lists every difference, exiting with 1 if there are any.
 
 Options:
     --source <DIR>    Source directory (default: rootfs)
+    --manifest <FILE> Build from a manifest instead of a source directory
     --output <FILE>   Output image (default: lemonfs.img)
     --blocks <COUNT>  Image size in 512-byte blocks (default: 32768)
+    --compress        Compress files of at least 1024 bytes when it saves space
+    --source-date-epoch <SECONDS>
+                      Latest timestamp to store; LemonFS has none to clamp yet
+    --print-digest    Print the SHA-256 digest of the image
     -h, --help        Show this help
 
 The source directory's children become entries in the image root. LemonFS file
 names are limited to 24 UTF-8 bytes and files are limited to 8192 bytes.
-Symlinks and other non-regular entries are skipped.";
+Blocks containing only zeros are stored as unallocated holes and files of at
+most 64 bytes are stored inline in their inode. Extended attributes in the
+`user.*` namespace are imported. Symlinks and other non-regular entries are
+skipped. Identical inputs give byte-identical images: entries are imported in
+name order and unused blocks are zeroed.
+
+A manifest lists the entries of the image as TOML: `[[file]]` tables with a
+`destination` and either a host `source` or inline `content`, and
+`[[directory]]` tables with a `destination` and an optional host `source` tree.
+Both take `attributes = { \"user.name\" = \"value\" }`. Sources are relative to
+the manifest, missing parent directories are created. The manifest is checked
+before formatting and errors name its line; symlinks and modes are rejected
+since LemonFS has neither.";
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Config {
-    pub source: PathBuf,
+    pub source: Source,
     pub output: PathBuf,
     pub total_blocks: usize,
+    pub compress: bool,
+
+    /// Upper bound for timestamps stored in the image, as in the
+    /// `SOURCE_DATE_EPOCH` convention. LemonFS doesn't store any timestamps
+    /// yet, so the value is accepted but has nothing to clamp.
+    pub source_date_epoch: Option<u64>,
+
+    /// Print the SHA-256 digest of the image after building it.
+    pub print_digest: bool,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
-            source: DEFAULT_SOURCE.into(),
+            source: Source::Directory(DEFAULT_SOURCE.into()),
             output: DEFAULT_OUTPUT.into(),
             total_blocks: DEFAULT_BLOCKS,
+            compress: false,
+            source_date_epoch: None,
+            print_digest: false,
+        }
+    }
+}
+
+/// What a new image is built from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// A host directory whose children become the image root.
+    Directory(PathBuf),
+
+    /// A manifest listing every entry, see `--manifest`.
+    Manifest(PathBuf),
+}
+
+impl Source {
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::Directory(path) | Self::Manifest(path) => path,
         }
     }
 }
 
+/// Options of the `resize` subcommand.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResizeConfig {
+    pub image: PathBuf,
+
+    /// New size in blocks, `None` grows to the size of the image file.
+    pub total_blocks: Option<usize>,
+}
+
+/// Options of the `snapshot` subcommand.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotConfig {
+    pub image: PathBuf,
+    pub action: SnapshotAction,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotAction {
+    Create(String),
+    List,
+    Delete(String),
+    Rollback(String),
+}
+
+/// Options of the `list` subcommand.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListConfig {
+    pub image: PathBuf,
+
+    /// Glob the paths have to match, `None` lists everything.
+    pub pattern: Option<String>,
+}
+
+/// Options of the `convert` subcommand.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConvertConfig {
+    /// The ext2 image to convert.
+    pub image: PathBuf,
+    pub output: PathBuf,
+    pub total_blocks: usize,
+    pub compress: bool,
+}
+
+/// Options of the `extract` subcommand.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtractConfig {
+    pub image: PathBuf,
+
+    /// Host directory the root of the image is extracted into, created if
+    /// missing.
+    pub dest: PathBuf,
+}
+
+/// Options of the `verify` subcommand.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyConfig {
+    pub image: PathBuf,
+
+    /// Host directory the image root is compared with.
+    pub source: PathBuf,
+}
+
+/// Options of the read-only inspection subcommands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InspectConfig {
+    pub image: PathBuf,
+    pub query: InspectQuery,
+
+    /// Print JSON instead of human-readable text.
+    pub json: bool,
+}
+
+/// What to inspect, one variant per subcommand.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InspectQuery {
+    Ls(String),
+    Cat(String),
+    Stat(String),
+    Tree(String),
+    SuperBlock,
+    INode(u32),
+}
+
+/// Options of the subcommands changing an image in place.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpdateConfig {
+    pub image: PathBuf,
+    pub action: UpdateAction,
+
+    /// Compress written files, see `Config::compress`.
+    pub compress: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpdateAction {
+    /// Copies a host file or directory tree to a path in the image.
+    Add {
+        host: PathBuf,
+        path: String,
+    },
+    Remove(String),
+
+    /// Creates a directory, with `parents` also the missing ones above it.
+    Mkdir {
+        path: String,
+        parents: bool,
+    },
+
+    /// Copies the children of a host directory into the image root.
+    Sync(PathBuf),
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Command {
     Build(Config),
+    Resize(ResizeConfig),
+    Upgrade(PathBuf),
+    Snapshot(SnapshotConfig),
+    List(ListConfig),
+    Convert(ConvertConfig),
+    Extract(ExtractConfig),
+    Inspect(InspectConfig),
+    Update(UpdateConfig),
+    Verify(VerifyConfig),
     Help,
 }
 
 impl Command {
     pub fn parse(args: impl IntoIterator<Item = OsString>) -> Result<Self, BuildError> {
+        let mut args = args.into_iter().peekable();
+
+        if args.peek().is_some_and(|argument| argument == "resize") {
+            args.next();
+            return Self::parse_resize(args);
+        }
+
+        if args.peek().is_some_and(|argument| argument == "upgrade") {
+            args.next();
+            return Self::parse_upgrade(args);
+        }
+
+        if args.peek().is_some_and(|argument| argument == "snapshot") {
+            args.next();
+            return Self::parse_snapshot(args);
+        }
+
+        if args.peek().is_some_and(|argument| argument == "list") {
+            args.next();
+            return Self::parse_list(args);
+        }
+
+        if args.peek().is_some_and(|argument| argument == "convert") {
+            args.next();
+            return Self::parse_convert(args);
+        }
+
+        if args.peek().is_some_and(|argument| argument == "extract") {
+            args.next();
+            return Self::parse_extract(args);
+        }
+
+        if args.peek().is_some_and(|argument| argument == "verify") {
+            args.next();
+            return Self::parse_verify(args);
+        }
+
+        let update = ["add", "rm", "mkdir", "sync"];
+        if let Some(action) = args
+            .peek()
+            .and_then(|argument| argument.to_str())
+            .filter(|argument| update.contains(argument))
+            .map(String::from)
+        {
+            args.next();
+            return Self::parse_update(&action, args);
+        }
+
+        let inspect = ["ls", "cat", "stat", "tree", "superblock", "inode"];
+        if let Some(query) = args
+            .peek()
+            .and_then(|argument| argument.to_str())
+            .filter(|argument| inspect.contains(argument))
+            .map(String::from)
+        {
+            args.next();
+            return Self::parse_inspect(&query, args);
+        }
+
+        Self::parse_build(args)
+    }
+
+    fn parse_build(args: impl Iterator<Item = OsString>) -> Result<Self, BuildError> {
         let mut config = Config::default();
         let mut source_seen = false;
+        let mut manifest_seen = false;
         let mut output_seen = false;
         let mut blocks_seen = false;
+        let mut compress_seen = false;
+        let mut epoch_seen = false;
+        let mut digest_seen = false;
         let mut args = args.into_iter();
 
         while let Some(argument) = args.next() {
@@ -61,7 +351,11 @@ impl Command {
                 Some("-h" | "--help") => return Ok(Self::Help),
                 Some("--source") => {
                     reject_duplicate(&mut source_seen, "--source")?;
-                    config.source = next_value(&mut args, "--source")?.into();
+                    config.source = Source::Directory(next_value(&mut args, "--source")?.into());
+                }
+                Some("--manifest") => {
+                    reject_duplicate(&mut manifest_seen, "--manifest")?;
+                    config.source = Source::Manifest(next_value(&mut args, "--manifest")?.into());
                 }
                 Some("--output") => {
                     reject_duplicate(&mut output_seen, "--output")?;
@@ -69,16 +363,25 @@ impl Command {
                 }
                 Some("--blocks") => {
                     reject_duplicate(&mut blocks_seen, "--blocks")?;
-                    let value = next_value(&mut args, "--blocks")?;
-                    let value = value
-                        .to_str()
-                        .ok_or_else(|| BuildError::new("--blocks must be valid UTF-8"))?;
-                    config.total_blocks = value
-                        .parse()
-                        .map_err(|_| BuildError::new(format!("invalid block count {value:?}")))?;
-                    if config.total_blocks == 0 {
-                        return Err(BuildError::new("--blocks must be greater than zero"));
-                    }
+                    config.total_blocks = parse_blocks(next_value(&mut args, "--blocks")?)?;
+                }
+                Some("--compress") => {
+                    reject_duplicate(&mut compress_seen, "--compress")?;
+                    config.compress = true;
+                }
+                Some("--source-date-epoch") => {
+                    reject_duplicate(&mut epoch_seen, "--source-date-epoch")?;
+                    let value = next_value(&mut args, "--source-date-epoch")?;
+                    let epoch = value.to_str().and_then(|value| value.parse().ok());
+                    config.source_date_epoch = Some(epoch.ok_or_else(|| {
+                        BuildError::new(format!(
+                            "--source-date-epoch must be seconds since 1970, got {value:?}"
+                        ))
+                    })?);
+                }
+                Some("--print-digest") => {
+                    reject_duplicate(&mut digest_seen, "--print-digest")?;
+                    config.print_digest = true;
                 }
                 Some(argument) => {
                     return Err(BuildError::new(format!("unknown argument {argument:?}")));
@@ -87,8 +390,384 @@ impl Command {
             }
         }
 
+        if source_seen && manifest_seen {
+            return Err(BuildError::new(
+                "--source and --manifest can't be used together",
+            ));
+        }
+
         Ok(Self::Build(config))
     }
+
+    fn parse_resize(mut args: impl Iterator<Item = OsString>) -> Result<Self, BuildError> {
+        let mut image = None;
+        let mut total_blocks = None;
+
+        while let Some(argument) = args.next() {
+            match argument.to_str() {
+                Some("-h" | "--help") => return Ok(Self::Help),
+                Some("--blocks") => {
+                    if total_blocks.is_some() {
+                        return Err(BuildError::new("--blocks may only be specified once"));
+                    }
+                    total_blocks = Some(parse_blocks(next_value(&mut args, "--blocks")?)?);
+                }
+                Some(argument) if argument.starts_with('-') => {
+                    return Err(BuildError::new(format!("unknown argument {argument:?}")));
+                }
+                _ if image.is_none() => image = Some(PathBuf::from(argument)),
+                _ => return Err(BuildError::new("resize takes exactly one image")),
+            }
+        }
+
+        Ok(Self::Resize(ResizeConfig {
+            image: image.ok_or_else(|| BuildError::new("resize requires an image"))?,
+            total_blocks,
+        }))
+    }
+
+    fn parse_upgrade(args: impl Iterator<Item = OsString>) -> Result<Self, BuildError> {
+        let mut image = None;
+
+        for argument in args {
+            match argument.to_str() {
+                Some("-h" | "--help") => return Ok(Self::Help),
+                Some(argument) if argument.starts_with('-') => {
+                    return Err(BuildError::new(format!("unknown argument {argument:?}")));
+                }
+                _ if image.is_none() => image = Some(PathBuf::from(argument)),
+                _ => return Err(BuildError::new("upgrade takes exactly one image")),
+            }
+        }
+
+        Ok(Self::Upgrade(image.ok_or_else(|| {
+            BuildError::new("upgrade requires an image")
+        })?))
+    }
+
+    fn parse_snapshot(args: impl Iterator<Item = OsString>) -> Result<Self, BuildError> {
+        let mut positional = Vec::new();
+
+        for argument in args {
+            match argument.to_str() {
+                Some("-h" | "--help") => return Ok(Self::Help),
+                Some(argument) if argument.starts_with('-') => {
+                    return Err(BuildError::new(format!("unknown argument {argument:?}")));
+                }
+                _ => positional.push(argument),
+            }
+        }
+
+        let mut positional = positional.into_iter();
+        let image = positional
+            .next()
+            .ok_or_else(|| BuildError::new("snapshot requires an image"))?;
+        let action = positional
+            .next()
+            .ok_or_else(|| BuildError::new("snapshot requires an action"))?;
+        let name = positional
+            .next()
+            .map(|name| {
+                name.into_string()
+                    .map_err(|_| BuildError::new("snapshot names must be valid UTF-8"))
+            })
+            .transpose()?;
+        let required = || {
+            name.clone()
+                .ok_or_else(|| BuildError::new("snapshot requires a name"))
+        };
+
+        let action = match action.to_str() {
+            Some("create") => SnapshotAction::Create(required()?),
+            Some("delete") => SnapshotAction::Delete(required()?),
+            Some("rollback") => SnapshotAction::Rollback(required()?),
+            Some("list") if name.is_none() => SnapshotAction::List,
+            Some("list") => return Err(BuildError::new("list takes no name")),
+            _ => {
+                return Err(BuildError::new(format!(
+                    "unknown snapshot action {action:?}"
+                )));
+            }
+        };
+
+        if positional.next().is_some() {
+            return Err(BuildError::new("too many arguments for snapshot"));
+        }
+
+        Ok(Self::Snapshot(SnapshotConfig {
+            image: image.into(),
+            action,
+        }))
+    }
+
+    fn parse_list(args: impl Iterator<Item = OsString>) -> Result<Self, BuildError> {
+        let mut image = None;
+        let mut pattern = None;
+
+        for argument in args {
+            match argument.to_str() {
+                Some("-h" | "--help") => return Ok(Self::Help),
+                Some(argument) if argument.starts_with('-') => {
+                    return Err(BuildError::new(format!("unknown argument {argument:?}")));
+                }
+                _ if image.is_none() => image = Some(PathBuf::from(argument)),
+                Some(argument) if pattern.is_none() => pattern = Some(argument.to_string()),
+                None => return Err(BuildError::new("patterns must be valid UTF-8")),
+                _ => return Err(BuildError::new("list takes an image and one pattern")),
+            }
+        }
+
+        Ok(Self::List(ListConfig {
+            image: image.ok_or_else(|| BuildError::new("list requires an image"))?,
+            pattern,
+        }))
+    }
+
+    fn parse_convert(mut args: impl Iterator<Item = OsString>) -> Result<Self, BuildError> {
+        let mut image = None;
+        let mut output = None;
+        let mut total_blocks = None;
+        let mut compress_seen = false;
+
+        while let Some(argument) = args.next() {
+            match argument.to_str() {
+                Some("-h" | "--help") => return Ok(Self::Help),
+                Some("--output") => {
+                    if output.is_some() {
+                        return Err(BuildError::new("--output may only be specified once"));
+                    }
+                    output = Some(next_value(&mut args, "--output")?.into());
+                }
+                Some("--blocks") => {
+                    if total_blocks.is_some() {
+                        return Err(BuildError::new("--blocks may only be specified once"));
+                    }
+                    total_blocks = Some(parse_blocks(next_value(&mut args, "--blocks")?)?);
+                }
+                Some("--compress") => reject_duplicate(&mut compress_seen, "--compress")?,
+                Some(argument) if argument.starts_with('-') => {
+                    return Err(BuildError::new(format!("unknown argument {argument:?}")));
+                }
+                _ if image.is_none() => image = Some(PathBuf::from(argument)),
+                _ => return Err(BuildError::new("convert takes exactly one image")),
+            }
+        }
+
+        Ok(Self::Convert(ConvertConfig {
+            image: image.ok_or_else(|| BuildError::new("convert requires an ext2 image"))?,
+            output: output.unwrap_or_else(|| DEFAULT_OUTPUT.into()),
+            total_blocks: total_blocks.unwrap_or(DEFAULT_BLOCKS),
+            compress: compress_seen,
+        }))
+    }
+
+    fn parse_extract(mut args: impl Iterator<Item = OsString>) -> Result<Self, BuildError> {
+        let mut image = None;
+        let mut dest = None;
+
+        while let Some(argument) = args.next() {
+            match argument.to_str() {
+                Some("-h" | "--help") => return Ok(Self::Help),
+                Some("--image") => {
+                    if image.is_some() {
+                        return Err(BuildError::new("--image may only be specified once"));
+                    }
+                    image = Some(next_value(&mut args, "--image")?.into());
+                }
+                Some("--dest") => {
+                    if dest.is_some() {
+                        return Err(BuildError::new("--dest may only be specified once"));
+                    }
+                    dest = Some(next_value(&mut args, "--dest")?.into());
+                }
+                _ => return Err(BuildError::new(format!("unknown argument {argument:?}"))),
+            }
+        }
+
+        Ok(Self::Extract(ExtractConfig {
+            image: image.unwrap_or_else(|| DEFAULT_OUTPUT.into()),
+            dest: dest.ok_or_else(|| BuildError::new("extract requires --dest"))?,
+        }))
+    }
+
+    fn parse_verify(mut args: impl Iterator<Item = OsString>) -> Result<Self, BuildError> {
+        let mut image = None;
+        let mut source = None;
+
+        while let Some(argument) = args.next() {
+            match argument.to_str() {
+                Some("-h" | "--help") => return Ok(Self::Help),
+                Some("--image") => {
+                    if image.is_some() {
+                        return Err(BuildError::new("--image may only be specified once"));
+                    }
+                    image = Some(next_value(&mut args, "--image")?.into());
+                }
+                Some("--source") => {
+                    if source.is_some() {
+                        return Err(BuildError::new("--source may only be specified once"));
+                    }
+                    source = Some(next_value(&mut args, "--source")?.into());
+                }
+                _ => return Err(BuildError::new(format!("unknown argument {argument:?}"))),
+            }
+        }
+
+        Ok(Self::Verify(VerifyConfig {
+            image: image.unwrap_or_else(|| DEFAULT_OUTPUT.into()),
+            source: source.unwrap_or_else(|| DEFAULT_SOURCE.into()),
+        }))
+    }
+
+    fn parse_inspect(
+        query: &str,
+        args: impl Iterator<Item = OsString>,
+    ) -> Result<Self, BuildError> {
+        let mut positional = Vec::new();
+        let mut json = false;
+
+        for argument in args {
+            match argument.to_str() {
+                Some("-h" | "--help") => return Ok(Self::Help),
+                Some("--json") => reject_duplicate(&mut json, "--json")?,
+                Some(argument) if argument.starts_with('-') => {
+                    return Err(BuildError::new(format!("unknown argument {argument:?}")));
+                }
+                _ => positional.push(argument),
+            }
+        }
+
+        let mut positional = positional.into_iter();
+        let image = positional
+            .next()
+            .ok_or_else(|| BuildError::new(format!("{query} requires an image")))?;
+        let mut operand = |name: &str| {
+            positional
+                .next()
+                .map(|operand| {
+                    operand
+                        .into_string()
+                        .map_err(|_| BuildError::new(format!("{name} must be valid UTF-8")))
+                })
+                .transpose()
+        };
+        let required = |operand: Option<String>, name: &str| {
+            operand.ok_or_else(|| BuildError::new(format!("{query} requires {name}")))
+        };
+
+        let query = match query {
+            "ls" => InspectQuery::Ls(operand("paths")?.unwrap_or_else(|| "/".into())),
+            "tree" => InspectQuery::Tree(operand("paths")?.unwrap_or_else(|| "/".into())),
+            "cat" => InspectQuery::Cat(required(operand("paths")?, "a path")?),
+            "stat" => InspectQuery::Stat(required(operand("paths")?, "a path")?),
+            "superblock" => InspectQuery::SuperBlock,
+            _ => {
+                let index = required(operand("inode indices")?, "an inode index")?;
+                InspectQuery::INode(
+                    index
+                        .parse()
+                        .map_err(|_| BuildError::new(format!("invalid inode index {index:?}")))?,
+                )
+            }
+        };
+
+        if positional.next().is_some() {
+            return Err(BuildError::new("too many arguments"));
+        }
+
+        Ok(Self::Inspect(InspectConfig {
+            image: image.into(),
+            query,
+            json,
+        }))
+    }
+
+    fn parse_update(
+        action: &str,
+        mut args: impl Iterator<Item = OsString>,
+    ) -> Result<Self, BuildError> {
+        let mut image = None;
+        let mut source = None;
+        let mut compress = false;
+        let mut parents = false;
+        let mut positional = Vec::new();
+
+        while let Some(argument) = args.next() {
+            match argument.to_str() {
+                Some("-h" | "--help") => return Ok(Self::Help),
+                Some("--image") => {
+                    if image.is_some() {
+                        return Err(BuildError::new("--image may only be specified once"));
+                    }
+                    image = Some(next_value(&mut args, "--image")?.into());
+                }
+                Some("--source") if action == "sync" => {
+                    if source.is_some() {
+                        return Err(BuildError::new("--source may only be specified once"));
+                    }
+                    source = Some(next_value(&mut args, "--source")?.into());
+                }
+                Some("--compress") if matches!(action, "add" | "sync") => {
+                    reject_duplicate(&mut compress, "--compress")?;
+                }
+                Some("-p") if action == "mkdir" => reject_duplicate(&mut parents, "-p")?,
+                Some(argument) if argument.starts_with('-') => {
+                    return Err(BuildError::new(format!("unknown argument {argument:?}")));
+                }
+                _ => positional.push(argument),
+            }
+        }
+
+        let image_path = |argument: OsString| {
+            argument
+                .into_string()
+                .map_err(|_| BuildError::new("image paths must be valid UTF-8"))
+        };
+        let mut positional = positional.into_iter();
+        let action = match (action, positional.next(), positional.next()) {
+            ("add", Some(host), Some(path)) => UpdateAction::Add {
+                host: host.into(),
+                path: image_path(path)?,
+            },
+            ("rm", Some(path), None) => UpdateAction::Remove(image_path(path)?),
+            ("mkdir", Some(path), None) => UpdateAction::Mkdir {
+                path: image_path(path)?,
+                parents,
+            },
+            ("sync", None, None) => {
+                UpdateAction::Sync(source.unwrap_or_else(|| DEFAULT_SOURCE.into()))
+            }
+            ("add", _, _) => {
+                return Err(BuildError::new("add takes a host path and an image path"));
+            }
+            ("sync", _, _) => return Err(BuildError::new("sync takes no paths, see --source")),
+            (action, _, _) => {
+                return Err(BuildError::new(format!("{action} takes one image path")));
+            }
+        };
+        if positional.next().is_some() {
+            return Err(BuildError::new("too many arguments"));
+        }
+
+        Ok(Self::Update(UpdateConfig {
+            image: image.unwrap_or_else(|| DEFAULT_OUTPUT.into()),
+            action,
+            compress,
+        }))
+    }
+}
+
+fn parse_blocks(value: OsString) -> Result<usize, BuildError> {
+    let value = value
+        .to_str()
+        .ok_or_else(|| BuildError::new("--blocks must be valid UTF-8"))?;
+    let blocks = value
+        .parse()
+        .map_err(|_| BuildError::new(format!("invalid block count {value:?}")))?;
+    if blocks == 0 {
+        return Err(BuildError::new("--blocks must be greater than zero"));
+    }
+    Ok(blocks)
 }
 
 fn reject_duplicate(seen: &mut bool, option: &str) -> Result<(), BuildError> {
@@ -135,6 +814,21 @@ pub struct ImportSummary {
     pub directories: usize,
     pub files: usize,
     pub skipped: Vec<PathBuf>,
+
+    /// Number of all-zero blocks imported as unallocated holes.
+    pub holes: usize,
+
+    /// Number of files small enough to be stored inside their inode.
+    pub inline: usize,
+
+    /// Number of files stored compressed.
+    pub compressed: usize,
+
+    /// Number of imported `user.*` extended attributes.
+    pub xattrs: usize,
+
+    /// Number of hard links imported as a separate copy of their file.
+    pub hard_links: usize,
 }
 
 struct FileBlockDevice {
@@ -167,6 +861,15 @@ impl FileBlockDevice {
             .write(true)
             .open(path)
             .map_err(|error| io_error("open formatted image", path, error))?;
+        Self::from_file(file, path)
+    }
+
+    fn open_read_only(path: &Path) -> Result<Self, BuildError> {
+        let file = File::open(path).map_err(|error| io_error("open image", path, error))?;
+        Self::from_file(file, path)
+    }
+
+    fn from_file(file: File, path: &Path) -> Result<Self, BuildError> {
         let byte_len = file
             .metadata()
             .map_err(|error| io_error("inspect formatted image", path, error))?
@@ -225,24 +928,54 @@ impl Drop for TemporaryImage {
 }
 
 pub fn build_image(config: &Config) -> Result<ImportSummary, BuildError> {
-    let metadata = fs::symlink_metadata(&config.source)
-        .map_err(|error| io_error("inspect source directory", &config.source, error))?;
+    let source = match &config.source {
+        Source::Directory(source) => source,
+        Source::Manifest(path) => {
+            // Validate the whole manifest before anything is formatted.
+            let manifest = Manifest::load(path)?;
+            for source in manifest.source_directories() {
+                validate_output_location(source, &config.output)?;
+            }
+
+            return write_new_image(
+                &config.output,
+                config.total_blocks,
+                |filesystem, summary| manifest.apply(filesystem, config.compress, summary),
+            );
+        }
+    };
+
+    let metadata = fs::symlink_metadata(source)
+        .map_err(|error| io_error("inspect source directory", source, error))?;
     if !metadata.file_type().is_dir() {
         return Err(BuildError::new(format!(
             "source {} is not a directory",
-            config.source.display()
+            source.display()
         )));
     }
-    validate_output_location(&config.source, &config.output)?;
+    validate_output_location(source, &config.output)?;
+
+    write_new_image(
+        &config.output,
+        config.total_blocks,
+        |filesystem, summary| import_directory(filesystem, source, "/", config.compress, summary),
+    )
+}
 
-    if u32::try_from(config.total_blocks).is_err() {
+/// Formats a new image of `total_blocks` beside `output`, fills it with
+/// `import` and only replaces `output` once that succeeded.
+fn write_new_image(
+    output: &Path,
+    total_blocks: usize,
+    import: impl FnOnce(&mut Filesystem<FileBlockDevice>, &mut ImportSummary) -> Result<(), BuildError>,
+) -> Result<ImportSummary, BuildError> {
+    if u32::try_from(total_blocks).is_err() {
         return Err(BuildError::new(format!(
-            "block count {} exceeds the LemonFS limit",
-            config.total_blocks
+            "block count {total_blocks} exceeds the LemonFS limit"
         )));
     }
 
-    let (temporary_path, device) = create_temporary_image(&config.output, config.total_blocks)?;
+    let (temporary_path, device) = create_temporary_image(output, total_blocks)?;
     let mut temporary = TemporaryImage {
         path: temporary_path,
         keep: false,
@@ -255,72 +988,284 @@ pub fn build_image(config: &Config) -> Result<ImportSummary, BuildError> {
         .map_err(|error| BuildError::new(format!("mount new image: {error}")))?;
 
     let mut summary = ImportSummary::default();
-    import_directory(&mut filesystem, &config.source, "/", &mut summary)?;
-    filesystem.flush();
-    drop(filesystem);
-
-    fs::rename(&temporary.path, &config.output)
-        .map_err(|error| io_error("replace output image", &config.output, error))?;
+    import(&mut filesystem, &mut summary)?;
+    // Identical inputs have to give identical images, whatever the import
+    // freed on the way.
+    filesystem
+        .zero_unused()
+        .map_err(|error| BuildError::new(format!("clear unused blocks: {error}")))?;
+    drop(filesystem.unmount());
+
+    fs::rename(&temporary.path, output)
+        .map_err(|error| io_error("replace output image", output, error))?;
     temporary.keep = true;
 
     Ok(summary)
 }
 
-fn validate_output_location(source: &Path, output: &Path) -> Result<(), BuildError> {
-    let source = fs::canonicalize(source)
-        .map_err(|error| io_error("resolve source directory", source, error))?;
-    let output_name = output
-        .file_name()
-        .filter(|name| !name.is_empty())
-        .ok_or_else(|| BuildError::new("output must name an image file"))?;
-    let output_parent = output.parent().unwrap_or_else(|| Path::new("."));
-    let output_parent = fs::canonicalize(output_parent)
-        .map_err(|error| io_error("resolve output directory", output_parent, error))?;
-    let output = output_parent.join(output_name);
-
-    if output.starts_with(&source) {
+/// Builds a LemonFS image from the files and directories of an ext2 image.
+///
+/// LemonFS has no links, so every hard link becomes a copy of its file and
+/// symlinks and special files are skipped. Permissions, ownership and
+/// timestamps are not represented by LemonFS either.
+pub fn convert_image(config: &ConvertConfig) -> Result<ImportSummary, BuildError> {
+    let device = FileBlockDevice::open_read_only(&config.image)?;
+    let ext2 = Ext2::mount(device).map_err(|error| {
+        BuildError::new(format!(
+            "mount ext2 image {}: {error}",
+            config.image.display()
+        ))
+    })?;
+
+    write_new_image(
+        &config.output,
+        config.total_blocks,
+        |filesystem, summary| {
+            // Directories are seen too, an entry pointing back at one of them
+            // would recurse forever.
+            let root = ext2
+                .inode("/")
+                .map_err(|error| BuildError::new(format!("read ext2 directory /: {error}")))?;
+            let mut seen = HashSet::from([root.number]);
+            convert_directory(&ext2, filesystem, "/", config.compress, &mut seen, summary)
+        },
+    )
+}
+
+fn convert_directory(
+    ext2: &Ext2<FileBlockDevice>,
+    filesystem: &mut Filesystem<FileBlockDevice>,
+    directory: &str,
+    compress: bool,
+    seen: &mut HashSet<u32>,
+    summary: &mut ImportSummary,
+) -> Result<(), BuildError> {
+    let entries = ext2
+        .entries(directory)
+        .map_err(|error| BuildError::new(format!("read ext2 directory {directory}: {error}")))?;
+
+    for entry in entries {
+        let path = child_path(directory, &entry.name);
+        if path == "/lost+found" {
+            continue;
+        }
+
+        match entry.kind {
+            Some(FileKind::Directory) => {
+                if !seen.insert(entry.inode) {
+                    return Err(BuildError::new(format!(
+                        "ext2 directory {path} is linked more than once, the image is corrupted"
+                    )));
+                }
+                filesystem.mkdir(&path).map_err(|error| {
+                    BuildError::new(format!("create directory {path}: {error}"))
+                })?;
+                summary.directories += 1;
+                convert_directory(ext2, filesystem, &path, compress, seen, summary)?;
+            }
+            Some(FileKind::File) => {
+                let read_error = |error| BuildError::new(format!("read ext2 file {path}: {error}"));
+                let inode = ext2.inode(&path).map_err(read_error)?;
+                if inode.size > MAX_FILE_SIZE {
+                    return Err(BuildError::new(format!(
+                        "ext2 file {path} is {} bytes; LemonFS files are limited to {MAX_FILE_SIZE} bytes",
+                        inode.size
+                    )));
+                }
+                let contents = ext2.read_bytes(&path).map_err(read_error)?;
+
+                filesystem
+                    .create_file(&path)
+                    .map_err(|error| BuildError::new(format!("create file {path}: {error}")))?;
+                write_contents(filesystem, &path, &contents, compress, summary)
+                    .map_err(|error| BuildError::new(format!("write file {path}: {error}")))?;
+                summary.files += 1;
+                if !seen.insert(inode.number) {
+                    summary.hard_links += 1;
+                }
+            }
+            _ => summary.skipped.push(path.into()),
+        }
+    }
+
+    Ok(())
+}
+
+/// What `update_image` changed in an image.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct UpdateSummary {
+    /// The directories created and the files written, counted like a build.
+    /// Host entries other than files and directories end up in `skipped`.
+    pub written: ImportSummary,
+
+    /// Files among `written.files` which existed already and got their
+    /// content replaced.
+    pub updated: usize,
+
+    /// Files and directories which already matched the host.
+    pub unchanged: usize,
+
+    pub removed: usize,
+
+    /// Image paths which are a file on one side and a directory on the
+    /// other. They are left as they are.
+    pub conflicts: Vec<String>,
+}
+
+/// Runs an `UpdateAction` on an existing image, mounting it read-write.
+///
+/// Files are only written when their content differs from the host, and
+/// `user.*` extended attributes only when their value does. There is no
+/// temporary copy, an error leaves the changes made so far in place.
+pub fn update_image(config: &UpdateConfig) -> Result<UpdateSummary, BuildError> {
+    if let UpdateAction::Sync(source) = &config.action {
+        let metadata = fs::symlink_metadata(source)
+            .map_err(|error| io_error("inspect source directory", source, error))?;
+        if !metadata.is_dir() {
+            return Err(BuildError::new(format!(
+                "source {} is not a directory",
+                source.display()
+            )));
+        }
+    }
+
+    let device = FileBlockDevice::open(&config.image)?;
+    let mut filesystem = Filesystem::new(device)
+        .map_err(|error| BuildError::new(format!("mount image: {error}")))?;
+
+    let mut summary = UpdateSummary::default();
+    let result = match &config.action {
+        UpdateAction::Add { host, path } => {
+            add_entry(&mut filesystem, host, path, config.compress, &mut summary)
+        }
+        UpdateAction::Remove(path) => remove_file(&filesystem, path, &mut summary),
+        UpdateAction::Mkdir { path, parents } => {
+            make_directory(&filesystem, path, *parents, &mut summary)
+        }
+        UpdateAction::Sync(source) => {
+            sync_directory(&mut filesystem, source, "/", config.compress, &mut summary)
+        }
+    };
+    drop(filesystem.unmount());
+
+    result.map(|()| summary)
+}
+
+fn add_entry(
+    filesystem: &mut Filesystem<FileBlockDevice>,
+    host_path: &Path,
+    lemon_path: &str,
+    compress: bool,
+    summary: &mut UpdateSummary,
+) -> Result<(), BuildError> {
+    let file_type = fs::symlink_metadata(host_path)
+        .map_err(|error| io_error("inspect", host_path, error))?
+        .file_type();
+    if !file_type.is_dir() && !file_type.is_file() {
         return Err(BuildError::new(format!(
-            "output image {} cannot be inside source directory {}",
-            output.display(),
-            source.display()
+            "{} is not a regular file or directory",
+            host_path.display()
+        )));
+    }
+
+    // Like `cp`, a file added to a directory keeps its name.
+    let into_directory = filesystem
+        .stat(lemon_path)
+        .is_ok_and(|metadata| metadata.is_directory);
+    let lemon_path = match host_path.file_name() {
+        Some(name) if file_type.is_file() && into_directory => {
+            child_path(lemon_path, utf8_name(name, host_path)?)
+        }
+        _ => lemon_path.to_string(),
+    };
+
+    sync_entry(
+        filesystem,
+        host_path,
+        file_type.is_dir(),
+        &lemon_path,
+        compress,
+        summary,
+    )
+}
+
+fn remove_file(
+    filesystem: &Filesystem<FileBlockDevice>,
+    path: &str,
+    summary: &mut UpdateSummary,
+) -> Result<(), BuildError> {
+    let metadata = filesystem
+        .stat(path)
+        .map_err(|error| BuildError::new(format!("remove {path}: {error}")))?;
+    if metadata.is_directory {
+        return Err(BuildError::new(format!(
+            "remove {path}: LemonFS can only remove files"
         )));
     }
 
+    filesystem
+        .remove_dir_entry(path)
+        .map_err(|error| BuildError::new(format!("remove {path}: {error}")))?;
+    summary.removed += 1;
     Ok(())
 }
 
-fn create_temporary_image(
-    output: &Path,
-    total_blocks: usize,
-) -> Result<(PathBuf, FileBlockDevice), BuildError> {
-    let file_name = output
-        .file_name()
-        .filter(|name| !name.is_empty())
-        .ok_or_else(|| BuildError::new("output must name an image file"))?;
-    let parent = output.parent().unwrap_or_else(|| Path::new("."));
+fn make_directory(
+    filesystem: &Filesystem<FileBlockDevice>,
+    path: &str,
+    parents: bool,
+    summary: &mut UpdateSummary,
+) -> Result<(), BuildError> {
+    let failed = |(path, error): (String, filesystem::Error)| {
+        BuildError::new(format!("create directory {path}: {error}"))
+    };
 
-    for attempt in 0..100 {
-        let mut temporary_name = file_name.to_os_string();
-        temporary_name.push(format!(".tmp-{}-{attempt}", std::process::id()));
-        let path = parent.join(temporary_name);
-        match FileBlockDevice::create_new(&path, total_blocks) {
-            Ok(device) => return Ok((path, device)),
-            Err(_error) if path.exists() => continue,
-            Err(error) => return Err(error),
-        }
+    if !parents {
+        filesystem
+            .mkdir(path)
+            .map_err(|error| failed((path.into(), error)))?;
+        summary.written.directories += 1;
+        return Ok(());
     }
 
-    Err(BuildError::new(format!(
-        "could not allocate a temporary image beside {}",
-        output.display()
-    )))
+    summary.unchanged +=
+        create_directories(filesystem, path, &mut summary.written).map_err(failed)?;
+    Ok(())
 }
 
-fn import_directory(
+/// Creates `path` and the directories above it which don't exist yet,
+/// returning how many of them already existed. Errors carry the directory
+/// that couldn't be created.
+pub(crate) fn create_directories(
+    filesystem: &Filesystem<FileBlockDevice>,
+    path: &str,
+    summary: &mut ImportSummary,
+) -> Result<usize, (String, filesystem::Error)> {
+    let mut existing = 0;
+    let mut current = String::from("/");
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        current = child_path(&current, name);
+        match filesystem.stat(&current) {
+            Ok(metadata) if metadata.is_directory => existing += 1,
+            Ok(_) => return Err((current, filesystem::Error::NotADirectory)),
+            Err(filesystem::Error::NotFound) => {
+                if let Err(error) = filesystem.mkdir(&current) {
+                    return Err((current, error));
+                }
+                summary.directories += 1;
+            }
+            Err(error) => return Err((current, error)),
+        }
+    }
+    Ok(existing)
+}
+
+fn sync_directory(
     filesystem: &mut Filesystem<FileBlockDevice>,
     host_directory: &Path,
     lemon_directory: &str,
-    summary: &mut ImportSummary,
+    compress: bool,
+    summary: &mut UpdateSummary,
 ) -> Result<(), BuildError> {
     let reader = fs::read_dir(host_directory)
         .map_err(|error| io_error("read source directory", host_directory, error))?;
@@ -335,218 +1280,1951 @@ fn import_directory(
             .file_type()
             .map_err(|error| io_error("inspect source entry", &host_path, error))?;
         if !file_type.is_dir() && !file_type.is_file() {
-            summary.skipped.push(host_path);
+            summary.written.skipped.push(host_path);
             continue;
         }
 
         let entry_name = entry.file_name();
-        let name = utf8_name(&entry_name, &host_path)?;
-        let lemon_path = child_path(lemon_directory, name);
+        let lemon_path = child_path(lemon_directory, utf8_name(&entry_name, &host_path)?);
+        sync_entry(
+            filesystem,
+            &host_path,
+            file_type.is_dir(),
+            &lemon_path,
+            compress,
+            summary,
+        )?;
+    }
 
-        if file_type.is_dir() {
-            filesystem.mkdir(&lemon_path).map_err(|error| {
-                BuildError::new(format!(
-                    "create directory {} from {}: {error}",
-                    lemon_path,
-                    host_path.display()
-                ))
-            })?;
-            summary.directories += 1;
-            import_directory(filesystem, &host_path, &lemon_path, summary)?;
+    Ok(())
+}
+
+/// Brings `lemon_path` in line with the host file or directory at
+/// `host_path`, recursing into directories.
+fn sync_entry(
+    filesystem: &mut Filesystem<FileBlockDevice>,
+    host_path: &Path,
+    is_directory: bool,
+    lemon_path: &str,
+    compress: bool,
+    summary: &mut UpdateSummary,
+) -> Result<(), BuildError> {
+    let lemon_error = |error| {
+        BuildError::new(format!(
+            "{lemon_path} from {}: {error}",
+            host_path.display()
+        ))
+    };
+    let existing = match filesystem.stat(lemon_path) {
+        Ok(metadata) => Some(metadata),
+        Err(filesystem::Error::NotFound) => None,
+        Err(error) => return Err(lemon_error(error)),
+    };
+    if existing.is_some_and(|metadata| metadata.is_directory != is_directory) {
+        summary.conflicts.push(lemon_path.into());
+        return Ok(());
+    }
+
+    if is_directory {
+        if existing.is_some() {
+            summary.unchanged += 1;
         } else {
-            let metadata = entry
-                .metadata()
-                .map_err(|error| io_error("inspect source file", &host_path, error))?;
-            if metadata.len() > MAX_FILE_SIZE {
-                return Err(BuildError::new(format!(
-                    "source file {} is {} bytes; LemonFS files are limited to {} bytes",
-                    host_path.display(),
-                    metadata.len(),
-                    MAX_FILE_SIZE
-                )));
-            }
-            let contents = fs::read(&host_path)
-                .map_err(|error| io_error("read source file", &host_path, error))?;
-            filesystem.create_file(&lemon_path).map_err(|error| {
+            filesystem.mkdir(lemon_path).map_err(lemon_error)?;
+            summary.written.directories += 1;
+        }
+        sync_xattrs(filesystem, host_path, lemon_path, summary)?;
+        return sync_directory(filesystem, host_path, lemon_path, compress, summary);
+    }
+
+    let size = fs::metadata(host_path)
+        .map_err(|error| io_error("inspect source file", host_path, error))?
+        .len();
+    if size > MAX_FILE_SIZE {
+        return Err(BuildError::new(format!(
+            "source file {} is {size} bytes; LemonFS files are limited to {MAX_FILE_SIZE} bytes",
+            host_path.display(),
+        )));
+    }
+    let contents =
+        fs::read(host_path).map_err(|error| io_error("read source file", host_path, error))?;
+
+    match existing {
+        Some(metadata)
+            if metadata.size == contents.len()
+                && filesystem.read_bytes(lemon_path).map_err(lemon_error)? == contents =>
+        {
+            summary.unchanged += 1;
+        }
+        Some(_) => {
+            filesystem.set_len(lemon_path, 0).map_err(lemon_error)?;
+            write_contents(
+                filesystem,
+                lemon_path,
+                &contents,
+                compress,
+                &mut summary.written,
+            )
+            .map_err(lemon_error)?;
+            summary.written.files += 1;
+            summary.updated += 1;
+        }
+        None => {
+            filesystem.create_file(lemon_path).map_err(lemon_error)?;
+            write_contents(
+                filesystem,
+                lemon_path,
+                &contents,
+                compress,
+                &mut summary.written,
+            )
+            .map_err(lemon_error)?;
+            summary.written.files += 1;
+        }
+    }
+
+    sync_xattrs(filesystem, host_path, lemon_path, summary)
+}
+
+/// Sets the `user.*` extended attributes of the host entry whose value in
+/// the image differs. Attributes only the image has are kept.
+fn sync_xattrs(
+    filesystem: &Filesystem<FileBlockDevice>,
+    host_path: &Path,
+    lemon_path: &str,
+    summary: &mut UpdateSummary,
+) -> Result<(), BuildError> {
+    let xattrs = host_user_xattrs(host_path)
+        .map_err(|error| io_error("read extended attributes of", host_path, error))?;
+
+    for (name, value) in xattrs {
+        if filesystem.get_xattr(lemon_path, &name).ok() == Some(value.clone()) {
+            continue;
+        }
+        filesystem
+            .set_xattr(lemon_path, &name, &value)
+            .map_err(|error| {
                 BuildError::new(format!(
-                    "create file {} from {}: {error}",
-                    lemon_path,
+                    "set extended attribute {name} of {lemon_path} from {}: {error}",
                     host_path.display()
                 ))
             })?;
-            filesystem
-                .write_to_file(&lemon_path, &contents)
-                .map_err(|error| {
-                    BuildError::new(format!(
-                        "write file {} from {}: {error}",
-                        lemon_path,
-                        host_path.display()
-                    ))
-                })?;
-            summary.files += 1;
-        }
+        summary.written.xattrs += 1;
     }
 
     Ok(())
 }
 
-fn utf8_name<'a>(name: &'a OsStr, path: &Path) -> Result<&'a str, BuildError> {
-    name.to_str().ok_or_else(|| {
-        BuildError::new(format!(
-            "source entry {} does not have a UTF-8 name",
-            path.display()
-        ))
-    })
+/// What `extract_image` wrote to the host and what it left out.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ExtractSummary {
+    pub directories: usize,
+    pub files: usize,
+
+    /// Image paths of the entries which already existed on the host and
+    /// were left as they are, together with everything below them.
+    pub collisions: Vec<PathBuf>,
+
+    /// Image paths of the entries whose names can't be used on the host.
+    pub skipped: Vec<PathBuf>,
 }
 
-fn child_path(parent: &str, name: &str) -> String {
-    if parent == "/" {
-        format!("/{name}")
-    } else {
-        format!("{parent}/{name}")
+/// Recreates the files and directories of an image below `dest`, mounting
+/// the image read-only.
+///
+/// Existing host files are never overwritten but reported as collisions,
+/// existing directories are merged. Extended attributes are not restored.
+pub fn extract_image(config: &ExtractConfig) -> Result<ExtractSummary, BuildError> {
+    let device = FileBlockDevice::open_read_only(&config.image)?;
+    let filesystem = Filesystem::mount_read_only(device)
+        .map_err(|error| BuildError::new(format!("mount image: {error}")))?;
+    fs::create_dir_all(&config.dest)
+        .map_err(|error| io_error("create destination directory", &config.dest, error))?;
+
+    let mut summary = ExtractSummary::default();
+    let mut walker = filesystem
+        .walk("/")
+        .map_err(|error| BuildError::new(format!("walk image: {error}")))?;
+
+    while let Some(entry) = walker.next() {
+        if entry.depth == 0 {
+            continue;
+        }
+
+        // Parents are checked before their children get yielded.
+        if !is_host_name(entry.name()) {
+            summary.skipped.push(entry.path.into());
+            walker.skip_dir();
+            continue;
+        }
+        let host_path = config.dest.join(entry.path.trim_start_matches('/'));
+
+        if entry.metadata.is_directory {
+            match fs::create_dir(&host_path) {
+                Ok(()) => summary.directories += 1,
+                Err(error) if error.kind() == ErrorKind::AlreadyExists => {
+                    let existing = fs::symlink_metadata(&host_path)
+                        .map_err(|error| io_error("inspect destination", &host_path, error))?;
+                    if !existing.is_dir() {
+                        summary.collisions.push(entry.path.into());
+                        walker.skip_dir();
+                    }
+                }
+                Err(error) => return Err(io_error("create directory", &host_path, error)),
+            }
+            continue;
+        }
+
+        let contents = filesystem
+            .read_bytes(&entry.path)
+            .map_err(|error| BuildError::new(format!("read file {}: {error}", entry.path)))?;
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&host_path)
+        {
+            Ok(mut file) => {
+                file.write_all(&contents)
+                    .map_err(|error| io_error("write file", &host_path, error))?;
+                summary.files += 1;
+            }
+            Err(error) if error.kind() == ErrorKind::AlreadyExists => {
+                summary.collisions.push(entry.path.into());
+            }
+            Err(error) => return Err(io_error("create file", &host_path, error)),
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Returns `false` for names which would not name a single entry in the
+/// destination, only a damaged image holds those.
+fn is_host_name(name: &str) -> bool {
+    !matches!(name, "" | "." | "..") && !name.contains(['/', '\0'])
+}
+
+/// How an entry of the image differs from the source tree, by image path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference {
+    /// Only the source tree has the entry.
+    Missing(String),
+
+    /// Only the image has the entry.
+    Extra(String),
+
+    /// A file on one side is a directory on the other.
+    Kind(String),
+    Content(String),
+
+    /// The `user.*` extended attributes differ.
+    Xattrs(String),
+}
+
+impl Difference {
+    pub fn path(&self) -> &str {
+        match self {
+            Self::Missing(path)
+            | Self::Extra(path)
+            | Self::Kind(path)
+            | Self::Content(path)
+            | Self::Xattrs(path) => path,
+        }
+    }
+}
+
+/// SHA-256 digest of the image file as lowercase hex.
+pub fn image_digest(image: &Path) -> Result<String, BuildError> {
+    let mut file = File::open(image).map_err(|error| io_error("open image", image, error))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * BLOCK_SIZE];
+
+    loop {
+        let read = file
+            .read(&mut buffer)
+            .map_err(|error| io_error("read image", image, error))?;
+        if read == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.update(&buffer[..read]);
+    }
+}
+
+/// Compares the image with the source tree it should have been built from.
+/// Entries `build_image` skips, like symlinks, are ignored.
+pub fn verify_image(config: &VerifyConfig) -> Result<Vec<Difference>, BuildError> {
+    let metadata = fs::symlink_metadata(&config.source)
+        .map_err(|error| io_error("inspect source directory", &config.source, error))?;
+    if !metadata.file_type().is_dir() {
+        return Err(BuildError::new(format!(
+            "source {} is not a directory",
+            config.source.display()
+        )));
     }
+
+    let device = FileBlockDevice::open_read_only(&config.image)?;
+    let filesystem = Filesystem::mount_read_only(device)
+        .map_err(|error| BuildError::new(format!("mount image: {error}")))?;
+
+    let mut differences = Vec::new();
+    verify_directory(&filesystem, &config.source, "/", &mut differences)?;
+    differences.sort_by(|a, b| a.path().cmp(b.path()));
+    Ok(differences)
 }
 
-fn io_error(action: &str, path: &Path, error: std::io::Error) -> BuildError {
-    BuildError::new(format!("{action} {}: {error}", path.display()))
-}
+fn verify_directory(
+    filesystem: &Filesystem<FileBlockDevice>,
+    host_directory: &Path,
+    lemon_directory: &str,
+    differences: &mut Vec<Difference>,
+) -> Result<(), BuildError> {
+    let lemon_error = |error| BuildError::new(format!("read {lemon_directory}: {error}"));
+    let mut image_names = filesystem.read_dir(lemon_directory).map_err(lemon_error)?;
+
+    let reader = fs::read_dir(host_directory)
+        .map_err(|error| io_error("read source directory", host_directory, error))?;
+    let mut entries = reader
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|error| io_error("read entry in source directory", host_directory, error))?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let host_path = entry.path();
+        let file_type = entry
+            .file_type()
+            .map_err(|error| io_error("inspect source entry", &host_path, error))?;
+        if !file_type.is_dir() && !file_type.is_file() {
+            continue;
+        }
+
+        let entry_name = entry.file_name();
+        let name = utf8_name(&entry_name, &host_path)?;
+        let lemon_path = child_path(lemon_directory, name);
+        let Some(position) = image_names.iter().position(|image_name| image_name == name) else {
+            differences.push(Difference::Missing(lemon_path));
+            continue;
+        };
+        image_names.remove(position);
+
+        let lemon_error = |error| BuildError::new(format!("read {lemon_path}: {error}"));
+        let metadata = filesystem.stat(&lemon_path).map_err(lemon_error)?;
+        if metadata.is_directory != file_type.is_dir() {
+            differences.push(Difference::Kind(lemon_path));
+            continue;
+        }
+        if file_type.is_file() {
+            let contents = fs::read(&host_path)
+                .map_err(|error| io_error("read source file", &host_path, error))?;
+            if filesystem.read_bytes(&lemon_path).map_err(lemon_error)? != contents {
+                differences.push(Difference::Content(lemon_path.clone()));
+            }
+        }
+
+        let host_xattrs = host_user_xattrs(&host_path)
+            .map_err(|error| io_error("read extended attributes of", &host_path, error))?;
+        let mut image_xattrs = Vec::new();
+        for name in filesystem.list_xattr(&lemon_path).map_err(lemon_error)? {
+            if name.starts_with("user.") {
+                let value = filesystem
+                    .get_xattr(&lemon_path, &name)
+                    .map_err(lemon_error)?;
+                image_xattrs.push((name, value));
+            }
+        }
+        image_xattrs.sort();
+        if image_xattrs != host_xattrs {
+            differences.push(Difference::Xattrs(lemon_path.clone()));
+        }
+
+        if file_type.is_dir() {
+            verify_directory(filesystem, &host_path, &lemon_path, differences)?;
+        }
+    }
+
+    differences.extend(
+        image_names
+            .into_iter()
+            .map(|name| Difference::Extra(child_path(lemon_directory, &name))),
+    );
+
+    Ok(())
+}
+
+/// Block counts of an image before and after `resize_image`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ResizeSummary {
+    pub old_blocks: usize,
+    pub new_blocks: usize,
+}
+
+/// Grows the filesystem in an existing image, extending the file first when
+/// a larger `total_blocks` is requested.
+pub fn resize_image(config: &ResizeConfig) -> Result<ResizeSummary, BuildError> {
+    if let Some(total_blocks) = config.total_blocks {
+        let image_bytes = total_blocks
+            .checked_mul(BLOCK_SIZE)
+            .filter(|_| u32::try_from(total_blocks).is_ok())
+            .ok_or_else(|| {
+                BuildError::new(format!(
+                    "block count {total_blocks} exceeds the LemonFS limit"
+                ))
+            })?;
+        let file = OpenOptions::new()
+            .write(true)
+            .open(&config.image)
+            .map_err(|error| io_error("open image", &config.image, error))?;
+        let current = file
+            .metadata()
+            .map_err(|error| io_error("inspect image", &config.image, error))?
+            .len();
+        if (image_bytes as u64) < current {
+            return Err(BuildError::new(format!(
+                "image {} is larger than {total_blocks} blocks; shrinking is not supported",
+                config.image.display()
+            )));
+        }
+        file.set_len(image_bytes as u64)
+            .map_err(|error| io_error("extend image", &config.image, error))?;
+    }
+
+    let mut device = FileBlockDevice::open(&config.image)?;
+    let new_blocks = device.total_blocks();
+    let mut filesystem = Filesystem::new(device)
+        .map_err(|error| BuildError::new(format!("mount image: {error}")))?;
+    let old_blocks = filesystem.total_blocks();
+
+    filesystem.grow(new_blocks).map_err(|error| match error {
+        filesystem::Error::OperationNotSupported => BuildError::new(format!(
+            "image {} is smaller than its filesystem",
+            config.image.display()
+        )),
+        error => BuildError::new(format!("grow filesystem: {error}")),
+    })?;
+    drop(filesystem.unmount());
+
+    Ok(ResizeSummary {
+        old_blocks,
+        new_blocks,
+    })
+}
+
+/// Converts `image` in place to the current format version, returning the
+/// version it had before.
+pub fn upgrade_image(image: &Path) -> Result<u32, BuildError> {
+    let device = FileBlockDevice::open(image)?;
+    Filesystem::upgrade(device)
+        .map_err(|error| BuildError::new(format!("upgrade {}: {error}", image.display())))
+}
+
+/// Runs a snapshot `action` on `image`, returning the snapshots afterwards.
+/// Listing mounts the image read-only.
+pub fn snapshot_image(config: &SnapshotConfig) -> Result<Vec<SnapshotInfo>, BuildError> {
+    let device = FileBlockDevice::open(&config.image)?;
+    let mounted = match config.action {
+        SnapshotAction::List => Filesystem::mount_read_only(device),
+        _ => Filesystem::new(device),
+    };
+    let mut filesystem =
+        mounted.map_err(|error| BuildError::new(format!("mount image: {error}")))?;
+
+    let result = match &config.action {
+        SnapshotAction::Create(name) => filesystem.create_snapshot(name),
+        SnapshotAction::List => Ok(()),
+        SnapshotAction::Delete(name) => filesystem.delete_snapshot(name),
+        SnapshotAction::Rollback(name) => filesystem.rollback(name),
+    };
+    let snapshots = filesystem.snapshots();
+    drop(filesystem.unmount());
+
+    result.map_err(|error| BuildError::new(format!("snapshot: {error}")))?;
+    Ok(snapshots)
+}
+
+/// Returns every entry of `image` matching the pattern in depth-first
+/// order, mounting it read-only.
+pub fn list_image(config: &ListConfig) -> Result<Vec<WalkEntry>, BuildError> {
+    let device = FileBlockDevice::open(&config.image)?;
+    let filesystem = Filesystem::mount_read_only(device)
+        .map_err(|error| BuildError::new(format!("mount image: {error}")))?;
+
+    let entries = filesystem
+        .walk("/")
+        .map_err(|error| BuildError::new(format!("list: {error}")))?
+        .filter(|entry| {
+            config
+                .pattern
+                .as_deref()
+                .is_none_or(|pattern| glob_match(pattern, &entry.path))
+        })
+        .collect();
+    Ok(entries)
+}
+
+fn validate_output_location(source: &Path, output: &Path) -> Result<(), BuildError> {
+    let source = fs::canonicalize(source)
+        .map_err(|error| io_error("resolve source directory", source, error))?;
+    let output_name = output
+        .file_name()
+        .filter(|name| !name.is_empty())
+        .ok_or_else(|| BuildError::new("output must name an image file"))?;
+    let output_parent = output.parent().unwrap_or_else(|| Path::new("."));
+    let output_parent = fs::canonicalize(output_parent)
+        .map_err(|error| io_error("resolve output directory", output_parent, error))?;
+    let output = output_parent.join(output_name);
+
+    if output.starts_with(&source) {
+        return Err(BuildError::new(format!(
+            "output image {} cannot be inside source directory {}",
+            output.display(),
+            source.display()
+        )));
+    }
+
+    Ok(())
+}
+
+fn create_temporary_image(
+    output: &Path,
+    total_blocks: usize,
+) -> Result<(PathBuf, FileBlockDevice), BuildError> {
+    let file_name = output
+        .file_name()
+        .filter(|name| !name.is_empty())
+        .ok_or_else(|| BuildError::new("output must name an image file"))?;
+    let parent = output.parent().unwrap_or_else(|| Path::new("."));
+
+    for attempt in 0..100 {
+        let mut temporary_name = file_name.to_os_string();
+        temporary_name.push(format!(".tmp-{}-{attempt}", std::process::id()));
+        let path = parent.join(temporary_name);
+        match FileBlockDevice::create_new(&path, total_blocks) {
+            Ok(device) => return Ok((path, device)),
+            Err(_error) if path.exists() => continue,
+            Err(error) => return Err(error),
+        }
+    }
+
+    Err(BuildError::new(format!(
+        "could not allocate a temporary image beside {}",
+        output.display()
+    )))
+}
+
+fn import_directory(
+    filesystem: &mut Filesystem<FileBlockDevice>,
+    host_directory: &Path,
+    lemon_directory: &str,
+    compress: bool,
+    summary: &mut ImportSummary,
+) -> Result<(), BuildError> {
+    let reader = fs::read_dir(host_directory)
+        .map_err(|error| io_error("read source directory", host_directory, error))?;
+    let mut entries = reader
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|error| io_error("read entry in source directory", host_directory, error))?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let host_path = entry.path();
+        let file_type = entry
+            .file_type()
+            .map_err(|error| io_error("inspect source entry", &host_path, error))?;
+        if !file_type.is_dir() && !file_type.is_file() {
+            summary.skipped.push(host_path);
+            continue;
+        }
+
+        let entry_name = entry.file_name();
+        let name = utf8_name(&entry_name, &host_path)?;
+        let lemon_path = child_path(lemon_directory, name);
+
+        if file_type.is_dir() {
+            filesystem.mkdir(&lemon_path).map_err(|error| {
+                BuildError::new(format!(
+                    "create directory {} from {}: {error}",
+                    lemon_path,
+                    host_path.display()
+                ))
+            })?;
+            summary.directories += 1;
+            import_xattrs(filesystem, &host_path, &lemon_path, summary)?;
+            import_directory(filesystem, &host_path, &lemon_path, compress, summary)?;
+        } else {
+            let metadata = entry
+                .metadata()
+                .map_err(|error| io_error("inspect source file", &host_path, error))?;
+            if metadata.len() > MAX_FILE_SIZE {
+                return Err(BuildError::new(format!(
+                    "source file {} is {} bytes; LemonFS files are limited to {} bytes",
+                    host_path.display(),
+                    metadata.len(),
+                    MAX_FILE_SIZE
+                )));
+            }
+            let contents = fs::read(&host_path)
+                .map_err(|error| io_error("read source file", &host_path, error))?;
+            filesystem.create_file(&lemon_path).map_err(|error| {
+                BuildError::new(format!(
+                    "create file {} from {}: {error}",
+                    lemon_path,
+                    host_path.display()
+                ))
+            })?;
+            write_contents(filesystem, &lemon_path, &contents, compress, summary).map_err(
+                |error| {
+                    BuildError::new(format!(
+                        "write file {} from {}: {error}",
+                        lemon_path,
+                        host_path.display()
+                    ))
+                },
+            )?;
+            import_xattrs(filesystem, &host_path, &lemon_path, summary)?;
+            summary.files += 1;
+        }
+    }
+
+    Ok(())
+}
+
+fn import_xattrs(
+    filesystem: &mut Filesystem<FileBlockDevice>,
+    host_path: &Path,
+    lemon_path: &str,
+    summary: &mut ImportSummary,
+) -> Result<(), BuildError> {
+    let xattrs = host_user_xattrs(host_path)
+        .map_err(|error| io_error("read extended attributes of", host_path, error))?;
+
+    for (name, value) in xattrs {
+        filesystem
+            .set_xattr(lemon_path, &name, &value)
+            .map_err(|error| {
+                BuildError::new(format!(
+                    "set extended attribute {name} of {} from {}: {error}",
+                    lemon_path,
+                    host_path.display()
+                ))
+            })?;
+        summary.xattrs += 1;
+    }
+
+    Ok(())
+}
+
+/// Reads the `user.*` extended attributes of a host entry without following
+/// symlinks. Other namespaces such as `security.*` describe the host and are
+/// not imported.
+#[cfg(target_os = "linux")]
+fn host_user_xattrs(path: &Path) -> std::io::Result<Vec<(String, Vec<u8>)>> {
+    use std::ffi::{CString, c_char, c_void};
+    use std::io::{Error, ErrorKind};
+    use std::os::unix::ffi::OsStrExt;
+
+    unsafe extern "C" {
+        fn llistxattr(path: *const c_char, list: *mut c_char, size: usize) -> isize;
+        fn lgetxattr(
+            path: *const c_char,
+            name: *const c_char,
+            value: *mut c_void,
+            size: usize,
+        ) -> isize;
+    }
+
+    /// Calls `query` once for the size and once for the contents, both
+    /// calls follow the `*xattr` convention of returning -1 on errors.
+    fn read_sized(mut query: impl FnMut(*mut u8, usize) -> isize) -> std::io::Result<Vec<u8>> {
+        let size = query(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let mut buffer = vec![0; size as usize];
+        let size = query(buffer.as_mut_ptr(), buffer.len());
+        if size < 0 {
+            return Err(Error::last_os_error());
+        }
+        buffer.truncate(size as usize);
+
+        Ok(buffer)
+    }
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: `c_path` is NUL terminated and `list` is valid for `size` bytes.
+    let names =
+        match read_sized(|list, size| unsafe { llistxattr(c_path.as_ptr(), list.cast(), size) }) {
+            Ok(names) => names,
+            Err(error) if error.kind() == ErrorKind::Unsupported => return Ok(Vec::new()),
+            Err(error) => return Err(error),
+        };
+
+    let mut xattrs = Vec::new();
+    for name in names
+        .split(|byte| *byte == 0)
+        .filter(|name| name.starts_with(b"user."))
+    {
+        let c_name = CString::new(name)?;
+        // SAFETY: both strings are NUL terminated and `value` is valid for
+        // `size` bytes.
+        let value = read_sized(|value, size| unsafe {
+            lgetxattr(c_path.as_ptr(), c_name.as_ptr(), value.cast(), size)
+        })?;
+        let name = String::from_utf8(name.to_vec()).map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidData,
+                "extended attribute name is not UTF-8",
+            )
+        })?;
+        xattrs.push((name, value));
+    }
+    // The order of the host list depends on its filesystem.
+    xattrs.sort();
+
+    Ok(xattrs)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn host_user_xattrs(_path: &Path) -> std::io::Result<Vec<(String, Vec<u8>)>> {
+    Ok(Vec::new())
+}
+
+/// Writes a whole file, compressed if requested and worth it and sparse
+/// otherwise.
+fn write_contents(
+    filesystem: &mut Filesystem<FileBlockDevice>,
+    path: &str,
+    contents: &[u8],
+    compress: bool,
+    summary: &mut ImportSummary,
+) -> Result<(), filesystem::Error> {
+    if compress && contents.len() >= COMPRESS_THRESHOLD {
+        filesystem.write_compressed(path, contents)?;
+        if filesystem.stat(path)?.compressed {
+            summary.compressed += 1;
+            return Ok(());
+        }
+        filesystem.set_len(path, 0)?;
+    }
+
+    summary.holes += write_sparse(filesystem, path, contents)?;
+    if filesystem.stat(path)?.inline {
+        summary.inline += 1;
+    }
+
+    Ok(())
+}
+
+/// Writes `contents` skipping blocks which only contain zeros, so they end up
+/// as holes in the image. Returns the number of holes.
+fn write_sparse(
+    filesystem: &mut Filesystem<FileBlockDevice>,
+    path: &str,
+    contents: &[u8],
+) -> Result<usize, filesystem::Error> {
+    let mut holes = 0;
+
+    for (index, chunk) in contents.chunks(BLOCK_SIZE).enumerate() {
+        if chunk.iter().all(|byte| *byte == 0) {
+            holes += 1;
+            continue;
+        }
+        filesystem.write_at(path, index * BLOCK_SIZE, chunk)?;
+    }
+
+    filesystem.set_len(path, contents.len())?;
+
+    Ok(holes)
+}
+
+fn utf8_name<'a>(name: &'a OsStr, path: &Path) -> Result<&'a str, BuildError> {
+    name.to_str().ok_or_else(|| {
+        BuildError::new(format!(
+            "source entry {} does not have a UTF-8 name",
+            path.display()
+        ))
+    })
+}
+
+fn child_path(parent: &str, name: &str) -> String {
+    if parent == "/" {
+        format!("/{name}")
+    } else {
+        format!("{parent}/{name}")
+    }
+}
+
+fn io_error(action: &str, path: &Path, error: std::io::Error) -> BuildError {
+    BuildError::new(format!("{action} {}: {error}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const TEST_BLOCKS: usize = 1024;
+    static NEXT_TEMP: AtomicUsize = AtomicUsize::new(0);
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let id = NEXT_TEMP.fetch_add(1, Ordering::Relaxed);
+            let path = std::env::temp_dir()
+                .join(format!("lemon-shark-mkfs-test-{}-{id}", std::process::id()));
+            fs::create_dir(&path).unwrap();
+            Self(path)
+        }
+
+        fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+            self.0.join(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn strings<'a>(args: &'a [&str]) -> impl Iterator<Item = OsString> + 'a {
+        args.iter().map(OsString::from)
+    }
+
+    #[test]
+    fn parses_defaults() {
+        assert_eq!(
+            Command::parse(std::iter::empty()).unwrap(),
+            Command::Build(Config::default())
+        );
+    }
+
+    #[test]
+    fn parses_named_options() {
+        assert_eq!(
+            Command::parse(strings(&[
+                "--source",
+                "input",
+                "--output",
+                "disk.img",
+                "--blocks",
+                "2048",
+                "--compress",
+            ]))
+            .unwrap(),
+            Command::Build(Config {
+                source: Source::Directory("input".into()),
+                output: "disk.img".into(),
+                total_blocks: 2048,
+                compress: true,
+                ..Config::default()
+            })
+        );
+    }
+
+    #[test]
+    fn parses_help_and_rejects_invalid_arguments() {
+        assert_eq!(Command::parse(strings(&["--help"])).unwrap(), Command::Help);
+        assert!(Command::parse(strings(&["--wat"])).is_err());
+        assert!(Command::parse(strings(&["--source"])).is_err());
+        assert!(Command::parse(strings(&["--blocks", "zero"])).is_err());
+        assert!(Command::parse(strings(&["--blocks", "0"])).is_err());
+        assert!(Command::parse(strings(&["--output", "one", "--output", "two"])).is_err());
+        assert!(Command::parse(strings(&["--compress", "--compress"])).is_err());
+    }
+
+    #[test]
+    fn parses_manifest() {
+        assert_eq!(
+            Command::parse(strings(&["--manifest", "image.toml", "--compress"])).unwrap(),
+            Command::Build(Config {
+                source: Source::Manifest("image.toml".into()),
+                compress: true,
+                ..Config::default()
+            })
+        );
+        assert!(
+            Command::parse(strings(&["--manifest", "image.toml", "--source", "rootfs"])).is_err()
+        );
+    }
+
+    #[test]
+    fn builds_from_a_manifest() {
+        let temp = TempDir::new();
+        let tree = temp.join("tree");
+        fs::create_dir_all(tree.join("nested")).unwrap();
+        fs::write(tree.join("nested/note.txt"), b"from the tree").unwrap();
+        fs::write(temp.join("init"), "init ".repeat(300)).unwrap();
+        let manifest = temp.join("image.toml");
+        fs::write(
+            &manifest,
+            r#"
+[[file]]
+source = "init"
+destination = "/bin/init"
+
+[[file]]
+destination = "/etc/motd"
+content = "Welcome\n"
+attributes = { "user.mime_type" = "text/plain" }
+
+[[directory]]
+source = "tree"
+destination = "/usr/share"
+"#,
+        )
+        .unwrap();
+
+        let output = temp.join("result.img");
+        let summary = build_image(&Config {
+            source: Source::Manifest(manifest.clone()),
+            output: output.clone(),
+            total_blocks: TEST_BLOCKS,
+            compress: true,
+            ..Config::default()
+        })
+        .unwrap();
+        assert_eq!(summary.directories, 5);
+        assert_eq!(summary.files, 3);
+        assert_eq!(summary.compressed, 1);
+        assert_eq!(summary.xattrs, 1);
+
+        let filesystem =
+            Filesystem::mount_read_only(FileBlockDevice::open(&output).unwrap()).unwrap();
+        assert_eq!(
+            filesystem.read_file("/bin/init").unwrap(),
+            "init ".repeat(300)
+        );
+        assert_eq!(filesystem.read_file("/etc/motd").unwrap(), "Welcome\n");
+        assert_eq!(
+            filesystem.get_xattr("/etc/motd", "user.mime_type").unwrap(),
+            b"text/plain"
+        );
+        assert_eq!(
+            filesystem.read_file("/usr/share/nested/note.txt").unwrap(),
+            "from the tree"
+        );
+        drop(filesystem);
+
+        // Errors are found before the output is touched.
+        fs::write(
+            &manifest,
+            "[[file]]\ndestination = \"/a\"\ncontent = \"a\"\n\n[[symlink]]\n",
+        )
+        .unwrap();
+        let before = fs::read(&output).unwrap();
+        let error = build_image(&Config {
+            source: Source::Manifest(manifest.clone()),
+            output: output.clone(),
+            total_blocks: TEST_BLOCKS,
+            compress: false,
+            ..Config::default()
+        })
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "{}:5: symlinks are not supported, LemonFS has no links",
+                manifest.display()
+            )
+        );
+        assert_eq!(fs::read(&output).unwrap(), before);
+    }
+
+    #[test]
+    fn imports_nested_tree_and_file_contents() {
+        let temp = TempDir::new();
+        let source = temp.join("source");
+        fs::create_dir(&source).unwrap();
+        fs::create_dir(source.join("empty")).unwrap();
+        fs::create_dir(source.join("nested")).unwrap();
+        fs::write(source.join(".hidden"), b"secret").unwrap();
+        fs::write(source.join("binary.dat"), [0, 159, 146, 150]).unwrap();
+        let long_contents = "lemon".repeat(140);
+        fs::write(source.join("nested/note.txt"), &long_contents).unwrap();
+
+        let output = temp.join("result.img");
+        let summary = build_image(&Config {
+            source: Source::Directory(source),
+            output: output.clone(),
+            total_blocks: TEST_BLOCKS,
+            compress: false,
+            ..Config::default()
+        })
+        .unwrap();
+
+        assert_eq!(summary.directories, 2);
+        assert_eq!(summary.files, 3);
+        assert!(summary.skipped.is_empty());
+
+        let filesystem =
+            Filesystem::mount_read_only(FileBlockDevice::open(&output).unwrap()).unwrap();
+        assert!(filesystem.mounted_clean());
+        assert_eq!(filesystem.read_file("/.hidden").unwrap(), "secret");
+        assert_eq!(
+            filesystem.read_file("/nested/note.txt").unwrap(),
+            long_contents
+        );
+        let mut listing = String::new();
+        filesystem.dump_dir("/empty", &mut listing).unwrap();
+        assert!(listing.contains("./"));
+        assert!(listing.contains("../"));
+
+        let second_output = temp.join("second.img");
+        build_image(&Config {
+            source: Source::Directory(temp.join("source")),
+            output: second_output.clone(),
+            total_blocks: TEST_BLOCKS,
+            compress: false,
+            ..Config::default()
+        })
+        .unwrap();
+        assert_eq!(fs::read(output).unwrap(), fs::read(second_output).unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn skips_symlinks_without_following_them() {
+        use std::os::unix::fs::symlink;
+
+        let temp = TempDir::new();
+        let source = temp.join("source");
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("real.txt"), b"real").unwrap();
+        symlink("real.txt", source.join("link.txt")).unwrap();
+
+        let output = temp.join("result.img");
+        let summary = build_image(&Config {
+            source: Source::Directory(source),
+            output: output.clone(),
+            total_blocks: TEST_BLOCKS,
+            compress: false,
+            ..Config::default()
+        })
+        .unwrap();
+
+        assert_eq!(summary.files, 1);
+        assert_eq!(summary.skipped.len(), 1);
+        let filesystem = Filesystem::new(FileBlockDevice::open(&output).unwrap()).unwrap();
+        assert_eq!(filesystem.read_file("/real.txt").unwrap(), "real");
+        assert!(filesystem.read_file("/link.txt").is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn imports_user_xattrs() {
+        use std::ffi::{CString, c_char, c_void};
+        use std::os::unix::ffi::OsStrExt;
+
+        unsafe extern "C" {
+            fn setxattr(
+                path: *const c_char,
+                name: *const c_char,
+                value: *const c_void,
+                size: usize,
+                flags: i32,
+            ) -> i32;
+        }
+
+        let set = |path: &Path, name: &str, value: &[u8]| {
+            let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+            let name = CString::new(name).unwrap();
+            // SAFETY: both strings are NUL terminated and `value` is valid.
+            unsafe {
+                setxattr(
+                    path.as_ptr(),
+                    name.as_ptr(),
+                    value.as_ptr().cast(),
+                    value.len(),
+                    0,
+                ) == 0
+            }
+        };
+
+        let temp = TempDir::new();
+        let source = temp.join("source");
+        fs::create_dir_all(source.join("dir")).unwrap();
+        fs::write(source.join("file.txt"), b"tagged").unwrap();
+        if !set(&source.join("file.txt"), "user.mime_type", b"text/plain") {
+            eprintln!("skipping: the host filesystem doesn't support user xattrs");
+            return;
+        }
+        assert!(set(&source.join("dir"), "user.label", b"assets"));
+
+        let output = temp.join("result.img");
+        let summary = build_image(&Config {
+            source: Source::Directory(source),
+            output: output.clone(),
+            total_blocks: TEST_BLOCKS,
+            compress: false,
+            ..Config::default()
+        })
+        .unwrap();
+
+        assert_eq!(summary.xattrs, 2);
+        let filesystem =
+            Filesystem::mount_read_only(FileBlockDevice::open(&output).unwrap()).unwrap();
+        assert_eq!(
+            filesystem.list_xattr("/file.txt").unwrap(),
+            ["user.mime_type"]
+        );
+        assert_eq!(
+            filesystem.get_xattr("/file.txt", "user.mime_type").unwrap(),
+            b"text/plain"
+        );
+        assert_eq!(
+            filesystem.get_xattr("/dir", "user.label").unwrap(),
+            b"assets"
+        );
+    }
+
+    #[test]
+    fn imports_zero_runs_as_holes() {
+        let temp = TempDir::new();
+        let source = temp.join("source");
+        fs::create_dir(&source).unwrap();
+        let mut contents = vec![0u8; 4 * BLOCK_SIZE + 7];
+        contents[BLOCK_SIZE + 3] = b'x';
+        fs::write(source.join("sparse.bin"), &contents).unwrap();
+        fs::write(source.join("zeros.bin"), vec![0u8; 2 * BLOCK_SIZE]).unwrap();
+
+        let output = temp.join("result.img");
+        let summary = build_image(&Config {
+            source: Source::Directory(source),
+            output: output.clone(),
+            total_blocks: TEST_BLOCKS,
+            compress: false,
+            ..Config::default()
+        })
+        .unwrap();
+        assert_eq!(summary.holes, 6);
+
+        let filesystem =
+            Filesystem::mount_read_only(FileBlockDevice::open(&output).unwrap()).unwrap();
+        assert_eq!(filesystem.read_bytes("/sparse.bin").unwrap(), contents);
+        let metadata = filesystem.stat("/sparse.bin").unwrap();
+        assert_eq!(metadata.size, contents.len());
+        assert_eq!(metadata.allocated_blocks, 1);
+        let metadata = filesystem.stat("/zeros.bin").unwrap();
+        assert_eq!(metadata.size, 2 * BLOCK_SIZE);
+        assert_eq!(metadata.allocated_blocks, 0);
+    }
+
+    #[test]
+    fn imports_small_files_inline() {
+        let temp = TempDir::new();
+        let source = temp.join("source");
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("tiny.txt"), b"tiny").unwrap();
+        fs::write(source.join("large.txt"), vec![b'l'; 65]).unwrap();
+
+        let output = temp.join("result.img");
+        let summary = build_image(&Config {
+            source: Source::Directory(source),
+            output: output.clone(),
+            total_blocks: TEST_BLOCKS,
+            compress: false,
+            ..Config::default()
+        })
+        .unwrap();
+        assert_eq!(summary.inline, 1);
+
+        let filesystem =
+            Filesystem::mount_read_only(FileBlockDevice::open(&output).unwrap()).unwrap();
+        let metadata = filesystem.stat("/tiny.txt").unwrap();
+        assert!(metadata.inline);
+        assert_eq!(metadata.allocated_blocks, 0);
+        assert_eq!(filesystem.read_file("/tiny.txt").unwrap(), "tiny");
+        let metadata = filesystem.stat("/large.txt").unwrap();
+        assert!(!metadata.inline);
+        assert_eq!(metadata.allocated_blocks, 1);
+    }
+
+    #[test]
+    fn compresses_large_files_when_requested() {
+        let temp = TempDir::new();
+        let source = temp.join("source");
+        fs::create_dir(&source).unwrap();
+        let text = "key = value\n".repeat(200);
+        fs::write(source.join("config.txt"), &text).unwrap();
+        fs::write(source.join("small.txt"), "key = value\n".repeat(50)).unwrap();
+        let mut sparse = vec![0u8; 3 * BLOCK_SIZE];
+        sparse[0] = 1;
+        fs::write(source.join("sparse.bin"), &sparse).unwrap();
+
+        let output = temp.join("result.img");
+        let summary = build_image(&Config {
+            source: Source::Directory(source),
+            output: output.clone(),
+            total_blocks: TEST_BLOCKS,
+            compress: true,
+            ..Config::default()
+        })
+        .unwrap();
+        assert_eq!(summary.compressed, 2);
+        assert_eq!(summary.holes, 0);
+
+        let filesystem =
+            Filesystem::mount_read_only(FileBlockDevice::open(&output).unwrap()).unwrap();
+        let metadata = filesystem.stat("/config.txt").unwrap();
+        assert!(metadata.compressed);
+        assert_eq!(metadata.size, text.len());
+        assert_eq!(filesystem.read_file("/config.txt").unwrap(), text);
+        assert!(!filesystem.stat("/small.txt").unwrap().compressed);
+        assert_eq!(filesystem.read_bytes("/sparse.bin").unwrap(), sparse);
+    }
+
+    #[test]
+    fn parses_resize() {
+        assert_eq!(
+            Command::parse(strings(&["resize", "disk.img", "--blocks", "4096"])).unwrap(),
+            Command::Resize(ResizeConfig {
+                image: "disk.img".into(),
+                total_blocks: Some(4096),
+            })
+        );
+        assert_eq!(
+            Command::parse(strings(&["resize", "disk.img"])).unwrap(),
+            Command::Resize(ResizeConfig {
+                image: "disk.img".into(),
+                total_blocks: None,
+            })
+        );
+        assert!(Command::parse(strings(&["resize"])).is_err());
+        assert!(Command::parse(strings(&["resize", "a.img", "b.img"])).is_err());
+        assert!(Command::parse(strings(&["resize", "a.img", "--compress"])).is_err());
+    }
+
+    #[test]
+    fn resize_grows_image_and_keeps_files() {
+        let temp = TempDir::new();
+        let source = temp.join("source");
+        fs::create_dir(&source).unwrap();
+        let contents = vec![b'r'; 3 * BLOCK_SIZE];
+        fs::write(source.join("file.bin"), &contents).unwrap();
+        let output = temp.join("result.img");
+        build_image(&Config {
+            source: Source::Directory(source),
+            output: output.clone(),
+            total_blocks: TEST_BLOCKS,
+            compress: false,
+            ..Config::default()
+        })
+        .unwrap();
+
+        let summary = resize_image(&ResizeConfig {
+            image: output.clone(),
+            total_blocks: Some(4 * TEST_BLOCKS),
+        })
+        .unwrap();
+        assert_eq!(
+            summary,
+            ResizeSummary {
+                old_blocks: TEST_BLOCKS,
+                new_blocks: 4 * TEST_BLOCKS,
+            }
+        );
+
+        // Growing to the file size after an external `truncate -s`.
+        let file = OpenOptions::new().write(true).open(&output).unwrap();
+        file.set_len((5 * TEST_BLOCKS * BLOCK_SIZE) as u64).unwrap();
+        let summary = resize_image(&ResizeConfig {
+            image: output.clone(),
+            total_blocks: None,
+        })
+        .unwrap();
+        assert_eq!(summary.new_blocks, 5 * TEST_BLOCKS);
+
+        let filesystem =
+            Filesystem::mount_read_only(FileBlockDevice::open(&output).unwrap()).unwrap();
+        assert!(filesystem.mounted_clean());
+        assert_eq!(filesystem.total_blocks(), 5 * TEST_BLOCKS);
+        assert_eq!(filesystem.read_bytes("/file.bin").unwrap(), contents);
+
+        assert!(
+            resize_image(&ResizeConfig {
+                image: output,
+                total_blocks: Some(TEST_BLOCKS),
+            })
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn parses_upgrade() {
+        assert_eq!(
+            Command::parse(strings(&["upgrade", "disk.img"])).unwrap(),
+            Command::Upgrade("disk.img".into())
+        );
+        assert!(Command::parse(strings(&["upgrade"])).is_err());
+        assert!(Command::parse(strings(&["upgrade", "a.img", "b.img"])).is_err());
+    }
+
+    #[test]
+    fn upgrade_converts_golden_v2_image() {
+        let temp = TempDir::new();
+        let image = temp.join("v2.img");
+        fs::copy(
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("../filesystem/tests/fixtures/v2.img"),
+            &image,
+        )
+        .unwrap();
+
+        assert!(Filesystem::new(FileBlockDevice::open(&image).unwrap()).is_err());
+        assert_eq!(upgrade_image(&image).unwrap(), 2);
+        assert!(Filesystem::new(FileBlockDevice::open(&image).unwrap()).is_ok());
+        assert!(upgrade_image(&temp.join("missing.img")).is_err());
+    }
+
+    #[test]
+    fn parses_convert() {
+        assert_eq!(
+            Command::parse(strings(&["convert", "disk.ext2", "--blocks", "2048"])).unwrap(),
+            Command::Convert(ConvertConfig {
+                image: "disk.ext2".into(),
+                output: DEFAULT_OUTPUT.into(),
+                total_blocks: 2048,
+                compress: false,
+            })
+        );
+        assert!(Command::parse(strings(&["convert"])).is_err());
+        assert!(Command::parse(strings(&["convert", "a.img", "b.img"])).is_err());
+        assert!(Command::parse(strings(&["convert", "a.img", "--output"])).is_err());
+    }
+
+    #[test]
+    fn convert_copies_files_from_an_ext2_image() {
+        let temp = TempDir::new();
+        let output = temp.join("converted.img");
+        let summary = convert_image(&ConvertConfig {
+            image: Path::new(env!("CARGO_MANIFEST_DIR")).join("../ext2/tests/fixtures/tree.img"),
+            output: output.clone(),
+            total_blocks: TEST_BLOCKS,
+            compress: false,
+        })
+        .unwrap();
+
+        assert_eq!(summary.directories, 2);
+        assert_eq!(summary.files, 3);
+        assert_eq!(summary.hard_links, 1);
+        assert_eq!(summary.skipped, [PathBuf::from("/docs/link")]);
+
+        let filesystem =
+            Filesystem::mount_read_only(FileBlockDevice::open(&output).unwrap()).unwrap();
+        assert_eq!(
+            filesystem.read_dir("/").unwrap(),
+            ["bin", "docs", "hello.txt"]
+        );
+        assert_eq!(
+            filesystem.read_bytes("/docs/hello.txt").unwrap(),
+            b"Hello from ext2!\n"
+        );
+        assert_eq!(
+            filesystem.read_bytes("/docs/readme.md").unwrap(),
+            b"# Docs\n\nConverted from an ext2 image.\n"
+        );
+
+        // Files too large for LemonFS fail the whole conversion.
+        let big = temp.join("big.img");
+        assert!(
+            convert_image(&ConvertConfig {
+                image: Path::new(env!("CARGO_MANIFEST_DIR")).join("../ext2/tests/fixtures/big.img"),
+                output: big.clone(),
+                total_blocks: TEST_BLOCKS,
+                compress: false,
+            })
+            .is_err()
+        );
+        assert!(!big.exists());
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::sync::atomic::{AtomicUsize, Ordering};
+    #[test]
+    fn convert_rejects_directory_loops() {
+        let temp = TempDir::new();
+        let mut image =
+            fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("../ext2/tests/fixtures/tree.img"))
+                .unwrap();
+        // Points the entry of /bin, a directory with a 3 byte name, back at
+        // the root directory.
+        let at = image
+            .windows(5)
+            .position(|entry| entry == b"\x03\x02bin")
+            .unwrap();
+        image[at - 6..at - 2].copy_from_slice(&2u32.to_le_bytes());
+        let looping = temp.join("looping.img");
+        fs::write(&looping, image).unwrap();
+
+        let error = convert_image(&ConvertConfig {
+            image: looping,
+            output: temp.join("converted.img"),
+            total_blocks: TEST_BLOCKS,
+            compress: false,
+        })
+        .unwrap_err();
+        assert!(error.to_string().contains("/bin is linked more than once"));
+    }
 
-    const TEST_BLOCKS: usize = 1024;
-    static NEXT_TEMP: AtomicUsize = AtomicUsize::new(0);
+    #[test]
+    fn parses_extract() {
+        assert_eq!(
+            Command::parse(strings(&["extract", "--dest", "out"])).unwrap(),
+            Command::Extract(ExtractConfig {
+                image: DEFAULT_OUTPUT.into(),
+                dest: "out".into(),
+            })
+        );
+        assert_eq!(
+            Command::parse(strings(&[
+                "extract", "--image", "disk.img", "--dest", "out"
+            ]))
+            .unwrap(),
+            Command::Extract(ExtractConfig {
+                image: "disk.img".into(),
+                dest: "out".into(),
+            })
+        );
+        assert!(Command::parse(strings(&["extract"])).is_err());
+        assert!(Command::parse(strings(&["extract", "--dest"])).is_err());
+        assert!(Command::parse(strings(&["extract", "disk.img", "--dest", "out"])).is_err());
+        assert!(Command::parse(strings(&["extract", "--dest", "a", "--dest", "b"])).is_err());
+    }
 
-    struct TempDir(PathBuf);
+    #[test]
+    fn extract_round_trips_an_imported_tree() {
+        let temp = TempDir::new();
+        let source = temp.join("source");
+        fs::create_dir_all(source.join("nested/deeper")).unwrap();
+        fs::create_dir(source.join("empty")).unwrap();
+        fs::write(source.join(".hidden"), b"secret").unwrap();
+        fs::write(source.join("nested/note.txt"), "lemon".repeat(140)).unwrap();
+        let mut sparse = vec![0; 3 * BLOCK_SIZE];
+        sparse.extend(b"end");
+        fs::write(source.join("nested/deeper/sparse.bin"), &sparse).unwrap();
+        fs::write(source.join("squeeze.txt"), "shark ".repeat(500)).unwrap();
+        fs::write(source.join("empty.txt"), b"").unwrap();
+
+        let image = temp.join("result.img");
+        let import = build_image(&Config {
+            source: Source::Directory(source.clone()),
+            output: image.clone(),
+            total_blocks: TEST_BLOCKS,
+            compress: true,
+            ..Config::default()
+        })
+        .unwrap();
+        assert_eq!(import.compressed, 2);
 
-    impl TempDir {
-        fn new() -> Self {
-            let id = NEXT_TEMP.fetch_add(1, Ordering::Relaxed);
-            let path = std::env::temp_dir()
-                .join(format!("lemon-shark-mkfs-test-{}-{id}", std::process::id()));
-            fs::create_dir(&path).unwrap();
-            Self(path)
-        }
+        let dest = temp.join("out/tree");
+        let summary = extract_image(&ExtractConfig {
+            image: image.clone(),
+            dest: dest.clone(),
+        })
+        .unwrap();
+        assert_eq!(summary.directories, import.directories);
+        assert_eq!(summary.files, import.files);
+        assert!(summary.collisions.is_empty());
+        assert!(summary.skipped.is_empty());
 
-        fn join(&self, path: impl AsRef<Path>) -> PathBuf {
-            self.0.join(path)
+        fn tree(root: &Path, directory: &Path, out: &mut Vec<(PathBuf, Option<Vec<u8>>)>) {
+            let mut entries: Vec<_> = fs::read_dir(directory)
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .collect();
+            entries.sort();
+            for path in entries {
+                let relative = path.strip_prefix(root).unwrap().to_path_buf();
+                if path.is_dir() {
+                    out.push((relative, None));
+                    tree(root, &path, out);
+                } else {
+                    out.push((relative, Some(fs::read(&path).unwrap())));
+                }
+            }
         }
+        let (mut imported, mut extracted) = (Vec::new(), Vec::new());
+        tree(&source, &source, &mut imported);
+        tree(&dest, &dest, &mut extracted);
+        assert_eq!(imported.len(), 8);
+        assert_eq!(imported, extracted);
+
+        // Importing the extracted tree again gives the same image.
+        let second = temp.join("second.img");
+        build_image(&Config {
+            source: Source::Directory(dest),
+            output: second.clone(),
+            total_blocks: TEST_BLOCKS,
+            compress: true,
+            ..Config::default()
+        })
+        .unwrap();
+        assert_eq!(fs::read(image).unwrap(), fs::read(second).unwrap());
     }
 
-    impl Drop for TempDir {
-        fn drop(&mut self) {
-            let _ = fs::remove_dir_all(&self.0);
-        }
-    }
+    #[test]
+    fn extract_reports_collisions_without_overwriting() {
+        let temp = TempDir::new();
+        let source = temp.join("source");
+        fs::create_dir_all(source.join("docs")).unwrap();
+        fs::create_dir(source.join("bin")).unwrap();
+        fs::write(source.join("docs/readme.txt"), b"from the image").unwrap();
+        fs::write(source.join("docs/new.txt"), b"new").unwrap();
+        fs::write(source.join("bin/tool"), b"tool").unwrap();
+        let image = temp.join("result.img");
+        build_image(&Config {
+            source: Source::Directory(source),
+            output: image.clone(),
+            total_blocks: TEST_BLOCKS,
+            compress: false,
+            ..Config::default()
+        })
+        .unwrap();
 
-    fn strings<'a>(args: &'a [&str]) -> impl Iterator<Item = OsString> + 'a {
-        args.iter().map(OsString::from)
+        let dest = temp.join("out");
+        fs::create_dir_all(dest.join("docs")).unwrap();
+        fs::write(dest.join("docs/readme.txt"), b"edited on the host").unwrap();
+        fs::write(dest.join("bin"), b"not a directory").unwrap();
+
+        let mut summary = extract_image(&ExtractConfig {
+            image,
+            dest: dest.clone(),
+        })
+        .unwrap();
+        summary.collisions.sort();
+        assert_eq!(
+            summary.collisions,
+            [PathBuf::from("/bin"), PathBuf::from("/docs/readme.txt")]
+        );
+        assert_eq!((summary.directories, summary.files), (0, 1));
+
+        assert_eq!(fs::read(dest.join("docs/new.txt")).unwrap(), b"new");
+        assert_eq!(
+            fs::read(dest.join("docs/readme.txt")).unwrap(),
+            b"edited on the host"
+        );
+        assert_eq!(fs::read(dest.join("bin")).unwrap(), b"not a directory");
+        assert!(!is_host_name(".."));
+        assert!(!is_host_name("a\0b"));
     }
 
     #[test]
-    fn parses_defaults() {
+    fn parses_inspect() {
+        let inspect = |query, json| {
+            Command::Inspect(InspectConfig {
+                image: "disk.img".into(),
+                query,
+                json,
+            })
+        };
         assert_eq!(
-            Command::parse(std::iter::empty()).unwrap(),
-            Command::Build(Config::default())
+            Command::parse(strings(&["ls", "disk.img"])).unwrap(),
+            inspect(InspectQuery::Ls("/".into()), false)
+        );
+        assert_eq!(
+            Command::parse(strings(&["cat", "disk.img", "/a.txt", "--json"])).unwrap(),
+            inspect(InspectQuery::Cat("/a.txt".into()), true)
+        );
+        assert_eq!(
+            Command::parse(strings(&["inode", "--json", "disk.img", "12"])).unwrap(),
+            inspect(InspectQuery::INode(12), true)
+        );
+        assert_eq!(
+            Command::parse(strings(&["superblock", "disk.img"])).unwrap(),
+            inspect(InspectQuery::SuperBlock, false)
         );
+        assert!(Command::parse(strings(&["stat", "disk.img"])).is_err());
+        assert!(Command::parse(strings(&["inode", "disk.img", "root"])).is_err());
+        assert!(Command::parse(strings(&["superblock", "disk.img", "/"])).is_err());
+        assert!(Command::parse(strings(&["tree"])).is_err());
+        assert!(Command::parse(strings(&["ls", "disk.img", "--json", "--json"])).is_err());
     }
 
     #[test]
-    fn parses_named_options() {
+    fn inspect_reads_images_as_text_and_json() {
+        let temp = TempDir::new();
+        let source = temp.join("source");
+        fs::create_dir_all(source.join("docs")).unwrap();
+        fs::write(source.join("docs/readme.txt"), b"read \"me\"\n").unwrap();
+        fs::write(source.join("raw.bin"), [0xff; 600]).unwrap();
+        let image = temp.join("result.img");
+        build_image(&Config {
+            source: Source::Directory(source),
+            output: image.clone(),
+            total_blocks: TEST_BLOCKS,
+            compress: false,
+            ..Config::default()
+        })
+        .unwrap();
+
+        let inspect = |query, json| {
+            let output = inspect_image(&InspectConfig {
+                image: image.clone(),
+                query,
+                json,
+            })
+            .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
         assert_eq!(
-            Command::parse(strings(&[
-                "--source", "input", "--output", "disk.img", "--blocks", "2048",
-            ]))
-            .unwrap(),
-            Command::Build(Config {
-                source: "input".into(),
-                output: "disk.img".into(),
-                total_blocks: 2048,
+            inspect(InspectQuery::Cat("/docs/readme.txt".into()), false),
+            "read \"me\"\n"
+        );
+        assert_eq!(
+            inspect(InspectQuery::Cat("/docs/readme.txt".into()), true),
+            "{\"path\":\"/docs/readme.txt\",\"size\":10,\"text\":\"read \\\"me\\\"\\n\"}\n"
+        );
+        assert_eq!(
+            inspect(InspectQuery::Tree("/".into()), false),
+            "/\n  docs/\n    readme.txt\n  raw.bin\n"
+        );
+        assert_eq!(
+            inspect(InspectQuery::Ls("/docs".into()), true),
+            "[{\"name\":\"readme.txt\",\"inode\":2,\"directory\":false,\"size\":10}]\n"
+        );
+        let stat = inspect(InspectQuery::Stat("/raw.bin".into()), false);
+        assert!(stat.contains("size        600\n"));
+        assert!(stat.contains("blocks      2\n"));
+
+        let superblock = inspect(InspectQuery::SuperBlock, false);
+        assert!(superblock.contains(&format!("total blocks  {TEST_BLOCKS}\n")));
+        assert!(superblock.contains("state         clean\n"));
+        assert!(superblock.contains("problem       none\n"));
+        let superblock = inspect(InspectQuery::SuperBlock, true);
+        assert!(
+            superblock.contains("\"regions\":[{\"name\":\"superblock\",\"start\":0,\"blocks\":1},")
+        );
+
+        let inode = inspect(InspectQuery::INode(3), false);
+        assert!(inode.contains("allocated     yes\n"));
+        assert!(inode.contains("size          600\n"));
+        assert!(inode.contains("\n  0000  58 02 00 00 "));
+        let inode = inspect(InspectQuery::INode(3), true);
+        assert!(inode.contains("\"size\":600,\"inline\":false"));
+
+        // The superblock is still decoded when the image doesn't mount.
+        let mut bytes = fs::read(&image).unwrap();
+        bytes[12..16].copy_from_slice(&7u32.to_le_bytes());
+        fs::write(&image, bytes).unwrap();
+        assert!(
+            inspect(InspectQuery::SuperBlock, false).contains("problem       InvalidSuperblock\n")
+        );
+        assert!(
+            inspect_image(&InspectConfig {
+                image: image.clone(),
+                query: InspectQuery::Ls("/".into()),
+                json: false,
             })
+            .is_err()
         );
     }
 
     #[test]
-    fn parses_help_and_rejects_invalid_arguments() {
-        assert_eq!(Command::parse(strings(&["--help"])).unwrap(), Command::Help);
-        assert!(Command::parse(strings(&["--wat"])).is_err());
-        assert!(Command::parse(strings(&["--source"])).is_err());
-        assert!(Command::parse(strings(&["--blocks", "zero"])).is_err());
-        assert!(Command::parse(strings(&["--blocks", "0"])).is_err());
-        assert!(Command::parse(strings(&["--output", "one", "--output", "two"])).is_err());
+    fn parses_update() {
+        assert_eq!(
+            Command::parse(strings(&["add", "notes.txt", "/docs", "--compress"])).unwrap(),
+            Command::Update(UpdateConfig {
+                image: DEFAULT_OUTPUT.into(),
+                action: UpdateAction::Add {
+                    host: "notes.txt".into(),
+                    path: "/docs".into(),
+                },
+                compress: true,
+            })
+        );
+        assert_eq!(
+            Command::parse(strings(&["mkdir", "-p", "/a/b", "--image", "disk.img"])).unwrap(),
+            Command::Update(UpdateConfig {
+                image: "disk.img".into(),
+                action: UpdateAction::Mkdir {
+                    path: "/a/b".into(),
+                    parents: true,
+                },
+                compress: false,
+            })
+        );
+        assert_eq!(
+            Command::parse(strings(&["sync", "--source", "tree"])).unwrap(),
+            Command::Update(UpdateConfig {
+                image: DEFAULT_OUTPUT.into(),
+                action: UpdateAction::Sync("tree".into()),
+                compress: false,
+            })
+        );
+        assert!(Command::parse(strings(&["add", "notes.txt"])).is_err());
+        assert!(Command::parse(strings(&["rm"])).is_err());
+        assert!(Command::parse(strings(&["rm", "/a", "/b"])).is_err());
+        assert!(Command::parse(strings(&["rm", "-p", "/a"])).is_err());
+        assert!(Command::parse(strings(&["sync", "/a"])).is_err());
+        assert!(Command::parse(strings(&["mkdir", "--compress", "/a"])).is_err());
     }
 
     #[test]
-    fn imports_nested_tree_and_file_contents() {
+    fn add_mkdir_and_rm_change_an_image_in_place() {
         let temp = TempDir::new();
         let source = temp.join("source");
-        fs::create_dir(&source).unwrap();
-        fs::create_dir(source.join("empty")).unwrap();
-        fs::create_dir(source.join("nested")).unwrap();
-        fs::write(source.join(".hidden"), b"secret").unwrap();
-        fs::write(source.join("binary.dat"), [0, 159, 146, 150]).unwrap();
-        let long_contents = "lemon".repeat(140);
-        fs::write(source.join("nested/note.txt"), &long_contents).unwrap();
-
-        let output = temp.join("result.img");
-        let summary = build_image(&Config {
-            source,
-            output: output.clone(),
+        fs::create_dir_all(source.join("docs")).unwrap();
+        fs::write(source.join("docs/readme.txt"), b"old").unwrap();
+        let image = temp.join("result.img");
+        build_image(&Config {
+            source: Source::Directory(source),
+            output: image.clone(),
             total_blocks: TEST_BLOCKS,
+            compress: false,
+            ..Config::default()
         })
         .unwrap();
 
-        assert_eq!(summary.directories, 2);
-        assert_eq!(summary.files, 3);
-        assert!(summary.skipped.is_empty());
+        let update = |action| {
+            update_image(&UpdateConfig {
+                image: image.clone(),
+                action,
+                compress: false,
+            })
+        };
 
-        let mut filesystem = Filesystem::new(FileBlockDevice::open(&output).unwrap()).unwrap();
-        assert_eq!(filesystem.read_file("/.hidden").unwrap(), "secret");
+        let summary = update(UpdateAction::Mkdir {
+            path: "/a/b".into(),
+            parents: true,
+        })
+        .unwrap();
+        assert_eq!(summary.written.directories, 2);
+        assert!(
+            update(UpdateAction::Mkdir {
+                path: "/a".into(),
+                parents: false,
+            })
+            .is_err()
+        );
+
+        // A file added to a directory keeps its name.
+        let notes = temp.join("notes.txt");
+        fs::write(&notes, b"notes").unwrap();
+        let summary = update(UpdateAction::Add {
+            host: notes,
+            path: "/a/b".into(),
+        })
+        .unwrap();
+        assert_eq!((summary.written.files, summary.updated), (1, 0));
+
+        let tree = temp.join("tree");
+        fs::create_dir_all(tree.join("nested")).unwrap();
+        fs::write(tree.join("readme.txt"), b"new").unwrap();
+        fs::write(tree.join("nested/more.txt"), b"more").unwrap();
+        let summary = update(UpdateAction::Add {
+            host: tree,
+            path: "/docs".into(),
+        })
+        .unwrap();
+        assert_eq!(summary.written.directories, 1);
+        assert_eq!((summary.written.files, summary.updated), (2, 1));
+        assert_eq!(summary.unchanged, 1);
+
+        let summary = update(UpdateAction::Remove("/docs/nested/more.txt".into())).unwrap();
+        assert_eq!(summary.removed, 1);
+        assert!(update(UpdateAction::Remove("/docs/nested".into())).is_err());
+        assert!(update(UpdateAction::Remove("/missing".into())).is_err());
+
+        let filesystem =
+            Filesystem::mount_read_only(FileBlockDevice::open(&image).unwrap()).unwrap();
+        assert!(filesystem.mounted_clean());
+        assert_eq!(filesystem.read_file("/a/b/notes.txt").unwrap(), "notes");
+        assert_eq!(filesystem.read_file("/docs/readme.txt").unwrap(), "new");
         assert_eq!(
-            filesystem.read_file("/nested/note.txt").unwrap(),
-            long_contents
+            filesystem.read_dir("/docs/nested").unwrap(),
+            Vec::<String>::new()
         );
-        let mut listing = String::new();
-        filesystem.dump_dir("/empty", &mut listing).unwrap();
-        assert!(listing.contains("./"));
-        assert!(listing.contains("../"));
+    }
 
-        let second_output = temp.join("second.img");
+    #[test]
+    fn sync_only_rewrites_changed_files() {
+        let temp = TempDir::new();
+        let source = temp.join("source");
+        fs::create_dir_all(source.join("docs")).unwrap();
+        fs::write(source.join("docs/same.txt"), "same ".repeat(200)).unwrap();
+        fs::write(source.join("docs/edit.txt"), b"before").unwrap();
+        fs::write(source.join("shrink.bin"), vec![9; 3 * BLOCK_SIZE]).unwrap();
+        let image = temp.join("result.img");
         build_image(&Config {
-            source: temp.join("source"),
-            output: second_output.clone(),
+            source: Source::Directory(source.clone()),
+            output: image.clone(),
             total_blocks: TEST_BLOCKS,
+            compress: false,
+            ..Config::default()
         })
         .unwrap();
-        assert_eq!(fs::read(output).unwrap(), fs::read(second_output).unwrap());
+
+        // Files the kernel wrote only exist in the image.
+        {
+            let filesystem = Filesystem::new(FileBlockDevice::open(&image).unwrap()).unwrap();
+            filesystem.create_file("/kernel.log").unwrap();
+            filesystem.write_to_file("/kernel.log", b"boot").unwrap();
+            drop(filesystem.unmount());
+        }
+
+        fs::write(source.join("docs/edit.txt"), b"after").unwrap();
+        fs::write(source.join("shrink.bin"), [1; 10]).unwrap();
+        fs::create_dir(source.join("new")).unwrap();
+        fs::write(source.join("new/file.txt"), b"new").unwrap();
+        fs::create_dir(source.join("kernel.log")).unwrap();
+
+        let sync = || {
+            update_image(&UpdateConfig {
+                image: image.clone(),
+                action: UpdateAction::Sync(source.clone()),
+                compress: false,
+            })
+            .unwrap()
+        };
+        let before = temp.join("before.img");
+        fs::copy(&image, &before).unwrap();
+        let summary = sync();
+        assert_eq!(summary.written.directories, 1);
+        assert_eq!((summary.written.files, summary.updated), (3, 2));
+        assert_eq!(summary.unchanged, 2);
+        assert_eq!(summary.conflicts, ["/kernel.log"]);
+
+        let filesystem =
+            Filesystem::mount_read_only(FileBlockDevice::open(&image).unwrap()).unwrap();
+        assert_eq!(filesystem.read_file("/docs/edit.txt").unwrap(), "after");
+        assert_eq!(filesystem.read_bytes("/shrink.bin").unwrap(), [1; 10]);
+        assert_eq!(filesystem.read_file("/new/file.txt").unwrap(), "new");
+        assert_eq!(filesystem.read_file("/kernel.log").unwrap(), "boot");
+
+        // The unchanged file kept its blocks.
+        let same = filesystem.stat("/docs/same.txt").unwrap().inode;
+        let blocks = |image: &Path| {
+            let mut device = FileBlockDevice::open_read_only(image).unwrap();
+            Filesystem::inspect_inode(&mut device, same).unwrap().blocks
+        };
+        assert_eq!(blocks(&before), blocks(&image));
+
+        // A second sync finds nothing to do.
+        let summary = sync();
+        assert_eq!(summary.written, ImportSummary::default());
+        assert_eq!(summary.unchanged, 6);
     }
 
-    #[cfg(unix)]
     #[test]
-    fn skips_symlinks_without_following_them() {
-        use std::os::unix::fs::symlink;
+    fn parses_snapshot() {
+        assert_eq!(
+            Command::parse(strings(&["snapshot", "disk.img", "create", "clean"])).unwrap(),
+            Command::Snapshot(SnapshotConfig {
+                image: "disk.img".into(),
+                action: SnapshotAction::Create("clean".into()),
+            })
+        );
+        assert_eq!(
+            Command::parse(strings(&["snapshot", "disk.img", "list"])).unwrap(),
+            Command::Snapshot(SnapshotConfig {
+                image: "disk.img".into(),
+                action: SnapshotAction::List,
+            })
+        );
+        assert!(Command::parse(strings(&["snapshot"])).is_err());
+        assert!(Command::parse(strings(&["snapshot", "disk.img"])).is_err());
+        assert!(Command::parse(strings(&["snapshot", "disk.img", "create"])).is_err());
+        assert!(Command::parse(strings(&["snapshot", "disk.img", "list", "x"])).is_err());
+        assert!(Command::parse(strings(&["snapshot", "disk.img", "undo", "x"])).is_err());
+        assert!(Command::parse(strings(&["snapshot", "disk.img", "delete", "a", "b"])).is_err());
+    }
+
+    #[test]
+    fn list_walks_the_image_and_filters_by_glob() {
+        assert_eq!(
+            Command::parse(strings(&["list", "disk.img", "**/*.txt"])).unwrap(),
+            Command::List(ListConfig {
+                image: "disk.img".into(),
+                pattern: Some("**/*.txt".into()),
+            })
+        );
+        assert!(Command::parse(strings(&["list"])).is_err());
+        assert!(Command::parse(strings(&["list", "disk.img", "a", "b"])).is_err());
 
         let temp = TempDir::new();
         let source = temp.join("source");
-        fs::create_dir(&source).unwrap();
-        fs::write(source.join("real.txt"), b"real").unwrap();
-        symlink("real.txt", source.join("link.txt")).unwrap();
+        fs::create_dir_all(source.join("docs")).unwrap();
+        fs::write(source.join("docs/readme.txt"), b"read me").unwrap();
+        fs::write(source.join("notes.txt"), b"notes").unwrap();
+        fs::write(source.join("run.sh"), b"run").unwrap();
+        let output = temp.join("result.img");
+        build_image(&Config {
+            source: Source::Directory(source),
+            output: output.clone(),
+            total_blocks: TEST_BLOCKS,
+            compress: false,
+            ..Config::default()
+        })
+        .unwrap();
+
+        let list = |pattern: Option<&str>| -> Vec<String> {
+            list_image(&ListConfig {
+                image: output.clone(),
+                pattern: pattern.map(String::from),
+            })
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.path)
+            .collect()
+        };
+
+        let mut all = list(None);
+        all.sort();
+        assert_eq!(
+            all,
+            ["/", "/docs", "/docs/readme.txt", "/notes.txt", "/run.sh"]
+        );
+        let mut texts = list(Some("**/*.txt"));
+        texts.sort();
+        assert_eq!(texts, ["/docs/readme.txt", "/notes.txt"]);
+        assert_eq!(list(Some("/*.sh")), ["/run.sh"]);
+    }
 
+    #[test]
+    fn snapshot_rollback_restores_imported_files() {
+        let temp = TempDir::new();
+        let source = temp.join("source");
+        fs::create_dir(&source).unwrap();
+        let contents = vec![b's'; 3 * BLOCK_SIZE];
+        fs::write(source.join("file.bin"), &contents).unwrap();
         let output = temp.join("result.img");
-        let summary = build_image(&Config {
-            source,
+        build_image(&Config {
+            source: Source::Directory(source),
             output: output.clone(),
             total_blocks: TEST_BLOCKS,
+            compress: false,
+            ..Config::default()
         })
         .unwrap();
 
-        assert_eq!(summary.files, 1);
-        assert_eq!(summary.skipped.len(), 1);
-        let mut filesystem = Filesystem::new(FileBlockDevice::open(&output).unwrap()).unwrap();
-        assert_eq!(filesystem.read_file("/real.txt").unwrap(), "real");
-        assert!(filesystem.read_file("/link.txt").is_err());
+        let run = |action| {
+            snapshot_image(&SnapshotConfig {
+                image: output.clone(),
+                action,
+            })
+        };
+
+        let snapshots = run(SnapshotAction::Create("clean".into())).unwrap();
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].name, "clean");
+
+        let filesystem = Filesystem::new(FileBlockDevice::open(&output).unwrap()).unwrap();
+        filesystem.remove_dir_entry("/file.bin").unwrap();
+        filesystem.create_file("/new.txt").unwrap();
+        drop(filesystem.unmount());
+
+        run(SnapshotAction::Rollback("clean".into())).unwrap();
+        let filesystem =
+            Filesystem::mount_read_only(FileBlockDevice::open(&output).unwrap()).unwrap();
+        assert_eq!(filesystem.read_bytes("/file.bin").unwrap(), contents);
+        assert!(filesystem.stat("/new.txt").is_err());
+
+        assert!(run(SnapshotAction::Create("clean".into())).is_err());
+        assert!(
+            run(SnapshotAction::Delete("clean".into()))
+                .unwrap()
+                .is_empty()
+        );
+        assert!(run(SnapshotAction::List).unwrap().is_empty());
+        assert!(run(SnapshotAction::Rollback("clean".into())).is_err());
     }
 
     #[test]
@@ -563,9 +3241,11 @@ mod tests {
         fs::write(&output, b"keep me").unwrap();
 
         let error = build_image(&Config {
-            source,
+            source: Source::Directory(source),
             output: output.clone(),
             total_blocks: TEST_BLOCKS,
+            compress: false,
+            ..Config::default()
         })
         .unwrap_err();
 
@@ -577,9 +3257,11 @@ mod tests {
     fn rejects_missing_source_and_long_names() {
         let temp = TempDir::new();
         let missing = build_image(&Config {
-            source: temp.join("missing"),
+            source: Source::Directory(temp.join("missing")),
             output: temp.join("missing.img"),
             total_blocks: TEST_BLOCKS,
+            compress: false,
+            ..Config::default()
         });
         assert!(missing.is_err());
 
@@ -587,9 +3269,11 @@ mod tests {
         fs::create_dir(&source).unwrap();
         fs::write(source.join("this-name-is-more-than-24-bytes"), b"x").unwrap();
         let error = build_image(&Config {
-            source,
+            source: Source::Directory(source),
             output: temp.join("long-name.img"),
             total_blocks: TEST_BLOCKS,
+            compress: false,
+            ..Config::default()
         })
         .unwrap_err();
         assert!(error.to_string().contains("NameTooLong"));
@@ -602,8 +3286,10 @@ mod tests {
         fs::create_dir(&source).unwrap();
         let error = build_image(&Config {
             output: source.join("lemonfs.img"),
-            source,
+            source: Source::Directory(source),
             total_blocks: TEST_BLOCKS,
+            compress: false,
+            ..Config::default()
         })
         .unwrap_err();
         assert!(error.to_string().contains("cannot be inside source"));
@@ -615,11 +3301,117 @@ mod tests {
         let source = temp.join("source");
         fs::create_dir(&source).unwrap();
         let error = build_image(&Config {
-            source,
+            source: Source::Directory(source),
             output: temp.join("small.img"),
             total_blocks: 1,
+            compress: false,
+            ..Config::default()
         })
         .unwrap_err();
         assert!(error.to_string().contains("DeviceTooSmall"));
     }
+
+    #[test]
+    fn parses_reproducibility_options_and_verify() {
+        assert_eq!(
+            Command::parse(strings(&[
+                "--source-date-epoch",
+                "1700000000",
+                "--print-digest",
+            ]))
+            .unwrap(),
+            Command::Build(Config {
+                source_date_epoch: Some(1_700_000_000),
+                print_digest: true,
+                ..Config::default()
+            })
+        );
+        assert!(Command::parse(strings(&["--source-date-epoch", "yesterday"])).is_err());
+        assert!(Command::parse(strings(&["--print-digest", "--print-digest"])).is_err());
+
+        assert_eq!(
+            Command::parse(strings(&["verify"])).unwrap(),
+            Command::Verify(VerifyConfig {
+                image: DEFAULT_OUTPUT.into(),
+                source: DEFAULT_SOURCE.into(),
+            })
+        );
+        assert_eq!(
+            Command::parse(strings(&[
+                "verify", "--image", "disk.img", "--source", "tree"
+            ]))
+            .unwrap(),
+            Command::Verify(VerifyConfig {
+                image: "disk.img".into(),
+                source: "tree".into(),
+            })
+        );
+        assert!(Command::parse(strings(&["verify", "tree"])).is_err());
+    }
+
+    #[test]
+    fn identical_trees_give_identical_images_and_verify() {
+        let temp = TempDir::new();
+        let first = temp.join("first");
+        let second = temp.join("second");
+        let files: [(&str, Vec<u8>); 4] = [
+            ("docs/readme.txt", b"read me".to_vec()),
+            ("docs/long.txt", "lemon shark ".repeat(300).into_bytes()),
+            ("bin/tool", (0..3000).map(|i| (i * 7 % 251) as u8).collect()),
+            ("empty.txt", Vec::new()),
+        ];
+        for (root, order) in [(&first, [0, 1, 2, 3]), (&second, [3, 2, 1, 0])] {
+            for index in order {
+                let (path, contents) = &files[index];
+                let path = root.join(path);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, contents).unwrap();
+            }
+        }
+
+        let build = |source: &Path, output: &Path| {
+            build_image(&Config {
+                source: Source::Directory(source.into()),
+                output: output.into(),
+                total_blocks: TEST_BLOCKS,
+                compress: true,
+                ..Config::default()
+            })
+            .unwrap();
+        };
+        build(&first, &temp.join("first.img"));
+        build(&second, &temp.join("second.img"));
+        assert_eq!(
+            fs::read(temp.join("first.img")).unwrap(),
+            fs::read(temp.join("second.img")).unwrap()
+        );
+        assert_eq!(
+            image_digest(&temp.join("first.img")).unwrap(),
+            image_digest(&temp.join("second.img")).unwrap()
+        );
+
+        let verify = |source: &Path| {
+            verify_image(&VerifyConfig {
+                image: temp.join("first.img"),
+                source: source.into(),
+            })
+            .unwrap()
+        };
+        assert_eq!(verify(&second), []);
+
+        fs::write(second.join("docs/readme.txt"), b"read me too").unwrap();
+        fs::remove_file(second.join("bin/tool")).unwrap();
+        fs::create_dir(second.join("bin/tool")).unwrap();
+        fs::remove_file(second.join("empty.txt")).unwrap();
+        fs::write(second.join("new.txt"), b"new").unwrap();
+        assert_eq!(
+            verify(&second),
+            [
+                Difference::Kind("/bin/tool".into()),
+                Difference::Content("/docs/readme.txt".into()),
+                Difference::Extra("/empty.txt".into()),
+                Difference::Missing("/new.txt".into()),
+            ]
+        );
+    }
 }
@@ -1,4 +1,10 @@
-use mkfs::{Command, USAGE};
+use std::io::Write;
+use std::path::Path;
+
+use mkfs::{
+    Command, ConvertConfig, Difference, ExtractConfig, InspectConfig, ListConfig, ResizeConfig,
+    SnapshotAction, SnapshotConfig, USAGE, UpdateAction, UpdateConfig, VerifyConfig,
+};
 
 fn main() {
     let command = match Command::parse(std::env::args_os().skip(1)) {
@@ -9,9 +15,21 @@ fn main() {
         }
     };
 
-    let Command::Build(config) = command else {
-        println!("{USAGE}");
-        return;
+    let config = match command {
+        Command::Build(config) => config,
+        Command::Resize(config) => return resize(&config),
+        Command::Upgrade(image) => return upgrade(&image),
+        Command::Snapshot(config) => return snapshot(&config),
+        Command::List(config) => return list(&config),
+        Command::Convert(config) => return convert(&config),
+        Command::Extract(config) => return extract(&config),
+        Command::Inspect(config) => return inspect(&config),
+        Command::Update(config) => return update(&config),
+        Command::Verify(config) => return verify(&config),
+        Command::Help => {
+            println!("{USAGE}");
+            return;
+        }
     };
 
     match mkfs::build_image(&config) {
@@ -21,12 +39,16 @@ fn main() {
             }
 
             println!(
-                "Created {} from {} ({} directories, {} files, {} skipped; {} blocks, {} bytes)",
+                "Created {} from {} ({} directories, {} files, {} skipped, {} holes, {} inline, {} compressed, {} xattrs; {} blocks, {} bytes)",
                 config.output.display(),
-                config.source.display(),
+                config.source.path().display(),
                 summary.directories,
                 summary.files,
                 summary.skipped.len(),
+                summary.holes,
+                summary.inline,
+                summary.compressed,
+                summary.xattrs,
                 config.total_blocks,
                 config.total_blocks * filesystem::BLOCK_SIZE,
             );
@@ -36,4 +58,230 @@ fn main() {
             std::process::exit(1);
         }
     }
+
+    if config.print_digest {
+        match mkfs::image_digest(&config.output) {
+            Ok(digest) => println!("{digest}  {}", config.output.display()),
+            Err(error) => {
+                eprintln!("error: {error}");
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+fn resize(config: &ResizeConfig) {
+    match mkfs::resize_image(config) {
+        Ok(summary) => println!(
+            "Resized {} from {} to {} blocks ({} bytes)",
+            config.image.display(),
+            summary.old_blocks,
+            summary.new_blocks,
+            summary.new_blocks * filesystem::BLOCK_SIZE,
+        ),
+        Err(error) => {
+            eprintln!("error: {error}");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn upgrade(image: &Path) {
+    match mkfs::upgrade_image(image) {
+        Ok(version) => println!(
+            "Upgraded {} from format version {version} to the current version",
+            image.display()
+        ),
+        Err(error) => {
+            eprintln!("error: {error}");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn snapshot(config: &SnapshotConfig) {
+    let snapshots = match mkfs::snapshot_image(config) {
+        Ok(snapshots) => snapshots,
+        Err(error) => {
+            eprintln!("error: {error}");
+            std::process::exit(1);
+        }
+    };
+
+    match &config.action {
+        SnapshotAction::Create(name) => println!("Created snapshot {name:?}"),
+        SnapshotAction::Delete(name) => println!("Deleted snapshot {name:?}"),
+        SnapshotAction::Rollback(name) => println!("Rolled back to snapshot {name:?}"),
+        SnapshotAction::List => {
+            for snapshot in snapshots {
+                println!("{:<24}  {:>5} inodes", snapshot.name, snapshot.inodes);
+            }
+        }
+    }
+}
+
+fn list(config: &ListConfig) {
+    let entries = match mkfs::list_image(config) {
+        Ok(entries) => entries,
+        Err(error) => {
+            eprintln!("error: {error}");
+            std::process::exit(1);
+        }
+    };
+
+    for entry in entries {
+        let kind = if entry.metadata.is_directory {
+            'd'
+        } else {
+            '-'
+        };
+        println!("{kind} {:>8}  {}", entry.metadata.size, entry.path);
+    }
+}
+
+fn convert(config: &ConvertConfig) {
+    match mkfs::convert_image(config) {
+        Ok(summary) => {
+            for path in &summary.skipped {
+                eprintln!("warning: skipping unsupported entry {}", path.display());
+            }
+
+            println!(
+                "Converted {} to {} ({} directories, {} files, {} hard links copied, {} skipped; {} blocks, {} bytes)",
+                config.image.display(),
+                config.output.display(),
+                summary.directories,
+                summary.files,
+                summary.hard_links,
+                summary.skipped.len(),
+                config.total_blocks,
+                config.total_blocks * filesystem::BLOCK_SIZE,
+            );
+        }
+        Err(error) => {
+            eprintln!("error: {error}");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn extract(config: &ExtractConfig) {
+    match mkfs::extract_image(config) {
+        Ok(summary) => {
+            for path in &summary.collisions {
+                eprintln!(
+                    "warning: {} already exists, not overwriting it",
+                    path.display()
+                );
+            }
+            for path in &summary.skipped {
+                eprintln!(
+                    "warning: skipping entry {} with an unusable name",
+                    path.display()
+                );
+            }
+
+            println!(
+                "Extracted {} into {} ({} directories, {} files, {} collisions, {} skipped)",
+                config.image.display(),
+                config.dest.display(),
+                summary.directories,
+                summary.files,
+                summary.collisions.len(),
+                summary.skipped.len(),
+            );
+        }
+        Err(error) => {
+            eprintln!("error: {error}");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn inspect(config: &InspectConfig) {
+    let output = match mkfs::inspect_image(config) {
+        Ok(output) => output,
+        Err(error) => {
+            eprintln!("error: {error}");
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(error) = std::io::stdout().write_all(&output) {
+        eprintln!("error: write output: {error}");
+        std::process::exit(1);
+    }
+}
+
+fn update(config: &UpdateConfig) {
+    let summary = match mkfs::update_image(config) {
+        Ok(summary) => summary,
+        Err(error) => {
+            eprintln!("error: {error}");
+            std::process::exit(1);
+        }
+    };
+
+    match &config.action {
+        UpdateAction::Remove(path) => println!("Removed {path} from {}", config.image.display()),
+        UpdateAction::Mkdir { path, .. } => {
+            println!("Created {path} in {}", config.image.display())
+        }
+        UpdateAction::Add { .. } | UpdateAction::Sync(_) => {
+            for path in &summary.written.skipped {
+                eprintln!("warning: skipping unsupported entry {}", path.display());
+            }
+            for path in &summary.conflicts {
+                eprintln!("warning: not replacing {path}, file and directory don't match");
+            }
+
+            println!(
+                "Updated {} ({} directories created, {} files written of which {} replaced, {} unchanged, {} xattrs, {} skipped, {} conflicts)",
+                config.image.display(),
+                summary.written.directories,
+                summary.written.files,
+                summary.updated,
+                summary.unchanged,
+                summary.written.xattrs,
+                summary.written.skipped.len(),
+                summary.conflicts.len(),
+            );
+        }
+    }
+}
+
+fn verify(config: &VerifyConfig) {
+    let differences = match mkfs::verify_image(config) {
+        Ok(differences) => differences,
+        Err(error) => {
+            eprintln!("error: {error}");
+            std::process::exit(1);
+        }
+    };
+
+    for difference in &differences {
+        match difference {
+            Difference::Missing(path) => println!("missing from image: {path}"),
+            Difference::Extra(path) => println!("only in image: {path}"),
+            Difference::Kind(path) => println!("file and directory don't match: {path}"),
+            Difference::Content(path) => println!("content differs: {path}"),
+            Difference::Xattrs(path) => println!("extended attributes differ: {path}"),
+        }
+    }
+
+    if differences.is_empty() {
+        println!(
+            "{} matches {}",
+            config.image.display(),
+            config.source.display()
+        );
+    } else {
+        println!(
+            "{} differs from {} in {} entries",
+            config.image.display(),
+            config.source.display(),
+            differences.len()
+        );
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,723 @@
+//! Image descriptions in a small subset of TOML, see `Manifest::load`.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use filesystem::{Filesystem, MAX_NAME_LEN, MAX_XATTR_NAME, MAX_XATTR_VALUE, MAX_XATTRS};
+
+use crate::{
+    BuildError, FileBlockDevice, ImportSummary, MAX_FILE_SIZE, create_directories,
+    import_directory, import_xattrs, io_error, write_contents,
+};
+
+/// A message and the manifest line it's about.
+type ManifestError = (usize, String);
+
+/// Extended attribute names and values in manifest order.
+type Attributes = Vec<(String, Vec<u8>)>;
+
+/// The entries of an image in the order they're created.
+///
+/// ```toml
+/// [[directory]]
+/// destination = "/etc"
+/// attributes = { "user.owner" = "root" }
+///
+/// [[directory]]
+/// source = "rootfs/docs"  # a host tree, relative to the manifest
+/// destination = "/docs"
+///
+/// [[file]]
+/// source = "build/init"
+/// destination = "/bin/init"
+///
+/// [[file]]
+/// destination = "/etc/motd"
+/// content = "Welcome to lemon shark\n"
+/// ```
+///
+/// Missing parent directories are created. Tables and keys LemonFS can't
+/// represent, `[[symlink]]` and `mode`, are rejected rather than ignored.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Manifest {
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Entry {
+    /// Line of the `[[...]]` header, for errors while building.
+    line: usize,
+    destination: String,
+    kind: EntryKind,
+    attributes: Attributes,
+}
+
+#[derive(Debug, PartialEq)]
+enum EntryKind {
+    File(Content),
+
+    /// A directory, with the host tree to import into it.
+    Directory(Option<PathBuf>),
+}
+
+#[derive(Debug, PartialEq)]
+enum Content {
+    Source(PathBuf),
+    Inline(Vec<u8>),
+}
+
+impl Manifest {
+    /// Reads and validates the manifest at `path`. Sources are resolved
+    /// relative to its directory and have to exist.
+    pub(crate) fn load(path: &Path) -> Result<Self, BuildError> {
+        let text =
+            fs::read_to_string(path).map_err(|error| io_error("read manifest", path, error))?;
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        Self::parse(&text, base).map_err(|(line, message)| {
+            BuildError::new(format!("{}:{line}: {message}", path.display()))
+        })
+    }
+
+    /// Directory trees imported by the manifest.
+    pub(crate) fn source_directories(&self) -> impl Iterator<Item = &Path> {
+        self.entries.iter().filter_map(|entry| match &entry.kind {
+            EntryKind::Directory(source) => source.as_deref(),
+            EntryKind::File(_) => None,
+        })
+    }
+
+    fn parse(text: &str, base: &Path) -> Result<Self, ManifestError> {
+        let mut entries = Vec::new();
+        // Every directory the entries create, including missing parents,
+        // with the line of the first entry creating it.
+        let mut directories: HashMap<String, usize> = HashMap::new();
+        for table in parse_tables(text)? {
+            let entry = Entry::from_table(table, base)?;
+            if let Some(earlier) = entries
+                .iter()
+                .find(|earlier: &&Entry| earlier.destination == entry.destination)
+            {
+                return Err((
+                    entry.line,
+                    format!(
+                        "{} is already created on line {}",
+                        entry.destination, earlier.line
+                    ),
+                ));
+            }
+            if let Some(file) = entries.iter().find(|earlier: &&Entry| {
+                matches!(earlier.kind, EntryKind::File(_))
+                    && entry
+                        .destination
+                        .strip_prefix(&earlier.destination)
+                        .is_some_and(|rest| rest.starts_with('/'))
+            }) {
+                return Err((
+                    entry.line,
+                    format!(
+                        "{} is a file, created on line {}",
+                        file.destination, file.line
+                    ),
+                ));
+            }
+            if let (EntryKind::File(_), Some(line)) =
+                (&entry.kind, directories.get(&entry.destination))
+            {
+                return Err((
+                    entry.line,
+                    format!(
+                        "{} is a directory, created on line {line}",
+                        entry.destination
+                    ),
+                ));
+            }
+
+            let mut path = entry.destination.as_str();
+            if let EntryKind::File(_) = entry.kind {
+                path = path.rsplit_once('/').unwrap().0;
+            }
+            while !path.is_empty() {
+                directories.entry(path.into()).or_insert(entry.line);
+                path = path.rsplit_once('/').unwrap().0;
+            }
+            entries.push(entry);
+        }
+        Ok(Self { entries })
+    }
+
+    /// Creates the entries in `filesystem`.
+    pub(crate) fn apply(
+        &self,
+        filesystem: &mut Filesystem<FileBlockDevice>,
+        compress: bool,
+        summary: &mut ImportSummary,
+    ) -> Result<(), BuildError> {
+        for entry in &self.entries {
+            let error = |error: filesystem::Error| {
+                BuildError::new(format!(
+                    "{} from line {}: {error}",
+                    entry.destination, entry.line
+                ))
+            };
+
+            let (parent, _) = entry.destination.rsplit_once('/').unwrap();
+            create_directories(filesystem, parent, summary).map_err(|(_, cause)| error(cause))?;
+
+            match &entry.kind {
+                EntryKind::File(content) => {
+                    let contents = match content {
+                        Content::Source(source) => fs::read(source)
+                            .map_err(|error| io_error("read source file", source, error))?,
+                        Content::Inline(bytes) => bytes.clone(),
+                    };
+                    filesystem.create_file(&entry.destination).map_err(error)?;
+                    write_contents(filesystem, &entry.destination, &contents, compress, summary)
+                        .map_err(error)?;
+                    summary.files += 1;
+                    if let Content::Source(source) = content {
+                        import_xattrs(filesystem, source, &entry.destination, summary)?;
+                    }
+                }
+                EntryKind::Directory(source) => {
+                    create_directories(filesystem, &entry.destination, summary)
+                        .map_err(|(_, cause)| error(cause))?;
+                    if let Some(source) = source {
+                        import_xattrs(filesystem, source, &entry.destination, summary)?;
+                        import_directory(
+                            filesystem,
+                            source,
+                            &entry.destination,
+                            compress,
+                            summary,
+                        )?;
+                    }
+                }
+            }
+
+            for (name, value) in &entry.attributes {
+                filesystem
+                    .set_xattr(&entry.destination, name, value)
+                    .map_err(error)?;
+                summary.xattrs += 1;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Entry {
+    fn from_table(mut table: Table, base: &Path) -> Result<Self, ManifestError> {
+        let line = table.line;
+
+        if table.name == "symlink" {
+            return Err((
+                line,
+                "symlinks are not supported, LemonFS has no links".into(),
+            ));
+        }
+        if let Some((line, _)) = table.take("mode") {
+            return Err((
+                line,
+                "modes are not supported, LemonFS has no permissions".into(),
+            ));
+        }
+
+        let destination = match table.take("destination") {
+            Some((line, value)) => destination(line, value.into_string(line, "destination")?)?,
+            None => return Err((line, "missing destination".into())),
+        };
+        let source = table
+            .take("source")
+            .map(|(line, value)| {
+                let source = base.join(value.into_string(line, "source")?);
+                Ok::<_, ManifestError>((line, source))
+            })
+            .transpose()?;
+        let content = table.take("content");
+        let attributes = match table.take("attributes") {
+            Some((line, value)) => attributes(line, value)?,
+            None => Vec::new(),
+        };
+
+        let kind = match table.name.as_str() {
+            "file" => EntryKind::File(match (source, content) {
+                (Some((line, source)), None) => {
+                    let metadata = fs::metadata(&source)
+                        .map_err(|error| (line, format!("source {}: {error}", source.display())))?;
+                    if !metadata.is_file() {
+                        return Err((line, format!("{} is not a file", source.display())));
+                    }
+                    if metadata.len() > MAX_FILE_SIZE {
+                        return Err((
+                            line,
+                            format!(
+                                "{} is {} bytes; LemonFS files are limited to {MAX_FILE_SIZE} bytes",
+                                source.display(),
+                                metadata.len()
+                            ),
+                        ));
+                    }
+                    Content::Source(source)
+                }
+                (None, Some((line, content))) => {
+                    let content = content.into_string(line, "content")?.into_bytes();
+                    if content.len() as u64 > MAX_FILE_SIZE {
+                        return Err((
+                            line,
+                            format!("LemonFS files are limited to {MAX_FILE_SIZE} bytes"),
+                        ));
+                    }
+                    Content::Inline(content)
+                }
+                _ => return Err((line, "a file needs either source or content".into())),
+            }),
+            "directory" => {
+                if let Some((line, _)) = content {
+                    return Err((line, "directories have no content".into()));
+                }
+                EntryKind::Directory(match source {
+                    Some((line, source)) if !source.is_dir() => {
+                        return Err((line, format!("{} is not a directory", source.display())));
+                    }
+                    source => source.map(|(_, source)| source),
+                })
+            }
+            name => {
+                return Err((
+                    line,
+                    format!("unknown table [[{name}]], expected [[file]] or [[directory]]"),
+                ));
+            }
+        };
+
+        if let Some((key, (line, _))) = table.keys.into_iter().next() {
+            return Err((line, format!("unknown key {key}")));
+        }
+
+        Ok(Self {
+            line,
+            destination,
+            kind,
+            attributes,
+        })
+    }
+}
+
+fn destination(line: usize, path: String) -> Result<String, ManifestError> {
+    if !path.starts_with('/') {
+        return Err((line, format!("destination {path:?} must be absolute")));
+    }
+    let names: Vec<_> = path.split('/').filter(|name| !name.is_empty()).collect();
+    if names.is_empty() {
+        return Err((line, "the root can't be a destination".into()));
+    }
+    for name in &names {
+        if matches!(*name, "." | "..") {
+            return Err((
+                line,
+                format!("destination {path:?} must not contain {name}"),
+            ));
+        }
+        if name.len() > MAX_NAME_LEN {
+            return Err((
+                line,
+                format!("{name:?} is longer than {MAX_NAME_LEN} bytes"),
+            ));
+        }
+    }
+    Ok(format!("/{}", names.join("/")))
+}
+
+fn attributes(line: usize, value: Value) -> Result<Attributes, ManifestError> {
+    let Value::Table(pairs) = value else {
+        return Err((line, "attributes must be an inline table".into()));
+    };
+    if pairs.len() > MAX_XATTRS {
+        return Err((
+            line,
+            format!("at most {MAX_XATTRS} attributes are supported"),
+        ));
+    }
+
+    pairs
+        .into_iter()
+        .map(|(name, value)| {
+            let value = value.into_string(line, &name)?;
+            if !name.starts_with("user.") || name.len() > MAX_XATTR_NAME {
+                return Err((
+                    line,
+                    format!(
+                        "attribute {name:?} must be in the user.* namespace and at most {MAX_XATTR_NAME} bytes"
+                    ),
+                ));
+            }
+            if value.len() > MAX_XATTR_VALUE {
+                return Err((
+                    line,
+                    format!("attribute {name} is longer than {MAX_XATTR_VALUE} bytes"),
+                ));
+            }
+            Ok((name, value.into_bytes()))
+        })
+        .collect()
+}
+
+/// A `[[name]]` table with its keys and the lines they're on.
+#[derive(Debug)]
+struct Table {
+    name: String,
+    line: usize,
+    keys: Vec<(String, (usize, Value))>,
+}
+
+impl Table {
+    fn take(&mut self, key: &str) -> Option<(usize, Value)> {
+        let position = self.keys.iter().position(|(name, _)| name == key)?;
+        Some(self.keys.remove(position).1)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Bool(bool),
+    Table(Vec<(String, Value)>),
+}
+
+impl Value {
+    fn into_string(self, line: usize, key: &str) -> Result<String, ManifestError> {
+        match self {
+            Self::String(value) => Ok(value),
+            _ => Err((line, format!("{key} must be a string"))),
+        }
+    }
+}
+
+/// Parses the supported subset of TOML: arrays of tables, one `key = value`
+/// per line, strings, integers, booleans and inline tables. Errors carry
+/// their line number.
+fn parse_tables(text: &str) -> Result<Vec<Table>, ManifestError> {
+    let mut tables: Vec<Table> = Vec::new();
+
+    for (index, raw) in text.lines().enumerate() {
+        let line = index + 1;
+        let mut cursor = Cursor::new(raw);
+        cursor.skip_whitespace();
+        if cursor.at_end() {
+            continue;
+        }
+
+        if cursor.eat("[[") {
+            let name = cursor.bare_key();
+            if name.is_empty() || !cursor.eat("]]") {
+                return Err((line, "expected a table header like [[file]]".into()));
+            }
+            cursor.end().map_err(|message| (line, message))?;
+            tables.push(Table {
+                name: name.into(),
+                line,
+                keys: Vec::new(),
+            });
+            continue;
+        }
+        if cursor.peek() == Some('[') {
+            return Err((
+                line,
+                "only arrays of tables like [[file]] are supported".into(),
+            ));
+        }
+
+        let (key, value) = cursor.key_value().map_err(|message| (line, message))?;
+        cursor.end().map_err(|message| (line, message))?;
+        let Some(table) = tables.last_mut() else {
+            return Err((line, format!("{key} is outside of a table")));
+        };
+        if table.keys.iter().any(|(name, _)| *name == key) {
+            return Err((line, format!("duplicate key {key}")));
+        }
+        table.keys.push((key, (line, value)));
+    }
+
+    Ok(tables)
+}
+
+struct Cursor<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(line: &'a str) -> Self {
+        Self { rest: line }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest.chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.rest = &self.rest[c.len_utf8()..];
+        Some(c)
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        match self.rest.strip_prefix(token) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.rest = self.rest.trim_start_matches([' ', '\t']);
+        if self.rest.starts_with('#') {
+            self.rest = "";
+        }
+    }
+
+    fn at_end(&self) -> bool {
+        self.rest.is_empty()
+    }
+
+    /// Only whitespace and a comment may follow.
+    fn end(&mut self) -> Result<(), String> {
+        self.skip_whitespace();
+        match self.peek() {
+            None => Ok(()),
+            Some(c) => Err(format!("unexpected {c:?}")),
+        }
+    }
+
+    fn bare_key(&mut self) -> &'a str {
+        let len = self
+            .rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+            .unwrap_or(self.rest.len());
+        let (key, rest) = self.rest.split_at(len);
+        self.rest = rest;
+        key
+    }
+
+    fn key(&mut self) -> Result<String, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('"' | '\'') => self.string(),
+            _ => match self.bare_key() {
+                "" => Err("expected a key".into()),
+                key => Ok(key.into()),
+            },
+        }
+    }
+
+    fn key_value(&mut self) -> Result<(String, Value), String> {
+        let key = self.key()?;
+        self.skip_whitespace();
+        if !self.eat("=") {
+            return Err(format!("expected = after {key}"));
+        }
+        self.skip_whitespace();
+        Ok((key, self.value()?))
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.peek() {
+            Some('"' | '\'') => Ok(Value::String(self.string()?)),
+            Some('{') => {
+                self.bump();
+                let mut pairs = Vec::new();
+                loop {
+                    self.skip_whitespace();
+                    if self.eat("}") {
+                        return Ok(Value::Table(pairs));
+                    }
+                    if !pairs.is_empty() && !self.eat(",") {
+                        return Err("expected , or } in inline table".into());
+                    }
+                    let (key, value) = self.key_value()?;
+                    if pairs.iter().any(|(name, _)| *name == key) {
+                        return Err(format!("duplicate key {key}"));
+                    }
+                    pairs.push((key, value));
+                }
+            }
+            _ if self.eat("true") => Ok(Value::Bool(true)),
+            _ if self.eat("false") => Ok(Value::Bool(false)),
+            _ => {
+                let word = self.bare_key().replace('_', "");
+                let parsed = match word.strip_prefix("0o") {
+                    Some(octal) => i64::from_str_radix(octal, 8),
+                    None => word.parse(),
+                };
+                parsed
+                    .map(Value::Integer)
+                    .map_err(|_| "expected a string, integer, boolean or inline table".into())
+            }
+        }
+    }
+
+    /// A basic string with escapes or a literal string in single quotes.
+    fn string(&mut self) -> Result<String, String> {
+        let quote = self.bump().unwrap();
+        let mut value = String::new();
+        loop {
+            match self.bump() {
+                None => return Err("unterminated string".into()),
+                Some(c) if c == quote => return Ok(value),
+                Some('\\') if quote == '"' => value.push(match self.bump() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('u') => {
+                        let digits = self.rest.get(..4).ok_or("invalid \\u escape")?;
+                        self.rest = &self.rest[4..];
+                        u32::from_str_radix(digits, 16)
+                            .ok()
+                            .and_then(char::from_u32)
+                            .ok_or("invalid \\u escape")?
+                    }
+                    c => return Err(format!("unknown escape \\{}", c.unwrap_or(' '))),
+                }),
+                Some(c) => value.push(c),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Manifest, ManifestError> {
+        Manifest::parse(text, Path::new(env!("CARGO_MANIFEST_DIR")))
+    }
+
+    #[test]
+    fn parses_entries() {
+        let manifest = parse(
+            r#"
+# An image with a message of the day.
+[[directory]]
+destination = "/etc/"   # trailing slashes are dropped
+attributes = { "user.owner" = 'root', "user.note" = "a \"b\"!" }
+
+[[file]]
+destination = "/etc/motd"
+content = "Welcome\n"
+
+[[file]]
+source = "Cargo.toml"
+destination = "/Cargo.toml"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            manifest.entries,
+            [
+                Entry {
+                    line: 3,
+                    destination: "/etc".into(),
+                    kind: EntryKind::Directory(None),
+                    attributes: vec![
+                        ("user.owner".into(), b"root".to_vec()),
+                        ("user.note".into(), b"a \"b\"!".to_vec()),
+                    ],
+                },
+                Entry {
+                    line: 7,
+                    destination: "/etc/motd".into(),
+                    kind: EntryKind::File(Content::Inline(b"Welcome\n".to_vec())),
+                    attributes: Vec::new(),
+                },
+                Entry {
+                    line: 11,
+                    destination: "/Cargo.toml".into(),
+                    kind: EntryKind::File(Content::Source(
+                        Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml")
+                    )),
+                    attributes: Vec::new(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn errors_point_at_lines() {
+        let error = |text: &str| parse(text).unwrap_err();
+
+        assert_eq!(error("destination = \"/a\"").0, 1);
+        assert_eq!(
+            error("[[file]]\ndestination = \"/a\"\nmode = 0o644"),
+            (
+                3,
+                "modes are not supported, LemonFS has no permissions".into()
+            )
+        );
+        assert_eq!(
+            error("[[file]]\n\n[[symlink]]\ndestination = \"/a\"\ntarget = \"/b\"").0,
+            1
+        );
+        assert_eq!(
+            error("[[directory]]\ndestination = \"/a\"\n[[symlink]]\ndestination = \"/b\"").0,
+            3
+        );
+        assert_eq!(
+            error("[[file]]\ndestination = \"/a\"\nsource = \"missing.txt\"").0,
+            3
+        );
+        assert_eq!(
+            error("[[file]]\ndestination = \"/a\"\ncontent = \"x\"\nsource = \"Cargo.toml\""),
+            (1, "a file needs either source or content".into())
+        );
+        assert_eq!(
+            error(
+                "[[file]]\ndestination = \"/a\"\ncontent = \"x\"\n[[file]]\ndestination = \"/a/b\"\ncontent = \"y\""
+            ),
+            (4, "/a is a file, created on line 1".into())
+        );
+        assert_eq!(
+            error("[[directory]]\ndestination = \"/a\"\n\n[[directory]]\ndestination = \"/a/\""),
+            (4, "/a is already created on line 1".into())
+        );
+        assert_eq!(
+            error(
+                "[[directory]]\ndestination = \"/a/b\"\n[[file]]\ndestination = \"/a\"\ncontent = \"x\""
+            ),
+            (3, "/a is a directory, created on line 1".into())
+        );
+        assert_eq!(
+            error(
+                "[[file]]\ndestination = \"/a/b/c\"\ncontent = \"x\"\n[[file]]\ndestination = \"/a/b\"\ncontent = \"y\""
+            ),
+            (4, "/a/b is a directory, created on line 1".into())
+        );
+        assert_eq!(
+            error("[[directory]]\ndestination = \"/a\"\nowner = \"root\""),
+            (3, "unknown key owner".into())
+        );
+        assert_eq!(
+            error("[[directory]]\ndestination = \"/a\"\nattributes = { \"security.x\" = \"y\" }").0,
+            3
+        );
+        assert_eq!(
+            error("[[file]]\ndestination = \"/a-name-longer-than-24-bytes\"").0,
+            2
+        );
+        assert_eq!(
+            error("[[file]]\ndestination = \"/a").1,
+            "unterminated string"
+        );
+        assert_eq!(
+            error("[file]").1,
+            "only arrays of tables like [[file]] are supported"
+        );
+        assert_eq!(error("[[file]] x").1, "unexpected 'x'");
+    }
+}
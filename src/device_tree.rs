@@ -17,7 +17,9 @@ struct SystemInfo {
     mmio_regions: Vec<PhysRange>,
     fdt_range: PhysRange,
     total_memory: usize,
-    block_device_addr: usize,
+    /// VirtIO block devices in the order they're given to QEMU.
+    block_device_addrs: Vec<usize>,
+    bootargs: String,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -176,7 +178,7 @@ impl SystemInfo {
                 .ok_or(DeviceTreeError::AddressOverflow)
         })?;
 
-        let mut block_device_addr = None;
+        let mut block_device_addrs = Vec::new();
 
         for node in fdt.all_nodes().filter(node_is_enabled) {
             if node_has_compatible(&node, "virtio,mmio")
@@ -191,11 +193,25 @@ impl SystemInfo {
                 // TODO(mt): refer to docs here to avoid magic 0x008 value.
                 let device_id = unsafe { core::ptr::read_volatile((addr + 0x008) as *const u32) };
                 if device_id == 2 {
-                    block_device_addr = Some(addr);
+                    block_device_addrs.push(addr);
                 }
             }
         }
 
+        // QEMU hands out the MMIO slots from the top, so the first `-device`
+        // has the highest address but comes last in the device tree.
+        block_device_addrs.reverse();
+        if block_device_addrs.is_empty() {
+            return Err(DeviceTreeError::MissingBlockDevice);
+        }
+
+        // QEMU's `-append` ends up in `/chosen/bootargs`. The node is optional.
+        let bootargs = fdt
+            .find_node("/chosen")
+            .and_then(|chosen| chosen.property("bootargs"))
+            .and_then(|property| property.as_str())
+            .unwrap_or_default();
+
         let cpu = fdt.cpus().next().ok_or(DeviceTreeError::MissingCpu)?;
         let isa = cpu
             .properties()
@@ -214,7 +230,8 @@ impl SystemInfo {
             mmio_regions,
             fdt_range,
             total_memory,
-            block_device_addr: block_device_addr.ok_or(DeviceTreeError::MissingBlockDevice)?,
+            block_device_addrs,
+            bootargs: String::from(bootargs),
         };
 
         log::info!("memory regions: {:?}", system_info.memory_regions);
@@ -224,6 +241,7 @@ impl SystemInfo {
         );
         log::info!("MMIO regions: {:?}", system_info.mmio_regions);
         log::info!("FDT range: {:x?}", system_info.fdt_range);
+        log::info!("bootargs: {:?}", system_info.bootargs);
 
         Ok(system_info)
     }
@@ -280,8 +298,20 @@ pub fn cpu_isa() -> String {
     system_info().cpu_isa.clone()
 }
 
+/// The first VirtIO block device, holding the root filesystem.
 pub fn block_device_addr() -> usize {
-    system_info().block_device_addr
+    system_info().block_device_addrs[0]
+}
+
+/// All VirtIO block devices in the order they're given to QEMU, the first
+/// one holds the root filesystem.
+pub fn block_device_addrs() -> &'static [usize] {
+    &system_info().block_device_addrs
+}
+
+/// The kernel command line from `/chosen/bootargs`, empty when not present.
+pub fn bootargs() -> &'static str {
+    &system_info().bootargs
 }
 
 /// Return the page-aligned MMIO windows used by the kernel from an early-boot FDT.
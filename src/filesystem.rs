@@ -1,11 +1,16 @@
 extern crate alloc;
-use crate::println::UartWriter;
+use crate::vfs::{self, Driver, FileType};
 use crate::virtio2::LockedBlockDevice;
 use crate::{print, println, ramdisk};
 use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 use filesystem::{BlockDevice, Filesystem};
 
-pub use filesystem::{BLOCK_SIZE, BlockIndex, Error, INodeIndex};
+pub use filesystem::{
+    BLOCK_SIZE, BlockIndex, Error, FileHandle, INodeIndex, LockInfo, LockKind, Metadata,
+    SnapshotInfo, WatchEvent, WatchId,
+};
 
 /// The concrete block device used by the kernel, wrapping either the in-memory
 /// ramdisk or the VirtIO persistent storage.
@@ -122,7 +127,7 @@ impl KernelBlockDevice {
     }
 }
 
-static FS: spin::Mutex<LockedFilesystem> = spin::Mutex::new(LockedFilesystem::new());
+static FS: spin::RwLock<LockedFilesystem> = spin::RwLock::new(LockedFilesystem::new());
 
 struct LockedFilesystem {
     inner: Option<Filesystem<KernelBlockDevice>>,
@@ -137,7 +142,11 @@ impl LockedFilesystem {
         self.inner.is_some()
     }
 
-    fn get(&mut self) -> &mut Filesystem<KernelBlockDevice> {
+    fn get(&self) -> &Filesystem<KernelBlockDevice> {
+        self.inner.as_ref().unwrap()
+    }
+
+    fn get_mut(&mut self) -> &mut Filesystem<KernelBlockDevice> {
         self.inner.as_mut().unwrap()
     }
 
@@ -145,105 +154,331 @@ impl LockedFilesystem {
         self.inner = Some(filesystem);
     }
 
-    pub fn mkdir(&mut self, path: &str) -> Result<INodeIndex, Error> {
+    pub fn mkdir(&self, path: &str) -> Result<INodeIndex, Error> {
         self.get().mkdir(path)
     }
 
-    pub fn create_file(&mut self, path: &str) -> Result<INodeIndex, Error> {
+    pub fn create_file(&self, path: &str) -> Result<INodeIndex, Error> {
         self.get().create_file(path)
     }
 
-    pub fn remove_dir_entry(&mut self, path: &str) -> Result<(), Error> {
+    pub fn remove_dir_entry(&self, path: &str) -> Result<(), Error> {
         self.get().remove_dir_entry(path)
     }
 
-    fn dump_dir(&mut self, path: &str) -> Result<(), Error> {
-        self.get().dump_dir(path, &mut UartWriter)
+    fn rename(&self, from: &str, to: &str) -> Result<(), Error> {
+        self.get().rename(from, to)
     }
 
     fn dump(&mut self) {
-        self.get().block_device_mut().dump_non_empty_pages()
+        self.get_mut().block_device_mut().dump_non_empty_pages()
     }
 
-    fn write_to_file(&mut self, path: &str, bytes: &[u8]) -> Result<usize, Error> {
+    fn write_to_file(&self, path: &str, bytes: &[u8]) -> Result<usize, Error> {
         self.get().write_to_file(path, bytes)
     }
 
-    fn read_file(&mut self, path: &str) -> Result<String, Error> {
+    fn read_file(&self, path: &str) -> Result<String, Error> {
         self.get().read_file(path)
     }
 
+    fn stat(&self, path: &str) -> Result<Metadata, Error> {
+        self.get().stat(path)
+    }
+
     fn reset(&mut self) {
         ramdisk::reset();
     }
 
-    fn tree(&mut self) {
-        self.get().tree(&mut UartWriter)
+    fn flush(&self) {
+        self.get().flush()
     }
 
-    fn flush(&mut self) {
-        self.get().flush()
+    fn fsync(&self, path: &str) -> Result<(), Error> {
+        self.get().fsync(path)
+    }
+
+    fn open(&self, path: &str) -> Result<FileHandle, Error> {
+        self.get().open(path)
+    }
+
+    fn close(&self, handle: FileHandle) -> Result<(), Error> {
+        self.get().close(handle)
+    }
+
+    fn lock(&self, handle: FileHandle, kind: LockKind, blocking: bool) -> Result<(), Error> {
+        self.get().lock(handle, kind, blocking)
+    }
+
+    fn unlock(&self, handle: FileHandle) -> Result<(), Error> {
+        self.get().unlock(handle)
+    }
+
+    fn locks(&self) -> Vec<LockInfo> {
+        self.get().locks()
+    }
+
+    fn watch(&self, path: &str) -> Result<WatchId, Error> {
+        self.get().watch(path)
+    }
+
+    fn unwatch(&self, id: WatchId) -> Result<(), Error> {
+        self.get().unwatch(id)
+    }
+
+    fn read_events(&self, id: WatchId) -> Result<Vec<WatchEvent>, Error> {
+        self.get().read_events(id)
+    }
+
+    fn create_snapshot(&mut self, name: &str) -> Result<(), Error> {
+        self.get_mut().create_snapshot(name)
+    }
+
+    fn snapshots(&self) -> Vec<SnapshotInfo> {
+        self.get().snapshots()
+    }
+
+    fn delete_snapshot(&mut self, name: &str) -> Result<(), Error> {
+        self.get_mut().delete_snapshot(name)
+    }
+
+    fn rollback(&mut self, name: &str) -> Result<(), Error> {
+        self.get_mut().rollback(name)
+    }
+
+    fn unmount(&mut self) {
+        match self.inner.take() {
+            Some(fs) => drop(fs.unmount()),
+            None => log::info!("filesystem is not mounted"),
+        }
     }
 }
 
 /// Those functions are wrappers around the `LockedFilesystem` for the shell
-/// to do some filesystem operations.
+/// to do some filesystem operations. They always operate on the root
+/// filesystem, plain file operations across mount points go through `vfs`.
 ///
-/// This is the only place where the `.lock()` should be called to avoid
-/// deadlocks.
+/// This is the only place where `FS` should be locked to avoid deadlocks.
+/// File operations only take the lock shared, the `Filesystem` locks the
+/// inodes it touches itself. Operations on the whole tree like snapshots
+/// and unmounting take it exclusively.
 pub mod api {
     use super::*;
 
     pub fn dump() {
-        (*FS.lock()).dump();
-    }
-
-    pub fn dump_dir(path: &str) -> Result<(), Error> {
-        (*FS.lock()).dump_dir(path)
+        (*FS.write()).dump();
     }
 
     pub fn mkdir(name: &str) -> Result<INodeIndex, Error> {
-        (*FS.lock()).mkdir(name)
+        (*FS.read()).mkdir(name)
     }
 
     pub fn create_file(name: &str) -> Result<INodeIndex, Error> {
-        (*FS.lock()).create_file(name)
+        (*FS.read()).create_file(name)
     }
 
     pub fn remove_dir_entry(path: &str) -> Result<(), Error> {
-        (*FS.lock()).remove_dir_entry(path)
+        (*FS.read()).remove_dir_entry(path)
+    }
+
+    pub fn rename(from: &str, to: &str) -> Result<(), Error> {
+        (*FS.read()).rename(from, to)
     }
 
     pub fn write_to_file(path: &str, text: String) -> Result<usize, Error> {
-        (*FS.lock()).write_to_file(path, text.as_bytes())
+        (*FS.read()).write_to_file(path, text.as_bytes())
     }
 
     pub fn read_file(path: &str) -> Result<String, Error> {
-        (*FS.lock()).read_file(path)
+        (*FS.read()).read_file(path)
     }
 
-    pub fn reset() {
-        (*FS.lock()).reset();
+    pub fn stat(path: &str) -> Result<Metadata, Error> {
+        (*FS.read()).stat(path)
     }
 
-    pub fn tree() {
-        (*FS.lock()).tree();
+    pub fn reset() {
+        (*FS.write()).reset();
     }
 
     pub fn flush() {
-        (*FS.lock()).flush();
+        (*FS.read()).flush();
+    }
+
+    pub fn fsync(path: &str) -> Result<(), Error> {
+        (*FS.read()).fsync(path)
+    }
+
+    pub fn open(path: &str) -> Result<FileHandle, Error> {
+        (*FS.read()).open(path)
+    }
+
+    pub fn close(handle: FileHandle) -> Result<(), Error> {
+        (*FS.read()).close(handle)
+    }
+
+    /// Never waits: the kernel runs a single thread, so nothing could
+    /// release a conflicting lock while this spins holding `FS`. A conflict
+    /// returns `Error::WouldBlock`.
+    pub fn lock(handle: FileHandle, kind: LockKind) -> Result<(), Error> {
+        (*FS.read()).lock(handle, kind, false)
+    }
+
+    pub fn unlock(handle: FileHandle) -> Result<(), Error> {
+        (*FS.read()).unlock(handle)
+    }
+
+    pub fn locks() -> Vec<LockInfo> {
+        (*FS.read()).locks()
+    }
+
+    pub fn watch(path: &str) -> Result<WatchId, Error> {
+        (*FS.read()).watch(path)
+    }
+
+    pub fn unwatch(id: WatchId) -> Result<(), Error> {
+        (*FS.read()).unwatch(id)
+    }
+
+    pub fn read_events(id: WatchId) -> Result<Vec<WatchEvent>, Error> {
+        (*FS.read()).read_events(id)
+    }
+
+    pub fn create_snapshot(name: &str) -> Result<(), Error> {
+        (*FS.write()).create_snapshot(name)
+    }
+
+    pub fn snapshots() -> Vec<SnapshotInfo> {
+        (*FS.read()).snapshots()
+    }
+
+    pub fn delete_snapshot(name: &str) -> Result<(), Error> {
+        (*FS.write()).delete_snapshot(name)
+    }
+
+    pub fn rollback(name: &str) -> Result<(), Error> {
+        (*FS.write()).rollback(name)
+    }
+
+    pub fn unmount() {
+        (*FS.write()).unmount();
+    }
+
+    /// Runs `f` with the block device the root filesystem is on, see
+    /// `Filesystem::with_block_device`.
+    pub fn with_block_device<R>(f: impl FnOnce(&mut KernelBlockDevice) -> R) -> Result<R, Error> {
+        match &FS.read().inner {
+            Some(fs) => Ok(fs.with_block_device(f)),
+            None => Err(Error::NotFound),
+        }
+    }
+}
+
+/// The `Filesystem` in `FS` as seen by the VFS, mounted at `/`.
+struct RootFilesystem;
+
+impl RootFilesystem {
+    fn with<R>(
+        f: impl FnOnce(&Filesystem<KernelBlockDevice>) -> Result<R, Error>,
+    ) -> Result<R, Error> {
+        match &FS.read().inner {
+            Some(fs) => f(fs),
+            None => Err(Error::NotFound),
+        }
+    }
+}
+
+impl Driver for RootFilesystem {
+    fn fs_type(&self) -> &'static str {
+        "lemonfs"
+    }
+
+    fn stat(&self, path: &str) -> Result<Metadata, Error> {
+        Self::with(|fs| Driver::stat(fs, path))
+    }
+
+    fn read(&self, path: &str) -> Result<Vec<u8>, Error> {
+        Self::with(|fs| fs.read(path))
+    }
+
+    fn write(&self, path: &str, bytes: &[u8]) -> Result<usize, Error> {
+        Self::with(|fs| Driver::write(fs, path, bytes))
+    }
+
+    fn read_at(&self, path: &str, offset: usize, buf: &mut [u8]) -> Result<usize, Error> {
+        Self::with(|fs| fs.read_at(path, offset, buf))
+    }
+
+    fn write_at(&self, path: &str, offset: usize, bytes: &[u8]) -> Result<usize, Error> {
+        Self::with(|fs| Driver::write_at(fs, path, offset, bytes))
+    }
+
+    fn readdir(&self, path: &str) -> Result<Vec<String>, Error> {
+        Self::with(|fs| fs.readdir(path))
+    }
+
+    fn create(&self, path: &str, file_type: FileType) -> Result<(), Error> {
+        Self::with(|fs| fs.create(path, file_type))
+    }
+
+    fn remove(&self, path: &str) -> Result<(), Error> {
+        Self::with(|fs| fs.remove(path))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), Error> {
+        Self::with(|fs| Driver::rename(fs, from, to))
+    }
+
+    fn sync(&self) {
+        let _ = Self::with(|fs| {
+            fs.flush();
+            Ok(())
+        });
     }
 }
 
+/// Returns `true` if the kernel command line asks for a read-only root.
+///
+/// Like on Linux, `ro` and `rw` are accepted and the last one wins.
+fn read_only_requested(bootargs: &str) -> bool {
+    bootargs
+        .split_whitespace()
+        .rev()
+        .find_map(|arg| match arg {
+            "ro" => Some(true),
+            "rw" => Some(false),
+            _ => None,
+        })
+        .unwrap_or(false)
+}
+
 /// Initializes the Filesystem by reading the superblock or defaulting it
 /// if it doesn't exist.
-pub fn init_with_device(dev: KernelBlockDevice) {
-    if FS.lock().is_some() {
+///
+/// The filesystem is mounted read-only when `ro` is passed on the kernel
+/// command line or when the image needs an upgrade.
+///
+/// The filesystem is mounted at `/` of the VFS afterwards.
+pub fn init_with_device(mut dev: KernelBlockDevice) {
+    if FS.read().is_some() {
         log::info!("not initializing the filesystem again");
         return;
     }
 
-    let fs = match Filesystem::new(dev) {
+    // Images of an older format can still be read, but have to be converted
+    // with `mkfs upgrade` before they can be written to.
+    let needs_upgrade = Filesystem::needs_upgrade(&mut dev).unwrap_or(false);
+    if needs_upgrade {
+        log::warn!("filesystem has an old format version, mounting read-only");
+    }
+
+    let mounted = if needs_upgrade || read_only_requested(crate::device_tree::bootargs()) {
+        Filesystem::mount_read_only(dev)
+    } else {
+        Filesystem::new(dev)
+    };
+
+    let mut fs = match mounted {
         Ok(fs) => fs,
         Err(e) => {
             log::error!("Could not initialize filesystem: {e}");
@@ -251,7 +486,24 @@ pub fn init_with_device(dev: KernelBlockDevice) {
         }
     };
 
-    (*FS.lock()).init(fs);
+    // Make use of a device that got larger since the image was created.
+    let device_blocks = fs.block_device_mut().total_blocks();
+    if !fs.is_read_only()
+        && device_blocks > fs.total_blocks()
+        && let Err(e) = fs.grow(device_blocks)
+    {
+        log::error!("Could not grow filesystem to {device_blocks} blocks: {e}");
+    }
+
+    let source = match fs.block_device_mut() {
+        KernelBlockDevice::Ramdisk => "ramdisk",
+        KernelBlockDevice::VirtIO(_) => "virtio",
+    };
+    (*FS.write()).init(fs);
+
+    if let Err(e) = vfs::mount_root(source, Arc::new(RootFilesystem)) {
+        log::error!("Could not mount the filesystem at /: {e}");
+    }
 
     log::info!("initialized");
 }
@@ -1,6 +1,11 @@
 extern crate alloc;
+use alloc::vec::Vec;
 
 static EARLY_BUF: spin::Mutex<EarlyBuffer> = spin::Mutex::new(EarlyBuffer::new());
+static LOG_RING: spin::Mutex<LogRing> = spin::Mutex::new(LogRing::new());
+
+/// Number of bytes of recent log lines kept for `recent`.
+pub const LOG_RING_SIZE: usize = 8192;
 
 // Buffers messages during logging while the `virtio console device` is not
 // initialized.
@@ -33,6 +38,57 @@ impl EarlyBuffer {
     }
 }
 
+/// Keeps the most recent log lines, without colors, overwriting the oldest
+/// ones once full.
+struct LogRing {
+    buf: [u8; LOG_RING_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl LogRing {
+    const fn new() -> Self {
+        Self {
+            buf: [0u8; LOG_RING_SIZE],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    fn append(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if self.len < LOG_RING_SIZE {
+                self.buf[(self.start + self.len) % LOG_RING_SIZE] = byte;
+                self.len += 1;
+            } else {
+                self.buf[self.start] = byte;
+                self.start = (self.start + 1) % LOG_RING_SIZE;
+            }
+        }
+    }
+
+    fn copy_to(&self, out: &mut Vec<u8>) {
+        let end = self.start + self.len;
+        out.extend_from_slice(&self.buf[self.start..end.min(LOG_RING_SIZE)]);
+        out.extend_from_slice(&self.buf[..end.saturating_sub(LOG_RING_SIZE)]);
+
+        // The oldest line got partially overwritten.
+        if self.len == LOG_RING_SIZE
+            && let Some(newline) = out.iter().position(|&byte| byte == b'\n')
+        {
+            out.drain(..=newline);
+        }
+    }
+}
+
+/// Returns the most recent log lines, at most `LOG_RING_SIZE` bytes.
+pub fn recent() -> Vec<u8> {
+    // Allocate before locking, the allocator may log as well.
+    let mut out = Vec::with_capacity(LOG_RING_SIZE);
+    LOG_RING.lock().copy_to(&mut out);
+    out
+}
+
 /// Flushes buffered log messages once `virtio console device` is initialized.
 pub fn flush_early_buffer() {
     crate::virtio2::console_write(EARLY_BUF.lock().as_bytes());
@@ -75,7 +131,7 @@ impl log::Log for KernelLogger {
 
     fn log(&self, record: &log::Record) {
         use core::fmt::Write;
-        let mut buf = StackString::<512>::new();
+        let mut line = StackString::<512>::new();
         let label = module_prefix(record.target());
         // ANSI color codes: yellow for WARN, red for ERROR, reset after level.
         let (pre, post) = match record.level() {
@@ -84,15 +140,21 @@ impl log::Log for KernelLogger {
             _ => ("", ""),
         };
         // Level is at most 5 chars (DEBUG). Label padded to 8 chars for alignment.
-        let _ = writeln!(
-            buf,
-            "{}[{:<5} {:<8}] {}{}",
-            pre,
+        let _ = write!(
+            line,
+            "[{:<5} {:<8}] {}",
             record.level(),
             label,
-            record.args(),
-            post,
+            record.args()
         );
+
+        let mut ring = LOG_RING.lock();
+        ring.append(line.as_bytes());
+        ring.append(b"\n");
+        drop(ring);
+
+        let mut buf = StackString::<512>::new();
+        let _ = writeln!(buf, "{pre}{}{post}", line.as_str());
         let written = crate::virtio2::console_write(buf.as_bytes());
         if !written {
             EARLY_BUF.lock().append(buf.as_bytes());
@@ -120,12 +182,19 @@ impl<const N: usize> StackString<N> {
     fn as_bytes(&self) -> &[u8] {
         &self.buf[..self.len]
     }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(self.as_bytes()).expect("Only whole characters are written")
+    }
 }
 
 impl<const N: usize> core::fmt::Write for StackString<N> {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         let remaining = N - self.len;
-        let to_copy = s.len().min(remaining);
+        let mut to_copy = s.len().min(remaining);
+        while !s.is_char_boundary(to_copy) {
+            to_copy -= 1;
+        }
         self.buf[self.len..self.len + to_copy].copy_from_slice(&s.as_bytes()[..to_copy]);
         self.len += to_copy;
         Ok(())
@@ -23,6 +23,7 @@ pub mod scheduler;
 pub mod shell;
 pub mod timer;
 pub mod trap_handler;
+pub mod vfs;
 pub mod virtio;
 pub mod virtio2;
 
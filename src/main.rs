@@ -7,7 +7,7 @@ use lemon_shark::{
     filesystem::{self, KernelBlockDevice},
     interrupts,
     kernel_layout::KernelLayout,
-    logo, page_frame_allocator, page_table, println, shell, timer, trap_handler, virtio2,
+    logo, page_frame_allocator, page_table, println, shell, timer, trap_handler, vfs, virtio2,
 };
 
 // This is the section that we mapped first in the linker script `linker.ld`
@@ -48,6 +48,7 @@ extern "C" fn _start(_: usize, device_table_addr: usize) -> ! {
 
     let virtio_device = virtio2::make_device();
     filesystem::init_with_device(KernelBlockDevice::VirtIO(virtio_device));
+    vfs::mount_defaults();
 
     println!("{}", logo::SHARK);
     println!("Welcome to LemonShark v0.0.1");
@@ -32,6 +32,14 @@ impl FrameArena {
         Some(self.range.start() + index * PAGE_SIZE)
     }
 
+    fn used_frames(&self) -> usize {
+        self.used
+            .as_words()
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
     fn contains(&self, addr: PhysAddr) -> bool {
         self.range.start() <= addr && addr < self.range.end()
     }
@@ -66,6 +74,13 @@ impl FrameArena {
     }
 }
 
+/// Number of page frames, each `PAGE_SIZE` bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    pub total: usize,
+    pub used: usize,
+}
+
 /// Manages all page-aligned portions of RAM which are not reserved by the
 /// firmware, the live FDT, or the kernel image.
 struct PageFrameAllocator {
@@ -113,6 +128,13 @@ impl PageFrameAllocator {
         arena.free(addr)
     }
 
+    fn stats(&self) -> FrameStats {
+        FrameStats {
+            total: self.arenas.iter().map(|arena| arena.used.len()).sum(),
+            used: self.arenas.iter().map(FrameArena::used_frames).sum(),
+        }
+    }
+
     fn ranges(&self) -> Vec<PhysRange> {
         self.arenas.iter().map(|arena| arena.range).collect()
     }
@@ -126,6 +148,10 @@ pub fn free_frame(addr: PhysAddr) {
     PAGE_FRAME_ALLOCATOR.lock().as_mut().unwrap().free(addr);
 }
 
+pub fn stats() -> FrameStats {
+    PAGE_FRAME_ALLOCATOR.lock().as_ref().unwrap().stats()
+}
+
 /// Return a snapshot of the physical ranges managed by the allocator.
 pub(crate) fn managed_ranges() -> Vec<PhysRange> {
     PAGE_FRAME_ALLOCATOR.lock().as_ref().unwrap().ranges()
@@ -12,6 +12,24 @@ fn write_char_to_uart(c: char) {
     }
 }
 
+/// To read from the UART, we need to check wether there is some data available
+/// by reading the Line status register and check for the set bit.
+pub fn try_read_byte() -> Option<u8> {
+    const UART: usize = 0x10_000_000;
+    const RECEIVE_BUFFER_REGISTER_OFFSET: usize = 0;
+    const LINE_STATUS_REGISTER_OFFSET: usize = 5;
+
+    let uart = UART as *const u8;
+
+    unsafe {
+        if uart.add(LINE_STATUS_REGISTER_OFFSET).read_volatile() & 0x1 != 0 {
+            Some(uart.add(RECEIVE_BUFFER_REGISTER_OFFSET).read_volatile())
+        } else {
+            None
+        }
+    }
+}
+
 pub struct UartWriter;
 
 impl core::fmt::Write for UartWriter {
@@ -22,6 +22,13 @@ struct Process {
     running: bool,
 }
 
+/// A process as listed by `processes`.
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessInfo {
+    pub id: usize,
+    pub running: bool,
+}
+
 impl Scheduler {
     fn next(&mut self, current_trap_frame: TrapFrame) -> *mut TrapFrame {
         self.processes[self.currently_running].running = false;
@@ -57,3 +64,20 @@ impl Scheduler {
         self.processes.push(process);
     }
 }
+
+/// Returns every process known to the scheduler, none before it's started.
+pub fn processes() -> Vec<ProcessInfo> {
+    let scheduler = SCHEDULER.lock();
+    let Some(scheduler) = scheduler.as_ref() else {
+        return Vec::new();
+    };
+
+    scheduler
+        .processes
+        .iter()
+        .map(|process| ProcessInfo {
+            id: process.id,
+            running: process.running,
+        })
+        .collect()
+}
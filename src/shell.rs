@@ -1,16 +1,20 @@
 extern crate alloc;
-use core::str::FromStr;
 
 use alloc::boxed::Box;
 use alloc::string::String;
 use alloc::vec::Vec;
 
+use crate::filesystem::{FileHandle, LockKind, WatchEvent};
 use crate::riscv;
+use crate::vfs::{Error, FileType};
 
 use crate::{print, println};
 
 const HISTORY_CAPACITY: usize = 100;
 
+/// Longest range `cat` reads at once, its buffer is allocated upfront.
+const MAX_CAT_RANGE: usize = 64 * 1024;
+
 struct CommandHistory {
     entries: Vec<String>,
 }
@@ -55,18 +59,11 @@ fn redraw_line(line: &str) {
     print!("\r\x1b[2K> {line}");
 }
 
-/// To read from the UART, we need to check wether there is some data available
-/// by reading the Line status register and check for the set bit.
 fn read_line_and_display(history: &CommandHistory) -> String {
-    const UART: usize = 0x10_000_000;
-    const RECEIVE_BUFFER_REGISTER_OFFSET: usize = 0;
-    const LINE_STATUS_REGISTER_OFFSET: usize = 5;
     const ASCII_ESCAPE: u8 = 27;
     const ASCII_BACKSPACE: u8 = 8;
     const ASCII_DELETE: u8 = 127;
 
-    let uart = UART as *const u8;
-
     let mut s = String::new();
     let mut draft = String::new();
     let mut history_index = history.entries.len();
@@ -74,78 +71,76 @@ fn read_line_and_display(history: &CommandHistory) -> String {
 
     print!("> ");
 
-    unsafe {
-        loop {
-            if uart.add(LINE_STATUS_REGISTER_OFFSET).read_volatile() & 0x1 != 0 {
-                let c = uart.add(RECEIVE_BUFFER_REGISTER_OFFSET).read_volatile();
-
-                // TODO(mt): I don't know if this is just QEMU but when pressing
-                // enter, it first does a '\r' so we can use this to end the
-                // line.
-                if c == b'\r' {
-                    print!("\n");
-                    break;
-                }
+    loop {
+        let Some(c) = crate::println::try_read_byte() else {
+            continue;
+        };
 
-                match input_state {
-                    InputState::Escape => {
-                        // ANSI control sequences begin with ESC followed by `[`. Arrow
-                        // keys then provide one final byte identifying the direction.
-                        input_state = if c == b'[' {
-                            InputState::ControlSequence
-                        } else {
-                            InputState::Normal
-                        };
-                        continue;
-                    }
-                    InputState::ControlSequence => {
-                        input_state = InputState::Normal;
-
-                        match c {
-                            b'A' if history_index > 0 => {
-                                // ESC [ A: Up arrow
-                                if history_index == history.entries.len() {
-                                    draft = s.clone();
-                                }
-                                history_index -= 1;
-                                s.clone_from(&history.entries[history_index]);
-                                redraw_line(&s);
-                            }
-                            b'B' if history_index < history.entries.len() => {
-                                // ESC [ B: Down arrow
-                                history_index += 1;
-                                if history_index == history.entries.len() {
-                                    s.clone_from(&draft);
-                                } else {
-                                    s.clone_from(&history.entries[history_index]);
-                                }
-                                redraw_line(&s);
-                            }
-                            _ => {}
+        // TODO(mt): I don't know if this is just QEMU but when pressing
+        // enter, it first does a '\r' so we can use this to end the
+        // line.
+        if c == b'\r' {
+            print!("\n");
+            break;
+        }
+
+        match input_state {
+            InputState::Escape => {
+                // ANSI control sequences begin with ESC followed by `[`. Arrow
+                // keys then provide one final byte identifying the direction.
+                input_state = if c == b'[' {
+                    InputState::ControlSequence
+                } else {
+                    InputState::Normal
+                };
+                continue;
+            }
+            InputState::ControlSequence => {
+                input_state = InputState::Normal;
+
+                match c {
+                    b'A' if history_index > 0 => {
+                        // ESC [ A: Up arrow
+                        if history_index == history.entries.len() {
+                            draft = s.clone();
                         }
-                        continue;
+                        history_index -= 1;
+                        s.clone_from(&history.entries[history_index]);
+                        redraw_line(&s);
                     }
-                    InputState::Normal => {}
-                }
-
-                if c == ASCII_ESCAPE {
-                    input_state = InputState::Escape;
-                    continue;
-                }
-
-                // Terminals commonly send either DEL or BS for the backspace key.
-                if c == ASCII_DELETE || c == ASCII_BACKSPACE {
-                    if s.pop().is_some() {
-                        print!("\x08 \x08");
+                    b'B' if history_index < history.entries.len() => {
+                        // ESC [ B: Down arrow
+                        history_index += 1;
+                        if history_index == history.entries.len() {
+                            s.clone_from(&draft);
+                        } else {
+                            s.clone_from(&history.entries[history_index]);
+                        }
+                        redraw_line(&s);
                     }
-                    continue;
+                    _ => {}
                 }
+                continue;
+            }
+            InputState::Normal => {}
+        }
 
-                print!("{}", c as char);
+        if c == ASCII_ESCAPE {
+            input_state = InputState::Escape;
+            continue;
+        }
 
-                s.push(c as char);
+        // Terminals commonly send either DEL or BS for the backspace key.
+        if c == ASCII_DELETE || c == ASCII_BACKSPACE {
+            if s.pop().is_some() {
+                print!("\x08 \x08");
             }
+            continue;
         }
+
+        print!("{}", c as char);
+
+        s.push(c as char);
     }
 
     s
@@ -198,7 +193,9 @@ fn memory() {
 }
 
 fn exit() {
-    crate::filesystem::api::flush();
+    crate::vfs::unmount_all();
+    // The root filesystem stays in `FS` after leaving the mount table.
+    crate::filesystem::api::unmount();
     crate::exit_qemu(0);
 }
 
@@ -225,24 +222,217 @@ fn help() {
     println!("  mkdir <name>        -- creates a new directory");
     println!("  touch <name>        -- creates a new file");
     println!("  rm <path>           -- removes a file");
+    println!("  mv <from> <to>      -- renames or moves a file");
+    println!("  cd [dir]            -- change the working directory, relative paths start there");
+    println!("  pwd                 -- print the working directory");
+    println!("  mount               -- list mounted filesystems");
+    println!(
+        "  mount <dev> <dir>   -- mount a filesystem at a directory, dev: ramdisk, vdb, tmpfs, proc, dev"
+    );
+    println!("  umount <dir>        -- unmount the filesystem mounted at a directory");
+    println!("  find [dir] [-name <pattern>]");
+    println!("                      -- list everything below dir, or only names matching pattern");
+    println!("  watch <path>        -- print changes of a file or directory until ctrl-c");
     println!("  dumpfs              -- dump of the filesystem");
     println!("  cat <file>          -- print content of file to the console");
+    println!("  cat <file> <offset> [len]");
+    println!("                      -- print len bytes (one block by default) from offset on");
+    println!("  stat <path>         -- show size and allocated blocks of a file");
     println!("  uptime              -- show for how long the system is running");
     println!("  write <file> <text> -- write text to the file");
+    println!("  writeat <file> <offset> <text>");
+    println!("                      -- overwrite the file with text from offset on");
     println!("  tree                -- show a tree view of the filesystem");
     println!("  flush               -- flush filesystem metadata to disk");
+    println!("  fsync <path>        -- flush the metadata of a single file to disk");
+    println!("  snapshot create|delete|rollback <name>");
+    println!("                      -- take, delete or restore a snapshot of the filesystem");
+    println!("  snapshot list       -- list all snapshots");
+    println!("  open <path>         -- open a file to lock it, prints the handle");
+    println!("  close <handle>      -- close a handle and release its lock");
+    println!("  lock <handle> shared|exclusive");
+    println!(
+        "                      -- take an advisory lock, fails while another handle conflicts"
+    );
+    println!("  unlock <handle>     -- release the lock of a handle");
+    println!("  locks               -- show all advisory locks and their holders");
     println!("  history             -- show recently entered commands");
+    println!("  path arguments with *, ? or ** run the command for every matching path, or every");
+    println!("  combination of matching paths if there are several patterns");
     println!("  allocate <n>        -- allocate memory of size n to test the kernel allocator");
 }
 
-fn normalize_root_path(path: &str) -> String {
-    let mut path = String::from_str(path).unwrap();
+fn snapshot(action: &SnapshotAction) {
+    let result = match action {
+        SnapshotAction::Create(name) => crate::filesystem::api::create_snapshot(name),
+        SnapshotAction::Delete(name) => crate::filesystem::api::delete_snapshot(name),
+        SnapshotAction::Rollback(name) => crate::filesystem::api::rollback(name),
+        SnapshotAction::List => {
+            for snapshot in crate::filesystem::api::snapshots() {
+                println!("  {:<24}  {:>5} inodes", snapshot.name, snapshot.inodes);
+            }
+            Ok(())
+        }
+    };
+
+    if let Err(e) = result {
+        println!("snapshot failed: {e:?}");
+    }
+}
+
+/// Prints the events of `path` until ctrl-c is pressed.
+fn watch(path: &str) {
+    const ASCII_END_OF_TEXT: u8 = 3;
+
+    let id = match crate::vfs::root_path(path).and_then(|path| crate::filesystem::api::watch(&path))
+    {
+        Ok(id) => id,
+        Err(e) => {
+            println!("watch failed: {e:?}");
+            return;
+        }
+    };
+
+    println!("watching {path}, press ctrl-c to stop");
+    while crate::println::try_read_byte() != Some(ASCII_END_OF_TEXT) {
+        for event in crate::filesystem::api::read_events(id).unwrap_or_default() {
+            match event {
+                WatchEvent::Created(path) => println!("  created  {path}"),
+                WatchEvent::Removed(path) => println!("  removed  {path}"),
+                WatchEvent::Modified(path) => println!("  modified {path}"),
+                WatchEvent::Renamed { from, to } => println!("  renamed  {from} -> {to}"),
+                WatchEvent::Overflow => println!("  (events dropped)"),
+            }
+        }
+        core::hint::spin_loop();
+    }
+
+    let _ = crate::filesystem::api::unwatch(id);
+}
+
+fn locks() {
+    for lock in crate::filesystem::api::locks() {
+        let kind = match lock.kind {
+            LockKind::Shared => "shared",
+            LockKind::Exclusive => "exclusive",
+        };
+        println!(
+            "  {:>4}  {:<9}  {:>5}  {}",
+            lock.handle.inner(),
+            kind,
+            lock.inode.inner(),
+            lock.path
+        );
+    }
+}
+
+fn parse_handle(handle: &str) -> Option<FileHandle> {
+    handle.parse().ok().map(FileHandle::new)
+}
+
+/// Turns a path typed by the user into an absolute one, relative paths
+/// start at the working directory `cwd`.
+fn absolute_path(path: &str, cwd: &str) -> String {
+    crate::vfs::absolute_path(cwd, path)
+}
+
+/// Expands every argument containing wildcards into the paths it matches,
+/// with one line per combination, e.g. `cat *.txt` into a `cat` of every
+/// text file. Arguments without wildcards or matches are kept as they are.
+fn expand_globs(line: &str, cwd: &str) -> Vec<String> {
+    let parts: Vec<&str> = line.trim().split(' ').collect();
+
+    // `find` matches its pattern itself.
+    if parts.len() < 2 || parts[0] == "find" {
+        return alloc::vec![String::from(line)];
+    }
+
+    let mut lines = alloc::vec![String::from(parts[0])];
+    for part in &parts[1..] {
+        let mut choices = Vec::new();
+        if crate::vfs::is_glob(part) {
+            choices = crate::vfs::glob(&absolute_path(part, cwd));
+        }
+        if choices.is_empty() {
+            choices.push(String::from(*part));
+        }
 
-    if !path.starts_with('/') {
-        path.insert(0, '/');
+        lines = lines
+            .iter()
+            .flat_map(|line| {
+                choices
+                    .iter()
+                    .map(move |choice| alloc::format!("{line} {choice}"))
+            })
+            .collect();
     }
 
-    path
+    lines
+}
+
+fn cd(cwd: &mut String, path: &str) {
+    match crate::vfs::stat(path) {
+        Ok(metadata) if metadata.is_directory => *cwd = String::from(path),
+        Ok(_) => println!("cd failed: {:?}", Error::NotADirectory),
+        Err(e) => println!("cd failed: {e:?}"),
+    }
+}
+
+fn ls(dir: &str) -> Result<(), Error> {
+    let names = crate::vfs::readdir(dir)?;
+
+    println!("  TYPE  INODE       SIZE  NAME");
+    println!("  ----  -----  ---------  ----");
+    for name in names {
+        let path = absolute_path(&name, dir);
+        let Ok(metadata) = crate::vfs::stat(&path) else {
+            continue;
+        };
+        let (kind, size, name) = crate::vfs::entry_display(&metadata, name);
+        println!(
+            "  {kind}     [{:>3}]  {size:>9}  {name}",
+            metadata.inode.inner()
+        );
+    }
+
+    Ok(())
+}
+
+/// Prints the tree below `/`, including every mounted filesystem.
+fn tree() {
+    fn inner(dir: &str, prefix: &str) {
+        let names = crate::vfs::readdir(dir).unwrap_or_default();
+        let count = names.len();
+
+        for (i, name) in names.into_iter().enumerate() {
+            let path = absolute_path(&name, dir);
+            let Ok(metadata) = crate::vfs::stat(&path) else {
+                continue;
+            };
+
+            let is_last = i == count - 1;
+            let connector = if is_last { "└── " } else { "├── " };
+            let (kind, size, name) = crate::vfs::entry_display(&metadata, name);
+            println!("{prefix}{connector}{kind}  {size:>9}  {name}");
+
+            if metadata.is_directory {
+                let child_prefix = alloc::format!("{prefix}{}   ", if is_last { " " } else { "│" });
+                inner(&path, &child_prefix);
+            }
+        }
+    }
+
+    println!("/");
+    inner("/", "");
+}
+
+fn mounts() {
+    for mount in crate::vfs::mounts() {
+        println!(
+            "  {:<8}  {:<8}  {}",
+            mount.source, mount.fs_type, mount.path
+        );
+    }
 }
 
 fn shell_allocate(size: usize) {
@@ -280,27 +470,116 @@ enum ShellCommand {
     Hello,
     Exit,
     MemoryDump,
-    Timer { secs: usize },
+    Timer {
+        secs: usize,
+    },
     SysInfo,
     Uptime,
     Help,
-    Allocate { size: usize },
-    Bench { n: usize, size: usize },
-    Ls { path: String }, // INodeIndex for now
-    Mkdir { name: String },
+    Allocate {
+        size: usize,
+    },
+    Bench {
+        n: usize,
+        size: usize,
+    },
+    Ls {
+        path: String,
+    }, // INodeIndex for now
+    Mkdir {
+        name: String,
+    },
     DumpFs,
-    Write { path: String, text: String },
-    Cat { path: String },
-    Touch { path: String },
-    Rm { path: String },
+    Write {
+        path: String,
+        text: String,
+    },
+    Cat {
+        path: String,
+        /// Offset and length to read instead of the whole file, block
+        /// devices can only be read like this.
+        range: Option<(usize, usize)>,
+    },
+    WriteAt {
+        path: String,
+        offset: usize,
+        text: String,
+    },
+    Stat {
+        path: String,
+    },
+    Touch {
+        path: String,
+    },
+    Rm {
+        path: String,
+    },
+    Mv {
+        from: String,
+        to: String,
+    },
+    Find {
+        query: FindQuery,
+    },
+    Mount {
+        dev: String,
+        dir: String,
+    },
+    Umount {
+        dir: String,
+    },
+    Mounts,
+    Cd {
+        path: String,
+    },
+    Pwd,
+    Watch {
+        path: String,
+    },
     Tree,
     Flush,
+    Fsync {
+        path: String,
+    },
+    Snapshot {
+        action: SnapshotAction,
+    },
+    Open {
+        path: String,
+    },
+    Close {
+        handle: FileHandle,
+    },
+    Lock {
+        request: LockRequest,
+    },
+    Unlock {
+        handle: FileHandle,
+    },
+    Locks,
     History,
 }
 
+enum SnapshotAction {
+    Create(String),
+    List,
+    Delete(String),
+    Rollback(String),
+}
+
+struct FindQuery {
+    dir: String,
+    pattern: Option<String>,
+}
+
+struct LockRequest {
+    handle: FileHandle,
+    kind: LockKind,
+}
+
 impl ShellCommand {
     /// A very naive way of reading user-input but for this shell it's fine :)
-    fn from_line(line: &str) -> Option<ShellCommand> {
+    fn from_line(line: &str, cwd: &str) -> Option<ShellCommand> {
         let parts: Vec<&str> = line.trim().split(' ').collect();
 
         if parts.is_empty() {
@@ -332,41 +611,139 @@ impl ShellCommand {
             }
             "help" => ShellCommand::Help,
             "mkdir" => {
-                let name = normalize_root_path(parts.get(1)?);
+                let name = absolute_path(parts.get(1)?, cwd);
                 ShellCommand::Mkdir { name }
             }
             "touch" => {
-                let name = normalize_root_path(parts.get(1)?);
+                let name = absolute_path(parts.get(1)?, cwd);
                 ShellCommand::Touch { path: name }
             }
             "rm" => {
-                let path = normalize_root_path(parts.get(1)?);
+                let path = absolute_path(parts.get(1)?, cwd);
                 ShellCommand::Rm { path }
             }
+            "pwd" => ShellCommand::Pwd,
+            "cd" => ShellCommand::Cd {
+                path: absolute_path(parts.get(1).unwrap_or(&"/"), cwd),
+            },
+            "mount" => match (parts.get(1), parts.get(2)) {
+                (None, _) => ShellCommand::Mounts,
+                (Some(dev), Some(dir)) => ShellCommand::Mount {
+                    dev: String::from(*dev),
+                    dir: absolute_path(dir, cwd),
+                },
+                _ => return None,
+            },
+            "umount" => ShellCommand::Umount {
+                dir: absolute_path(parts.get(1)?, cwd),
+            },
+            "mv" => ShellCommand::Mv {
+                from: absolute_path(parts.get(1)?, cwd),
+                to: absolute_path(parts.get(2)?, cwd),
+            },
+            "find" => {
+                let (dir, rest) = match parts.get(1) {
+                    Some(dir) if *dir != "-name" => (*dir, &parts[2..]),
+                    _ => (".", &parts[1..]),
+                };
+                let pattern = match rest {
+                    [] => None,
+                    ["-name", pattern] => Some(String::from(*pattern)),
+                    _ => return None,
+                };
+                ShellCommand::Find {
+                    query: FindQuery {
+                        dir: absolute_path(dir, cwd),
+                        pattern,
+                    },
+                }
+            }
+            "watch" => {
+                let path = absolute_path(parts.get(1)?, cwd);
+                ShellCommand::Watch { path }
+            }
             "ls" => {
-                let dir = normalize_root_path(parts.get(1).unwrap_or_else(|| &"."));
+                let dir = absolute_path(parts.get(1).unwrap_or(&"."), cwd);
                 ShellCommand::Ls { path: dir }
             }
             "dumpfs" => ShellCommand::DumpFs,
             "flush" => ShellCommand::Flush,
+            "fsync" => {
+                let path = absolute_path(parts.get(1)?, cwd);
+                ShellCommand::Fsync { path }
+            }
             "write" => {
                 let (head, rest) = parts.split_at(2);
                 ShellCommand::Write {
-                    path: normalize_root_path(head.get(1)?),
+                    path: absolute_path(head.get(1)?, cwd),
+                    text: rest.join(" "),
+                }
+            }
+            "writeat" => {
+                let (head, rest) = parts.split_at(parts.len().min(3));
+                ShellCommand::WriteAt {
+                    path: absolute_path(head.get(1)?, cwd),
+                    offset: head.get(2)?.parse().ok()?,
                     text: rest.join(" "),
                 }
             }
             "cat" => {
-                let path = normalize_root_path(parts.get(1)?);
-                ShellCommand::Cat { path }
+                let path = absolute_path(parts.get(1)?, cwd);
+                let range = match parts[2..] {
+                    [] => None,
+                    [offset] => Some((offset.parse().ok()?, crate::filesystem::BLOCK_SIZE)),
+                    [offset, len] => Some((offset.parse().ok()?, len.parse().ok()?)),
+                    _ => return None,
+                };
+                ShellCommand::Cat { path, range }
+            }
+            "stat" => {
+                let path = absolute_path(parts.get(1)?, cwd);
+                ShellCommand::Stat { path }
+            }
+            "snapshot" => {
+                let name = parts.get(2).map(|name| String::from(*name));
+                let action = match *parts.get(1)? {
+                    "create" => SnapshotAction::Create(name?),
+                    "list" => SnapshotAction::List,
+                    "delete" => SnapshotAction::Delete(name?),
+                    "rollback" => SnapshotAction::Rollback(name?),
+                    _ => return None,
+                };
+                ShellCommand::Snapshot { action }
             }
+            "open" => {
+                let path = absolute_path(parts.get(1)?, cwd);
+                ShellCommand::Open { path }
+            }
+            "close" => ShellCommand::Close {
+                handle: parse_handle(parts.get(1)?)?,
+            },
+            "lock" => {
+                let handle = parse_handle(parts.get(1)?)?;
+                let kind = match *parts.get(2)? {
+                    "shared" => LockKind::Shared,
+                    "exclusive" => LockKind::Exclusive,
+                    _ => return None,
+                };
+                if parts.len() > 3 {
+                    return None;
+                }
+                ShellCommand::Lock {
+                    request: LockRequest { handle, kind },
+                }
+            }
+            "unlock" => ShellCommand::Unlock {
+                handle: parse_handle(parts.get(1)?)?,
+            },
+            "locks" => ShellCommand::Locks,
             _ => return None,
         };
 
         Some(command)
     }
 
-    fn call(&self, history: &CommandHistory) {
+    fn call(&self, history: &CommandHistory, cwd: &mut String) {
         match self {
             ShellCommand::Help => help(),
             ShellCommand::Hello => hello(),
@@ -377,47 +754,145 @@ impl ShellCommand {
             ShellCommand::Allocate { size } => shell_allocate(*size),
             ShellCommand::Timer { secs } => crate::timer::new_time(*secs),
             ShellCommand::Ls { path: dir } => {
-                if let Err(e) = crate::filesystem::api::dump_dir(dir) {
+                if let Err(e) = ls(dir) {
                     println!("ls failed: {e:?}");
                 }
             }
             ShellCommand::Mkdir { name } => {
-                if let Err(e) = crate::filesystem::api::mkdir(name) {
+                if let Err(e) = crate::vfs::create(name, FileType::Directory) {
                     println!("mkdir failed: {e:?}");
                 }
             }
             ShellCommand::Touch { path: name } => {
-                if let Err(e) = crate::filesystem::api::create_file(name) {
+                if let Err(e) = crate::vfs::create(name, FileType::File) {
                     println!("touch failed: {e:?}");
                 }
             }
             ShellCommand::Rm { path } => {
-                if let Err(e) = crate::filesystem::api::remove_dir_entry(path) {
+                if let Err(e) = crate::vfs::remove(path) {
                     println!("rm failed: {e:?}");
                 }
             }
+            ShellCommand::Mv { from, to } => {
+                if let Err(e) = crate::vfs::rename(from, to) {
+                    println!("mv failed: {e:?}");
+                }
+            }
+            ShellCommand::Find { query } => match crate::vfs::walk(&query.dir, None) {
+                Ok(entries) => entries
+                    .iter()
+                    .filter(|entry| {
+                        query
+                            .pattern
+                            .as_deref()
+                            .is_none_or(|pattern| crate::vfs::glob_match(pattern, entry.name()))
+                    })
+                    .for_each(|entry| println!("{}", entry.path)),
+                Err(e) => println!("find failed: {e:?}"),
+            },
+            ShellCommand::Watch { path } => watch(path),
+            ShellCommand::Cd { path } => cd(cwd, path),
+            ShellCommand::Pwd => println!("{cwd}"),
+            ShellCommand::Mount { dev, dir } => {
+                if let Err(e) = crate::vfs::mount(dev, dir) {
+                    println!("mount failed: {e:?}");
+                }
+            }
+            ShellCommand::Umount { dir } => {
+                if let Err(e) = crate::vfs::umount(dir) {
+                    println!("umount failed: {e:?}");
+                }
+            }
+            ShellCommand::Mounts => mounts(),
             ShellCommand::DumpFs => {
                 crate::filesystem::api::dump();
             }
-            ShellCommand::Cat { path } => match crate::filesystem::api::read_file(path) {
-                Ok(output) => println!("{output}"),
+            ShellCommand::Cat {
+                path,
+                range: Some((offset, len)),
+            } => {
+                let mut buf = alloc::vec![0; (*len).min(MAX_CAT_RANGE)];
+                match crate::vfs::read_at(path, *offset, &mut buf) {
+                    Ok(read) => println!("{}", String::from_utf8_lossy(&buf[..read])),
+                    Err(e) => println!("cat failed: {e:?}"),
+                }
+            }
+            ShellCommand::Cat { path, range: None } => match crate::vfs::read(path) {
+                Ok(output) => println!("{}", String::from_utf8_lossy(&output)),
                 Err(e) => println!("cat failed: {e:?}"),
             },
+            ShellCommand::Stat { path } => match crate::vfs::stat(path) {
+                Ok(metadata) => {
+                    let kind = if metadata.is_directory {
+                        "directory"
+                    } else if metadata.inline {
+                        "file (inline)"
+                    } else if metadata.compressed {
+                        "file (compressed)"
+                    } else {
+                        "file"
+                    };
+                    println!("  Path:   {path}");
+                    println!("  Type:   {kind}");
+                    println!("  INode:  {}", metadata.inode.inner());
+                    println!("  Size:   {} bytes", metadata.size);
+                    println!(
+                        "  Blocks: {} allocated ({} bytes)",
+                        metadata.allocated_blocks,
+                        metadata.allocated_blocks * crate::filesystem::BLOCK_SIZE
+                    );
+                }
+                Err(e) => println!("stat failed: {e:?}"),
+            },
             ShellCommand::Uptime => {
                 let time = crate::timer::uptime();
                 println!("Currently running for {time}s");
             }
             ShellCommand::Write { path, text } => {
-                if let Err(e) = crate::filesystem::api::write_to_file(path, text.clone()) {
+                if let Err(e) = crate::vfs::write(path, text.as_bytes()) {
                     println!("Writing to file failed: {e}");
                 }
             }
-            ShellCommand::Tree => {
-                crate::filesystem::api::tree();
+            ShellCommand::WriteAt { path, offset, text } => {
+                if let Err(e) = crate::vfs::write_at(path, *offset, text.as_bytes()) {
+                    println!("Writing to file failed: {e}");
+                }
             }
+            ShellCommand::Tree => tree(),
             ShellCommand::Flush => {
                 crate::filesystem::api::flush();
             }
+            ShellCommand::Fsync { path } => {
+                if let Err(e) = crate::vfs::root_path(path)
+                    .and_then(|path| crate::filesystem::api::fsync(&path))
+                {
+                    println!("fsync failed: {e:?}");
+                }
+            }
+            ShellCommand::Snapshot { action } => snapshot(action),
+            ShellCommand::Open { path } => match crate::vfs::root_path(path)
+                .and_then(|path| crate::filesystem::api::open(&path))
+            {
+                Ok(handle) => println!("opened {path} as handle {}", handle.inner()),
+                Err(e) => println!("open failed: {e:?}"),
+            },
+            ShellCommand::Close { handle } => {
+                if let Err(e) = crate::filesystem::api::close(*handle) {
+                    println!("close failed: {e:?}");
+                }
+            }
+            ShellCommand::Lock { request } => {
+                let LockRequest { handle, kind } = request;
+                if let Err(e) = crate::filesystem::api::lock(*handle, *kind) {
+                    println!("lock failed: {e:?}");
+                }
+            }
+            ShellCommand::Unlock { handle } => {
+                if let Err(e) = crate::filesystem::api::unlock(*handle) {
+                    println!("unlock failed: {e:?}");
+                }
+            }
+            ShellCommand::Locks => locks(),
             ShellCommand::History => history.print(),
         }
     }
@@ -427,14 +902,17 @@ impl ShellCommand {
 /// and reads from the UART and outputs something based on the command.
 pub fn shell() -> ! {
     let mut history = CommandHistory::new();
+    let mut cwd = String::from("/");
 
     loop {
         let line = read_line_and_display(&history);
         history.push(line.clone());
 
-        match ShellCommand::from_line(&line) {
-            Some(command) => command.call(&history),
-            None => println!("ShellCommand not found: '{line}'"),
+        for line in expand_globs(&line, &cwd) {
+            match ShellCommand::from_line(&line, &cwd) {
+                Some(command) => command.call(&history, &mut cwd),
+                None => println!("ShellCommand not found: '{line}'"),
+            }
         }
     }
 }
@@ -0,0 +1,267 @@
+extern crate alloc;
+use super::{Driver, Error, FileType, INodeIndex, Metadata};
+use crate::filesystem::{BLOCK_SIZE, BlockIndex, KernelBlockDevice, api};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use filesystem::BlockDevice;
+
+/// The devices of the kernel as files, mounted at `/dev`.
+///
+/// Block devices are read and written at offsets only, with `read_at` and
+/// `write_at`, as reading them whole wouldn't fit into memory. Writing to
+/// them bypasses any filesystem mounted on them.
+pub struct Devfs;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Device {
+    /// The VirtIO block device the root filesystem is on, if any.
+    Vda,
+    Ram0,
+    Console,
+    /// Reads nothing, discards what's written.
+    Null,
+    /// Reads zeros, discards what's written.
+    Zero,
+    /// Reads pseudo-random bytes, not suitable for cryptography.
+    Random,
+}
+
+const DEVICES: &[(&str, Device)] = &[
+    ("vda", Device::Vda),
+    ("ram0", Device::Ram0),
+    ("console", Device::Console),
+    ("null", Device::Null),
+    ("zero", Device::Zero),
+    ("random", Device::Random),
+];
+
+impl Device {
+    fn is_block_device(self) -> bool {
+        matches!(self, Device::Vda | Device::Ram0)
+    }
+
+    /// Runs `f` with the block device, `None` for character devices.
+    fn with_block_device<R>(
+        self,
+        f: impl FnOnce(&mut KernelBlockDevice) -> R,
+    ) -> Result<Option<R>, Error> {
+        match self {
+            Device::Ram0 => Ok(Some(f(&mut KernelBlockDevice::Ramdisk))),
+            Device::Vda => api::with_block_device(|dev| match dev {
+                KernelBlockDevice::VirtIO(_) => Ok(Some(f(dev))),
+                KernelBlockDevice::Ramdisk => Err(Error::NotFound),
+            })?,
+            _ => Ok(None),
+        }
+    }
+
+    /// The size of a block device, character devices have none.
+    fn size(self) -> Result<usize, Error> {
+        let blocks = self.with_block_device(|dev| dev.total_blocks())?;
+        Ok(blocks.unwrap_or(0) * BLOCK_SIZE)
+    }
+}
+
+impl Devfs {
+    fn device(path: &str) -> Result<(usize, Device), Error> {
+        let name = path.trim_start_matches('/');
+        let (index, device) = DEVICES
+            .iter()
+            .enumerate()
+            .find(|(_, (device, _))| *device == name)
+            .map(|(index, (_, device))| (index, *device))
+            .ok_or(Error::NotFound)?;
+
+        // Without a VirtIO device there is no `vda`.
+        device.size()?;
+        Ok((index, device))
+    }
+}
+
+/// Reads blocks of `dev` into `buf` starting at the byte `offset`.
+fn read_blocks(dev: &mut KernelBlockDevice, offset: usize, buf: &mut [u8]) -> usize {
+    let size = dev.total_blocks() * BLOCK_SIZE;
+    let len = buf.len().min(size.saturating_sub(offset));
+
+    let mut block = [0; BLOCK_SIZE];
+    let mut done = 0;
+    while done < len {
+        let position = offset + done;
+        let in_block = position % BLOCK_SIZE;
+        let chunk = (BLOCK_SIZE - in_block).min(len - done);
+
+        dev.read_block(
+            BlockIndex::from_raw((position / BLOCK_SIZE) as u32),
+            &mut block,
+        );
+        buf[done..done + chunk].copy_from_slice(&block[in_block..in_block + chunk]);
+        done += chunk;
+    }
+    done
+}
+
+/// Writes `bytes` to `dev` starting at the byte `offset`, blocks that are
+/// only partially overwritten are read first.
+fn write_blocks(dev: &mut KernelBlockDevice, offset: usize, bytes: &[u8]) -> Result<usize, Error> {
+    let size = dev.total_blocks() * BLOCK_SIZE;
+    if offset.checked_add(bytes.len()).is_none_or(|end| end > size) {
+        return Err(Error::NoSpaceLeft);
+    }
+
+    let mut block = [0; BLOCK_SIZE];
+    let mut done = 0;
+    while done < bytes.len() {
+        let position = offset + done;
+        let index = BlockIndex::from_raw((position / BLOCK_SIZE) as u32);
+        let in_block = position % BLOCK_SIZE;
+        let chunk = (BLOCK_SIZE - in_block).min(bytes.len() - done);
+
+        if chunk < BLOCK_SIZE {
+            dev.read_block(index, &mut block);
+        }
+        block[in_block..in_block + chunk].copy_from_slice(&bytes[done..done + chunk]);
+        dev.write_block(index, &block);
+        done += chunk;
+    }
+    Ok(done)
+}
+
+static RANDOM_STATE: AtomicU64 = AtomicU64::new(0);
+
+/// Fills `buf` from a xorshift generator seeded with the time it's first
+/// used.
+fn fill_random(buf: &mut [u8]) {
+    let mut state = RANDOM_STATE.load(Ordering::Relaxed);
+    if state == 0 {
+        state = crate::riscv::asm::rdtime() as u64 | 1;
+    }
+
+    for chunk in buf.chunks_mut(8) {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        chunk.copy_from_slice(&state.to_le_bytes()[..chunk.len()]);
+    }
+    RANDOM_STATE.store(state, Ordering::Relaxed);
+}
+
+impl Driver for Devfs {
+    fn fs_type(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn stat(&self, path: &str) -> Result<Metadata, Error> {
+        let (inode, size, is_directory) = if path == "/" {
+            (INodeIndex::root(), self.readdir(path)?.len(), true)
+        } else {
+            let (index, device) = Self::device(path)?;
+            (INodeIndex::new(index as u32 + 1), device.size()?, false)
+        };
+
+        Ok(Metadata {
+            inode,
+            size,
+            allocated_blocks: 0,
+            is_directory,
+            inline: false,
+            compressed: false,
+        })
+    }
+
+    /// Block devices can't be read whole, `zero` and `random` read a single
+    /// block.
+    fn read(&self, path: &str) -> Result<Vec<u8>, Error> {
+        if path == "/" {
+            return Err(Error::IsDirectory);
+        }
+
+        let (_, device) = Self::device(path)?;
+        if device.is_block_device() {
+            return Err(Error::FileTooLarge);
+        }
+
+        let mut buf = match device {
+            Device::Zero | Device::Random => alloc::vec![0; BLOCK_SIZE],
+            _ => Vec::new(),
+        };
+        let len = if device == Device::Console {
+            // Whatever got typed but wasn't read yet.
+            while let Some(byte) = crate::println::try_read_byte() {
+                buf.push(byte);
+            }
+            buf.len()
+        } else {
+            self.read_at(path, 0, &mut buf)?
+        };
+        buf.truncate(len);
+        Ok(buf)
+    }
+
+    fn read_at(&self, path: &str, offset: usize, buf: &mut [u8]) -> Result<usize, Error> {
+        let (_, device) = Self::device(path)?;
+        if let Some(read) = device.with_block_device(|dev| read_blocks(dev, offset, buf))? {
+            return Ok(read);
+        }
+
+        match device {
+            Device::Zero => buf.fill(0),
+            Device::Random => fill_random(buf),
+            Device::Console => {
+                let mut read = 0;
+                while read < buf.len()
+                    && let Some(byte) = crate::println::try_read_byte()
+                {
+                    buf[read] = byte;
+                    read += 1;
+                }
+                return Ok(read);
+            }
+            _ => return Ok(0),
+        }
+        Ok(buf.len())
+    }
+
+    /// Writes to the console or discards the bytes, block devices are only
+    /// written at offsets.
+    fn write(&self, path: &str, bytes: &[u8]) -> Result<usize, Error> {
+        let (_, device) = Self::device(path)?;
+        if device.is_block_device() {
+            return Err(Error::OperationNotSupported);
+        }
+        self.write_at(path, 0, bytes)
+    }
+
+    fn write_at(&self, path: &str, offset: usize, bytes: &[u8]) -> Result<usize, Error> {
+        let (_, device) = Self::device(path)?;
+        if let Some(written) = device.with_block_device(|dev| write_blocks(dev, offset, bytes))? {
+            return written;
+        }
+
+        if device == Device::Console {
+            crate::print!("{}", String::from_utf8_lossy(bytes));
+        }
+        Ok(bytes.len())
+    }
+
+    fn readdir(&self, path: &str) -> Result<Vec<String>, Error> {
+        if path != "/" {
+            Self::device(path)?;
+            return Err(Error::NotADirectory);
+        }
+
+        Ok(DEVICES
+            .iter()
+            .filter(|(_, device)| device.size().is_ok())
+            .map(|(name, _)| String::from(*name))
+            .collect())
+    }
+
+    fn create(&self, _path: &str, _file_type: FileType) -> Result<(), Error> {
+        Err(Error::OperationNotSupported)
+    }
+
+    fn remove(&self, _path: &str) -> Result<(), Error> {
+        Err(Error::OperationNotSupported)
+    }
+}
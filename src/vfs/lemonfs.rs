@@ -0,0 +1,55 @@
+extern crate alloc;
+use super::{Driver, Error, FileType, Metadata};
+use alloc::string::String;
+use alloc::vec::Vec;
+use filesystem::{BlockDevice, Filesystem};
+
+impl<D: BlockDevice + Send> Driver for Filesystem<D> {
+    fn fs_type(&self) -> &'static str {
+        "lemonfs"
+    }
+
+    fn stat(&self, path: &str) -> Result<Metadata, Error> {
+        Filesystem::stat(self, path)
+    }
+
+    fn read(&self, path: &str) -> Result<Vec<u8>, Error> {
+        self.read_bytes(path)
+    }
+
+    fn write(&self, path: &str, bytes: &[u8]) -> Result<usize, Error> {
+        self.write_to_file(path, bytes)
+    }
+
+    fn write_at(&self, path: &str, offset: usize, bytes: &[u8]) -> Result<usize, Error> {
+        Filesystem::write_at(self, path, offset, bytes)
+    }
+
+    fn readdir(&self, path: &str) -> Result<Vec<String>, Error> {
+        self.read_dir(path)
+    }
+
+    fn create(&self, path: &str, file_type: FileType) -> Result<(), Error> {
+        match file_type {
+            FileType::File => self.create_file(path),
+            FileType::Directory => self.mkdir(path),
+        }
+        .map(|_| ())
+    }
+
+    fn remove(&self, path: &str) -> Result<(), Error> {
+        self.remove_dir_entry(path)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), Error> {
+        Filesystem::rename(self, from, to)
+    }
+
+    fn sync(&self) {
+        self.flush()
+    }
+
+    fn unmount(&self) {
+        self.mark_clean()
+    }
+}
@@ -0,0 +1,357 @@
+//! The virtual filesystem: every filesystem the kernel knows about is mounted
+//! at a directory and paths are resolved across those mount points.
+//!
+//! Paths handed to a `Driver` are always absolute and relative to the root
+//! of the mounted filesystem, `/tmp/notes` on a filesystem mounted at `/tmp`
+//! is `/notes` for its driver. The mount points themselves have to be
+//! directories of the filesystem they're mounted on.
+//!
+//! `MOUNTS` is never held while calling into a driver, drivers like `Procfs`
+//! read the mount table themselves.
+
+extern crate alloc;
+use crate::filesystem::KernelBlockDevice;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use ext2::Ext2;
+use fat32::Fat32;
+use filesystem::Filesystem;
+
+mod devfs;
+mod lemonfs;
+mod mount_table;
+mod procfs;
+mod read_only;
+mod tmpfs;
+
+pub use devfs::Devfs;
+pub use filesystem::{Error, INodeIndex, Metadata, WalkEntry, entry_display, glob_match, is_glob};
+pub use mount_table::{MountInfo, MountTable};
+pub use procfs::Procfs;
+pub use tmpfs::Tmpfs;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileType {
+    File,
+    Directory,
+}
+
+/// A filesystem that can be mounted into the `MountTable`.
+///
+/// All paths are absolute within the filesystem, see the module docs.
+pub trait Driver: Send + Sync {
+    /// Short name of the filesystem type as shown by `mounts`.
+    fn fs_type(&self) -> &'static str;
+
+    fn stat(&self, path: &str) -> Result<Metadata, Error>;
+
+    /// Reads the whole file at `path`.
+    fn read(&self, path: &str) -> Result<Vec<u8>, Error>;
+
+    /// Appends `bytes` to the file at `path`, returning how many got written.
+    fn write(&self, path: &str, bytes: &[u8]) -> Result<usize, Error>;
+
+    /// Reads into `buf` starting at `offset`, returning how many bytes got
+    /// read. Nothing is read at or past the end of the file.
+    fn read_at(&self, path: &str, offset: usize, buf: &mut [u8]) -> Result<usize, Error> {
+        let data = self.read(path)?;
+        let rest = data.get(offset..).unwrap_or_default();
+        let len = rest.len().min(buf.len());
+        buf[..len].copy_from_slice(&rest[..len]);
+        Ok(len)
+    }
+
+    /// Overwrites the file at `path` with `bytes` starting at `offset`,
+    /// returning how many got written.
+    fn write_at(&self, _path: &str, _offset: usize, _bytes: &[u8]) -> Result<usize, Error> {
+        Err(Error::OperationNotSupported)
+    }
+
+    /// Returns the names of the entries of a directory, without `.` and `..`.
+    fn readdir(&self, path: &str) -> Result<Vec<String>, Error>;
+
+    fn create(&self, path: &str, file_type: FileType) -> Result<(), Error>;
+
+    fn remove(&self, path: &str) -> Result<(), Error>;
+
+    /// Resolves `path` to its inode.
+    fn lookup(&self, path: &str) -> Result<INodeIndex, Error> {
+        self.stat(path).map(|metadata| metadata.inode)
+    }
+
+    fn rename(&self, _from: &str, _to: &str) -> Result<(), Error> {
+        Err(Error::OperationNotSupported)
+    }
+
+    /// Writes everything that's only kept in memory to the device.
+    fn sync(&self) {}
+
+    /// Called once the filesystem got removed from the mount table, syncs
+    /// it unless the filesystem has more to record.
+    fn unmount(&self) {
+        self.sync();
+    }
+}
+
+static MOUNTS: spin::RwLock<MountTable> = spin::RwLock::new(MountTable::new());
+
+/// Mounts the root filesystem at `/`, which has to happen before anything
+/// else can be mounted.
+pub fn mount_root(source: &str, driver: Arc<dyn Driver>) -> Result<(), Error> {
+    MOUNTS.write().mount("/", source, driver)
+}
+
+/// Mounts the filesystem `source` at the directory `path`.
+///
+/// Known sources:
+/// * `ramdisk`: the in-memory block device, formatted with LemonFS if it
+///   doesn't hold a filesystem yet
+/// * `vdb`: the second VirtIO block device, read-only if it holds FAT32 or
+///   ext2, LemonFS otherwise
+/// * `tmpfs`: a new, empty `Tmpfs`
+/// * `proc`: the kernel state as `Procfs`
+/// * `dev`: the devices as `Devfs`
+pub fn mount(source: &str, path: &str) -> Result<(), Error> {
+    let (parent, inner) = resolve(path)?;
+    if !parent.stat(&inner)?.is_directory {
+        return Err(Error::NotADirectory);
+    }
+
+    let mut mounts = MOUNTS.write();
+    let driver: Arc<dyn Driver> = match source {
+        // Devices can only be mounted once.
+        "ramdisk" | "vdb" if mounts.mounts().iter().any(|mount| mount.source == source) => {
+            return Err(Error::EntryExists);
+        }
+        "ramdisk" => Arc::new(open_ramdisk()?),
+        "vdb" => open_disk(1)?,
+        "tmpfs" => Arc::new(Tmpfs::new()),
+        "proc" => Arc::new(Procfs),
+        "dev" => Arc::new(Devfs),
+        _ => return Err(Error::NotFound),
+    };
+
+    mounts.mount(path, source, driver)
+}
+
+/// Mounts the filesystems every system has, `Procfs` at `/proc` and `Devfs`
+/// at `/dev`, creating their directories if needed.
+pub fn mount_defaults() {
+    for (source, path) in [("proc", "/proc"), ("dev", "/dev")] {
+        if let Err(Error::NotFound) = stat(path) {
+            let _ = create(path, FileType::Directory);
+        }
+
+        if let Err(e) = mount(source, path) {
+            log::error!("Could not mount {source} at {path}: {e}");
+        }
+    }
+}
+
+/// Unmounts the filesystem mounted at `path`, the root stays mounted until
+/// the kernel exits.
+pub fn umount(path: &str) -> Result<(), Error> {
+    if path == "/" {
+        return Err(Error::OperationNotSupported);
+    }
+
+    let driver = MOUNTS.write().unmount(path)?;
+    driver.unmount();
+    Ok(())
+}
+
+/// Unmounts every filesystem including the root before the kernel exits,
+/// the ones mounted below others first.
+pub fn unmount_all() {
+    let drivers = MOUNTS.write().unmount_all();
+    for driver in drivers {
+        driver.unmount();
+    }
+}
+
+pub fn mounts() -> Vec<MountInfo> {
+    MOUNTS.read().mounts()
+}
+
+/// Finds the driver responsible for `path` and the path within it.
+fn resolve(path: &str) -> Result<(Arc<dyn Driver>, String), Error> {
+    MOUNTS.read().resolve(path)
+}
+
+/// Returns `path` within the root filesystem, for what only the LemonFS at
+/// `/` supports like watches and handles. Paths on other mounts fail with
+/// `OperationNotSupported`.
+pub fn root_path(path: &str) -> Result<String, Error> {
+    let mounts = MOUNTS.read();
+    let (driver, inner) = mounts.resolve(path)?;
+    let (root, _) = mounts.resolve("/")?;
+    if !Arc::ptr_eq(&driver, &root) {
+        return Err(Error::OperationNotSupported);
+    }
+    Ok(inner)
+}
+
+pub fn lookup(path: &str) -> Result<INodeIndex, Error> {
+    let (driver, path) = resolve(path)?;
+    driver.lookup(&path)
+}
+
+pub fn stat(path: &str) -> Result<Metadata, Error> {
+    let (driver, path) = resolve(path)?;
+    driver.stat(&path)
+}
+
+pub fn read(path: &str) -> Result<Vec<u8>, Error> {
+    let (driver, path) = resolve(path)?;
+    driver.read(&path)
+}
+
+pub fn write(path: &str, bytes: &[u8]) -> Result<usize, Error> {
+    let (driver, path) = resolve(path)?;
+    driver.write(&path, bytes)
+}
+
+pub fn read_at(path: &str, offset: usize, buf: &mut [u8]) -> Result<usize, Error> {
+    let (driver, path) = resolve(path)?;
+    driver.read_at(&path, offset, buf)
+}
+
+pub fn write_at(path: &str, offset: usize, bytes: &[u8]) -> Result<usize, Error> {
+    let (driver, path) = resolve(path)?;
+    driver.write_at(&path, offset, bytes)
+}
+
+pub fn readdir(path: &str) -> Result<Vec<String>, Error> {
+    let (driver, path) = resolve(path)?;
+    driver.readdir(&path)
+}
+
+pub fn create(path: &str, file_type: FileType) -> Result<(), Error> {
+    let (driver, path) = resolve(path)?;
+    driver.create(&path, file_type)
+}
+
+pub fn remove(path: &str) -> Result<(), Error> {
+    let (driver, path) = resolve(path)?;
+    driver.remove(&path)
+}
+
+/// Renames within a single filesystem, moving between filesystems isn't
+/// supported.
+pub fn rename(from: &str, to: &str) -> Result<(), Error> {
+    let (driver, from) = resolve(from)?;
+    let (to_driver, to) = resolve(to)?;
+    if !Arc::ptr_eq(&driver, &to_driver) {
+        return Err(Error::OperationNotSupported);
+    }
+    driver.rename(&from, &to)
+}
+
+/// Returns `path` and everything below it depth-first, crossing into
+/// mounted filesystems. Entries below `max_depth` are left out.
+pub fn walk(path: &str, max_depth: Option<usize>) -> Result<Vec<WalkEntry>, Error> {
+    fn inner(
+        path: String,
+        metadata: Metadata,
+        depth: usize,
+        max_depth: Option<usize>,
+        out: &mut Vec<WalkEntry>,
+    ) {
+        let descend = metadata.is_directory && max_depth.is_none_or(|max| depth < max);
+        out.push(WalkEntry {
+            path: path.clone(),
+            metadata,
+            depth,
+        });
+        if !descend {
+            return;
+        }
+
+        let parent = path.trim_end_matches('/');
+        for name in readdir(&path).unwrap_or_default() {
+            let child = alloc::format!("{parent}/{name}");
+            // The entry may have been removed since its directory was read.
+            if let Ok(metadata) = stat(&child) {
+                inner(child, metadata, depth + 1, max_depth, out);
+            }
+        }
+    }
+
+    let path = absolute_path("/", path);
+    let metadata = stat(&path)?;
+    let mut entries = Vec::new();
+    inner(path, metadata, 0, max_depth, &mut entries);
+    Ok(entries)
+}
+
+/// Returns the sorted absolute paths matching the glob `pattern`, see
+/// `glob_match`. Relative patterns start at the root.
+pub fn glob(pattern: &str) -> Vec<String> {
+    let components: Vec<_> = pattern.split('/').filter(|s| !s.is_empty()).collect();
+    let literal = components.iter().take_while(|c| !is_glob(c)).count();
+    let rest = &components[literal..];
+    let max_depth = (!rest.contains(&"**")).then_some(rest.len());
+
+    let start = alloc::format!("/{}", components[..literal].join("/"));
+    let mut matches: Vec<_> = walk(&start, max_depth)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|entry| entry.path)
+        .filter(|path| glob_match(pattern, path))
+        .collect();
+
+    matches.sort();
+    matches
+}
+
+/// Turns `path` into an absolute path without `.` and `..`, relative paths
+/// start at the absolute directory `cwd`.
+///
+/// This is purely lexical, so `..` after a mount point leads back to the
+/// filesystem it's mounted on.
+pub fn absolute_path(cwd: &str, path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    if !path.starts_with('/') {
+        parts.extend(cwd.split('/').filter(|s| !s.is_empty()));
+    }
+
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+
+    alloc::format!("/{}", parts.join("/"))
+}
+
+/// Mounts LemonFS on the ramdisk, formatting it first if it holds no
+/// filesystem yet.
+fn open_ramdisk() -> Result<Filesystem<KernelBlockDevice>, Error> {
+    match Filesystem::new(KernelBlockDevice::Ramdisk) {
+        Err(Error::InvalidSuperblock) => {
+            Filesystem::format(KernelBlockDevice::Ramdisk)?;
+            Filesystem::new(KernelBlockDevice::Ramdisk)
+        }
+        mounted => mounted,
+    }
+}
+
+/// Mounts the `index`th VirtIO block device, with FAT32 or ext2 if it holds
+/// one of them and LemonFS otherwise.
+fn open_disk(index: usize) -> Result<Arc<dyn Driver>, Error> {
+    let disk = crate::virtio2::make_device_at(index).ok_or(Error::NotFound)?;
+    let mut device = KernelBlockDevice::VirtIO(disk);
+
+    if Fat32::probe(&mut device) {
+        Ok(Arc::new(Fat32::mount(device)?))
+    } else if Ext2::probe(&mut device) {
+        Ok(Arc::new(Ext2::mount(device)?))
+    } else {
+        Ok(Arc::new(Filesystem::new(device)?))
+    }
+}
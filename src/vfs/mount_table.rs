@@ -0,0 +1,134 @@
+extern crate alloc;
+use super::{Driver, Error, absolute_path};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// A mounted filesystem as returned by `MountTable::mounts`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MountInfo {
+    pub path: String,
+
+    /// What got mounted, e.g. the device.
+    pub source: String,
+    pub fs_type: &'static str,
+}
+
+struct Mount {
+    path: String,
+    source: String,
+    driver: Arc<dyn Driver>,
+}
+
+/// The mounted filesystems keyed by the directory they're mounted at.
+pub struct MountTable {
+    /// Sorted by path, so parents come before the filesystems mounted
+    /// below them.
+    mounts: Vec<Mount>,
+}
+
+impl MountTable {
+    pub const fn new() -> Self {
+        Self { mounts: Vec::new() }
+    }
+
+    /// Mounts `driver` at `path`. Only a single filesystem can be mounted at
+    /// a directory, and everything but the root needs a parent mount.
+    pub fn mount(
+        &mut self,
+        path: &str,
+        source: &str,
+        driver: Arc<dyn Driver>,
+    ) -> Result<(), Error> {
+        let path = absolute_path("/", path);
+        if path != "/" && self.mounts.is_empty() {
+            return Err(Error::NotFound);
+        }
+
+        match self.mounts.binary_search_by(|mount| mount.path.cmp(&path)) {
+            Ok(_) => Err(Error::EntryExists),
+            Err(at) => {
+                let mount = Mount {
+                    path,
+                    source: source.into(),
+                    driver,
+                };
+                self.mounts.insert(at, mount);
+                Ok(())
+            }
+        }
+    }
+
+    /// Removes the filesystem mounted at `path` and returns its driver.
+    /// Filesystems with others mounted below them can't be unmounted.
+    pub fn unmount(&mut self, path: &str) -> Result<Arc<dyn Driver>, Error> {
+        let path = absolute_path("/", path);
+        let at = self
+            .mounts
+            .iter()
+            .position(|mount| mount.path == path)
+            .ok_or(Error::NotFound)?;
+
+        if self
+            .mounts
+            .iter()
+            .any(|mount| mount.path != path && strip_mount(&mount.path, &path).is_some())
+        {
+            return Err(Error::NotEmpty);
+        }
+
+        Ok(self.mounts.remove(at).driver)
+    }
+
+    /// Removes every filesystem and returns their drivers, the ones mounted
+    /// below others first.
+    pub fn unmount_all(&mut self) -> Vec<Arc<dyn Driver>> {
+        self.mounts
+            .drain(..)
+            .rev()
+            .map(|mount| mount.driver)
+            .collect()
+    }
+
+    /// Finds the filesystem `path` belongs to, the one mounted at the
+    /// longest prefix of it, and returns its driver with the path within it.
+    pub fn resolve(&self, path: &str) -> Result<(Arc<dyn Driver>, String), Error> {
+        let path = absolute_path("/", path);
+
+        self.mounts
+            .iter()
+            .rev()
+            .find_map(|mount| {
+                let inner = strip_mount(&path, &mount.path)?;
+                Some((mount.driver.clone(), alloc::format!("/{inner}")))
+            })
+            .ok_or(Error::NotFound)
+    }
+
+    pub fn mounts(&self) -> Vec<MountInfo> {
+        self.mounts
+            .iter()
+            .map(|mount| MountInfo {
+                path: mount.path.clone(),
+                source: mount.source.clone(),
+                fs_type: mount.driver.fs_type(),
+            })
+            .collect()
+    }
+}
+
+/// Returns the rest of the absolute `path` if it's `mount_point` or below
+/// it, without the leading slash.
+fn strip_mount<'a>(path: &'a str, mount_point: &str) -> Option<&'a str> {
+    let rest = path.strip_prefix(mount_point)?;
+    if mount_point == "/" {
+        return Some(rest);
+    }
+
+    match rest.strip_prefix('/') {
+        Some(rest) => Some(rest),
+        None if rest.is_empty() => Some(rest),
+        // Only a common prefix like `/mnt` of `/mntx`.
+        None => None,
+    }
+}
@@ -0,0 +1,160 @@
+extern crate alloc;
+use super::{Driver, Error, FileType, INodeIndex, Metadata};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+/// A read-only filesystem exposing kernel state, the content of its files is
+/// generated whenever they're read.
+pub struct Procfs;
+
+/// Generates the content of a file.
+type Generator = fn() -> Vec<u8>;
+
+/// The files in the root of `Procfs` and how their content is generated.
+const FILES: &[(&str, Generator)] = &[
+    ("meminfo", meminfo),
+    ("uptime", uptime),
+    ("cpuinfo", cpuinfo),
+    ("memmap", memmap),
+    ("kmsg", crate::klog::recent),
+    ("processes", processes),
+    ("mounts", mounts),
+];
+
+fn meminfo() -> Vec<u8> {
+    let heap = crate::ALLOCATOR.stats();
+    let frames = crate::page_frame_allocator::stats();
+
+    let mut out = String::new();
+    let _ = writeln!(out, "HeapTotal:       {:>10} bytes", heap.total);
+    let _ = writeln!(out, "HeapUsed:        {:>10} bytes", heap.used);
+    let _ = writeln!(out, "HeapFree:        {:>10} bytes", heap.free);
+    let _ = writeln!(out, "HeapFreeBlocks:  {:>10}", heap.free_blocks);
+    let _ = writeln!(
+        out,
+        "HeapLargestFree: {:>10} bytes",
+        heap.largest_free_block
+    );
+    let _ = writeln!(out, "FramesTotal:     {:>10}", frames.total);
+    let _ = writeln!(out, "FramesUsed:      {:>10}", frames.used);
+    let _ = writeln!(
+        out,
+        "FrameSize:       {:>10} bytes",
+        virtual_memory::PAGE_SIZE
+    );
+    out.into_bytes()
+}
+
+fn uptime() -> Vec<u8> {
+    let ms = crate::timer::uptime_ms();
+    alloc::format!("{}.{:03}\n", ms / 1000, ms % 1000).into_bytes()
+}
+
+fn cpuinfo() -> Vec<u8> {
+    let mut out = String::new();
+    let _ = writeln!(out, "cpus:            {}", crate::device_tree::cpus());
+    let _ = writeln!(out, "isa:             {}", crate::device_tree::cpu_isa());
+    let _ = writeln!(
+        out,
+        "timer frequency: {} Hz",
+        crate::device_tree::timer_frequency()
+    );
+    out.into_bytes()
+}
+
+fn memmap() -> Vec<u8> {
+    let regions = [
+        ("memory", crate::device_tree::memory_regions()),
+        ("reserved", crate::device_tree::reserved_memory_regions()),
+        ("mmio", crate::device_tree::system_mmio_regions()),
+    ];
+
+    let mut out = String::new();
+    for (kind, ranges) in regions {
+        for range in ranges {
+            let _ = writeln!(out, "{:#012x}-{:#012x} {kind}", range.start(), range.end());
+        }
+    }
+    out.into_bytes()
+}
+
+fn processes() -> Vec<u8> {
+    let mut out = String::from("  PID  STATE\n");
+    for process in crate::scheduler::processes() {
+        let state = if process.running { "running" } else { "ready" };
+        let _ = writeln!(out, "{:>5}  {state}", process.id);
+    }
+    out.into_bytes()
+}
+
+fn mounts() -> Vec<u8> {
+    let mut out = String::new();
+    for mount in super::mounts() {
+        let _ = writeln!(out, "{} {} {}", mount.source, mount.path, mount.fs_type);
+    }
+    out.into_bytes()
+}
+
+impl Procfs {
+    fn file(path: &str) -> Result<(usize, Generator), Error> {
+        let name = path.trim_start_matches('/');
+        FILES
+            .iter()
+            .enumerate()
+            .find(|(_, (file, _))| *file == name)
+            .map(|(index, (_, generate))| (index, *generate))
+            .ok_or(Error::NotFound)
+    }
+}
+
+impl Driver for Procfs {
+    fn fs_type(&self) -> &'static str {
+        "proc"
+    }
+
+    fn stat(&self, path: &str) -> Result<Metadata, Error> {
+        let (inode, size, is_directory) = if path == "/" {
+            (INodeIndex::root(), FILES.len(), true)
+        } else {
+            let (index, generate) = Self::file(path)?;
+            (INodeIndex::new(index as u32 + 1), generate().len(), false)
+        };
+
+        Ok(Metadata {
+            inode,
+            size,
+            allocated_blocks: 0,
+            is_directory,
+            inline: false,
+            compressed: false,
+        })
+    }
+
+    fn read(&self, path: &str) -> Result<Vec<u8>, Error> {
+        if path == "/" {
+            return Err(Error::IsDirectory);
+        }
+        Self::file(path).map(|(_, generate)| generate())
+    }
+
+    fn write(&self, _path: &str, _bytes: &[u8]) -> Result<usize, Error> {
+        Err(Error::ReadOnly)
+    }
+
+    fn readdir(&self, path: &str) -> Result<Vec<String>, Error> {
+        if path != "/" {
+            Self::file(path)?;
+            return Err(Error::NotADirectory);
+        }
+        Ok(FILES.iter().map(|(name, _)| String::from(*name)).collect())
+    }
+
+    fn create(&self, _path: &str, _file_type: FileType) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    fn remove(&self, _path: &str) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+}
@@ -0,0 +1,50 @@
+extern crate alloc;
+use super::{Driver, Error, FileType, Metadata};
+use alloc::string::String;
+use alloc::vec::Vec;
+use ext2::Ext2;
+use fat32::Fat32;
+use filesystem::BlockDevice;
+
+/// Implements `Driver` for a filesystem that can only be read, with the
+/// `stat`, `read_bytes`, `read_at` and `read_dir` methods they all have.
+macro_rules! read_only_driver {
+    ($fs:ident, $fs_type:literal) => {
+        impl<D: BlockDevice + Send> Driver for $fs<D> {
+            fn fs_type(&self) -> &'static str {
+                $fs_type
+            }
+
+            fn stat(&self, path: &str) -> Result<Metadata, Error> {
+                $fs::stat(self, path)
+            }
+
+            fn read(&self, path: &str) -> Result<Vec<u8>, Error> {
+                self.read_bytes(path)
+            }
+
+            fn read_at(&self, path: &str, offset: usize, buf: &mut [u8]) -> Result<usize, Error> {
+                $fs::read_at(self, path, offset, buf)
+            }
+
+            fn write(&self, _path: &str, _bytes: &[u8]) -> Result<usize, Error> {
+                Err(Error::ReadOnly)
+            }
+
+            fn readdir(&self, path: &str) -> Result<Vec<String>, Error> {
+                self.read_dir(path)
+            }
+
+            fn create(&self, _path: &str, _file_type: FileType) -> Result<(), Error> {
+                Err(Error::ReadOnly)
+            }
+
+            fn remove(&self, _path: &str) -> Result<(), Error> {
+                Err(Error::ReadOnly)
+            }
+        }
+    };
+}
+
+read_only_driver!(Fat32, "fat32");
+read_only_driver!(Ext2, "ext2");
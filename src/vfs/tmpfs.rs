@@ -0,0 +1,230 @@
+extern crate alloc;
+use super::{Driver, Error, FileType, INodeIndex, Metadata};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// Largest file, so a write far past the end can't take the whole heap.
+const MAX_FILE_SIZE: usize = 1024 * 1024;
+
+/// A filesystem only kept on the kernel heap, its content is gone once it's
+/// unmounted.
+///
+/// Unlike LemonFS there is no limit on the length of names, files can grow
+/// up to `MAX_FILE_SIZE`. Directories list their entries
+/// sorted by name, their size is the number of entries.
+pub struct Tmpfs {
+    tree: Mutex<Tree>,
+}
+
+struct Tree {
+    root: Node,
+    next_inode: u32,
+}
+
+enum Node {
+    File {
+        inode: INodeIndex,
+        data: Vec<u8>,
+    },
+    Directory {
+        inode: INodeIndex,
+        entries: BTreeMap<String, Node>,
+    },
+}
+
+impl Tmpfs {
+    pub fn new() -> Self {
+        let root = Node::Directory {
+            inode: INodeIndex::root(),
+            entries: BTreeMap::new(),
+        };
+
+        Self {
+            tree: Mutex::new(Tree {
+                root,
+                next_inode: INodeIndex::root().inner() + 1,
+            }),
+        }
+    }
+}
+
+impl Node {
+    fn metadata(&self) -> Metadata {
+        let (inode, size, is_directory) = match self {
+            Node::File { inode, data } => (*inode, data.len(), false),
+            Node::Directory { inode, entries } => (*inode, entries.len(), true),
+        };
+
+        Metadata {
+            inode,
+            size,
+            allocated_blocks: 0,
+            is_directory,
+            inline: false,
+            compressed: false,
+        }
+    }
+}
+
+impl Tree {
+    fn node(&self, path: &str) -> Result<&Node, Error> {
+        let mut node = &self.root;
+        for name in components(path) {
+            node = match node {
+                Node::Directory { entries, .. } => entries.get(name).ok_or(Error::NotFound)?,
+                Node::File { .. } => return Err(Error::NotADirectory),
+            };
+        }
+        Ok(node)
+    }
+
+    fn node_mut(&mut self, path: &str) -> Result<&mut Node, Error> {
+        let mut node = &mut self.root;
+        for name in components(path) {
+            node = match node {
+                Node::Directory { entries, .. } => entries.get_mut(name).ok_or(Error::NotFound)?,
+                Node::File { .. } => return Err(Error::NotADirectory),
+            };
+        }
+        Ok(node)
+    }
+
+    /// Returns the entries of the parent directory of `path` and the name
+    /// of the last component, the root has none.
+    fn parent_mut<'a>(
+        &mut self,
+        path: &'a str,
+    ) -> Result<(&mut BTreeMap<String, Node>, &'a str), Error> {
+        let (parent, name) = match path.trim_end_matches('/').rsplit_once('/') {
+            Some((_, "")) | None => return Err(Error::NotFound),
+            Some(split) => split,
+        };
+
+        match self.node_mut(parent)? {
+            Node::Directory { entries, .. } => Ok((entries, name)),
+            Node::File { .. } => Err(Error::NotADirectory),
+        }
+    }
+
+    fn file_mut(&mut self, path: &str) -> Result<&mut Vec<u8>, Error> {
+        match self.node_mut(path)? {
+            Node::File { data, .. } => Ok(data),
+            Node::Directory { .. } => Err(Error::IsDirectory),
+        }
+    }
+}
+
+fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|s| !s.is_empty())
+}
+
+impl Driver for Tmpfs {
+    fn fs_type(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn stat(&self, path: &str) -> Result<Metadata, Error> {
+        Ok(self.tree.lock().node(path)?.metadata())
+    }
+
+    fn read(&self, path: &str) -> Result<Vec<u8>, Error> {
+        match self.tree.lock().node(path)? {
+            Node::File { data, .. } => Ok(data.clone()),
+            Node::Directory { .. } => Err(Error::IsDirectory),
+        }
+    }
+
+    fn write(&self, path: &str, bytes: &[u8]) -> Result<usize, Error> {
+        let mut tree = self.tree.lock();
+        let data = tree.file_mut(path)?;
+        if data.len() + bytes.len() > MAX_FILE_SIZE {
+            return Err(Error::FileTooLarge);
+        }
+        data.extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn write_at(&self, path: &str, offset: usize, bytes: &[u8]) -> Result<usize, Error> {
+        let mut tree = self.tree.lock();
+        let data = tree.file_mut(path)?;
+        let end = offset
+            .checked_add(bytes.len())
+            .filter(|end| *end <= MAX_FILE_SIZE)
+            .ok_or(Error::FileTooLarge)?;
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[offset..end].copy_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn readdir(&self, path: &str) -> Result<Vec<String>, Error> {
+        match self.tree.lock().node(path)? {
+            Node::Directory { entries, .. } => Ok(entries.keys().cloned().collect()),
+            Node::File { .. } => Err(Error::NotADirectory),
+        }
+    }
+
+    fn create(&self, path: &str, file_type: FileType) -> Result<(), Error> {
+        let mut tree = self.tree.lock();
+        let inode = INodeIndex::new(tree.next_inode);
+
+        let (entries, name) = tree.parent_mut(path)?;
+        if entries.contains_key(name) {
+            return Err(Error::EntryExists);
+        }
+
+        let node = match file_type {
+            FileType::File => Node::File {
+                inode,
+                data: Vec::new(),
+            },
+            FileType::Directory => Node::Directory {
+                inode,
+                entries: BTreeMap::new(),
+            },
+        };
+        entries.insert(name.into(), node);
+        tree.next_inode += 1;
+
+        Ok(())
+    }
+
+    fn remove(&self, path: &str) -> Result<(), Error> {
+        let mut tree = self.tree.lock();
+        let (entries, name) = tree.parent_mut(path)?;
+
+        match entries.get(name) {
+            None => Err(Error::NotFound),
+            Some(Node::Directory {
+                entries: children, ..
+            }) if !children.is_empty() => Err(Error::NotEmpty),
+            Some(_) => {
+                entries.remove(name);
+                Ok(())
+            }
+        }
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), Error> {
+        // A directory can't be moved below itself.
+        if to.starts_with(from) && to[from.len()..].starts_with('/') {
+            return Err(Error::OperationNotSupported);
+        }
+
+        let mut tree = self.tree.lock();
+        let (target, name) = tree.parent_mut(to)?;
+        if target.contains_key(name) {
+            return Err(Error::EntryExists);
+        }
+
+        let (entries, from_name) = tree.parent_mut(from)?;
+        let node = entries.remove(from_name).ok_or(Error::NotFound)?;
+
+        let (target, name) = tree.parent_mut(to).expect("Checked above");
+        target.insert(name.into(), node);
+        Ok(())
+    }
+}
@@ -56,9 +56,8 @@ pub struct LockedBlockDevice<'a> {
 }
 
 impl LockedBlockDevice<'_> {
-    fn new() -> Self {
-        let block_device_addr = device_tree::block_device_addr();
-        let header = NonNull::new(block_device_addr as *mut VirtIOHeader).unwrap();
+    fn new(addr: usize) -> Self {
+        let header = NonNull::new(addr as *mut VirtIOHeader).unwrap();
 
         let transport = unsafe { MmioTransport::new(header, 0x1000) }
             .unwrap_or_else(|e| panic!("Error creating VirtIO MMIO transport: {}", e));
@@ -85,8 +84,15 @@ impl LockedBlockDevice<'_> {
     }
 }
 
+/// Opens the block device holding the root filesystem.
 pub fn make_device() -> LockedBlockDevice<'static> {
-    LockedBlockDevice::new()
+    LockedBlockDevice::new(device_tree::block_device_addr())
+}
+
+/// Opens the `index`th block device given to QEMU, the root is the first.
+pub fn make_device_at(index: usize) -> Option<LockedBlockDevice<'static>> {
+    let addr = *device_tree::block_device_addrs().get(index)?;
+    Some(LockedBlockDevice::new(addr))
 }
 
 /// Simple allocator for the device. This is very simple as we're not having
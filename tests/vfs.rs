@@ -0,0 +1,254 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(lemon_shark::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+mod common;
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use lemon_shark::vfs::{self, Devfs, Driver, Error, FileType, Metadata, MountTable, Procfs, Tmpfs};
+use lemon_shark::{ALLOCATOR, trap_handler};
+
+global_asm!(
+    ".section .text.boot",
+    ".global _boot",
+    "_boot:",
+    "   la sp, _stack_top",
+    "   call _start",
+);
+
+#[unsafe(no_mangle)]
+pub extern "C" fn _start(_: usize, _: usize) -> ! {
+    let layout = common::init_kernel_layout();
+    trap_handler::init(layout);
+    unsafe { ALLOCATOR.init(layout) };
+
+    test_main();
+    loop {}
+}
+
+/// A driver without any files, only telling its mounts apart by name.
+struct Empty(&'static str);
+
+impl Driver for Empty {
+    fn fs_type(&self) -> &'static str {
+        self.0
+    }
+
+    fn stat(&self, _: &str) -> Result<Metadata, Error> {
+        Err(Error::NotFound)
+    }
+
+    fn read(&self, _: &str) -> Result<Vec<u8>, Error> {
+        Err(Error::NotFound)
+    }
+
+    fn write(&self, _: &str, _: &[u8]) -> Result<usize, Error> {
+        Err(Error::NotFound)
+    }
+
+    fn readdir(&self, _: &str) -> Result<Vec<String>, Error> {
+        Err(Error::NotFound)
+    }
+
+    fn create(&self, _: &str, _: FileType) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    fn remove(&self, _: &str) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+}
+
+fn resolved(table: &MountTable, path: &str) -> (&'static str, String) {
+    let (driver, inner) = table.resolve(path).unwrap();
+    (driver.fs_type(), inner)
+}
+
+#[test_case]
+fn paths_resolve_to_the_longest_mount() {
+    let mut table = MountTable::new();
+    assert_eq!(
+        table.mount("/mnt", "a", Arc::new(Empty("mnt"))),
+        Err(Error::NotFound)
+    );
+
+    table.mount("/", "disk", Arc::new(Empty("root"))).unwrap();
+    table.mount("/mnt", "a", Arc::new(Empty("mnt"))).unwrap();
+    table.mount("/mnt/b/", "b", Arc::new(Empty("b"))).unwrap();
+
+    assert_eq!(resolved(&table, "/"), ("root", "/".into()));
+    assert_eq!(
+        resolved(&table, "/mntx/file"),
+        ("root", "/mntx/file".into())
+    );
+    assert_eq!(resolved(&table, "/mnt"), ("mnt", "/".into()));
+    assert_eq!(resolved(&table, "/mnt/a/file"), ("mnt", "/a/file".into()));
+    assert_eq!(resolved(&table, "/mnt/b/c/../file"), ("b", "/file".into()));
+    assert_eq!(resolved(&table, "/mnt/b/.."), ("mnt", "/".into()));
+
+    let mounts = table.mounts();
+    assert_eq!(mounts.len(), 3);
+    assert_eq!(mounts[2].path, "/mnt/b");
+    assert_eq!(mounts[2].source, "b");
+}
+
+#[test_case]
+fn only_leaf_mounts_can_be_unmounted() {
+    let mut table = MountTable::new();
+    table.mount("/", "disk", Arc::new(Empty("root"))).unwrap();
+    table.mount("/mnt", "a", Arc::new(Empty("mnt"))).unwrap();
+    table.mount("/mnt/b", "b", Arc::new(Empty("b"))).unwrap();
+
+    assert_eq!(
+        table.mount("/mnt", "c", Arc::new(Empty("c"))).err(),
+        Some(Error::EntryExists)
+    );
+    assert_eq!(table.unmount("/mnt").err(), Some(Error::NotEmpty));
+    assert_eq!(table.unmount("/other").err(), Some(Error::NotFound));
+
+    assert_eq!(table.unmount("/mnt/b").unwrap().fs_type(), "b");
+    assert_eq!(table.unmount("/mnt").unwrap().fs_type(), "mnt");
+    assert_eq!(resolved(&table, "/mnt/b"), ("root", "/mnt/b".into()));
+
+    table.mount("/mnt", "a", Arc::new(Empty("mnt"))).unwrap();
+    let drivers = table.unmount_all();
+    let order: Vec<_> = drivers.iter().map(|driver| driver.fs_type()).collect();
+    assert_eq!(order, ["mnt", "root"]);
+    assert!(table.mounts().is_empty());
+}
+
+#[test_case]
+fn absolute_paths_are_lexical() {
+    assert_eq!(vfs::absolute_path("/usr/bin", "../lib//x"), "/usr/lib/x");
+    assert_eq!(vfs::absolute_path("/usr", "/etc/./../tmp/"), "/tmp");
+    assert_eq!(vfs::absolute_path("/", ".."), "/");
+    assert_eq!(vfs::absolute_path("/tmp", ""), "/tmp");
+}
+
+#[test_case]
+fn tmpfs_keeps_files_in_memory() {
+    let tmpfs = Tmpfs::new();
+    let long_name = "a-name-longer-than-lemonfs-allows.txt";
+    let path = alloc::format!("/dir/{long_name}");
+
+    tmpfs.create("/dir", FileType::Directory).unwrap();
+    tmpfs.create(&path, FileType::File).unwrap();
+    assert_eq!(
+        tmpfs.create("/dir", FileType::File),
+        Err(Error::EntryExists)
+    );
+    assert_eq!(
+        tmpfs.create("/missing/file", FileType::File),
+        Err(Error::NotFound)
+    );
+
+    let content = alloc::vec![b'x'; 3 * 8192];
+    assert_eq!(tmpfs.write(&path, &content), Ok(content.len()));
+    assert_eq!(tmpfs.write(&path, b"!"), Ok(1));
+    assert_eq!(tmpfs.read(&path).unwrap().len(), content.len() + 1);
+    assert_eq!(tmpfs.stat(&path).unwrap().size, content.len() + 1);
+    assert_eq!(tmpfs.read("/dir"), Err(Error::IsDirectory));
+
+    assert_eq!(tmpfs.write_at(&path, content.len() - 1, b"yz"), Ok(2));
+    let mut buf = [0; 3];
+    assert_eq!(tmpfs.read_at(&path, content.len() - 2, &mut buf), Ok(3));
+    assert_eq!(&buf, b"xyz");
+    assert_eq!(
+        tmpfs.write_at(&path, usize::MAX, b"x"),
+        Err(Error::FileTooLarge)
+    );
+    assert_eq!(
+        tmpfs.write_at(&path, 1 << 40, b"x"),
+        Err(Error::FileTooLarge)
+    );
+
+    tmpfs.create("/dir/b", FileType::File).unwrap();
+    assert_eq!(tmpfs.readdir("/dir").unwrap(), [long_name, "b"]);
+    assert_eq!(tmpfs.readdir(&path), Err(Error::NotADirectory));
+
+    assert_eq!(tmpfs.remove("/dir"), Err(Error::NotEmpty));
+    tmpfs.rename("/dir/b", "/c").unwrap();
+    assert_eq!(
+        tmpfs.rename("/dir", "/dir/sub"),
+        Err(Error::OperationNotSupported)
+    );
+    tmpfs.remove(&path).unwrap();
+    tmpfs.remove("/dir").unwrap();
+    assert_eq!(tmpfs.readdir("/").unwrap(), ["c"]);
+    assert_eq!(tmpfs.remove("/"), Err(Error::NotFound));
+}
+
+#[test_case]
+fn procfs_is_read_only() {
+    let names = Procfs.readdir("/").unwrap();
+    assert!(names.iter().any(|name| name == "meminfo"));
+    assert!(names.iter().any(|name| name == "kmsg"));
+    assert!(Procfs.stat("/").unwrap().is_directory);
+    assert!(!Procfs.stat("/kmsg").unwrap().is_directory);
+    assert!(Procfs.read("/kmsg").is_ok());
+
+    assert_eq!(Procfs.stat("/missing"), Err(Error::NotFound));
+    assert_eq!(Procfs.readdir("/kmsg"), Err(Error::NotADirectory));
+    assert_eq!(Procfs.write("/kmsg", b"x"), Err(Error::ReadOnly));
+    assert_eq!(Procfs.create("/file", FileType::File), Err(Error::ReadOnly));
+    assert_eq!(Procfs.remove("/kmsg"), Err(Error::ReadOnly));
+}
+
+#[test_case]
+fn devfs_character_devices() {
+    assert_eq!(Devfs.read("/null").unwrap(), []);
+    assert_eq!(Devfs.write("/null", b"gone"), Ok(4));
+
+    let mut buf = [1; 16];
+    assert_eq!(Devfs.read_at("/zero", 100, &mut buf), Ok(16));
+    assert_eq!(buf, [0; 16]);
+
+    let first = Devfs.read("/random").unwrap();
+    assert_ne!(first, Devfs.read("/random").unwrap());
+    assert!(!Devfs.stat("/random").unwrap().is_directory);
+
+    assert!(
+        Devfs
+            .readdir("/")
+            .unwrap()
+            .iter()
+            .any(|name| name == "ram0")
+    );
+    assert_eq!(Devfs.read("/ram0"), Err(Error::FileTooLarge));
+    assert_eq!(Devfs.stat("/missing"), Err(Error::NotFound));
+    assert_eq!(
+        Devfs.create("/file", FileType::File),
+        Err(Error::OperationNotSupported)
+    );
+}
+
+#[test_case]
+fn devfs_block_devices_at_offsets() {
+    vfs::mount_root("dev", Arc::new(Devfs)).unwrap();
+
+    // Crosses a block boundary, so both blocks are only partially written.
+    let offset = lemon_shark::filesystem::BLOCK_SIZE - 3;
+    assert_eq!(vfs::write_at("/ram0", offset, b"lemonade"), Ok(8));
+
+    let mut buf = [0; 8];
+    assert_eq!(vfs::read_at("/ram0", offset, &mut buf), Ok(8));
+    assert_eq!(&buf, b"lemonade");
+
+    let size = vfs::stat("/ram0").unwrap().size;
+    assert_eq!(vfs::read_at("/ram0", size - 2, &mut buf), Ok(2));
+    assert_eq!(
+        vfs::write_at("/ram0", size - 2, b"abc"),
+        Err(Error::NoSpaceLeft)
+    );
+    assert_eq!(
+        vfs::write_at("/ram0", usize::MAX, b"abc"),
+        Err(Error::NoSpaceLeft)
+    );
+}